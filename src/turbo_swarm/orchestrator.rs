@@ -11,12 +11,29 @@
 //! - Task Queue: Priority-based DAG execution
 //! - Cost Optimizer: Model selection, prompt caching, batching
 
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use tokio::sync::{RwLock, mpsc};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering as AtomicOrdering};
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify, RwLock, Semaphore, broadcast, mpsc};
+use tokio::time::Instant;
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, Span, error, info, info_span, instrument, warn};
+
+pub mod blocking;
+pub mod metrics;
+#[cfg(feature = "server")]
+pub mod server;
+
+use metrics::MetricsRegistry;
 
 // ============================================================================
 // CORE TYPES
@@ -26,6 +43,42 @@ pub type SessionId = Uuid;
 pub type AgentId = Uuid;
 pub type TaskId = Uuid;
 pub type UserId = String;
+/// A task together with the session it belongs to
+pub type SessionTask = (SessionId, TaskId);
+
+/// Source of new session, agent and task ids
+pub trait IdGenerator: Send + Sync {
+    fn next_id(&self) -> Uuid;
+}
+
+/// Default source: random v4 UUIDs
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RandomIdGenerator;
+
+impl IdGenerator for RandomIdGenerator {
+    fn next_id(&self) -> Uuid {
+        Uuid::new_v4()
+    }
+}
+
+/// Hands out 1, 2, 3, ... as UUIDs, so tests can predict ids. Share one
+/// between the manager, pool and queue to keep ids unique across all three.
+#[derive(Debug, Default)]
+pub struct SequentialIdGenerator {
+    issued: AtomicU64,
+}
+
+impl SequentialIdGenerator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl IdGenerator for SequentialIdGenerator {
+    fn next_id(&self) -> Uuid {
+        Uuid::from_u128(u128::from(self.issued.fetch_add(1, AtomicOrdering::Relaxed) + 1))
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Session {
@@ -33,9 +86,290 @@ pub struct Session {
     pub user_id: UserId,
     pub created_at: DateTime<Utc>,
     pub status: SessionStatus,
-    pub agents: Vec<AgentHandle>,
+    /// Changed only through the methods keeping `agent_counts` in step
+    agents: Vec<AgentHandle>,
+    pub project_spec: ProjectSpec,
+    /// Session this one was split off from by `create_subsession`
+    #[serde(default)]
+    pub parent_id: Option<SessionId>,
+    /// Mode the session is downshifted to under resource pressure; `None`
+    /// while it runs as `project_spec.parallelization` asks
+    #[serde(default)]
+    pub effective_mode: Option<ParallelizationMode>,
+    // Live handle only; rebuilt from the state space on restore
+    #[serde(skip, default = "SharedState::detached")]
     pub shared_state: Arc<SharedState>,
+    // Live handle only; re-synced with `status` on restore
+    #[serde(skip)]
+    pub control: Arc<SessionControl>,
+    // Live handle only; parent of the session's agent spans, reopened on restore
+    #[serde(skip, default = "Span::none")]
+    pub span: Span,
     pub metrics: SessionMetrics,
+    /// How `dispatch_ready` spreads this session's tasks over its coders
+    #[serde(default)]
+    pub assignment: AssignmentStrategy,
+    /// Smoothed task duration behind `SessionStatusReport::eta`
+    #[serde(default)]
+    pub eta: EtaEstimator,
+    /// Operator labels such as `env=prod`, for `SessionFilter::tags`; start
+    /// as `project_spec.tags`
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+    /// Times the session has been exported with `export_for_migration`;
+    /// each bundle can be imported once, at the epoch it was exported at
+    #[serde(default)]
+    pub epoch: u64,
+    /// Position of the next coder for `AssignmentStrategy::RoundRobin`
+    #[serde(skip)]
+    next_coder: usize,
+    /// `agents` by status, kept current by the methods that add, remove or
+    /// move agents; recounted on restore
+    #[serde(skip)]
+    agent_counts: StatusCounts,
+    /// Held in `Initializing` until `project_spec.readiness` passes, and not
+    /// admitted before then
+    #[serde(skip)]
+    awaiting_readiness: bool,
+    /// Agents of the initial roster still to come online under
+    /// `SessionManager::with_spawn_rate`; counted in the user's quota
+    #[serde(skip)]
+    agents_starting: usize,
+    /// Last task started or settled, for `SessionManager::reap_idle_sessions`;
+    /// restarts on restore
+    #[serde(skip, default = "Instant::now")]
+    last_activity: Instant,
+    /// Agents `reap_idle_sessions` handed back to the pool on pausing the
+    /// session, as (role, model, skills); `resume_session` spawns them again
+    #[serde(default)]
+    parked: Vec<(AgentRole, ModelPreference, Vec<String>)>,
+}
+
+/// A session's agents by status
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatusCounts {
+    pub idle: usize,
+    pub working: usize,
+    pub blocked: usize,
+    pub failed: usize,
+}
+
+impl StatusCounts {
+    fn of<'a>(agents: impl IntoIterator<Item = &'a AgentHandle>) -> Self {
+        let mut counts = Self::default();
+        for agent in agents {
+            counts.add(agent.status);
+        }
+        counts
+    }
+
+    pub fn total(&self) -> usize {
+        self.idle + self.working + self.blocked + self.failed
+    }
+
+    fn slot(&mut self, status: AgentStatus) -> &mut usize {
+        match status {
+            AgentStatus::Idle => &mut self.idle,
+            AgentStatus::Working => &mut self.working,
+            AgentStatus::Blocked => &mut self.blocked,
+            AgentStatus::Failed => &mut self.failed,
+        }
+    }
+
+    fn add(&mut self, status: AgentStatus) {
+        *self.slot(status) += 1;
+    }
+
+    fn remove(&mut self, status: AgentStatus) {
+        let slot = self.slot(status);
+        *slot = slot.saturating_sub(1);
+    }
+
+    fn absorb(&mut self, other: &StatusCounts) {
+        self.idle += other.idle;
+        self.working += other.working;
+        self.blocked += other.blocked;
+        self.failed += other.failed;
+    }
+}
+
+/// Picks which of a session's coders gets its next dispatched task
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AssignmentStrategy {
+    /// The coder with the fewest tasks in flight, then the fewest completed
+    #[default]
+    LeastLoaded,
+    /// Each coder in turn, however busy
+    RoundRobin,
+    /// Any coder, uniformly
+    Random,
+}
+
+impl AssignmentStrategy {
+    /// Index into `coders`, given as (tasks in flight, tasks completed)
+    fn choose(self, coders: &[(usize, usize)], next: usize) -> Option<usize> {
+        if coders.is_empty() {
+            return None;
+        }
+        match self {
+            AssignmentStrategy::LeastLoaded => (0..coders.len()).min_by_key(|&i| coders[i]),
+            AssignmentStrategy::RoundRobin => Some(next % coders.len()),
+            AssignmentStrategy::Random => Some(rand::Rng::gen_range(&mut rand::thread_rng(), 0..coders.len())),
+        }
+    }
+}
+
+impl SessionMetrics {
+    /// Mean duration of completed tasks, once there are any
+    pub fn avg_task_duration_sec(&self) -> Option<f64> {
+        (self.tasks_completed > 0 && self.total_duration_sec > 0.0)
+            .then(|| self.total_duration_sec / self.tasks_completed as f64)
+    }
+
+    pub fn throughput_tasks_per_sec(&self) -> f64 {
+        if self.total_duration_sec > 0.0 {
+            self.tasks_completed as f64 / self.total_duration_sec
+        } else {
+            0.0
+        }
+    }
+
+    /// Add `other`'s counts and totals into these
+    fn absorb(&mut self, other: &SessionMetrics) {
+        self.tasks_assigned += other.tasks_assigned;
+        self.tasks_completed += other.tasks_completed;
+        self.tasks_failed += other.tasks_failed;
+        self.total_cost += other.total_cost;
+        self.total_duration_sec += other.total_duration_sec;
+        self.agents_spawned += other.agents_spawned;
+        self.cost_saved += other.cost_saved;
+        self.rate_limited_sec += other.rate_limited_sec;
+        self.verification_failed += other.verification_failed;
+        for (role, cost) in &other.cost_by_role {
+            *self.cost_by_role.entry(*role).or_default() += cost;
+        }
+        for (model, cost) in &other.cost_by_model {
+            *self.cost_by_model.entry(*model).or_default() += cost;
+        }
+    }
+
+    /// Add a completed task's cost to the total and its breakdowns
+    fn charge(&mut self, cost: f64, role: Option<AgentRole>, model: Option<ModelPreference>) {
+        self.total_cost += cost;
+        if let Some(role) = role {
+            *self.cost_by_role.entry(role).or_default() += cost;
+        }
+        if let Some(model) = model {
+            *self.cost_by_model.entry(model).or_default() += cost;
+        }
+    }
+}
+
+impl Session {
+    fn span_for(session_id: SessionId, user_id: &str) -> Span {
+        info_span!("session", %session_id, user_id)
+    }
+
+    /// Set `status`, holding or releasing the session's agents to match
+    fn set_status(&mut self, status: SessionStatus) {
+        self.status = status;
+        if status == SessionStatus::Paused {
+            self.control.pause();
+        } else {
+            self.control.resume();
+        }
+    }
+
+    /// Waiting in `Initializing` for `admit_queued_sessions` to start it
+    fn is_queued(&self) -> bool {
+        self.status == SessionStatus::Initializing && !self.awaiting_readiness
+    }
+
+    fn verifiers(&self) -> Vec<AgentId> {
+        self.agents.iter().filter(|a| a.role == AgentRole::Verifier).map(|a| a.id).collect()
+    }
+
+    pub fn agents(&self) -> &[AgentHandle] {
+        &self.agents
+    }
+
+    /// Agents by status, without walking `agents`
+    pub fn agent_counts(&self) -> StatusCounts {
+        self.agent_counts
+    }
+
+    /// Agents by status, counted from `agents`: the slow path, for checking
+    /// `agent_counts` against
+    pub fn recount(&self) -> StatusCounts {
+        StatusCounts::of(&self.agents)
+    }
+
+    fn push_agent(&mut self, agent: AgentHandle) {
+        self.agent_counts.add(agent.status);
+        self.agents.push(agent);
+    }
+
+    fn remove_agent(&mut self, agent_id: AgentId) -> Option<AgentHandle> {
+        let index = self.agents.iter().position(|a| a.id == agent_id)?;
+        let agent = self.agents.remove(index);
+        self.agent_counts.remove(agent.status);
+        Some(agent)
+    }
+
+    fn replace_agent(&mut self, index: usize, agent: AgentHandle) {
+        self.agent_counts.remove(self.agents[index].status);
+        self.agent_counts.add(agent.status);
+        self.agents[index] = agent;
+    }
+
+    fn set_agents(&mut self, agents: Vec<AgentHandle>) {
+        self.agent_counts = StatusCounts::of(&agents);
+        self.agents = agents;
+    }
+
+    /// `AgentHandle::transition` on one of the session's agents, keeping
+    /// `agent_counts` current
+    fn transition_agent(&mut self, agent_id: AgentId, to: AgentStatus) -> Result<&mut AgentHandle, SwarmError> {
+        let agent = self.agents.iter_mut()
+            .find(|a| a.id == agent_id)
+            .ok_or(SwarmError::AgentNotFound)?;
+        let from = agent.status;
+        agent.transition(to)?;
+        self.agent_counts.remove(from);
+        self.agent_counts.add(to);
+        Ok(agent)
+    }
+
+    /// `AgentHandle::recover` on one of the session's failed agents, keeping
+    /// `agent_counts` current
+    fn recover_agent(&mut self, agent_id: AgentId) -> Result<(), SwarmError> {
+        let agent = self.agents.iter_mut()
+            .find(|a| a.id == agent_id)
+            .ok_or(SwarmError::AgentNotFound)?;
+        if agent.status != AgentStatus::Failed {
+            return Err(SwarmError::InvalidTransition { agent_id, from: agent.status, to: AgentStatus::Idle });
+        }
+        agent.recover();
+        self.agent_counts.remove(AgentStatus::Failed);
+        self.agent_counts.add(AgentStatus::Idle);
+        Ok(())
+    }
+
+    /// `transition_agent`, logging a rejected move like
+    /// `AgentHandle::transition_or_log`. `None` if the agent isn't here.
+    fn transition_agent_or_log(&mut self, agent_id: AgentId, to: AgentStatus) -> Option<&mut AgentHandle> {
+        let agent = self.agents.iter_mut().find(|a| a.id == agent_id)?;
+        let from = agent.status;
+        agent.transition_or_log(to);
+        self.agent_counts.remove(from);
+        self.agent_counts.add(agent.status);
+        Some(agent)
+    }
+
+    fn over_budget(&self) -> bool {
+        self.project_spec.budget_usd
+            .is_some_and(|cap| self.metrics.total_cost > cap)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
@@ -43,11 +377,12 @@ pub enum SessionStatus {
     Initializing,
     Active,
     Paused,
+    Draining,
     Completed,
     Failed,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct SessionMetrics {
     pub tasks_assigned: usize,
     pub tasks_completed: usize,
@@ -55,6 +390,22 @@ pub struct SessionMetrics {
     pub total_cost: f64,
     pub total_duration_sec: f64,
     pub agents_spawned: usize,
+    /// Spend avoided by serving prompts from the cache
+    #[serde(default)]
+    pub cost_saved: f64,
+    /// Time agents spent queued on model rate limits, the session's own
+    /// `max_requests_per_minute` included
+    #[serde(default)]
+    pub rate_limited_sec: f64,
+    /// Coder results rejected by a verifier and sent back to the queue
+    #[serde(default)]
+    pub verification_failed: usize,
+    /// `total_cost` split by the role of the agent that ran each task
+    #[serde(default)]
+    pub cost_by_role: HashMap<AgentRole, f64>,
+    /// `total_cost` split by the model that served each task
+    #[serde(default)]
+    pub cost_by_model: HashMap<ModelPreference, f64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -65,9 +416,96 @@ pub struct AgentHandle {
     pub status: AgentStatus,
     pub tasks_completed: usize,
     pub cost_incurred: f64,
+    /// Model that actually served each completed task, after any fallback
+    #[serde(default)]
+    pub models_used: HashMap<TaskId, ModelPreference>,
+    /// Last sign of life from the agent's task, as of the latest health sweep
+    #[serde(default = "Utc::now")]
+    pub last_heartbeat: DateTime<Utc>,
+    /// What the agent specializes in within its role, e.g. `"rust"`
+    #[serde(default)]
+    pub skills: Vec<String>,
+    /// What a `Blocked` agent is waiting on, e.g. a shared-state key
+    #[serde(default)]
+    pub blocked_on: Option<String>,
+    /// Affinity keys of the tasks this agent ran most recently, newest
+    /// first, at most `AFFINITY_CACHE_SIZE`
+    #[serde(default)]
+    pub affinity: VecDeque<String>,
+    /// What the agent's model supports, as its provider answered at spawn
+    #[serde(default)]
+    pub capabilities: Capabilities,
+    /// Work in progress on the current task, private to the agent's loop:
+    /// every clone of the handle has its own, none reaches `SharedState`, and
+    /// the loop clears its copy whenever a task finishes
+    #[serde(skip)]
+    scratch: HashMap<String, String>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+/// Affinity keys remembered per agent for `Task::affinity_key` routing
+pub const AFFINITY_CACHE_SIZE: usize = 8;
+
+/// An agent caught in a deadlock, and what it was waiting on
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockedAgent {
+    pub agent_id: AgentId,
+    pub waiting_on: Option<String>,
+}
+
+impl AgentHandle {
+    /// A note from this handle's scratch space
+    pub fn scratch(&self, key: &str) -> Option<&str> {
+        self.scratch.get(key).map(String::as_str)
+    }
+
+    pub fn set_scratch(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.scratch.insert(key.into(), value.into());
+    }
+
+    pub fn clear_scratch(&mut self) {
+        self.scratch.clear();
+    }
+
+    /// Whether the agent has every skill in `required`
+    fn covers(&self, required: &[String]) -> bool {
+        required.iter().all(|skill| self.skills.contains(skill))
+    }
+
+    /// Remember running a task with affinity `key`, evicting the oldest key
+    /// once the cache is full
+    fn remember_affinity(&mut self, key: &str) {
+        self.affinity.retain(|k| k != key);
+        self.affinity.push_front(key.to_string());
+        self.affinity.truncate(AFFINITY_CACHE_SIZE);
+    }
+
+    /// Move to status `to`, failing with `InvalidTransition` if
+    /// `AgentStatus::can_transition_to` forbids it
+    pub fn transition(&mut self, to: AgentStatus) -> Result<(), SwarmError> {
+        if !self.status.can_transition_to(to) {
+            return Err(SwarmError::InvalidTransition { agent_id: self.id, from: self.status, to });
+        }
+        self.status = to;
+        Ok(())
+    }
+
+    /// `transition` for reports racing a sweep that has already failed the
+    /// agent: a rejected move is logged and the status left alone
+    fn transition_or_log(&mut self, to: AgentStatus) {
+        if let Err(e) = self.transition(to) {
+            warn!(agent_id = %self.id, error = %e, "agent status change rejected");
+        }
+    }
+
+    /// Put a `Failed` agent back to `Idle`, the one way out of `Failed`
+    fn recover(&mut self) {
+        if self.status == AgentStatus::Failed {
+            self.status = AgentStatus::Idle;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum AgentRole {
     Planner,
     Coder,
@@ -76,14 +514,38 @@ pub enum AgentRole {
     Verifier,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum ModelPreference {
     GPT51,          // Fast planning
     ClaudeOpus45,   // Complex coding
     Gemini3Pro,     // Test generation
+    #[default]
     None,           // Browser automation
 }
 
+impl ModelPreference {
+    /// Models to try in order, starting with this one, when a provider is down
+    pub fn fallback_chain(self) -> &'static [ModelPreference] {
+        match self {
+            ModelPreference::GPT51 => &[ModelPreference::GPT51, ModelPreference::ClaudeOpus45],
+            ModelPreference::ClaudeOpus45 => &[ModelPreference::ClaudeOpus45, ModelPreference::GPT51],
+            ModelPreference::Gemini3Pro => &[ModelPreference::Gemini3Pro, ModelPreference::GPT51],
+            ModelPreference::None => &[ModelPreference::None],
+        }
+    }
+}
+
+/// Where an agent is in its lifecycle. Changes go through
+/// `AgentHandle::transition`, which allows:
+///
+/// - `Idle` → `Working`, `Blocked` or `Failed`
+/// - `Working` → `Idle`, `Blocked` or `Failed`
+/// - `Blocked` → `Idle`, `Working` or `Failed`
+/// - `Failed` → nowhere: only `SessionManager::recover_agent` brings a
+///   failed agent back, or a replacement takes its place
+///
+/// Staying put is always allowed. A late report from a failed agent never
+/// makes it look healthy, so it isn't given work again.
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum AgentStatus {
     Idle,
@@ -92,6 +554,13 @@ pub enum AgentStatus {
     Failed,
 }
 
+impl AgentStatus {
+    /// Whether an agent in this status may move to `to`
+    pub fn can_transition_to(self, to: AgentStatus) -> bool {
+        self == to || self != AgentStatus::Failed
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProjectSpec {
     pub name: String,
@@ -100,6 +569,211 @@ pub struct ProjectSpec {
     pub parallelization: ParallelizationMode,
     pub requires_browser: bool,
     pub estimated_complexity: Complexity,
+    // `budget_policy` kicks in once total_cost exceeds this
+    #[serde(default)]
+    pub budget_usd: Option<f64>,
+    #[serde(default)]
+    pub budget_policy: BudgetPolicy,
+    /// Collapse equivalent tasks enqueued for the session into one (see
+    /// `TaskQueue::set_dedup`)
+    #[serde(default)]
+    pub dedup_tasks: bool,
+    /// Labels the session starts with; see `SessionManager::set_tags`
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+    /// URL the session's final `SessionMetrics` are POSTed to, as JSON, once
+    /// it is `Completed` or `Failed`; see `deliver_webhook`
+    #[serde(default)]
+    pub completion_webhook: Option<String>,
+    /// Key for the HMAC-SHA256 signature of the webhook body, sent in
+    /// `WEBHOOK_SIGNATURE_HEADER`; unsigned without one. Read but never
+    /// written: checkpoints, snapshots and migration bundles leave it out,
+    /// so a session brought back from one delivers unsigned.
+    #[serde(default, skip_serializing)]
+    pub webhook_secret: Option<String>,
+    /// Most model requests the session's agents send per minute, across all
+    /// models and on top of the global `RateLimiter`; past it, they wait
+    #[serde(default)]
+    pub max_requests_per_minute: Option<u32>,
+    /// External dependency the session waits on, in `Initializing`, before
+    /// its agents start. Live only: a restored session doesn't wait again.
+    #[serde(skip)]
+    pub readiness: Option<ReadinessGate>,
+    /// Hard end of the session: tasks that couldn't finish by then aren't
+    /// started, and at the deadline the session fails with what it has
+    /// done; see `SessionManager::dispatch_ready`
+    #[serde(default)]
+    pub deadline: Option<DateTime<Utc>>,
+}
+
+/// What happens when a session's spend goes over `ProjectSpec::budget_usd`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BudgetPolicy {
+    /// Stop starting tasks; `resume_session` refuses while over budget
+    #[default]
+    PauseOnExceed,
+    /// Destroy the session, abandoning its in-flight tasks, so nothing more
+    /// is spent
+    KillOnExceed,
+    /// Keep running; only log and emit `SwarmEvent::BudgetBreached`
+    WarnOnly,
+}
+
+/// Turbo mode spawns this many agents per replicated instance
+const TURBO_AGENTS_PER_INSTANCE: usize = 10;
+
+impl ProjectSpec {
+    pub fn builder(name: impl Into<String>) -> ProjectSpecBuilder {
+        ProjectSpecBuilder::new(name)
+    }
+
+    /// Reject specs that can't produce a working session
+    pub fn validate(&self) -> Result<(), SwarmError> {
+        if self.name.trim().is_empty() {
+            return Err(SwarmError::InvalidSpec("name must not be empty".to_string()));
+        }
+        if self.replication_count == 0 {
+            return Err(SwarmError::InvalidSpec("replication_count must be at least 1".to_string()));
+        }
+        if let Some(budget) = self.budget_usd {
+            if !(budget.is_finite() && budget > 0.0) {
+                return Err(SwarmError::InvalidSpec(format!("budget_usd must be positive, got {budget}")));
+            }
+        }
+        if let Some(deadline) = self.deadline {
+            if deadline <= Utc::now() {
+                return Err(SwarmError::InvalidSpec(format!("deadline {deadline} has already passed")));
+            }
+        }
+        if self.max_requests_per_minute == Some(0) {
+            return Err(SwarmError::InvalidSpec("max_requests_per_minute must be positive".to_string()));
+        }
+        // Set directly rather than through `ReadinessGate::with_interval`
+        if self.readiness.as_ref().is_some_and(|gate| gate.interval.is_zero()) {
+            return Err(SwarmError::InvalidSpec("readiness interval must be positive".to_string()));
+        }
+        if let Some(url) = &self.completion_webhook {
+            let web = reqwest::Url::parse(url)
+                .is_ok_and(|u| matches!(u.scheme(), "http" | "https") && u.host_str().is_some());
+            if !web {
+                return Err(SwarmError::InvalidSpec(format!("completion_webhook must be an http(s) URL, got {url}")));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fluent construction of a validated `ProjectSpec`. Unset fields default to
+/// one sequential, medium-complexity `SoftwareDev` instance with no budget.
+#[derive(Debug, Clone)]
+pub struct ProjectSpecBuilder {
+    spec: ProjectSpec,
+}
+
+impl ProjectSpecBuilder {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            spec: ProjectSpec {
+                name: name.into(),
+                template: TemplateType::SoftwareDev,
+                replication_count: 1,
+                parallelization: ParallelizationMode::Sequential,
+                requires_browser: false,
+                estimated_complexity: Complexity::Medium,
+                budget_usd: None,
+                budget_policy: BudgetPolicy::default(),
+                dedup_tasks: false,
+                tags: HashMap::new(),
+                completion_webhook: None,
+                webhook_secret: None,
+                max_requests_per_minute: None,
+                readiness: None,
+                deadline: None,
+            },
+        }
+    }
+
+    pub fn template(mut self, template: TemplateType) -> Self {
+        self.spec.template = template;
+        self
+    }
+
+    pub fn replication_count(mut self, count: usize) -> Self {
+        self.spec.replication_count = count;
+        self
+    }
+
+    pub fn parallelization(mut self, mode: ParallelizationMode) -> Self {
+        self.spec.parallelization = mode;
+        self
+    }
+
+    pub fn requires_browser(mut self, required: bool) -> Self {
+        self.spec.requires_browser = required;
+        self
+    }
+
+    pub fn complexity(mut self, complexity: Complexity) -> Self {
+        self.spec.estimated_complexity = complexity;
+        self
+    }
+
+    pub fn budget_usd(mut self, budget: f64) -> Self {
+        self.spec.budget_usd = Some(budget);
+        self
+    }
+
+    pub fn budget_policy(mut self, policy: BudgetPolicy) -> Self {
+        self.spec.budget_policy = policy;
+        self
+    }
+
+    pub fn dedup_tasks(mut self, dedup: bool) -> Self {
+        self.spec.dedup_tasks = dedup;
+        self
+    }
+
+    pub fn tag(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.spec.tags.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn completion_webhook(mut self, url: impl Into<String>) -> Self {
+        self.spec.completion_webhook = Some(url.into());
+        self
+    }
+
+    pub fn webhook_secret(mut self, secret: impl Into<String>) -> Self {
+        self.spec.webhook_secret = Some(secret.into());
+        self
+    }
+
+    pub fn max_requests_per_minute(mut self, requests: u32) -> Self {
+        self.spec.max_requests_per_minute = Some(requests);
+        self
+    }
+
+    pub fn readiness(mut self, gate: ReadinessGate) -> Self {
+        self.spec.readiness = Some(gate);
+        self
+    }
+
+    pub fn deadline(mut self, deadline: DateTime<Utc>) -> Self {
+        self.spec.deadline = Some(deadline);
+        self
+    }
+
+    /// Validate and return the spec. In Turbo mode, `replication_count` is
+    /// clamped to what fits under `MAX_AGENTS_PER_SESSION`; the roster those
+    /// instances add up to is held under it too (see `compute_agent_counts`).
+    pub fn build(mut self) -> Result<ProjectSpec, SwarmError> {
+        self.spec.validate()?;
+        if self.spec.parallelization == ParallelizationMode::Turbo {
+            let max_instances = MAX_AGENTS_PER_SESSION / TURBO_AGENTS_PER_INSTANCE;
+            self.spec.replication_count = self.spec.replication_count.min(max_instances);
+        }
+        Ok(self.spec)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
@@ -110,7 +784,20 @@ pub enum TemplateType {
     Manufacturing,        // 15,000 actions
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+impl TemplateType {
+    /// Atomic actions in one instance of the template
+    pub fn action_count(self) -> usize {
+        match self {
+            TemplateType::HospitalIntegration => 1_440,
+            TemplateType::ResearchSprint => 4_000,
+            TemplateType::SoftwareDev => 72,
+            TemplateType::Manufacturing => 15_000,
+        }
+    }
+}
+
+// Ordered from fewest agents to most
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum ParallelizationMode {
     Sequential,
     Batch10,
@@ -118,7 +805,30 @@ pub enum ParallelizationMode {
     Turbo,  // 1000+ agents
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+/// How constrained the host is, as reported to `apply_resource_pressure`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PressureLevel {
+    #[default]
+    Normal,
+    Elevated,
+    High,
+    Critical,
+}
+
+impl PressureLevel {
+    /// The most parallel mode a session may run at under this pressure
+    fn cap(self, requested: ParallelizationMode) -> ParallelizationMode {
+        let ceiling = match self {
+            PressureLevel::Normal => ParallelizationMode::Turbo,
+            PressureLevel::Elevated => ParallelizationMode::Batch100,
+            PressureLevel::High => ParallelizationMode::Batch10,
+            PressureLevel::Critical => ParallelizationMode::Sequential,
+        };
+        requested.min(ceiling)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Complexity {
     Small,
     Medium,
@@ -126,15 +836,236 @@ pub enum Complexity {
     XLarge,
 }
 
+/// How good a model's answers are, for `ModelClients::select_model`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum QualityTier {
+    /// Boilerplate, summaries, formatting
+    Low,
+    #[default]
+    Standard,
+    /// Hard reasoning and complex code
+    High,
+}
+
 // ============================================================================
 // SESSION MANAGER
 // ============================================================================
 
+/// Hard cap on agents in a single session (Turbo mode)
+pub const MAX_AGENTS_PER_SESSION: usize = 10_000;
+
+/// Typical model usage of one action, for cost estimates
+const AVG_USAGE_PER_ACTION: TokenUsage = TokenUsage { input_tokens: 2_000, output_tokens: 1_000 };
+
+/// Typical wall time of one action on one coder, for duration estimates
+const AVG_SECS_PER_ACTION: f64 = 30.0;
+
+/// Spread of `CostEstimate::low` and `high` around `expected`
+const COST_ESTIMATE_RANGE: (f64, f64) = (0.5, 2.0);
+
+/// How many agents of each role a project starts with (verifiers aside,
+/// which depend on `VerifierConfig`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AgentCounts {
+    pub planners: usize,
+    pub coders: usize,
+    pub testers: usize,
+    pub browsers: usize,
+}
+
+impl AgentCounts {
+    pub fn total(&self) -> usize {
+        self.planners + self.coders + self.testers + self.browsers
+    }
+
+    fn testers_for(coders: usize) -> usize {
+        (coders / 4).max(1)
+    }
+
+    /// Cut coders, and the testers that go with them, to the most for which
+    /// the counts plus `extra(coders)` more agents fit in `max`; at least 1
+    fn fit(mut self, max: usize, extra: impl Fn(usize) -> usize) -> Self {
+        let fixed = self.planners + self.browsers;
+        let total = |coders: usize| fixed + coders + Self::testers_for(coders) + extra(coders);
+        if total(self.coders) <= max {
+            return self;
+        }
+        let (mut low, mut high) = (1, self.coders);
+        while low < high {
+            let mid = low + (high - low).div_ceil(2);
+            if total(mid) <= max {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
+        }
+        self.coders = low;
+        self.testers = Self::testers_for(low);
+        self
+    }
+}
+
+/// Initial agent counts for a project. All divisions round down, then:
+///
+/// - the parallelization mode sets a base size: 1, 10, 100, or (Turbo) 10
+///   per replicated instance, capped at `MAX_AGENTS_PER_SESSION`;
+/// - coders take a share of the base by complexity: 1/4 Small, 1/2 Medium,
+///   3/4 Large, all of it XLarge; at least 1;
+/// - testers are 1 per 4 coders, at least 1;
+/// - there is always 1 planner (on top of the base), and 1 browser agent if
+///   the project requires one;
+/// - if the total is still over `MAX_AGENTS_PER_SESSION`, coders (and with
+///   them testers) are cut until it fits.
+pub fn compute_agent_counts(project_spec: &ProjectSpec) -> AgentCounts {
+    let base = match project_spec.parallelization {
+        ParallelizationMode::Sequential => 1,
+        ParallelizationMode::Batch10 => 10,
+        ParallelizationMode::Batch100 => 100,
+        ParallelizationMode::Turbo => project_spec.replication_count
+            .saturating_mul(TURBO_AGENTS_PER_INSTANCE)
+            .min(MAX_AGENTS_PER_SESSION),
+    };
+
+    let coders = match project_spec.estimated_complexity {
+        Complexity::Small => base / 4,
+        Complexity::Medium => base / 2,
+        Complexity::Large => base * 3 / 4,
+        Complexity::XLarge => base,
+    }.max(1);
+
+    AgentCounts {
+        planners: 1,
+        coders,
+        testers: AgentCounts::testers_for(coders),
+        browsers: usize::from(project_spec.requires_browser),
+    }
+    .fit(MAX_AGENTS_PER_SESSION, |_| 0)
+}
+
+#[derive(Clone)]
 pub struct SessionManager {
     sessions: Arc<RwLock<HashMap<SessionId, Session>>>,
     agent_pool: Arc<AgentPool>,
     state_manager: Arc<StateManager>,
     task_queue: Arc<TaskQueue>,
+    autoscale: AutoscaleConfig,
+    quotas: QuotaConfig,
+    /// Shared like `interceptors`
+    verifier: Arc<std::sync::RwLock<VerifierConfig>>,
+    router: ModelRouter,
+    /// Per-user live sessions and agents; always locked after `sessions`
+    usage: Arc<RwLock<HashMap<UserId, UserUsage>>>,
+    /// Sessions created under a client's idempotency key, and when
+    idempotency_keys: Arc<Mutex<IdempotencyKeys>>,
+    idempotency_window: Duration,
+    events: broadcast::Sender<SwarmEvent>,
+    /// Last level given to `apply_resource_pressure`
+    pressure: Arc<std::sync::RwLock<PressureLevel>>,
+    /// Every event emitted for each live session, for `replay`
+    event_logs: Arc<std::sync::Mutex<HashMap<SessionId, EventLog>>>,
+    ids: Arc<dyn IdGenerator>,
+    saturation: SaturationPolicy,
+    /// Held while starting queued sessions, before `sessions`
+    admitting: Arc<Mutex<()>>,
+    /// In registration order; shared so the report processor sees later additions
+    interceptors: Arc<std::sync::RwLock<Vec<Arc<dyn TaskInterceptor>>>>,
+    /// Replace agents whose task panicked; shared like `interceptors`
+    respawn_on_panic: Arc<AtomicBool>,
+    /// Agents per second a new session's roster comes online at; `None`
+    /// spawns it all at once
+    spawn_rate: Option<u32>,
+    /// When `spawn_gradually` may next spawn an agent, for every session
+    /// alike, so concurrent sessions share `spawn_rate` rather than each
+    /// spawning at it
+    next_spawn: Arc<Mutex<Instant>>,
+    /// Users whose sessions `cancel_user_sessions` is taking down, with how
+    /// many calls are at it; checked under `usage`
+    cancelling: Arc<std::sync::Mutex<HashMap<UserId, usize>>>,
+    /// Sessions being restored or imported, whose ids are taken though
+    /// they aren't in `sessions` yet; checked under `sessions`
+    rehydrating: Arc<std::sync::Mutex<HashSet<SessionId>>>,
+    webhooks: Webhooks,
+    cancel_settle_timeout: Duration,
+}
+
+/// Events buffered per subscriber before it starts seeing `Lagged`
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+/// Backend keys indexing checkpointed sessions start with this
+const SESSION_INDEX_PREFIX: &str = "sessions:";
+
+/// Set once checkpoints from before `SESSION_INDEX_PREFIX` are indexed
+const SESSION_INDEX_READY: &str = "swarm:session_index";
+
+/// How long a `create_session` idempotency key keeps returning its session
+pub const IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Default for `SessionManager::with_cancel_settle_timeout`
+pub const CANCEL_SETTLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A user counted in `SessionManager::cancelling` for as long as one
+/// `cancel_user_sessions` call lives, however it ends
+struct Cancelling<'a> {
+    manager: &'a SessionManager,
+    user_id: &'a str,
+}
+
+impl<'a> Cancelling<'a> {
+    fn start(manager: &'a SessionManager, user_id: &'a str) -> Self {
+        *manager.cancelling().entry(user_id.to_string()).or_default() += 1;
+        Self { manager, user_id }
+    }
+}
+
+impl Drop for Cancelling<'_> {
+    fn drop(&mut self) {
+        let mut cancelling = self.manager.cancelling();
+        if let Some(calls) = cancelling.get_mut(self.user_id) {
+            *calls -= 1;
+            if *calls == 0 {
+                cancelling.remove(self.user_id);
+            }
+        }
+    }
+}
+
+/// A creation under an idempotency key: the session, once one was created
+#[derive(Debug, Clone)]
+struct IdempotentCreate {
+    session: Arc<tokio::sync::OnceCell<SessionId>>,
+    at: Instant,
+}
+
+/// Idempotency keys in use, and the same keys oldest first so expired ones
+/// go without a scan
+#[derive(Debug, Default)]
+struct IdempotencyKeys {
+    by_key: HashMap<(UserId, String), IdempotentCreate>,
+    by_age: VecDeque<(Instant, (UserId, String))>,
+}
+
+impl IdempotencyKeys {
+    /// The creation under `key`, started at `now` unless one is under way or
+    /// was made within `window`
+    fn get_or_start(&mut self, key: (UserId, String), now: Instant, window: Duration) -> IdempotentCreate {
+        while let Some((at, _)) = self.by_age.front() {
+            if now.duration_since(*at) < window {
+                break;
+            }
+            let (at, expired) = self.by_age.pop_front().expect("front checked above");
+            // A key re-used since has a newer entry of its own
+            if self.by_key.get(&expired).is_some_and(|create| create.at == at) {
+                self.by_key.remove(&expired);
+            }
+        }
+        self.by_key
+            .entry(key.clone())
+            .or_insert_with(|| {
+                self.by_age.push_back((now, key));
+                IdempotentCreate { session: Arc::default(), at: now }
+            })
+            .clone()
+    }
 }
 
 impl SessionManager {
@@ -143,126 +1074,816 @@ impl SessionManager {
         state_manager: Arc<StateManager>,
         task_queue: Arc<TaskQueue>,
     ) -> Self {
-        Self {
+        let manager = Self {
             sessions: Arc::new(RwLock::new(HashMap::new())),
             agent_pool,
             state_manager,
             task_queue,
+            autoscale: AutoscaleConfig::default(),
+            quotas: QuotaConfig::default(),
+            verifier: Arc::default(),
+            router: ModelRouter::default(),
+            usage: Arc::new(RwLock::new(HashMap::new())),
+            idempotency_keys: Arc::new(Mutex::new(IdempotencyKeys::default())),
+            idempotency_window: IDEMPOTENCY_WINDOW,
+            events: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+            pressure: Arc::new(std::sync::RwLock::new(PressureLevel::Normal)),
+            event_logs: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            ids: Arc::new(RandomIdGenerator),
+            saturation: SaturationPolicy::default(),
+            admitting: Arc::new(Mutex::new(())),
+            interceptors: Arc::new(std::sync::RwLock::new(vec![])),
+            respawn_on_panic: Arc::new(AtomicBool::new(false)),
+            spawn_rate: None,
+            next_spawn: Arc::new(Mutex::new(Instant::now())),
+            cancelling: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            rehydrating: Arc::new(std::sync::Mutex::new(HashSet::new())),
+            webhooks: Webhooks::new(WebhookConfig::default()),
+            cancel_settle_timeout: CANCEL_SETTLE_TIMEOUT,
+        };
+
+        // Consume agent reports in the background (requires a Tokio runtime)
+        if let Some(reports) = manager.agent_pool.take_reports() {
+            tokio::spawn(manager.clone().process_reports(reports));
         }
+        tokio::spawn(manager.clone().sync_agent_status());
+
+        manager
     }
 
-    /// Create a new parallel execution session
-    pub async fn create_session(
-        &self,
-        user_id: UserId,
-        project_spec: ProjectSpec,
-    ) -> Result<SessionId, SwarmError> {
-        let session_id = SessionId::new_v4();
-        
-        // Create shared state space
-        let shared_state = self.state_manager
-            .create_state_space(session_id)
-            .await?;
-        
-        // Spawn initial agents based on parallelization mode
-        let agents = self.spawn_initial_agents(
-            session_id,
-            &project_spec,
-            shared_state.clone(),
-        ).await?;
+    pub fn with_autoscale_config(mut self, config: AutoscaleConfig) -> Self {
+        self.autoscale = config;
+        self
+    }
+
+    pub fn with_quota_config(mut self, config: QuotaConfig) -> Self {
+        self.quotas = config;
+        self
+    }
+
+    /// Where completion webhooks may go and how long a delivery may take
+    pub fn with_webhook_config(mut self, config: WebhookConfig) -> Self {
+        self.webhooks = Webhooks::new(config);
+        self
+    }
+
+    pub fn with_verifier_config(self, config: VerifierConfig) -> Self {
+        *self.verifier.write().expect("verifier config lock poisoned") = config;
+        self
+    }
+
+    /// Choose models for new agents by role and project complexity
+    pub fn with_model_router(mut self, router: ModelRouter) -> Self {
+        self.router = router;
+        self
+    }
+
+    /// How long `create_session` remembers an idempotency key
+    pub fn with_idempotency_window(mut self, window: Duration) -> Self {
+        self.idempotency_window = window;
+        self
+    }
+
+    /// Longest `cancel_user_sessions` waits for sessions whose creation was
+    /// under way when it started
+    pub fn with_cancel_settle_timeout(mut self, timeout: Duration) -> Self {
+        self.cancel_settle_timeout = timeout;
+        self
+    }
+
+    /// Draw session ids from `ids` instead of at random
+    pub fn with_id_generator(mut self, ids: Arc<dyn IdGenerator>) -> Self {
+        self.ids = ids;
+        self
+    }
+
+    /// Run `interceptor` around every task the manager hands to an agent,
+    /// after any interceptors registered before it
+    pub fn with_task_interceptor(self, interceptor: Arc<dyn TaskInterceptor>) -> Self {
+        self.interceptors.write().expect("interceptors lock poisoned").push(interceptor);
+        self
+    }
+
+    /// Replace an agent whose task panicked with a fresh one of the same
+    /// role, model and skills, as `sweep_unhealthy_agents` can. Off by
+    /// default: the agent is left `Failed`.
+    pub fn with_respawn_on_panic(self, respawn: bool) -> Self {
+        self.respawn_on_panic.store(respawn, AtomicOrdering::SeqCst);
+        self
+    }
+
+    /// Bring new sessions' agents online at most `agents_per_sec` at a time
+    /// rather than all at once, so a Turbo roster doesn't hit the runtime
+    /// and the model clients in one burst. The rate is shared by every
+    /// session the manager runs. A session turns `Active` with its first
+    /// agent and grows from there; `SessionStatusReport::agents_starting`
+    /// shows how many are still to come.
+    pub fn with_spawn_rate(mut self, agents_per_sec: u32) -> Self {
+        self.spawn_rate = Some(agents_per_sec.max(1));
+        self
+    }
+
+    /// How many of a roster of `len` agents to spawn right away, leaving
+    /// the rest to `spawn_gradually`
+    fn spawned_upfront(&self, len: usize) -> usize {
+        match self.spawn_rate {
+            Some(_) => len.min(1),
+            None => len,
+        }
+    }
+
+    fn intercept_before(&self, task: &mut Task) {
+        for interceptor in self.interceptors.read().expect("interceptors lock poisoned").iter() {
+            interceptor.before(task);
+        }
+    }
+
+    fn intercept_after(&self, task: &Task, result: &mut TaskResult) {
+        for interceptor in self.interceptors.read().expect("interceptors lock poisoned").iter() {
+            interceptor.after(task, result);
+        }
+    }
+
+    /// What `create_session` does when the agent pool can't fit a new
+    /// session's agents
+    pub fn with_saturation_policy(mut self, policy: SaturationPolicy) -> Self {
+        self.saturation = policy;
+        self
+    }
+
+    /// Sessions and agents currently held by `user_id`
+    pub async fn user_usage(&self, user_id: &str) -> UserUsage {
+        self.usage.read().await.get(user_id).copied().unwrap_or_default()
+    }
+
+    /// Registry shared with the agent pool, for exporting to Prometheus
+    pub fn metrics(&self) -> MetricsRegistry {
+        self.agent_pool.metrics.clone()
+    }
+
+    /// Stream of orchestrator events. A subscriber that falls more than
+    /// `EVENT_CHANNEL_CAPACITY` events behind gets `RecvError::Lagged` and
+    /// skips ahead; the orchestrator never waits on subscribers.
+    pub fn subscribe(&self) -> broadcast::Receiver<SwarmEvent> {
+        self.events.subscribe()
+    }
+
+    fn emit(&self, event: SwarmEvent) {
+        if let Some(log) = self.event_logs().get_mut(&event.session_id()) {
+            log.append(event.clone());
+        }
+        // No subscribers is fine
+        let _ = self.events.send(event);
+    }
+
+    fn pressure(&self) -> PressureLevel {
+        *self.pressure.read().expect("pressure lock poisoned")
+    }
+
+    fn event_logs(&self) -> std::sync::MutexGuard<'_, HashMap<SessionId, EventLog>> {
+        self.event_logs.lock().expect("event log lock poisoned")
+    }
+
+    fn cancelling(&self) -> std::sync::MutexGuard<'_, HashMap<UserId, usize>> {
+        self.cancelling.lock().expect("cancelling lock poisoned")
+    }
+
+    fn rehydrating(&self) -> std::sync::MutexGuard<'_, HashSet<SessionId>> {
+        self.rehydrating.lock().expect("rehydrating lock poisoned")
+    }
+
+    /// Events recorded for a session since it was created in this process.
+    /// Restored sessions aren't logged, and a log is dropped with its session.
+    pub fn event_log(&self, session_id: SessionId) -> Option<EventLog> {
+        self.event_logs().get(&session_id).cloned()
+    }
+
+    /// Rebuild a session's final metrics from its event log, without
+    /// running anything. Each event carries the outcome decided live, so the
+    /// result matches the original run exactly.
+    pub fn replay(log: &EventLog) -> SessionMetrics {
+        let mut metrics = SessionMetrics::default();
+        for event in log.events() {
+            match *event {
+                SwarmEvent::AgentSpawned { .. } => metrics.agents_spawned += 1,
+                SwarmEvent::TaskStarted { role, verification, .. } => {
+                    if !verification && role != Some(AgentRole::Verifier) {
+                        metrics.tasks_assigned += 1;
+                    }
+                }
+                SwarmEvent::TaskCompleted {
+                    cost, cost_saved, duration_sec, throttled_sec, outcome, role, model, ..
+                } => {
+                    match outcome {
+                        CompletionOutcome::Accepted => {
+                            metrics.total_duration_sec += duration_sec;
+                            metrics.tasks_completed += 1;
+                        }
+                        CompletionOutcome::AwaitingVerification => metrics.total_duration_sec += duration_sec,
+                        CompletionOutcome::Uncounted => {}
+                    }
+                    metrics.charge(cost, role, model);
+                    metrics.cost_saved += cost_saved;
+                    metrics.rate_limited_sec += throttled_sec;
+                }
+                SwarmEvent::TaskFailed { dead_lettered, .. } => {
+                    if dead_lettered {
+                        metrics.tasks_failed += 1;
+                    }
+                }
+                SwarmEvent::TaskVerified { passed, dead_lettered, .. } => {
+                    if passed {
+                        metrics.tasks_completed += 1;
+                    } else {
+                        metrics.verification_failed += 1;
+                    }
+                    if dead_lettered {
+                        metrics.tasks_failed += 1;
+                    }
+                }
+                SwarmEvent::TaskUnsupported { .. } => metrics.tasks_failed += 1,
+                SwarmEvent::AgentPanicked { respawned, .. } => {
+                    if respawned.is_some() {
+                        metrics.agents_spawned += 1;
+                    }
+                }
+                SwarmEvent::SessionCreated { .. }
+                | SwarmEvent::TaskCancelled { .. }
+                | SwarmEvent::SessionCompleted { .. }
+                | SwarmEvent::BudgetBreached { .. }
+                | SwarmEvent::SessionIdle { .. }
+                | SwarmEvent::DeadlineExceeded { .. }
+                | SwarmEvent::Deadlocked { .. } => {}
+            }
+        }
+        metrics
+    }
+
+    /// Create a new parallel execution session.
+    ///
+    /// A retried call passing the same `idempotency_key` (per user, within
+    /// the idempotency window) gets the first call's session back instead
+    /// of a second one. A failed creation doesn't consume the key.
+    pub async fn create_session(
+        &self,
+        user_id: UserId,
+        project_spec: ProjectSpec,
+        idempotency_key: Option<String>,
+    ) -> Result<SessionId, SwarmError> {
+        let Some(key) = idempotency_key else {
+            return self.create_new_session(user_id, project_spec).await;
+        };
+
+        let create = self.idempotency_keys.lock().await.get_or_start(
+            (user_id.clone(), key),
+            Instant::now(),
+            self.idempotency_window,
+        );
+        // Only a concurrent retry under the same key waits for the first
+        // call, and then finds its session
+        create.session
+            .get_or_try_init(|| self.create_new_session(user_id, project_spec))
+            .await
+            .copied()
+    }
+
+    async fn create_new_session(
+        &self,
+        user_id: UserId,
+        project_spec: ProjectSpec,
+    ) -> Result<SessionId, SwarmError> {
+        project_spec.validate()?;
+        if let Some(url) = &project_spec.completion_webhook {
+            self.webhooks.check(url)?;
+        }
+        let session_id = self.ids.next_id();
+        // New sessions start downshifted while the host is under pressure
+        let requested = project_spec.parallelization;
+        let mode = self.pressure().cap(requested);
+        let effective_mode = (mode != requested).then_some(mode);
+        let roster = self.initial_roster(&ProjectSpec { parallelization: mode, ..project_spec.clone() });
+        // Neither rejected as saturated nor queued: no amount of waiting fits it
+        if roster.len() > self.agent_pool.max_agents() {
+            return Err(SwarmError::InvalidSpec(format!(
+                "{} agents can't fit in a pool of {}",
+                roster.len(),
+                self.agent_pool.max_agents(),
+            )));
+        }
+
+        // Queued sessions go first, so a large one isn't starved by small ones
+        let saturated = self.agent_pool.available_capacity().await < roster.len()
+            || (self.saturation == SaturationPolicy::Queue && self.has_queued_sessions().await);
+        if saturated && self.saturation == SaturationPolicy::Reject {
+            return Err(SwarmError::PoolSaturated { limit: self.agent_pool.max_agents() });
+        }
+        if let Some(requests_per_minute) = project_spec.max_requests_per_minute {
+            self.agent_pool.model_clients.set_session_rate(session_id, requests_per_minute)?;
+        }
+
+        // Reserve before spawning anything, so concurrent calls for the same
+        // user can't both squeeze under the limit
+        if let Err(e) = self.reserve_quota(&user_id, 1, roster.len()).await {
+            self.agent_pool.model_clients.clear_session_rate(session_id);
+            return Err(e);
+        }
+        self.agent_pool.set_session_template(session_id, project_spec.template);
+
+        let control = Arc::new(SessionControl::default());
+        let span = Session::span_for(session_id, &user_id);
+        // Opened first so the initial agent spawns are logged
+        self.event_logs().insert(session_id, EventLog::default());
+        let awaiting_readiness = project_spec.readiness.is_some();
+        let spawned = if awaiting_readiness {
+            Ok(None)
+        } else if saturated {
+            Err(SwarmError::PoolSaturated { limit: self.agent_pool.max_agents() })
+        } else {
+            self.spawn_initial_agents(session_id, &roster[..self.spawned_upfront(roster.len())], &control, &span).await.map(Some)
+        };
+        let (agents, shared_state, status) = match spawned {
+            Ok(Some((agents, shared_state))) => (agents, shared_state, SessionStatus::Active),
+            // Started by `admit_queued_sessions` once ready and there's room
+            Ok(None) => (vec![], SharedState::detached(), SessionStatus::Initializing),
+            Err(SwarmError::PoolSaturated { .. }) if self.saturation == SaturationPolicy::Queue => {
+                (vec![], SharedState::detached(), SessionStatus::Initializing)
+            }
+            Err(e) => {
+                self.event_logs().remove(&session_id);
+                self.agent_pool.forget_session(session_id);
+                self.agent_pool.model_clients.clear_session_rate(session_id);
+                self.release_quota(&user_id, 1, roster.len()).await;
+                return Err(e);
+            }
+        };
+        let agents_spawned = agents.len();
+        if project_spec.dedup_tasks {
+            self.task_queue.set_dedup(session_id, true).await;
+        }
         
-        let session = Session {
+        let mut session = Session {
             id: session_id,
             user_id,
             created_at: Utc::now(),
-            status: SessionStatus::Active,
-            agents,
+            status,
+            agents: vec![],
+            tags: project_spec.tags.clone(),
+            project_spec,
+            parent_id: None,
+            effective_mode,
             shared_state,
+            control,
+            span,
+            assignment: AssignmentStrategy::default(),
+            eta: EtaEstimator::default(),
+            epoch: 0,
+            next_coder: 0,
+            agent_counts: StatusCounts::default(),
+            awaiting_readiness,
+            agents_starting: 0,
+            parked: vec![],
+            last_activity: Instant::now(),
             metrics: SessionMetrics {
                 tasks_assigned: 0,
                 tasks_completed: 0,
                 tasks_failed: 0,
                 total_cost: 0.0,
                 total_duration_sec: 0.0,
-                agents_spawned: 0,
+                agents_spawned,
+                cost_saved: 0.0,
+                rate_limited_sec: 0.0,
+                verification_failed: 0,
+                cost_by_role: HashMap::new(),
+                cost_by_model: HashMap::new(),
             },
         };
-        
+        session.set_agents(agents);
+        let later = match status {
+            SessionStatus::Active => roster[self.spawned_upfront(roster.len())..].to_vec(),
+            _ => vec![],
+        };
+        session.agents_starting = later.len();
+
+        let user_id = session.user_id.clone();
+        session.span.in_scope(|| match (status, &session.project_spec.readiness) {
+            (_, Some(gate)) => info!(probe = ?gate.probe, "session waiting on its readiness probe"),
+            (SessionStatus::Initializing, None) => info!(agents = roster.len(), "session queued for a saturated pool"),
+            _ => info!(agents = agents_spawned, "session created"),
+        });
+        if let Some(gate) = session.project_spec.readiness.clone() {
+            self.spawn_readiness_wait(session_id, gate, session.span.clone());
+        }
+        if let Some(deadline) = session.project_spec.deadline {
+            self.spawn_deadline_watch(session_id, deadline, session.span.clone());
+        }
+        let span = session.span.clone();
         self.sessions.write().await.insert(session_id, session);
+        self.agent_pool.metrics.session_created();
+        self.emit(SwarmEvent::SessionCreated { session_id, user_id });
+        self.spawn_gradually(session_id, later, span);
         
         Ok(session_id)
     }
 
-    async fn spawn_initial_agents(
+    /// Whether any session is waiting in `Initializing` for pool capacity
+    async fn has_queued_sessions(&self) -> bool {
+        self.sessions.read().await.values().any(Session::is_queued)
+    }
+
+    /// Poll `gate`'s probe until it passes, then release the session to
+    /// `admit_queued_sessions`; past the gate's timeout, fail the session.
+    /// Stops early if the session is destroyed meanwhile.
+    fn spawn_readiness_wait(&self, session_id: SessionId, gate: ReadinessGate, span: Span) {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let ReadinessGate { probe, timeout, interval } = gate;
+            let ready = tokio::time::timeout(timeout, async {
+                let mut ticks = tokio::time::interval(interval);
+                loop {
+                    ticks.tick().await;
+                    if probe.is_ready().await {
+                        return;
+                    }
+                    let still_waiting = manager.sessions.read().await
+                        .get(&session_id)
+                        .is_some_and(|s| s.awaiting_readiness);
+                    if !still_waiting {
+                        return;
+                    }
+                }
+            }).await.is_ok();
+
+            {
+                let mut sessions = manager.sessions.write().await;
+                let Some(session) = sessions.get_mut(&session_id).filter(|s| s.awaiting_readiness) else { return };
+                session.awaiting_readiness = false;
+                if ready {
+                    info!("readiness probe passed");
+                } else {
+                    error!(?timeout, "readiness probe never passed; session failed");
+                    let (user_id, roster) = (session.user_id.clone(), manager.roster_for(session));
+                    manager.finish_session(session, SessionStatus::Failed);
+                    // Failed without agents, so it no longer holds its roster's quota
+                    manager.release_quota(&user_id, 0, roster.len()).await;
+                    return;
+                }
+            }
+            manager.admit_queued_sessions().await;
+        }.instrument(span));
+    }
+
+    /// At `deadline`, fail the session if it's still running, keeping what
+    /// its tasks finished: nothing new starts, tasks already running finish,
+    /// and `SwarmEvent::DeadlineExceeded` carries the metrics so far.
+    fn spawn_deadline_watch(&self, session_id: SessionId, deadline: DateTime<Utc>, span: Span) {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep((deadline - Utc::now()).to_std().unwrap_or_default()).await;
+            let Some(metrics) = manager.fail_session(session_id).await else { return };
+            warn!(
+                tasks_completed = metrics.tasks_completed,
+                tasks_assigned = metrics.tasks_assigned,
+                "deadline passed; session failed",
+            );
+            manager.emit(SwarmEvent::DeadlineExceeded { session_id, metrics });
+        }.instrument(span));
+    }
+
+    /// Fail a session that's still running, where it stands: its queued and
+    /// running tasks are dropped and its agents released along with their
+    /// quota. The session itself stays, with its metrics, until destroyed.
+    /// Returns those metrics, or `None` if the session wasn't running.
+    async fn fail_session(&self, session_id: SessionId) -> Option<SessionMetrics> {
+        let (agents, metrics) = {
+            let mut sessions = self.sessions.write().await;
+            let session = sessions.get_mut(&session_id).filter(|s| {
+                matches!(s.status, SessionStatus::Initializing | SessionStatus::Active | SessionStatus::Paused)
+            })?;
+            let held = match session.status {
+                // No agents yet, but its roster's quota
+                SessionStatus::Initializing => {
+                    session.awaiting_readiness = false;
+                    self.roster_for(session).len()
+                }
+                _ => session.agents.len() + session.agents_starting,
+            };
+            let agents: Vec<AgentId> = session.agents.iter().map(|a| a.id).collect();
+            session.set_agents(vec![]);
+            // Those still to come are never spawned; see `spawn_gradually`
+            session.agents_starting = 0;
+            self.finish_session(session, SessionStatus::Failed);
+            self.release_quota(&session.user_id, 0, held).await;
+            (agents, session.metrics.clone())
+        };
+
+        self.task_queue.purge_session(session_id).await;
+        for agent_id in agents {
+            if let Err(e) = self.agent_pool.release_agent(agent_id).await {
+                warn!(%session_id, %agent_id, error = %e, "failed session's agent not released");
+            }
+        }
+        self.admit_queued_sessions().await;
+        Some(metrics)
+    }
+
+    /// Agents a session started with, or will start with once admitted
+    fn roster_for(&self, session: &Session) -> Vec<(AgentRole, ModelPreference)> {
+        let parallelization = session.effective_mode.unwrap_or(session.project_spec.parallelization);
+        self.initial_roster(&ProjectSpec { parallelization, ..session.project_spec.clone() })
+    }
+
+    /// Start sessions queued by `SaturationPolicy::Queue`, oldest first,
+    /// while the agent pool has room for their agents. Runs whenever a
+    /// session is destroyed; call it after freeing agents some other way.
+    /// Returns the sessions started.
+    pub async fn admit_queued_sessions(&self) -> Vec<SessionId> {
+        let _admitting = self.admitting.lock().await;
+        let mut admitted = vec![];
+
+        loop {
+            let next = {
+                let sessions = self.sessions.read().await;
+                sessions.values()
+                    .filter(|s| s.is_queued())
+                    .min_by_key(|s| s.created_at)
+                    .map(|s| (s.id, s.user_id.clone(), self.roster_for(s), s.control.clone(), s.span.clone()))
+            };
+            let Some((session_id, user_id, roster, control, span)) = next else { break };
+            if self.agent_pool.available_capacity().await < roster.len() {
+                break;
+            }
+
+            let (now, later) = roster.split_at(self.spawned_upfront(roster.len()));
+            let (agents, shared_state) = match self.spawn_initial_agents(session_id, now, &control, &span).await {
+                Ok(spawned) => spawned,
+                // Taken by a spawn outside admission; wait for the next chance
+                Err(SwarmError::PoolSaturated { .. }) => break,
+                Err(e) => {
+                    span.in_scope(|| error!(error = %e, "queued session failed to start"));
+                    let mut sessions = self.sessions.write().await;
+                    if let Some(session) = sessions.get_mut(&session_id) {
+                        self.finish_session(session, SessionStatus::Failed);
+                        // Failed without agents, so it no longer holds its roster's quota
+                        self.release_quota(&user_id, 0, roster.len()).await;
+                    }
+                    continue;
+                }
+            };
+
+            let mut sessions = self.sessions.write().await;
+            match sessions.get_mut(&session_id).filter(|s| s.is_queued()) {
+                Some(session) => {
+                    session.metrics.agents_spawned = agents.len();
+                    session.set_agents(agents);
+                    session.shared_state = shared_state;
+                    session.agents_starting = later.len();
+                    session.set_status(SessionStatus::Active);
+                    span.in_scope(|| info!(agents = roster.len(), "queued session started"));
+                    self.spawn_gradually(session_id, later.to_vec(), span.clone());
+                    admitted.push(session_id);
+                }
+                // Destroyed while its agents were spawning
+                None => {
+                    drop(sessions);
+                    for agent in &agents {
+                        let _ = self.agent_pool.terminate_agent(agent.id).await;
+                    }
+                    let _ = self.state_manager.destroy_state_space(session_id).await;
+                }
+            }
+        }
+
+        admitted
+    }
+
+    /// Create a session for a piece of `parent`'s project, owned by the same
+    /// user. It runs like any other session, but `get_rolled_up_status` on
+    /// the parent counts it in, and destroying the parent destroys it too.
+    pub async fn create_subsession(
         &self,
-        session_id: SessionId,
-        project_spec: &ProjectSpec,
-        shared_state: Arc<SharedState>,
-    ) -> Result<Vec<AgentHandle>, SwarmError> {
-        let mut agents = vec![];
-        
-        // Calculate agent count based on parallelization mode
-        let agent_count = match project_spec.parallelization {
-            ParallelizationMode::Sequential => 1,
-            ParallelizationMode::Batch10 => 10,
-            ParallelizationMode::Batch100 => 100,
-            ParallelizationMode::Turbo => {
-                // TURBO: 10 agents per instance, up to 10K max
-                (project_spec.replication_count * 10).min(10000)
+        parent: SessionId,
+        project_spec: ProjectSpec,
+    ) -> Result<SessionId, SwarmError> {
+        let user_id = {
+            let sessions = self.sessions.read().await;
+            let parent = sessions.get(&parent)
+                .ok_or(SwarmError::SessionNotFound)?;
+            if !matches!(parent.status, SessionStatus::Active | SessionStatus::Paused) {
+                return Err(SwarmError::SessionNotActive(parent.status));
             }
+            parent.user_id.clone()
         };
 
-        // Always spawn 1 planner
-        let planner = self.agent_pool.spawn_agent(
+        let child = self.create_new_session(user_id, project_spec).await?;
+        let mut sessions = self.sessions.write().await;
+        if !sessions.contains_key(&parent) {
+            // Destroyed while the child was spawning, so it didn't cascade
+            drop(sessions);
+            self.destroy_session(child).await?;
+            return Err(SwarmError::SessionNotFound);
+        }
+        if let Some(session) = sessions.get_mut(&child) {
+            session.parent_id = Some(parent);
+            session.span.in_scope(|| info!(%parent, "linked to parent session"));
+        }
+        Ok(child)
+    }
+
+    /// Spawn an agent under the session's span
+    #[allow(clippy::too_many_arguments)]
+    async fn spawn_agent(
+        &self,
+        session_id: SessionId,
+        role: AgentRole,
+        model: ModelPreference,
+        skills: Vec<String>,
+        shared_state: Arc<SharedState>,
+        control: Arc<SessionControl>,
+        span: &Span,
+    ) -> Result<AgentHandle, SwarmError> {
+        let agent = self.agent_pool
+            .spawn_agent_with_skills(session_id, role, model, skills, shared_state, control)
+            .instrument(span.clone())
+            .await?;
+        self.emit(SwarmEvent::AgentSpawned {
             session_id,
-            AgentRole::Planner,
-            ModelPreference::GPT51,
-            shared_state.clone(),
-        ).await?;
-        agents.push(planner);
-
-        // Spawn parallel coders
-        let coder_count = match project_spec.estimated_complexity {
-            Complexity::Small => agent_count / 4,
-            Complexity::Medium => agent_count / 2,
-            Complexity::Large => (agent_count * 3) / 4,
-            Complexity::XLarge => agent_count,
-        }.max(1);
-
-        for _ in 0..coder_count {
-            let coder = self.agent_pool.spawn_agent(
-                session_id,
-                AgentRole::Coder,
-                ModelPreference::ClaudeOpus45,
-                shared_state.clone(),
-            ).await?;
-            agents.push(coder);
+            agent_id: agent.id,
+            role,
+            model,
+        });
+        Ok(agent)
+    }
+
+    async fn reserve_quota(&self, user_id: &str, sessions: usize, agents: usize) -> Result<(), SwarmError> {
+        let mut usage = self.usage.write().await;
+        if self.cancelling().contains_key(user_id) {
+            return Err(SwarmError::UserCancelling(user_id.to_string()));
         }
+        let user = usage.entry(user_id.to_string()).or_default();
 
-        // Spawn testers (1 per 4 coders)
-        let tester_count = (coder_count / 4).max(1);
-        for _ in 0..tester_count {
-            let tester = self.agent_pool.spawn_agent(
-                session_id,
-                AgentRole::Tester,
-                ModelPreference::Gemini3Pro,
-                shared_state.clone(),
-            ).await?;
-            agents.push(tester);
+        if sessions > 0 && user.sessions + sessions > self.quotas.max_concurrent_sessions_per_user {
+            return Err(SwarmError::QuotaExceeded {
+                user_id: user_id.to_string(),
+                resource: "concurrent sessions",
+                limit: self.quotas.max_concurrent_sessions_per_user,
+            });
+        }
+        if user.agents + agents > self.quotas.max_total_agents_per_user {
+            return Err(SwarmError::QuotaExceeded {
+                user_id: user_id.to_string(),
+                resource: "agents",
+                limit: self.quotas.max_total_agents_per_user,
+            });
         }
 
-        // Spawn browser agent if needed
-        if project_spec.requires_browser {
-            let browser = self.agent_pool.spawn_agent(
-                session_id,
-                AgentRole::Browser,
-                ModelPreference::None,
-                shared_state.clone(),
-            ).await?;
-            agents.push(browser);
+        user.sessions += sessions;
+        user.agents += agents;
+        Ok(())
+    }
+
+    async fn release_quota(&self, user_id: &str, sessions: usize, agents: usize) {
+        let mut usage = self.usage.write().await;
+        if let Some(user) = usage.get_mut(user_id) {
+            user.sessions = user.sessions.saturating_sub(sessions);
+            user.agents = user.agents.saturating_sub(agents);
+            if *user == UserUsage::default() {
+                usage.remove(user_id);
+            }
+        }
+    }
+
+    /// Spawn the roster into a fresh state space. On failure, agents spawned
+    /// so far are terminated again.
+    async fn spawn_initial_agents(
+        &self,
+        session_id: SessionId,
+        roster: &[(AgentRole, ModelPreference)],
+        control: &Arc<SessionControl>,
+        span: &Span,
+    ) -> Result<(Vec<AgentHandle>, Arc<SharedState>), SwarmError> {
+        let shared_state = self.state_manager
+            .create_state_space(session_id)
+            .await?;
+
+        let mut agents = Vec::with_capacity(roster.len());
+        for &(role, model) in roster {
+            match self.spawn_agent(session_id, role, model, vec![], shared_state.clone(), control.clone(), span).await {
+                Ok(agent) => agents.push(agent),
+                Err(e) => {
+                    for agent in &agents {
+                        let _ = self.agent_pool.terminate_agent(agent.id).await;
+                    }
+                    let _ = self.state_manager.destroy_state_space(session_id).await;
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok((agents, shared_state))
+    }
+
+    /// Spawn the rest of an active session's roster in the background, one
+    /// agent per slot of the manager's spawn rate. Agents are spawned
+    /// without holding `sessions`, then added. Stops once the session is no
+    /// longer running; a failed spawn gives the rest's quota back.
+    fn spawn_gradually(&self, session_id: SessionId, roster: Vec<(AgentRole, ModelPreference)>, span: Span) {
+        if self.spawn_rate.is_none() || roster.is_empty() {
+            return;
         }
+        let manager = self.clone();
+        tokio::spawn(async move {
+            // This slot went to the agent spawned inline
+            manager.spawn_slot().await;
+            for (spawned, &(role, model)) in roster.iter().enumerate() {
+                manager.spawn_slot().await;
+                let running = |s: &&mut Session| matches!(s.status, SessionStatus::Active | SessionStatus::Paused);
+                let Some((shared_state, control, agent_span)) = manager.sessions.write().await
+                    .get_mut(&session_id)
+                    .filter(running)
+                    .map(|s| (s.shared_state.clone(), s.control.clone(), s.span.clone()))
+                else {
+                    return;
+                };
+                let agent = manager.spawn_agent(session_id, role, model, vec![], shared_state, control, &agent_span).await;
+
+                let mut sessions = manager.sessions.write().await;
+                match (sessions.get_mut(&session_id).filter(running), agent) {
+                    (Some(session), Ok(agent)) => {
+                        session.push_agent(agent);
+                        session.metrics.agents_spawned += 1;
+                        session.agents_starting -= 1;
+                    }
+                    (Some(session), Err(e)) => {
+                        error!(error = %e, remaining = roster.len() - spawned, "session stopped growing");
+                        session.agents_starting = 0;
+                        let user_id = session.user_id.clone();
+                        manager.release_quota(&user_id, 0, roster.len() - spawned).await;
+                        return;
+                    }
+                    // Gone meanwhile, along with the rest's quota
+                    (None, agent) => {
+                        drop(sessions);
+                        if let Ok(agent) = agent {
+                            let _ = manager.agent_pool.terminate_agent(agent.id).await;
+                        }
+                        return;
+                    }
+                }
+            }
+            info!("session roster online");
+        }.instrument(span));
+    }
+
+    /// Wait for the next slot `with_spawn_rate` allows, across all sessions
+    async fn spawn_slot(&self) {
+        let Some(rate) = self.spawn_rate else { return };
+        // Whole nanoseconds, at least one, however high the rate
+        let period = Duration::from_nanos((1_000_000_000 / u64::from(rate)).max(1));
+        let at = {
+            let mut next = self.next_spawn.lock().await;
+            let at = (*next).max(Instant::now());
+            *next = at + period;
+            at
+        };
+        tokio::time::sleep_until(at).await;
+    }
+
+    fn verifier(&self) -> VerifierConfig {
+        self.verifier.read().expect("verifier config lock poisoned").clone()
+    }
+
+    /// Agents a new session starts with, as (role, model) pairs; never more
+    /// than `MAX_AGENTS_PER_SESSION`, verifiers included
+    fn initial_roster(&self, project_spec: &ProjectSpec) -> Vec<(AgentRole, ModelPreference)> {
+        let complexity = project_spec.estimated_complexity;
+        let model = |role| self.router.model_for(role, complexity);
+        let coders_per_verifier = self.verifier().coders_per_verifier;
+        let verifiers_for = |coders: usize| match coders_per_verifier {
+            Some(per) => coders.div_ceil(per.max(1)),
+            None => 1,
+        };
+        let counts = compute_agent_counts(project_spec).fit(MAX_AGENTS_PER_SESSION, verifiers_for);
+
+        let mut roster = vec![];
+        roster.extend((0..counts.planners).map(|_| (AgentRole::Planner, model(AgentRole::Planner))));
+        roster.extend((0..counts.coders).map(|_| (AgentRole::Coder, model(AgentRole::Coder))));
+        roster.extend((0..counts.testers).map(|_| (AgentRole::Tester, model(AgentRole::Tester))));
+
+        // Spawn verifiers to check coder output
+        roster.extend((0..verifiers_for(counts.coders)).map(|_| (AgentRole::Verifier, model(AgentRole::Verifier))));
+
+        roster.extend((0..counts.browsers).map(|_| (AgentRole::Browser, model(AgentRole::Browser))));
+        roster
+    }
 
-        Ok(agents)
+    /// A handle that can read sessions but not change them, for monitoring
+    pub fn observer(&self) -> SessionObserver {
+        SessionObserver {
+            sessions: self.sessions.clone(),
+            task_queue: self.task_queue.clone(),
+            agent_pool: self.agent_pool.clone(),
+            events: self.events.clone(),
+        }
     }
 
     /// Get current session status and metrics
@@ -270,27 +1891,85 @@ impl SessionManager {
         &self,
         session_id: SessionId,
     ) -> Result<SessionStatusReport, SwarmError> {
-        let sessions = self.sessions.read().await;
-        let session = sessions.get(&session_id)
-            .ok_or(SwarmError::SessionNotFound)?;
+        self.observer().get_session_status(session_id).await
+    }
 
-        // Collect agent statuses
-        let agent_statuses: Vec<AgentStatus> = session.agents
-            .iter()
-            .map(|a| a.status)
-            .collect();
+    /// Like `get_session_status`, with the metrics, agents and pending tasks
+    /// of every sub-session below `session_id` added in
+    pub async fn get_rolled_up_status(
+        &self,
+        session_id: SessionId,
+    ) -> Result<SessionStatusReport, SwarmError> {
+        self.observer().get_rolled_up_status(session_id).await
+    }
 
-        Ok(SessionStatusReport {
-            session_id: session.id,
-            status: session.status,
-            metrics: session.metrics.clone(),
-            agent_count: session.agents.len(),
-            agents_idle: agent_statuses.iter().filter(|s| **s == AgentStatus::Idle).count(),
-            agents_working: agent_statuses.iter().filter(|s| **s == AgentStatus::Working).count(),
+    /// Rough cost and duration of running `project_spec`, before creating it.
+    ///
+    /// Each of the template's actions, per replicated instance, is priced as
+    /// one coder call plus one verification call at typical usage, with the
+    /// models the router would pick. Duration assumes the coders work the
+    /// actions in parallel.
+    pub fn estimate_cost(&self, project_spec: &ProjectSpec) -> CostEstimate {
+        let complexity = project_spec.estimated_complexity;
+        let actions = project_spec.template.action_count()
+            .saturating_mul(project_spec.replication_count) as f64;
+
+        let per_action: f64 = [AgentRole::Coder, AgentRole::Verifier]
+            .into_iter()
+            .map(|role| ModelClients::cost_of(self.router.model_for(role, complexity), &AVG_USAGE_PER_ACTION))
+            .sum();
+        let expected = actions * per_action;
+        let (low, high) = COST_ESTIMATE_RANGE;
+
+        let coders = compute_agent_counts(project_spec).coders as f64;
+        CostEstimate {
+            low: expected * low,
+            expected,
+            high: expected * high,
+            // Past `Duration::MAX` for an absurd replication count
+            estimated_duration: Duration::try_from_secs_f64((actions / coders).ceil() * AVG_SECS_PER_ACTION)
+                .unwrap_or(Duration::MAX),
+        }
+    }
+
+    /// Dry run: have the planner break `project_spec` into a task DAG and
+    /// validate it, without creating a session, spawning agents or executing
+    /// anything. Only the planner's model call is billed; it's audited under
+    /// the nil session and agent ids. An approved plan can be passed to
+    /// `submit_plan` as is.
+    pub async fn plan_only(&self, project_spec: &ProjectSpec) -> Result<PlanReport, SwarmError> {
+        project_spec.validate()?;
+        let model = self.router.model_for(AgentRole::Planner, project_spec.estimated_complexity);
+        let CachedResponse { response, hit, .. } = self.agent_pool.model_clients
+            .complete_for(SessionId::nil(), AgentId::nil(), model, &planning_prompt(project_spec))
+            .await?;
+
+        let tasks = parse_plan(&response.text, self.task_queue.ids())?;
+        let (critical_path, critical_path_min) = critical_path(&tasks);
+        Ok(PlanReport {
+            total_estimated_min: tasks.iter().map(|t| t.estimated_time_min).sum(),
+            tasks,
+            critical_path,
+            critical_path_min,
+            model: response.model,
+            cost: if hit { 0.0 } else { ModelClients::cost_of(response.model, &response.usage) },
         })
     }
 
-    /// Pause execution (for resource management)
+    /// Status of every session matching `filter`, newest first
+    pub async fn list_sessions(&self, filter: SessionFilter) -> Vec<SessionStatusReport> {
+        self.observer().list_sessions(filter).await
+    }
+
+    /// Totals across every session on this node, with the `top_n` most
+    /// expensive; see `OrchestratorStats`
+    pub async fn aggregate_stats(&self, top_n: usize) -> OrchestratorStats {
+        self.observer().aggregate_stats(top_n).await
+    }
+
+    /// Pause execution (for resource management). Agents finish the task
+    /// they're on but start nothing new until `resume_session`. A completed
+    /// or failed session fails with `SessionNotActive`.
     pub async fn pause_session(
         &self,
         session_id: SessionId,
@@ -298,315 +1977,14811 @@ impl SessionManager {
         let mut sessions = self.sessions.write().await;
         let session = sessions.get_mut(&session_id)
             .ok_or(SwarmError::SessionNotFound)?;
+        // A finished session stays finished
+        if matches!(session.status, SessionStatus::Completed | SessionStatus::Failed) {
+            return Err(SwarmError::SessionNotActive(session.status));
+        }
 
-        session.status = SessionStatus::Paused;
+        session.set_status(SessionStatus::Paused);
         Ok(())
     }
 
-    /// Resume paused session
-    pub async fn resume_session(
+    /// Replace the session's tags
+    pub async fn set_tags(
         &self,
         session_id: SessionId,
+        tags: HashMap<String, String>,
     ) -> Result<(), SwarmError> {
         let mut sessions = self.sessions.write().await;
         let session = sessions.get_mut(&session_id)
             .ok_or(SwarmError::SessionNotFound)?;
 
-        session.status = SessionStatus::Active;
+        session.tags = tags;
         Ok(())
     }
 
-    /// Destroy session and clean up resources
-    pub async fn destroy_session(
+    /// Resume paused session. One `reap_idle_sessions` paused gets its
+    /// agents back first, within the user's agent quota, and stays paused
+    /// if they can't be spawned. A completed or failed session, its agents
+    /// and quota already released, fails with `SessionNotActive`.
+    pub async fn resume_session(
         &self,
         session_id: SessionId,
-    ) -> Result<SessionMetrics, SwarmError> {
-        let mut sessions = self.sessions.write().await;
-        let session = sessions.remove(&session_id)
-            .ok_or(SwarmError::SessionNotFound)?;
+    ) -> Result<(), SwarmError> {
+        let (user_id, parked, shared_state, control, span) = {
+            let mut sessions = self.sessions.write().await;
+            let session = sessions.get_mut(&session_id)
+                .ok_or(SwarmError::SessionNotFound)?;
+            if matches!(session.status, SessionStatus::Completed | SessionStatus::Failed) {
+                return Err(SwarmError::SessionNotActive(session.status));
+            }
 
-        // Clean up agents
-        for agent in &session.agents {
-            self.agent_pool.terminate_agent(agent.id).await?;
-        }
+            if session.over_budget() && session.project_spec.budget_policy != BudgetPolicy::WarnOnly {
+                return Err(SwarmError::BudgetExceeded);
+            }
 
-        // Clean up shared state
-        self.state_manager.destroy_state_space(session_id).await?;
+            if session.parked.is_empty() {
+                session.set_status(SessionStatus::Active);
+                session.last_activity = Instant::now();
+                return Ok(());
+            }
+            self.reserve_quota(&session.user_id, 0, session.parked.len()).await?;
+            session.agents_starting += session.parked.len();
+            let parked = std::mem::take(&mut session.parked);
+            (session.user_id.clone(), parked, session.shared_state.clone(), session.control.clone(), session.span.clone())
+        };
 
-        Ok(session.metrics)
+        let mut agents = Vec::with_capacity(parked.len());
+        for (role, model, skills) in parked.iter().cloned() {
+            match self.spawn_agent(session_id, role, model, skills, shared_state.clone(), control.clone(), &span).await {
+                Ok(agent) => agents.push(agent),
+                Err(e) => {
+                    for agent in &agents {
+                        let _ = self.agent_pool.terminate_agent(agent.id).await;
+                    }
+                    let mut sessions = self.sessions.write().await;
+                    // Failed or destroyed meanwhile, which released the quota
+                    if let Some(session) = sessions.get_mut(&session_id).filter(|s| s.agents_starting >= parked.len()) {
+                        session.agents_starting -= parked.len();
+                        self.release_quota(&user_id, 0, parked.len()).await;
+                        session.parked = parked;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let mut sessions = self.sessions.write().await;
+        match sessions.get_mut(&session_id).filter(|s| {
+            matches!(s.status, SessionStatus::Active | SessionStatus::Paused) && s.agents_starting >= parked.len()
+        }) {
+            Some(session) => {
+                session.agents_starting -= parked.len();
+                session.metrics.agents_spawned += agents.len();
+                for agent in agents {
+                    session.push_agent(agent);
+                }
+                session.set_status(SessionStatus::Active);
+                session.last_activity = Instant::now();
+                Ok(())
+            }
+            None => {
+                drop(sessions);
+                for agent in &agents {
+                    let _ = self.agent_pool.terminate_agent(agent.id).await;
+                }
+                Err(SwarmError::SessionNotFound)
+            }
+        }
     }
-}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct SessionStatusReport {
+    /// Hand a task to one of the session's agents; only active sessions accept
+    /// work. The agent gets the task as the `TaskInterceptor`s leave it.
+    pub async fn assign_task(
+        &self,
+        session_id: SessionId,
+        agent_id: AgentId,
+        mut task: Task,
+    ) -> Result<(), SwarmError> {
+        let sessions = self.sessions.read().await;
+        let session = sessions.get(&session_id)
+            .ok_or(SwarmError::SessionNotFound)?;
+        if session.status != SessionStatus::Active {
+            return Err(SwarmError::SessionNotActive(session.status));
+        }
+        let agent = session.agents.iter()
+            .find(|a| a.id == agent_id)
+            .ok_or(SwarmError::AgentNotFound)?;
+        if let Some(capability) = agent.capabilities.missing(&task.required_capabilities) {
+            return Err(SwarmError::UnsupportedCapability { agent_id, model: agent.model, capability });
+        }
+
+        // Record the owner, so a result from this agent is dropped if the
+        // task is reclaimed and handed to another
+        self.task_queue.claim(task.id, agent_id).await;
+        self.intercept_before(&mut task);
+
+        // Keep the read lock until the task is in flight, so a concurrent
+        // drain either sees it or rejects it
+        self.agent_pool.assign_task(agent_id, task).await
+    }
+
+    /// Run one of a session's queued tasks on `agent_id`, bypassing
+    /// `dispatch_ready`'s choice of coder. The agent must be idle and of the
+    /// role the task needs (a verifier for verification tasks, else a
+    /// coder) with a model supporting its `required_capabilities`, and the
+    /// task ready to start.
+    pub async fn assign_task_to_agent(
+        &self,
+        session_id: SessionId,
+        task_id: TaskId,
+        agent_id: AgentId,
+    ) -> Result<(), SwarmError> {
+        {
+            let sessions = self.sessions.read().await;
+            let session = sessions.get(&session_id)
+                .ok_or(SwarmError::SessionNotFound)?;
+            if session.status != SessionStatus::Active {
+                return Err(SwarmError::SessionNotActive(session.status));
+            }
+            let agent = session.agents.iter()
+                .find(|a| a.id == agent_id)
+                .ok_or(SwarmError::AgentNotFound)?;
+            let task = self.task_queue.outstanding_for(session_id).await
+                .into_iter()
+                .find(|t| t.id == task_id)
+                .ok_or(SwarmError::TaskNotFound(task_id))?;
+            let required = if task.verifies.is_some() { AgentRole::Verifier } else { AgentRole::Coder };
+            if agent.role != required {
+                return Err(SwarmError::WrongRole { agent_id, role: agent.role, required });
+            }
+            if let Some(capability) = agent.capabilities.missing(&task.required_capabilities) {
+                return Err(SwarmError::UnsupportedCapability { agent_id, model: agent.model, capability });
+            }
+            if agent.status != AgentStatus::Idle || self.agent_pool.loads(&[agent_id]).await != [Some(0)] {
+                return Err(SwarmError::AgentBusy(agent_id));
+            }
+        }
+
+        let task = self.task_queue.dequeue_task(session_id, task_id).await?;
+        if let Err(e) = self.assign_task(session_id, agent_id, task.clone()).await {
+            self.task_queue.requeue(task).await;
+            return Err(e);
+        }
+        self.agent_pool.charge_session(session_id);
+        Ok(())
+    }
+
+    /// Change how `dispatch_ready` picks among the session's coders
+    pub async fn set_assignment_strategy(
+        &self,
+        session_id: SessionId,
+        strategy: AssignmentStrategy,
+    ) -> Result<(), SwarmError> {
+        let mut sessions = self.sessions.write().await;
+        let session = sessions.get_mut(&session_id)
+            .ok_or(SwarmError::SessionNotFound)?;
+        session.assignment = strategy;
+        Ok(())
+    }
+
+    /// Grow a session by one agent of `role`, the router's model for it, and
+    /// `skills` (see `AgentPool::spawn_agent_with_skills`). Counts against
+    /// the user's agent quota, and fails with `QuotaExceeded` once the
+    /// session holds `MAX_AGENTS_PER_SESSION`. The agent is spawned without
+    /// holding `sessions`.
+    pub async fn add_agent(
+        &self,
+        session_id: SessionId,
+        role: AgentRole,
+        skills: Vec<String>,
+    ) -> Result<AgentId, SwarmError> {
+        let running = |s: &&mut Session| matches!(s.status, SessionStatus::Active | SessionStatus::Paused);
+        let (user_id, model, shared_state, control, span) = {
+            let mut sessions = self.sessions.write().await;
+            let session = sessions.get_mut(&session_id)
+                .ok_or(SwarmError::SessionNotFound)?;
+            if !running(&session) {
+                return Err(SwarmError::SessionNotActive(session.status));
+            }
+            if session.agents.len() + session.agents_starting + session.parked.len() >= MAX_AGENTS_PER_SESSION {
+                return Err(SwarmError::QuotaExceeded {
+                    user_id: session.user_id.clone(),
+                    resource: "agents per session",
+                    limit: MAX_AGENTS_PER_SESSION,
+                });
+            }
+            self.reserve_quota(&session.user_id, 0, 1).await?;
+            session.agents_starting += 1;
+            let model = self.router.model_for(role, session.project_spec.estimated_complexity);
+            (session.user_id.clone(), model, session.shared_state.clone(), session.control.clone(), session.span.clone())
+        };
+
+        let agent = self.spawn_agent(session_id, role, model, skills, shared_state, control, &span).await;
+
+        let mut sessions = self.sessions.write().await;
+        match (sessions.get_mut(&session_id).filter(|s| running(s) && s.agents_starting > 0), agent) {
+            (Some(session), Ok(agent)) => {
+                let agent_id = agent.id;
+                session.agents_starting -= 1;
+                session.push_agent(agent);
+                session.metrics.agents_spawned += 1;
+                Ok(agent_id)
+            }
+            (Some(session), Err(e)) => {
+                session.agents_starting -= 1;
+                self.release_quota(&user_id, 0, 1).await;
+                Err(e)
+            }
+            // Failed or destroyed meanwhile, which released the quota
+            (None, agent) => {
+                drop(sessions);
+                if let Ok(agent) = agent {
+                    let _ = self.agent_pool.terminate_agent(agent.id).await;
+                }
+                Err(SwarmError::SessionNotFound)
+            }
+        }
+    }
+
+    /// Cancel one of a session's tasks: drop it from `pending`, or abort it
+    /// mid-flight by signalling the agent running it. The agent goes back to
+    /// idle without counting the task, and a late result is discarded.
+    pub async fn cancel_task(
+        &self,
+        session_id: SessionId,
+        task_id: TaskId,
+    ) -> Result<(), SwarmError> {
+        let sessions = self.sessions.read().await;
+        let session = sessions.get(&session_id)
+            .ok_or(SwarmError::SessionNotFound)?;
+        let agents: Vec<AgentId> = session.agents.iter().map(|a| a.id).collect();
+
+        let removed = self.task_queue.cancel(task_id, session_id).await;
+        let running = self.agent_pool.cancel_task(task_id, &agents).await;
+        match removed {
+            Some(task) => task.cancellation.cancel(),
+            None if !running => return Err(SwarmError::TaskNotFound(task_id)),
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Results of a session's completed tasks, oldest first
+    pub async fn collect_results(&self, session_id: SessionId) -> Vec<TaskResult> {
+        let mut results = self.task_queue.results_for(session_id).await;
+        results.sort_by_key(|r| r.completed_at);
+        results
+    }
+
+    /// Validate a session's task plan and enqueue it. Fails fast with the
+    /// offending cycle, leaving the queue untouched, if dependencies loop.
+    pub async fn submit_plan(
+        &self,
+        session_id: SessionId,
+        mut tasks: Vec<Task>,
+    ) -> Result<(), SwarmError> {
+        let sessions = self.sessions.read().await;
+        let session = sessions.get(&session_id)
+            .ok_or(SwarmError::SessionNotFound)?;
+        if session.status != SessionStatus::Active {
+            return Err(SwarmError::SessionNotActive(session.status));
+        }
+
+        for task in &mut tasks {
+            task.session_id = Some(session_id);
+        }
+        self.task_queue.enqueue_plan(tasks).await
+    }
+
+    /// Validate a user-written plan (see `PlanDocument::validate`) and
+    /// submit it as `submit_plan` would, failing with every problem found
+    pub async fn submit_plan_document(
+        &self,
+        session_id: SessionId,
+        document: &PlanDocument,
+    ) -> Result<Vec<TaskId>, SwarmError> {
+        let tasks = document
+            .to_tasks(self.task_queue.ids())
+            .map_err(SwarmError::InvalidPlanDocument)?;
+        let ids = tasks.iter().map(|t| t.id).collect();
+        self.submit_plan(session_id, tasks).await?;
+        Ok(ids)
+    }
+
+    /// Make `dependent`, a task of one session, wait for the result of
+    /// `dependency`, a task of another (e.g. research feeding manufacturing).
+    /// Both are `(session, task)` pairs, and the dependent may be linked
+    /// before or after it is submitted; the upstream task must be submitted
+    /// already, else `TaskNotFound`.
+    ///
+    /// An upstream task that has already completed satisfies the link at
+    /// once, even if its session has since been destroyed. One that is
+    /// dead-lettered, cancelled or destroyed with its session takes the
+    /// dependent down with it, to the dead-letter set.
+    pub async fn link_dependency(
+        &self,
+        dependent: SessionTask,
+        dependency: SessionTask,
+    ) -> Result<(), SwarmError> {
+        let sessions = self.sessions.read().await;
+        let session = sessions.get(&dependent.0)
+            .ok_or(SwarmError::SessionNotFound)?;
+        if !matches!(session.status, SessionStatus::Active | SessionStatus::Paused) {
+            return Err(SwarmError::SessionNotActive(session.status));
+        }
+        // Linking to a session that is gone only works for a result it left
+        if !sessions.contains_key(&dependency.0)
+            && self.task_queue.published_result(dependency.0, dependency.1).await.is_none()
+        {
+            return Err(SwarmError::SessionNotFound);
+        }
+
+        self.task_queue.link_dependency(dependent, dependency).await
+    }
+
+    /// Move `session` to `Completed` or `Failed`. The first time it
+    /// finishes, its metrics go to the project's `completion_webhook` in the
+    /// background.
+    fn finish_session(&self, session: &mut Session, status: SessionStatus) {
+        let finished = matches!(session.status, SessionStatus::Completed | SessionStatus::Failed);
+        session.set_status(status);
+        session.control.end();
+        if finished {
+            return;
+        }
+        let Some(url) = session.project_spec.completion_webhook.clone() else { return };
+        let secret = session.project_spec.webhook_secret.clone();
+        let (session_id, metrics, webhooks) = (session.id, session.metrics.clone(), self.webhooks.clone());
+        tokio::spawn(
+            async move { deliver_webhook(&webhooks, &url, secret.as_deref(), session_id, status, &metrics).await }
+                .instrument(session.span.clone()),
+        );
+    }
+
+    /// Mark a session as finished and announce its final metrics
+    pub async fn complete_session(
+        &self,
+        session_id: SessionId,
+    ) -> Result<SessionMetrics, SwarmError> {
+        let metrics = {
+            let mut sessions = self.sessions.write().await;
+            let session = sessions.get_mut(&session_id)
+                .ok_or(SwarmError::SessionNotFound)?;
+            self.finish_session(session, SessionStatus::Completed);
+            session.metrics.clone()
+        };
+
+        self.emit(SwarmEvent::SessionCompleted { session_id, metrics: metrics.clone() });
+        Ok(metrics)
+    }
+
+    /// Destroy session and clean up resources
+    pub async fn destroy_session(
+        &self,
+        session_id: SessionId,
+    ) -> Result<SessionMetrics, SwarmError> {
+        let mut sessions = self.sessions.write().await;
+        let metrics = self.remove_tree(&mut sessions, session_id).await;
+        drop(sessions);
+
+        self.admit_queued_sessions().await;
+        metrics
+    }
+
+    /// Remove a session and its sub-sessions from `sessions` and tear them
+    /// down, deepest first. A failing teardown doesn't stop the rest: every
+    /// one goes as far as it can, and what failed is reported together as
+    /// `TeardownFailed`.
+    async fn remove_tree(
+        &self,
+        sessions: &mut HashMap<SessionId, Session>,
+        session_id: SessionId,
+    ) -> Result<SessionMetrics, SwarmError> {
+        let Some(session) = sessions.remove(&session_id) else {
+            return Err(SwarmError::SessionNotFound);
+        };
+
+        let mut errors = vec![];
+        let mut collect = |result: Result<SessionMetrics, SwarmError>| match result {
+            Ok(metrics) => Some(metrics),
+            Err(SwarmError::TeardownFailed { errors: failed, .. }) => {
+                errors.extend(failed);
+                None
+            }
+            Err(e) => {
+                errors.push(e);
+                None
+            }
+        };
+        // Found through their parent ids, which outlive the parent's entry
+        for child in descendants(sessions, session_id).into_iter().rev() {
+            if let Some(child) = sessions.remove(&child) {
+                collect(self.tear_down(child).await);
+            }
+        }
+        let metrics = collect(self.tear_down(session).await);
+        match metrics {
+            Some(metrics) if errors.is_empty() => Ok(metrics),
+            _ => Err(SwarmError::TeardownFailed { session_id, errors }),
+        }
+    }
+
+    /// Destroy every session `user_id` owns, returning each with its final
+    /// metrics, or the error its teardown ended with: it's gone either way.
+    /// New sessions for the user fail with `UserCancelling` until this
+    /// returns or is dropped, and sessions whose creation was already under
+    /// way are waited for, up to `with_cancel_settle_timeout`, and destroyed
+    /// too.
+    pub async fn cancel_user_sessions(&self, user_id: &str) -> Vec<(SessionId, Result<SessionMetrics, SwarmError>)> {
+        let _cancelling = Cancelling::start(self, user_id);
+        let settle_by = Instant::now() + self.cancel_settle_timeout;
+        let mut cancelled = vec![];
+        loop {
+            // Sub-sessions first, so each is reported rather than taken
+            // down with its parent
+            let leaves: Vec<SessionId> = {
+                let sessions = self.sessions.read().await;
+                let owned: Vec<&Session> = sessions.values().filter(|s| s.user_id == user_id).collect();
+                let parents: HashSet<SessionId> = owned.iter().filter_map(|s| s.parent_id).collect();
+                owned.iter().filter(|s| !parents.contains(&s.id)).map(|s| s.id).collect()
+            };
+            if leaves.is_empty() {
+                // Quota reserved but no session yet: a create still spawning
+                if self.usage.read().await.get(user_id).is_none_or(|u| u.sessions == 0) {
+                    break;
+                }
+                if Instant::now() >= settle_by {
+                    warn!(user_id, "gave up waiting for the user's sessions in creation; their quota may have leaked");
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                continue;
+            }
+            for session_id in leaves {
+                match self.destroy_session(session_id).await {
+                    // Destroyed by someone else meanwhile
+                    Err(SwarmError::SessionNotFound) => {}
+                    Err(err) => {
+                        warn!(%session_id, error = %err, "cancelled session not cleanly torn down");
+                        cancelled.push((session_id, Err(err)));
+                    }
+                    Ok(metrics) => cancelled.push((session_id, Ok(metrics))),
+                }
+            }
+        }
+
+        info!(user_id, sessions = cancelled.len(), "user's sessions cancelled");
+        cancelled
+    }
+
+    /// Release everything a session removed from `sessions` held. Every
+    /// step is tried; any that failed are reported as `TeardownFailed`.
+    async fn tear_down(&self, session: Session) -> Result<SessionMetrics, SwarmError> {
+        let session_id = session.id;
+        session.control.end();
+        self.agent_pool.metrics.session_destroyed();
+        self.agent_pool.forget_session(session_id);
+        self.agent_pool.model_clients.clear_session_rate(session_id);
+        self.task_queue.purge_session(session_id).await;
+        self.event_logs().remove(&session_id);
+        // A queued session holds its roster's quota without any agents yet
+        let reserved = match session.status {
+            SessionStatus::Initializing => self.roster_for(&session).len(),
+            _ => session.agents.len() + session.agents_starting,
+        };
+        self.release_quota(&session.user_id, 1, reserved).await;
+
+        // Clean up agents, keeping idle ones warm for the next session
+        let mut errors = vec![];
+        for agent in &session.agents {
+            errors.extend(self.agent_pool.release_agent(agent.id).await.err());
+        }
+
+        // Clean up shared state
+        errors.extend(self.state_manager.destroy_state_space(session_id).await.err());
+
+        // A destroyed session must not come back on the next restore
+        let backend = self.state_manager.backend();
+        for key in [Self::checkpoint_key(session_id), Self::task_checkpoint_key(session_id), Self::index_key(session_id)] {
+            errors.extend(backend.delete(&key).await.err());
+        }
+
+        if !errors.is_empty() {
+            return Err(SwarmError::TeardownFailed { session_id, errors });
+        }
+        Ok(session.metrics)
+    }
+
+    /// Gracefully shut a session down: stop accepting tasks, wait up to
+    /// `timeout` for assigned ones to finish, then destroy it.
+    ///
+    /// Tasks still running at the deadline are abandoned when their agents
+    /// are terminated. `destroy_session` remains the forceful path.
+    pub async fn drain_session(
+        &self,
+        session_id: SessionId,
+        timeout: Duration,
+    ) -> Result<DrainReport, SwarmError> {
+        let agent_ids: Vec<AgentId> = {
+            let mut sessions = self.sessions.write().await;
+            let session = sessions.get_mut(&session_id)
+                .ok_or(SwarmError::SessionNotFound)?;
+            // Also releases a paused session, so its queued tasks can finish
+            session.set_status(SessionStatus::Draining);
+            session.agents.iter().map(|a| a.id).collect()
+        };
+
+        let in_flight = self.agent_pool.in_flight(&agent_ids).await;
+        let abandoned = self.agent_pool
+            .wait_idle(&agent_ids, Instant::now() + timeout)
+            .await;
+        // Verdicts trail the reports that settled their tasks
+        self.agent_pool.flush_reports().await;
+        let metrics = self.destroy_session(session_id).await?;
+
+        Ok(DrainReport {
+            // Verifications started meanwhile count as in flight too
+            completed: in_flight.saturating_sub(abandoned),
+            abandoned,
+            metrics,
+        })
+    }
+
+    /// Prepare for process exit (e.g. on SIGTERM): stop dispatching to
+    /// active sessions, give the tasks their agents already hold up to
+    /// `timeout` to finish, then pause and checkpoint every active or paused
+    /// session so `restore_all` can pick it up.
+    ///
+    /// Sessions stay in memory, paused; sessions created meanwhile are left
+    /// alone. Safe to call while tasks are in flight.
+    pub async fn shutdown(&self, timeout: Duration) -> ShutdownReport {
+        let (session_ids, agent_ids) = {
+            let mut sessions = self.sessions.write().await;
+            let (mut session_ids, mut agent_ids) = (vec![], vec![]);
+            for (id, session) in sessions.iter_mut() {
+                match session.status {
+                    // Draining: held tasks keep running, nothing new is dispatched
+                    SessionStatus::Active => {
+                        session.set_status(SessionStatus::Draining);
+                        agent_ids.extend(session.agents.iter().map(|a| a.id));
+                    }
+                    // Already holding their agents; nothing will finish
+                    SessionStatus::Paused => {}
+                    _ => continue,
+                }
+                session_ids.push(*id);
+            }
+            (session_ids, agent_ids)
+        };
+
+        let in_flight = self.agent_pool.in_flight(&agent_ids).await;
+        let tasks_abandoned = self.agent_pool
+            .wait_idle(&agent_ids, Instant::now() + timeout)
+            .await;
+        // Checkpoint only once what the agents reported is in session state
+        self.agent_pool.flush_reports().await;
+
+        let mut report = ShutdownReport {
+            tasks_completed: in_flight.saturating_sub(tasks_abandoned),
+            tasks_abandoned,
+            ..ShutdownReport::default()
+        };
+        {
+            let mut sessions = self.sessions.write().await;
+            for session_id in &session_ids {
+                if let Some(session) = sessions.get_mut(session_id) {
+                    session.set_status(SessionStatus::Paused);
+                }
+            }
+        }
+        for session_id in session_ids {
+            match self.checkpoint_session(session_id).await {
+                Ok(()) => report.saved.push(session_id),
+                // Destroyed while we waited, or Redis is unreachable
+                Err(e) => {
+                    warn!(%session_id, error = %e, "session not checkpointed at shutdown");
+                    report.abandoned.push(session_id);
+                }
+            }
+        }
+        info!(saved = report.saved.len(), abandoned = report.abandoned.len(), "shutdown checkpoint done");
+        report
+    }
+
+    /// Grow or shrink a session's coder pool to match its queue backlog.
+    ///
+    /// Compares the session's pending tasks against its idle coders: spawns
+    /// coders when the backlog exceeds `scale_up_threshold`, terminates idle
+    /// ones when the surplus exceeds `scale_down_threshold`. Each call moves
+    /// at most `max_step` agents, so it's safe to run on a timer. Only an
+    /// `Active` session scales. Quota is reserved and the step planned under
+    /// `sessions`; agents are spawned and terminated outside it. A spawn that
+    /// fails ends the step with its error: the coders spawned before it stay,
+    /// counted in the session's metrics, and the rest of the quota goes back.
+    pub async fn autoscale(
+        &self,
+        session_id: SessionId,
+    ) -> Result<AutoscaleOutcome, SwarmError> {
+        let pending = self.task_queue.pending_len_for(session_id).await;
+        let config = &self.autoscale;
+
+        let (spawn, terminate, user_id, coder_model, shared_state, control, span) = {
+            let mut sessions = self.sessions.write().await;
+            let session = sessions.get_mut(&session_id)
+                .ok_or(SwarmError::SessionNotFound)?;
+            if session.status != SessionStatus::Active {
+                return Err(SwarmError::SessionNotActive(session.status));
+            }
+
+            let coders: Vec<&AgentHandle> = session.agents
+                .iter()
+                .filter(|a| a.role == AgentRole::Coder)
+                .collect();
+            let idle_coders: Vec<AgentId> = coders
+                .iter()
+                .filter(|a| a.status == AgentStatus::Idle)
+                .map(|a| a.id)
+                .collect();
+            let coder_model = coders.first().map_or_else(
+                || self.router.model_for(AgentRole::Coder, session.project_spec.estimated_complexity),
+                |a| a.model,
+            );
+            // Coders already starting count as there, so calls don't both
+            // scale the same gap
+            let total_coders = coders.len() + session.agents_starting;
+            // A downshifted session doesn't grow past its reduced roster
+            let coder_cap = session.effective_mode.map(|mode| {
+                compute_agent_counts(&ProjectSpec { parallelization: mode, ..session.project_spec.clone() }).coders
+            });
+            let backlog = pending.saturating_sub(idle_coders.len() + session.agents_starting);
+            let surplus = idle_coders.len().saturating_sub(pending);
+
+            let (mut spawn, mut terminate) = (0, vec![]);
+            if backlog > config.scale_up_threshold {
+                let headroom = config.max_agents
+                    .saturating_sub(session.agents.len() + session.agents_starting)
+                    .min(coder_cap.map_or(usize::MAX, |cap| cap.saturating_sub(total_coders)));
+                let mut reserved = 0;
+                for _ in 0..backlog.min(config.max_step).min(headroom) {
+                    match self.reserve_quota(&session.user_id, 0, 1).await {
+                        Ok(()) => reserved += 1,
+                        // Short of quota, grow as far as it goes
+                        Err(SwarmError::QuotaExceeded { .. }) => break,
+                        Err(e) => {
+                            self.release_quota(&session.user_id, 0, reserved).await;
+                            return Err(e);
+                        }
+                    }
+                }
+                session.agents_starting += reserved;
+                spawn = reserved;
+            } else if surplus > config.scale_down_threshold {
+                // Always keep one coder around
+                let count = (surplus - config.scale_down_threshold)
+                    .min(config.max_step)
+                    .min(coders.len().saturating_sub(1));
+                let idle: Vec<AgentId> = idle_coders.into_iter().take(count).collect();
+                // Out of the session first, so nothing is dispatched to them
+                for &agent_id in &idle {
+                    session.remove_agent(agent_id);
+                }
+                self.release_quota(&session.user_id, 0, idle.len()).await;
+                terminate = idle;
+            }
+            (spawn, terminate, session.user_id.clone(), coder_model, session.shared_state.clone(), session.control.clone(), session.span.clone())
+        };
+
+        let mut outcome = AutoscaleOutcome::default();
+        let mut failure = None;
+        for agent_id in terminate {
+            match self.agent_pool.terminate_agent(agent_id).await {
+                Ok(()) => outcome.terminated += 1,
+                Err(e) => {
+                    failure.get_or_insert(e);
+                }
+            }
+        }
+        if let Some(e) = failure {
+            return Err(e);
+        }
+
+        for started in 0..spawn {
+            let coder = self.spawn_agent(
+                session_id,
+                AgentRole::Coder,
+                coder_model,
+                vec![],
+                shared_state.clone(),
+                control.clone(),
+                &span,
+            ).await;
+
+            let mut sessions = self.sessions.write().await;
+            let Some(session) = sessions.get_mut(&session_id).filter(|s| {
+                matches!(s.status, SessionStatus::Active | SessionStatus::Paused) && s.agents_starting > 0
+            }) else {
+                // Failed or destroyed meanwhile, which released the quota
+                drop(sessions);
+                if let Ok(coder) = coder {
+                    let _ = self.agent_pool.terminate_agent(coder.id).await;
+                }
+                return Err(SwarmError::SessionNotFound);
+            };
+            match coder {
+                Ok(coder) => {
+                    session.agents_starting -= 1;
+                    session.push_agent(coder);
+                    session.metrics.agents_spawned += 1;
+                    outcome.spawned += 1;
+                }
+                Err(e) => {
+                    // This coder and the ones not started yet
+                    let unstarted = spawn - started;
+                    session.agents_starting = session.agents_starting.saturating_sub(unstarted);
+                    self.release_quota(&user_id, 0, unstarted).await;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// Downshift live sessions to what the host can carry at `level`, or
+    /// shift them back up as pressure eases. Each session's effective mode
+    /// becomes the lesser of its requested one and the level's ceiling
+    /// (e.g. `High` runs Turbo sessions as `Batch10`); new sessions and
+    /// `autoscale` respect it too.
+    ///
+    /// Sessions shrink by terminating idle agents only, so none fails and
+    /// busy agents finish their tasks; call again to trim those. Growing back
+    /// is bounded by the user's agent quota. Agents are spawned and
+    /// terminated without holding `sessions`.
+    pub async fn apply_resource_pressure(
+        &self,
+        level: PressureLevel,
+    ) -> Result<AutoscaleOutcome, SwarmError> {
+        *self.pressure.write().expect("pressure lock poisoned") = level;
+
+        // Decided under the locks, carried out without them
+        let mut resizes = vec![];
+        {
+            let mut sessions = self.sessions.write().await;
+            let mut usage = self.usage.write().await;
+            for session in sessions.values_mut() {
+                if !matches!(session.status, SessionStatus::Active | SessionStatus::Paused) {
+                    continue;
+                }
+                let requested = session.project_spec.parallelization;
+                let mode = level.cap(requested);
+                let previous = session.effective_mode;
+                session.effective_mode = (mode != requested).then_some(mode);
+                if session.effective_mode != previous {
+                    session.span.in_scope(|| info!(?level, ?mode, "parallelization shifted"));
+                }
+
+                let target = self.initial_roster(&ProjectSpec { parallelization: mode, ..session.project_spec.clone() });
+                let user = usage.entry(session.user_id.clone()).or_default();
+                let resize = self.plan_resize(session, user, &target, mode < requested);
+                if !resize.spawn.is_empty() || !resize.terminate.is_empty() {
+                    resizes.push(resize);
+                }
+            }
+        }
+
+        let mut outcome = AutoscaleOutcome::default();
+        let mut failure = None;
+        for resize in resizes {
+            for &agent_id in &resize.terminate {
+                match self.agent_pool.terminate_agent(agent_id).await {
+                    Ok(()) => outcome.terminated += 1,
+                    Err(e) => {
+                        failure.get_or_insert(e);
+                    }
+                }
+            }
+            let mut agents = Vec::with_capacity(resize.spawn.len());
+            for &(role, model) in &resize.spawn {
+                let shared_state = resize.shared_state.clone();
+                match self.spawn_agent(resize.session_id, role, model, vec![], shared_state, resize.control.clone(), &resize.span).await {
+                    Ok(agent) => agents.push(agent),
+                    Err(e) => {
+                        failure.get_or_insert(e);
+                        break;
+                    }
+                }
+            }
+            outcome.spawned += self.settle_resize(resize, agents).await;
+        }
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(outcome),
+        }
+    }
+
+    /// Work out the agents `session` lacks against `target` and, with
+    /// `shrink`, the idle ones beyond it, role by role. Quota for the spawns
+    /// is reserved and they count as starting; the idle agents leave the
+    /// session right away, so nothing is dispatched to them meanwhile.
+    fn plan_resize(
+        &self,
+        session: &mut Session,
+        user: &mut UserUsage,
+        target: &[(AgentRole, ModelPreference)],
+        shrink: bool,
+    ) -> Resize {
+        let mut wanted: Vec<(AgentRole, ModelPreference, usize)> = vec![];
+        for &(role, model) in target {
+            match wanted.iter_mut().find(|(r, ..)| *r == role) {
+                Some((.., count)) => *count += 1,
+                None => wanted.push((role, model, 1)),
+            }
+        }
+
+        let mut resize = Resize {
+            session_id: session.id,
+            user_id: session.user_id.clone(),
+            spawn: vec![],
+            terminate: vec![],
+            shared_state: session.shared_state.clone(),
+            control: session.control.clone(),
+            span: session.span.clone(),
+        };
+        for (role, model, want) in wanted {
+            let have = session.agents.iter().filter(|a| a.role == role).count();
+            if have < want {
+                let headroom = self.quotas.max_total_agents_per_user.saturating_sub(user.agents);
+                let count = (want - have).min(headroom);
+                resize.spawn.extend(std::iter::repeat_n((role, model), count));
+                user.agents += count;
+            } else if shrink && have > want {
+                let idle: Vec<AgentId> = session.agents
+                    .iter()
+                    .filter(|a| a.role == role && a.status == AgentStatus::Idle)
+                    .map(|a| a.id)
+                    .take(have - want)
+                    .collect();
+                for &agent_id in &idle {
+                    session.remove_agent(agent_id);
+                }
+                user.agents = user.agents.saturating_sub(idle.len());
+                resize.terminate.extend(idle);
+            }
+        }
+        session.agents_starting += resize.spawn.len();
+        resize
+    }
+
+    /// Add the agents a resize spawned to its session, giving back the quota
+    /// of those it didn't get to. Returns how many were added; a session gone
+    /// meanwhile took the quota with it, and its agents are terminated.
+    async fn settle_resize(&self, resize: Resize, agents: Vec<AgentHandle>) -> usize {
+        let planned = resize.spawn.len();
+        let mut sessions = self.sessions.write().await;
+        match sessions.get_mut(&resize.session_id).filter(|s| {
+            matches!(s.status, SessionStatus::Active | SessionStatus::Paused) && s.agents_starting >= planned
+        }) {
+            Some(session) => {
+                let spawned = agents.len();
+                session.agents_starting -= planned;
+                session.metrics.agents_spawned += spawned;
+                for agent in agents {
+                    session.push_agent(agent);
+                }
+                self.release_quota(&resize.user_id, 0, planned - spawned).await;
+                spawned
+            }
+            None => {
+                drop(sessions);
+                for agent in &agents {
+                    let _ = self.agent_pool.terminate_agent(agent.id).await;
+                }
+                0
+            }
+        }
+    }
+
+    /// Mode a session actually runs at: its requested one, unless downshifted
+    /// by `apply_resource_pressure`
+    pub async fn effective_mode(&self, session_id: SessionId) -> Result<ParallelizationMode, SwarmError> {
+        let sessions = self.sessions.read().await;
+        let session = sessions.get(&session_id)
+            .ok_or(SwarmError::SessionNotFound)?;
+        Ok(session.effective_mode.unwrap_or(session.project_spec.parallelization))
+    }
+
+    /// Put tasks in progress for longer than `timeout` back in the queue and
+    /// mark the agents holding them `Failed`. Returns the reclaimed count.
+    pub async fn reclaim_stale_tasks(&self, timeout: Duration) -> usize {
+        let reclaimed = self.task_queue.reclaim_stale(timeout).await;
+
+        for stale in &reclaimed {
+            let session_id = match stale.agent_id {
+                Some(agent_id) => {
+                    self.agent_pool.update_agent(agent_id, |a| a.transition_or_log(AgentStatus::Failed)).await;
+
+                    let mut sessions = self.sessions.write().await;
+                    let session = sessions.values_mut().find(|s| s.agents.iter().any(|a| a.id == agent_id));
+                    session.map(|session| {
+                        session.transition_agent_or_log(agent_id, AgentStatus::Failed);
+                        if stale.outcome == FailureOutcome::DeadLettered {
+                            session.metrics.tasks_failed += 1;
+                        }
+                        session.id
+                    })
+                }
+                None => None,
+            };
+
+            warn!(task_id = %stale.task_id, agent_id = ?stale.agent_id, outcome = ?stale.outcome, "stale task reclaimed");
+            if let (Some(session_id), Some(agent_id)) = (session_id, stale.agent_id) {
+                self.emit(SwarmEvent::TaskFailed {
+                    session_id,
+                    task_id: stale.task_id,
+                    agent_id,
+                    will_retry: matches!(stale.outcome, FailureOutcome::Retrying { .. }),
+                    dead_lettered: stale.outcome == FailureOutcome::DeadLettered,
+                });
+            }
+        }
+
+        reclaimed.len()
+    }
+
+    /// Run `reclaim_stale_tasks` every `interval` until the handle is aborted.
+    /// Fails with `InvalidSpec` for a zero `interval`.
+    pub fn spawn_stale_task_sweep(
+        &self,
+        timeout: Duration,
+        interval: Duration,
+    ) -> Result<tokio::task::JoinHandle<()>, SwarmError> {
+        if interval.is_zero() {
+            return Err(SwarmError::InvalidSpec("stale task sweep interval must be positive".to_string()));
+        }
+        let manager = self.clone();
+        Ok(tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                manager.reclaim_stale_tasks(timeout).await;
+            }
+        }))
+    }
+
+    /// Hand ready queued tasks to coders while fewer than `capacity` tasks
+    /// are in flight across the pool. Each slot goes to the session furthest
+    /// behind its weighted share (see `AgentPool::set_session_weight`), so a
+    /// large session can't starve a small one. Only sessions with an idle
+    /// coder and a verification backlog within
+    /// `VerifierConfig::max_backlog_per_verifier` take part; the session's
+    /// `AssignmentStrategy` then picks which coder runs the task. Returns how
+    /// many were dispatched.
+    ///
+    /// A session with a `ProjectSpec::deadline` only starts tasks expected
+    /// (by `EtaEstimator::expected_sec`) to finish before it. A ready task
+    /// needing a capability none of its session's coders has is
+    /// dead-lettered with `SwarmEvent::TaskUnsupported` rather than left to
+    /// wait. Each call also updates the `swarm_verification_backlog` gauge.
+    pub async fn dispatch_ready(&self, capacity: usize) -> Result<usize, SwarmError> {
+        // Published as the scheduler sees it, once a pass
+        let verifiers: Vec<AgentId> = self.sessions.read().await
+            .values()
+            .filter(|s| s.status == SessionStatus::Active)
+            .flat_map(Session::verifiers)
+            .collect();
+        self.agent_pool.metrics.verification_backlog_changed(self.agent_pool.backlog(&verifiers).await);
+
+        let mut dispatched = 0;
+        // Sessions none of whose ready tasks can start: each would miss the
+        // deadline or needs capabilities no idle coder has
+        let mut stuck = HashSet::new();
+        while self.agent_pool.in_flight_total().await < capacity {
+            let ready = self.task_queue.ready_sessions().await;
+
+            // Sessions that could start a task right now, with the coder to run it
+            let mut candidates = vec![];
+            {
+                let sessions = self.sessions.read().await;
+                for session in ready.iter().filter_map(|id| sessions.get(id)) {
+                    if session.status != SessionStatus::Active || stuck.contains(&session.id) {
+                        continue;
+                    }
+                    if let Some(max) = self.verifier().max_backlog_per_verifier {
+                        if self.agent_pool.backlog(&session.verifiers()).await > max {
+                            continue;
+                        }
+                    }
+                    if let Some(coder) = self.choose_coder(session, &[], &[]).await {
+                        candidates.push((session.id, coder));
+                    }
+                }
+            }
+
+            let Some((session_id, mut coder)) = self.agent_pool.pick_session(&candidates).await else {
+                break;
+            };
+            let (deadline, idle) = match self.sessions.read().await.get(&session_id) {
+                Some(s) => (s.project_spec.deadline.map(|at| (at, s.eta)), self.idle_coders(s).await),
+                None => (None, vec![]),
+            };
+            let now = Utc::now();
+            let startable = |task: &Task| {
+                idle.iter().any(|(_, caps)| caps.missing(&task.required_capabilities).is_none())
+                    && deadline.is_none_or(|(at, eta)| {
+                        // An estimate too long to add up can't make it either
+                        chrono::Duration::try_milliseconds((eta.expected_sec(task) * 1000.0) as i64)
+                            .and_then(|expected| now.checked_add_signed(expected))
+                            .is_some_and(|done| done <= at)
+                    })
+            };
+            let Some(task) = self.task_queue.dequeue_for_where(session_id, startable).await else {
+                if self.fail_unsupported(session_id).await == 0 {
+                    stuck.insert(session_id);
+                }
+                continue;
+            };
+            if let Some(session) = self.sessions.read().await.get(&session_id) {
+                // The coder holding the task's context beats skills and strategy
+                let affine = match &task.affinity_key {
+                    Some(key) => self.affine_coder(session, key).await,
+                    None => None,
+                };
+                let capable = |id: &AgentId| idle.iter()
+                    .any(|(idle, caps)| idle == id && caps.missing(&task.required_capabilities).is_none());
+                if let Some(affine) = affine.filter(capable) {
+                    coder = affine;
+                } else if !task.required_skills.is_empty() || !capable(&coder) {
+                    coder = self.choose_coder(session, &task.required_skills, &task.required_capabilities)
+                        .await
+                        .unwrap_or(coder);
+                }
+            }
+            if let Some(session) = self.sessions.write().await.get_mut(&session_id) {
+                if let Some(index) = session.agents
+                    .iter()
+                    .filter(|a| a.role == AgentRole::Coder)
+                    .position(|a| a.id == coder)
+                {
+                    session.next_coder = index + 1;
+                }
+                if let Some(key) = &task.affinity_key {
+                    // Only the last coder to run a key is its preferred one
+                    for agent in session.agents.iter_mut().filter(|a| a.role == AgentRole::Coder) {
+                        if agent.id == coder {
+                            agent.remember_affinity(key);
+                        } else {
+                            agent.affinity.retain(|k| k != key);
+                        }
+                    }
+                }
+            }
+            if let Err(e) = self.assign_task(session_id, coder, task.clone()).await {
+                self.task_queue.requeue(task).await;
+                return Err(e);
+            }
+            self.agent_pool.charge_session(session_id);
+            dispatched += 1;
+        }
+        Ok(dispatched)
+    }
+
+    /// Dead-letter `session_id`'s ready tasks needing a capability none of
+    /// its coders has, `Failed` ones included since they may be recovered.
+    /// Returns how many failed.
+    async fn fail_unsupported(&self, session_id: SessionId) -> usize {
+        let coders: Vec<Capabilities> = match self.sessions.read().await.get(&session_id) {
+            Some(session) => session.agents
+                .iter()
+                .filter(|a| a.role == AgentRole::Coder)
+                .map(|a| a.capabilities)
+                .collect(),
+            None => return 0,
+        };
+        // The capability it needs that no coder has, if any
+        let unsupported = |task: &Task| task.required_capabilities
+            .iter()
+            .copied()
+            .find(|&c| !coders.iter().any(|caps| caps.has(c)));
+
+        let mut failed = 0;
+        while let Some(task) = self.task_queue.dequeue_for_where(session_id, |t| unsupported(t).is_some()).await {
+            let capability = unsupported(&task).expect("dequeued for a capability no coder has");
+            self.task_queue.fail_terminal(task.id).await;
+            if let Some(session) = self.sessions.write().await.get_mut(&session_id) {
+                session.metrics.tasks_failed += 1;
+            }
+            warn!(%session_id, task_id = %task.id, ?capability, "no coder can run task, dead-lettered");
+            self.emit(SwarmEvent::TaskUnsupported { session_id, task_id: task.id, capability });
+            failed += 1;
+        }
+        failed
+    }
+
+    /// The session's coder to run a task needing `skills`, picked by its
+    /// assignment strategy among the coders covering them when one of those
+    /// is idle, else among all. `Failed` coders, and those whose model lacks
+    /// one of `capabilities`, are passed over. `None` while no coder is idle.
+    async fn choose_coder(
+        &self,
+        session: &Session,
+        skills: &[String],
+        capabilities: &[Capability],
+    ) -> Option<AgentId> {
+        let coders: Vec<&AgentHandle> = session.agents
+            .iter()
+            .filter(|a| a.role == AgentRole::Coder && a.status != AgentStatus::Failed)
+            .filter(|a| a.capabilities.missing(capabilities).is_none())
+            .collect();
+        let ids: Vec<AgentId> = coders.iter().map(|a| a.id).collect();
+        let loads = self.agent_pool.loads(&ids).await;
+        if !loads.contains(&Some(0)) {
+            return None;
+        }
+        let specialists = !skills.is_empty()
+            && loads.iter().zip(&coders).any(|(load, a)| *load == Some(0) && a.covers(skills));
+
+        // Coders running here, as (index, (in flight, completed))
+        let (indices, load): (Vec<usize>, Vec<(usize, usize)>) = loads
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| !specialists || coders[i].covers(skills))
+            .filter_map(|(i, load)| load.map(|n| (i, (n, coders[i].tasks_completed))))
+            .unzip();
+        // Round-robin resumes at the first running coder from the cursor on
+        let next = indices.iter()
+            .position(|&i| i >= session.next_coder)
+            .unwrap_or(0);
+        session.assignment.choose(&load, next).map(|pick| coders[indices[pick]].id)
+    }
+
+    /// The session's coders with nothing in flight, `Failed` ones aside,
+    /// with what their models support
+    async fn idle_coders(&self, session: &Session) -> Vec<(AgentId, Capabilities)> {
+        let coders: Vec<&AgentHandle> = session.agents
+            .iter()
+            .filter(|a| a.role == AgentRole::Coder && a.status != AgentStatus::Failed)
+            .collect();
+        let ids: Vec<AgentId> = coders.iter().map(|a| a.id).collect();
+        let loads = self.agent_pool.loads(&ids).await;
+        coders.into_iter()
+            .zip(loads)
+            .filter(|(_, load)| *load == Some(0))
+            .map(|(a, _)| (a.id, a.capabilities))
+            .collect()
+    }
+
+    /// The idle coder that last ran a task with affinity `key`, if any
+    async fn affine_coder(&self, session: &Session, key: &str) -> Option<AgentId> {
+        let coder = session.agents
+            .iter()
+            .find(|a| a.role == AgentRole::Coder && a.affinity.iter().any(|k| k == key))?;
+        let idle = coder.status != AgentStatus::Failed && self.agent_pool.loads(&[coder.id]).await == [Some(0)];
+        idle.then_some(coder.id)
+    }
+
+    /// Run `dispatch_ready` every `interval` until the handle is aborted.
+    /// Fails with `InvalidSpec` for a zero `interval`.
+    pub fn spawn_dispatcher(
+        &self,
+        capacity: usize,
+        interval: Duration,
+    ) -> Result<tokio::task::JoinHandle<()>, SwarmError> {
+        if interval.is_zero() {
+            return Err(SwarmError::InvalidSpec("dispatcher interval must be positive".to_string()));
+        }
+        let manager = self.clone();
+        Ok(tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                // A session that went away mid-dispatch is skipped next round
+                let _ = manager.dispatch_ready(capacity).await;
+            }
+        }))
+    }
+
+    /// Feed `source`'s tasks into `session_id` in the background. A full
+    /// queue holds the source back until a dequeue frees space. The feed
+    /// stops when the source runs dry or the session ends, even while
+    /// waiting on either (see `SessionControl::ended`).
+    pub fn spawn_task_source(
+        &self,
+        session_id: SessionId,
+        source: Arc<dyn TaskSource>,
+    ) -> tokio::task::JoinHandle<()> {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let Some(ended) = manager.sessions.read().await
+                .get(&session_id)
+                .filter(|s| !matches!(s.status, SessionStatus::Completed | SessionStatus::Failed))
+                .map(|s| s.control.ended())
+            else {
+                return;
+            };
+            loop {
+                let mut task = tokio::select! {
+                    _ = ended.cancelled() => break,
+                    next = source.next_task() => match next {
+                        Some(task) => task,
+                        None => break,
+                    },
+                };
+                task.session_id = Some(session_id);
+                let task_id = task.id;
+                let enqueued = tokio::select! {
+                    _ = ended.cancelled() => break,
+                    enqueued = manager.task_queue.enqueue_blocking(task) => enqueued,
+                };
+                if let Err(e) = enqueued {
+                    warn!(%session_id, %task_id, error = %e, "task from source rejected");
+                } else if ended.is_cancelled() {
+                    // Landed after the session's tasks were purged
+                    manager.task_queue.cancel(task_id, session_id).await;
+                    break;
+                }
+            }
+        })
+    }
+
+    /// Mark agents silent for longer than `max_silence` as `Failed` (see
+    /// `AgentPool::sweep_unhealthy`). With `respawn`, each is replaced by a
+    /// fresh agent of the same role and model, keeping its totals.
+    /// Returns the agents found unhealthy.
+    pub async fn sweep_unhealthy_agents(&self, max_silence: Duration, respawn: bool) -> Vec<AgentId> {
+        let unhealthy = self.agent_pool.sweep_unhealthy(max_silence).await;
+        if unhealthy.is_empty() {
+            return unhealthy;
+        }
+
+        let mut failed = vec![];
+        {
+            let mut sessions = self.sessions.write().await;
+            for session in sessions.values_mut() {
+                let ids: Vec<AgentId> = session.agents.iter()
+                    .map(|a| a.id)
+                    .filter(|id| unhealthy.contains(id))
+                    .collect();
+                for agent_id in ids {
+                    session.transition_agent_or_log(agent_id, AgentStatus::Failed);
+                    session.span.in_scope(|| warn!(%agent_id, respawn, "agent stopped sending heartbeats"));
+                    failed.push((session.id, agent_id));
+                }
+            }
+        }
+        if respawn {
+            for (session_id, agent_id) in failed {
+                self.respawn_agent(session_id, agent_id).await;
+            }
+        }
+
+        unhealthy
+    }
+
+    /// Terminate `agent_id`, one of `session_id`'s agents, and put a fresh
+    /// agent of the same role, model and skills in its place, keeping its
+    /// totals. Spawned without holding `sessions`. Returns the new agent, or
+    /// `None` if it couldn't be spawned; the old one then leaves the session
+    /// and gives back its quota.
+    async fn respawn_agent(&self, session_id: SessionId, agent_id: AgentId) -> Option<AgentId> {
+        let (old, shared_state, control, span) = {
+            let sessions = self.sessions.read().await;
+            let session = sessions.get(&session_id)?;
+            let old = session.agents.iter().find(|a| a.id == agent_id)?.clone();
+            (old, session.shared_state.clone(), session.control.clone(), session.span.clone())
+        };
+        let _ = self.agent_pool.terminate_agent(agent_id).await;
+        let spawned = self.spawn_agent(session_id, old.role, old.model, old.skills, shared_state, control, &span).await;
+
+        let mut sessions = self.sessions.write().await;
+        let held = sessions.get_mut(&session_id)
+            .filter(|s| matches!(s.status, SessionStatus::Active | SessionStatus::Paused))
+            .and_then(|s| s.agents.iter().position(|a| a.id == agent_id).map(|index| (s, index)));
+        match (held, spawned) {
+            // Quota usage is unchanged: one agent out, one in
+            (Some((session, index)), Ok(mut agent)) => {
+                let old = &session.agents[index];
+                agent.tasks_completed = old.tasks_completed;
+                agent.cost_incurred = old.cost_incurred;
+                agent.models_used = old.models_used.clone();
+                let new_id = agent.id;
+                session.replace_agent(index, agent);
+                session.metrics.agents_spawned += 1;
+                Some(new_id)
+            }
+            (Some((session, _)), Err(e)) => {
+                session.span.in_scope(|| warn!(%agent_id, error = %e, "agent not respawned; removed"));
+                session.remove_agent(agent_id);
+                let user_id = session.user_id.clone();
+                self.release_quota(&user_id, 0, 1).await;
+                None
+            }
+            // The session or the old agent went meanwhile, taking its quota
+            (None, spawned) => {
+                drop(sessions);
+                if let Ok(agent) = spawned {
+                    let _ = self.agent_pool.terminate_agent(agent.id).await;
+                }
+                None
+            }
+        }
+    }
+
+    /// Mark an agent `Blocked` on `waiting_on` (a state key, a dependency),
+    /// or with `None` back to `Idle`
+    pub async fn set_blocked(
+        &self,
+        session_id: SessionId,
+        agent_id: AgentId,
+        waiting_on: Option<String>,
+    ) -> Result<(), SwarmError> {
+        let mut sessions = self.sessions.write().await;
+        let status = if waiting_on.is_some() { AgentStatus::Blocked } else { AgentStatus::Idle };
+        let agent = sessions.get_mut(&session_id)
+            .ok_or(SwarmError::SessionNotFound)?
+            .transition_agent(agent_id, status)?;
+        agent.blocked_on = waiting_on;
+        drop(sessions);
+
+        self.agent_pool.update_agent(agent_id, |a| a.transition_or_log(status)).await;
+        Ok(())
+    }
+
+    /// Put a `Failed` agent back to `Idle`, so it's given work again. Only
+    /// for an agent whose task is still running, such as one
+    /// `sweep_unhealthy` caught stalled that has since come back; anything
+    /// else fails with `AgentNotFound` and should be replaced instead.
+    pub async fn recover_agent(&self, session_id: SessionId, agent_id: AgentId) -> Result<(), SwarmError> {
+        if !self.agent_pool.is_alive(agent_id).await {
+            return Err(SwarmError::AgentNotFound);
+        }
+        let mut sessions = self.sessions.write().await;
+        sessions.get_mut(&session_id)
+            .ok_or(SwarmError::SessionNotFound)?
+            .recover_agent(agent_id)?;
+        drop(sessions);
+
+        self.agent_pool.update_agent(agent_id, AgentHandle::recover).await;
+        info!(%session_id, %agent_id, "failed agent recovered");
+        Ok(())
+    }
+
+    /// Fail with `SwarmError::Deadlock` if every one of the session's agents
+    /// is `Blocked` while it has ready tasks nobody can pick up. Also emits
+    /// `SwarmEvent::Deadlocked`; the session itself is left as it is.
+    pub async fn detect_deadlock(&self, session_id: SessionId) -> Result<(), SwarmError> {
+        let blocked: Vec<BlockedAgent> = {
+            let sessions = self.sessions.read().await;
+            let session = sessions.get(&session_id).ok_or(SwarmError::SessionNotFound)?;
+            if session.agents.is_empty() || session.agents.iter().any(|a| a.status != AgentStatus::Blocked) {
+                return Ok(());
+            }
+            session.agents.iter()
+                .map(|a| BlockedAgent { agent_id: a.id, waiting_on: a.blocked_on.clone() })
+                .collect()
+        };
+        if !self.task_queue.ready_sessions().await.contains(&session_id) {
+            return Ok(());
+        }
+
+        warn!(%session_id, agents = blocked.len(), "session deadlocked: every agent is blocked");
+        self.emit(SwarmEvent::Deadlocked { session_id, blocked: blocked.clone() });
+        Err(SwarmError::Deadlock { session_id, blocked })
+    }
+
+    /// Run `detect_deadlock` over active sessions every `interval` until
+    /// `stop` is cancelled. With `fail_sessions`, a deadlocked session is
+    /// failed, releasing its agents and their quota; otherwise the
+    /// `Deadlocked` event is the only warning. Fails with `InvalidSpec` for a
+    /// zero `interval`.
+    pub fn spawn_deadlock_monitor(
+        &self,
+        interval: Duration,
+        fail_sessions: bool,
+        stop: CancellationToken,
+    ) -> Result<tokio::task::JoinHandle<()>, SwarmError> {
+        if interval.is_zero() {
+            return Err(SwarmError::InvalidSpec("deadlock monitor interval must be positive".to_string()));
+        }
+        let manager = self.clone();
+        Ok(tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                tokio::select! {
+                    _ = stop.cancelled() => return,
+                    _ = ticks.tick() => {}
+                }
+                let active: Vec<SessionId> = manager.sessions.read().await
+                    .values()
+                    .filter(|s| s.status == SessionStatus::Active)
+                    .map(|s| s.id)
+                    .collect();
+                for session_id in active {
+                    if !matches!(manager.detect_deadlock(session_id).await, Err(SwarmError::Deadlock { .. })) {
+                        continue;
+                    }
+                    if fail_sessions {
+                        manager.fail_session(session_id).await;
+                    }
+                }
+            }
+        }))
+    }
+
+    /// Apply `action` to each `Active` session that has had no pending or
+    /// in-progress tasks, and started or settled none, for longer than
+    /// `timeout`. Emits `SwarmEvent::SessionIdle` for each and returns them.
+    pub async fn reap_idle_sessions(&self, timeout: Duration, action: IdleAction) -> Vec<SessionId> {
+        let candidates: Vec<SessionId> = self.sessions.read().await
+            .values()
+            .filter(|s| s.status == SessionStatus::Active && s.agents_starting == 0)
+            .filter(|s| s.last_activity.elapsed() > timeout)
+            .map(|s| s.id)
+            .collect();
+
+        let mut reaped = vec![];
+        let mut freed = false;
+        for session_id in candidates {
+            // Checked again under the write lock: plans are submitted under
+            // the read lock, so none can land between the check and the reap
+            let mut sessions = self.sessions.write().await;
+            let Some(session) = sessions.get_mut(&session_id) else { continue };
+            let idle_for = session.last_activity.elapsed();
+            if session.status != SessionStatus::Active
+                || session.agents_starting > 0
+                || idle_for <= timeout
+                || self.task_queue.outstanding_len_for(session_id).await > 0
+            {
+                continue;
+            }
+
+            match action {
+                IdleAction::Pause => {
+                    let agents: Vec<AgentId> = session.agents.iter().map(|a| a.id).collect();
+                    session.parked = session.agents.iter()
+                        .map(|a| (a.role, a.model, a.skills.clone()))
+                        .collect();
+                    session.set_agents(vec![]);
+                    session.set_status(SessionStatus::Paused);
+                    let user_id = session.user_id.clone();
+                    self.release_quota(&user_id, 0, agents.len()).await;
+                    drop(sessions);
+                    for agent_id in agents {
+                        if let Err(e) = self.agent_pool.release_agent(agent_id).await {
+                            warn!(%session_id, %agent_id, error = %e, "idle session's agent not released");
+                        }
+                    }
+                }
+                IdleAction::Destroy => {
+                    if let Err(e) = self.remove_tree(&mut sessions, session_id).await {
+                        warn!(%session_id, error = %e, "idle session not cleanly torn down");
+                    }
+                }
+            }
+            freed = true;
+            info!(%session_id, idle_sec = idle_for.as_secs_f64(), ?action, "idle session reaped");
+            self.emit(SwarmEvent::SessionIdle { session_id, idle_sec: idle_for.as_secs_f64(), action });
+            reaped.push(session_id);
+        }
+
+        if freed {
+            self.admit_queued_sessions().await;
+        }
+        reaped
+    }
+
+    /// Run `reap_idle_sessions` every `interval` until the handle is aborted.
+    /// Fails with `InvalidSpec` for a zero `interval`.
+    pub fn spawn_idle_session_reaper(
+        &self,
+        timeout: Duration,
+        action: IdleAction,
+        interval: Duration,
+    ) -> Result<tokio::task::JoinHandle<()>, SwarmError> {
+        if interval.is_zero() {
+            return Err(SwarmError::InvalidSpec("idle reaper interval must be positive".to_string()));
+        }
+        let manager = self.clone();
+        Ok(tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                manager.reap_idle_sessions(timeout, action).await;
+            }
+        }))
+    }
+
+    // ------------------------------------------------------------------------
+    // Agent reports
+    // ------------------------------------------------------------------------
+
+    async fn process_reports(self, mut reports: mpsc::UnboundedReceiver<AgentReport>) {
+        while let Some(report) = reports.recv().await {
+            let finished = match &report {
+                AgentReport::Completed { agent_id, task, .. } => Some((*agent_id, task.id)),
+                AgentReport::Failed { agent_id, task_id, .. }
+                | AgentReport::Cancelled { agent_id, task_id, .. } => Some((*agent_id, *task_id)),
+                AgentReport::Flushed(flushed) => {
+                    flushed.notify_one();
+                    continue;
+                }
+                AgentReport::Started { .. } | AgentReport::Verified { .. } | AgentReport::Panicked { .. } => None,
+            };
+
+            // Per-report failures (unknown session, budget breach) are already
+            // reflected in session state; keep consuming
+            let _ = self.apply_report(report).await;
+
+            // Settle only once the outcome is recorded, so a drain that sees
+            // the agent idle also sees its metrics
+            if let Some((agent_id, task_id)) = finished {
+                self.agent_pool.task_settled(agent_id, task_id).await;
+            }
+        }
+    }
+
+    async fn apply_report(&self, report: AgentReport) -> Result<(), SwarmError> {
+        match report {
+            AgentReport::Started { session_id, agent_id, verification } => {
+                self.agent_pool.update_agent(agent_id, |a| a.transition_or_log(AgentStatus::Working)).await;
+
+                let mut sessions = self.sessions.write().await;
+                let session = sessions.get_mut(&session_id)
+                    .ok_or(SwarmError::SessionNotFound)?;
+                session.last_activity = Instant::now();
+                let role = session.transition_agent_or_log(agent_id, AgentStatus::Working).map(|agent| agent.role);
+                // Verification runs on behalf of a task already counted
+                if !verification && role != Some(AgentRole::Verifier) {
+                    session.metrics.tasks_assigned += 1;
+                }
+                drop(sessions);
+
+                self.emit(SwarmEvent::TaskStarted { session_id, agent_id, role, verification });
+                Ok(())
+            }
+            AgentReport::Completed {
+                session_id,
+                agent_id,
+                mut task,
+                output,
+                model,
+                usage,
+                cache_hit,
+                throttled_sec,
+                duration_sec,
+            } => {
+                let task_id = task.id;
+                let (cost, saved) = match ModelClients::cost_of(model, &usage) {
+                    cost if cache_hit => (0.0, cost),
+                    cost => (cost, 0.0),
+                };
+                // The queue's copy carries what earlier attempts spent
+                task.cost_incurred = match self.task_queue.charge(task_id, cost).await {
+                    Some(total) => total,
+                    None => task.cost_incurred + cost,
+                };
+                // A verification is paid for by the task it checks, which
+                // waits in progress for the verdict
+                if let Some(original) = &task.verifies {
+                    self.task_queue.charge(original.id, cost).await;
+                }
+                let over_ceiling = task.over_cost_ceiling();
+                let (task_cost, task_limit) = (task.cost_incurred, task.max_cost_usd.unwrap_or_default());
+                self.agent_pool.metrics.task_completed(duration_sec, cost);
+                self.agent_pool.metrics.rate_limit_waited(throttled_sec);
+                self.agent_pool.update_agent(agent_id, |a| {
+                    a.transition_or_log(AgentStatus::Idle);
+                    a.tasks_completed += 1;
+                    a.cost_incurred += cost;
+                    a.models_used.insert(task_id, model);
+                }).await;
+
+                let (over_budget, breach, verification, outcome, role, aborted) = {
+                    let mut sessions = self.sessions.write().await;
+                    let session = sessions.get_mut(&session_id)
+                        .ok_or(SwarmError::SessionNotFound)?;
+                    session.last_activity = Instant::now();
+                    let was_over_budget = session.over_budget();
+                    let role = session.transition_agent_or_log(agent_id, AgentStatus::Idle).map(|agent| {
+                        agent.tasks_completed += 1;
+                        agent.cost_incurred += cost;
+                        agent.models_used.insert(task_id, model);
+                        agent.role
+                    });
+
+                    // Coder output only counts once a verifier has passed it
+                    let checking = task.verifies.is_some();
+                    let verifier = match role {
+                        Some(AgentRole::Coder) if !over_ceiling && !checking => self.pick_checker(session, agent_id).await,
+                        _ => None,
+                    };
+                    let mut result = TaskResult {
+                        task_id,
+                        session_id,
+                        agent_id,
+                        model,
+                        output,
+                        cost,
+                        completed_at: Utc::now(),
+                    };
+                    self.intercept_after(&task, &mut result);
+                    task.result = Some(result);
+                    // Discard the result if the task was reclaimed from this agent
+                    let current = match (role, verifier) {
+                        // Too costly to keep: the output is dropped with the task
+                        _ if over_ceiling => self.task_queue.abort_as(task.clone(), agent_id).await,
+                        (_, Some(_)) => self.task_queue.owned_by(task_id, agent_id).await,
+                        // A verdict is recorded on the task it checks, not kept itself
+                        (Some(AgentRole::Verifier), None) => {
+                            self.task_queue.complete_as(task_id, agent_id).await
+                        }
+                        _ if checking => self.task_queue.complete_as(task_id, agent_id).await,
+                        _ => self.task_queue.complete_with(task.clone(), agent_id).await,
+                    };
+                    let verification = verifier.filter(|_| current);
+                    let aborted = current && over_ceiling;
+                    let counted = current && !over_ceiling && !checking && role != Some(AgentRole::Verifier);
+                    let outcome = match (counted, verification) {
+                        (false, _) => CompletionOutcome::Uncounted,
+                        (true, Some(_)) => CompletionOutcome::AwaitingVerification,
+                        (true, None) => CompletionOutcome::Accepted,
+                    };
+                    if outcome != CompletionOutcome::Uncounted {
+                        session.metrics.total_duration_sec += duration_sec;
+                        session.eta.observe(duration_sec);
+                    }
+                    if outcome == CompletionOutcome::Accepted {
+                        session.metrics.tasks_completed += 1;
+                    }
+                    if aborted {
+                        session.metrics.tasks_failed += 1;
+                    }
+                    session.metrics.charge(cost, role, Some(model));
+                    session.metrics.cost_saved += saved;
+                    session.metrics.rate_limited_sec += throttled_sec;
+
+                    let policy = session.project_spec.budget_policy;
+                    let over_budget = session.over_budget() && policy != BudgetPolicy::WarnOnly;
+                    // A draining session is already winding down; a killed
+                    // one is held until it's destroyed below
+                    if over_budget && session.status == SessionStatus::Active {
+                        session.set_status(SessionStatus::Paused);
+                    }
+                    let breach = (session.over_budget() && !was_over_budget)
+                        .then(|| (policy, session.metrics.clone()));
+                    (over_budget, breach, verification, outcome, role, aborted)
+                };
+
+                if let Some(verifier) = verification {
+                    // The verifier checks the result as the interceptors left it
+                    let output = task.result.as_ref().map(|r| r.output.clone()).unwrap_or_default();
+                    self.request_verification(verifier, Task::verification(self.task_queue.ids().next_id(), task, &output)).await;
+                }
+
+                info!(%session_id, %task_id, %agent_id, ?model, cost, cache_hit, "task completed");
+                self.emit(SwarmEvent::TaskCompleted {
+                    session_id,
+                    task_id,
+                    agent_id,
+                    cost,
+                    cost_saved: saved,
+                    duration_sec,
+                    throttled_sec,
+                    outcome,
+                    role,
+                    model: Some(model),
+                });
+                if aborted {
+                    warn!(%session_id, %task_id, cost = task_cost, "task over its cost ceiling, aborted");
+                    self.emit(SwarmEvent::TaskFailed {
+                        session_id,
+                        task_id,
+                        agent_id,
+                        will_retry: false,
+                        dead_lettered: true,
+                    });
+                }
+                if let Some((policy, mut metrics)) = breach {
+                    warn!(%session_id, ?policy, total_cost = metrics.total_cost, "session over budget");
+                    if policy == BudgetPolicy::KillOnExceed {
+                        metrics = self.destroy_session(session_id).await?;
+                    }
+                    self.emit(SwarmEvent::BudgetBreached { session_id, policy, metrics });
+                }
+                if over_budget {
+                    return Err(SwarmError::BudgetExceeded);
+                }
+                if aborted {
+                    return Err(SwarmError::TaskBudgetExceeded {
+                        task_id,
+                        cost: task_cost,
+                        limit: task_limit,
+                    });
+                }
+                Ok(())
+            }
+            AgentReport::Panicked { session_id, agent_id, message } => {
+                self.agent_pool.update_agent(agent_id, |a| a.transition_or_log(AgentStatus::Failed)).await;
+                self.agent_pool.abandon(agent_id).await;
+                let reclaimed = self.task_queue.reclaim_from(agent_id).await;
+
+                let held = {
+                    let mut sessions = self.sessions.write().await;
+                    let session = sessions.get_mut(&session_id)
+                        .ok_or(SwarmError::SessionNotFound)?;
+                    session.transition_agent_or_log(agent_id, AgentStatus::Failed);
+                    session.metrics.tasks_failed += reclaimed.iter()
+                        .filter(|r| r.outcome == FailureOutcome::DeadLettered)
+                        .count();
+                    session.agents.iter().any(|a| a.id == agent_id)
+                };
+                // Spawned off the lock, so other sessions' reports don't wait on it
+                let respawned = match held && self.respawn_on_panic.load(AtomicOrdering::SeqCst) {
+                    true => self.respawn_agent(session_id, agent_id).await,
+                    false => None,
+                };
+
+                for stale in &reclaimed {
+                    self.emit(SwarmEvent::TaskFailed {
+                        session_id,
+                        task_id: stale.task_id,
+                        agent_id,
+                        will_retry: matches!(stale.outcome, FailureOutcome::Retrying { .. }),
+                        dead_lettered: stale.outcome == FailureOutcome::DeadLettered,
+                    });
+                }
+                self.emit(SwarmEvent::AgentPanicked { session_id, agent_id, message, respawned });
+                Ok(())
+            }
+            AgentReport::Failed { session_id, agent_id, task_id, retriable } => {
+                // A reclaimed task's late failure mustn't cost its new attempt a retry
+                let outcome = match self.task_queue.owned_by(task_id, agent_id).await {
+                    true if retriable => self.task_queue.fail(task_id).await,
+                    true => self.task_queue.fail_terminal(task_id).await,
+                    false => None,
+                };
+                self.agent_pool.update_agent(agent_id, |a| a.transition_or_log(AgentStatus::Idle)).await;
+
+                {
+                    let mut sessions = self.sessions.write().await;
+                    let session = sessions.get_mut(&session_id)
+                        .ok_or(SwarmError::SessionNotFound)?;
+                    session.last_activity = Instant::now();
+                    session.transition_agent_or_log(agent_id, AgentStatus::Idle);
+                    // Only a task that has run out of retries counts as failed
+                    if outcome == Some(FailureOutcome::DeadLettered) {
+                        session.metrics.tasks_failed += 1;
+                    }
+                }
+
+                match outcome {
+                    Some(FailureOutcome::DeadLettered) => {
+                        error!(%session_id, %task_id, %agent_id, "task failed; retries exhausted");
+                    }
+                    Some(FailureOutcome::Retrying { attempt, delay }) => {
+                        warn!(%session_id, %task_id, %agent_id, attempt, ?delay, "task failed; retrying");
+                    }
+                    None => warn!(%session_id, %task_id, %agent_id, "task failed"),
+                }
+                self.emit(SwarmEvent::TaskFailed {
+                    session_id,
+                    task_id,
+                    agent_id,
+                    will_retry: matches!(outcome, Some(FailureOutcome::Retrying { .. })),
+                    dead_lettered: outcome == Some(FailureOutcome::DeadLettered),
+                });
+                Ok(())
+            }
+            AgentReport::Cancelled { session_id, agent_id, task_id } => {
+                self.agent_pool.update_agent(agent_id, |a| a.transition_or_log(AgentStatus::Idle)).await;
+
+                {
+                    let mut sessions = self.sessions.write().await;
+                    let session = sessions.get_mut(&session_id)
+                        .ok_or(SwarmError::SessionNotFound)?;
+                    session.transition_agent_or_log(agent_id, AgentStatus::Idle);
+                }
+
+                info!(%session_id, %task_id, %agent_id, "task cancelled");
+                self.emit(SwarmEvent::TaskCancelled { session_id, task_id, agent_id });
+                Ok(())
+            }
+            AgentReport::Verified { session_id, task, passed } => {
+                let task_id = task.id;
+                // Set by the pool when the coder took the task
+                let coder = task.assigned_to.unwrap_or_default();
+                if !self.task_queue.owned_by(task_id, coder).await {
+                    // Reclaimed while being verified; a later attempt owns it
+                    return Ok(());
+                }
+                let outcome = if passed {
+                    if !self.task_queue.complete_with(task, coder).await {
+                        return Ok(());
+                    }
+                    None
+                } else {
+                    self.task_queue.requeue(task).await
+                };
+
+                {
+                    let mut sessions = self.sessions.write().await;
+                    let session = sessions.get_mut(&session_id)
+                        .ok_or(SwarmError::SessionNotFound)?;
+                    session.last_activity = Instant::now();
+                    if passed {
+                        session.metrics.tasks_completed += 1;
+                    } else {
+                        session.metrics.verification_failed += 1;
+                    }
+                    if outcome == Some(FailureOutcome::DeadLettered) {
+                        session.metrics.tasks_failed += 1;
+                    }
+                }
+
+                if passed {
+                    info!(%session_id, %task_id, "task verified");
+                } else {
+                    warn!(%session_id, %task_id, "task rejected by verifier");
+                }
+                self.emit(SwarmEvent::TaskVerified {
+                    session_id,
+                    task_id,
+                    passed,
+                    dead_lettered: outcome == Some(FailureOutcome::DeadLettered),
+                });
+                Ok(())
+            }
+            // Answered in `process_reports`
+            AgentReport::Flushed(_) => Ok(()),
+        }
+    }
+
+    /// Agent to check `author`'s result: an idle verifier if any. With none
+    /// idle and the session's verifiers behind by more than
+    /// `VerifierConfig::max_backlog_per_verifier`, an idle tester takes the
+    /// check, or else an idle coder other than `author`, whose capacity the
+    /// held-back plan isn't using; otherwise it queues on a verifier. `None`
+    /// for a session without verifiers.
+    async fn pick_checker(&self, session: &Session, author: AgentId) -> Option<AgentId> {
+        let mut verifiers = session.agents.iter().filter(|a| a.role == AgentRole::Verifier);
+        let first = verifiers.clone().next()?;
+        if let Some(idle) = verifiers.find(|a| a.status == AgentStatus::Idle) {
+            return Some(idle.id);
+        }
+        if let Some(max) = self.verifier().max_backlog_per_verifier {
+            if self.agent_pool.backlog(&session.verifiers()).await > max {
+                let spare = |role| session.agents.iter()
+                    .find(|a| a.role == role && a.status == AgentStatus::Idle && a.id != author);
+                if let Some(spare) = spare(AgentRole::Tester).or_else(|| spare(AgentRole::Coder)) {
+                    return Some(spare.id);
+                }
+            }
+        }
+        Some(first.id)
+    }
+
+    async fn request_verification(&self, verifier: AgentId, mut check: Task) {
+        self.intercept_before(&mut check);
+        let agent_pool = self.agent_pool.clone();
+        let task_queue = self.task_queue.clone();
+        let original = check.verifies.clone();
+        // In flight from here, before the coder's task settles, so a drain
+        // waits for the verdict too
+        let claimed = agent_pool.claim(verifier, check).await;
+        // Handed over in the background so a paused verifier's full inbox
+        // can't stall report processing
+        tokio::spawn(async move {
+            let assigned = match claimed {
+                Ok(claimed) => agent_pool.hand_over(claimed).await,
+                Err(e) => Err(e),
+            };
+            if assigned.is_err() {
+                // Verifier is gone; the result can't be trusted
+                if let Some(original) = original {
+                    task_queue.requeue(*original).await;
+                }
+            }
+        });
+    }
+
+    /// Mirror status changes published on the message bus by agents running
+    /// in other processes. Local agents are authoritative through their report
+    /// channel, so their bus echoes are ignored.
+    async fn sync_agent_status(self) {
+        let Ok(mut subscriber) = self.agent_pool.bus.subscribe(AGENT_STATUS_SUBJECTS).await else {
+            return;
+        };
+
+        while let Some(message) = subscriber.next().await {
+            let Ok(update) = serde_json::from_slice::<AgentStatusUpdate>(&message.payload) else {
+                continue;
+            };
+            if self.agent_pool.is_local(update.agent_id).await {
+                continue;
+            }
+
+            let mut sessions = self.sessions.write().await;
+            if let Some(session) = sessions.get_mut(&update.session_id) {
+                session.transition_agent_or_log(update.agent_id, update.status);
+            }
+        }
+    }
+
+    // ------------------------------------------------------------------------
+    // Persistence
+    // ------------------------------------------------------------------------
+
+    fn checkpoint_key(session_id: SessionId) -> String {
+        format!("session:{}", session_id)
+    }
+
+    fn task_checkpoint_key(session_id: SessionId) -> String {
+        format!("session:{}:tasks", session_id)
+    }
+
+    /// Marks a checkpointed session for `restore_all` to find without
+    /// walking every `session:` key
+    fn index_key(session_id: SessionId) -> String {
+        format!("{}{}", SESSION_INDEX_PREFIX, session_id)
+    }
+
+    /// Serialize a session to Redis under `session:{id}`, and its queued
+    /// tasks and completed task ids and results under `session:{id}:tasks`
+    /// for `resume_from_checkpoint`. Indexed under `sessions:{id}` for
+    /// `restore_all`.
+    pub async fn checkpoint_session(
+        &self,
+        session_id: SessionId,
+    ) -> Result<(), SwarmError> {
+        let key = Self::checkpoint_key(session_id);
+        let payload = {
+            let sessions = self.sessions.read().await;
+            let session = sessions.get(&session_id)
+                .ok_or(SwarmError::SessionNotFound)?;
+            serde_json::to_string(session).map_err(|e| SwarmError::state(&key, e))?
+        };
+        let tasks_key = Self::task_checkpoint_key(session_id);
+        let (tasks, completed) = self.task_queue.progress_of(session_id).await;
+        let results = self.task_queue.results_for(session_id).await;
+        let tasks = serde_json::to_string(&TaskCheckpoint { tasks, completed, results })
+            .map_err(|e| SwarmError::state(&tasks_key, e))?;
+
+        let backend = self.state_manager.backend();
+        backend.set(&Self::index_key(session_id), String::new()).await?;
+        backend.set(&tasks_key, tasks).await?;
+        backend.set(&key, payload).await
+    }
+
+    /// Rehydrate a checkpointed session, reattaching its shared state and
+    /// respawning its agents (the old agent tasks died with the process).
+    pub async fn restore_session(
+        &self,
+        session_id: SessionId,
+    ) -> Result<(), SwarmError> {
+        let key = Self::checkpoint_key(session_id);
+        let payload = self.state_manager.backend()
+            .get(&key)
+            .await?
+            .ok_or(SwarmError::SessionNotFound)?;
+        let session: Session = serde_json::from_str(&payload)
+            .map_err(|e| SwarmError::state(&key, e))?;
+
+        self.rehydrate(session, false).await
+    }
+
+    /// `restore_session`, then pick the session's plan up where it left
+    /// off: the tasks it had completed count as done, with their results,
+    /// so their dependents are ready at once, and only the rest are queued
+    /// again. A task the queue's completion log (`TaskQueue::with_completion_log`)
+    /// shows finishing after the checkpoint counts as completed too, rather
+    /// than being paid for twice; verification tasks are dropped, their
+    /// originals decide.
+    pub async fn resume_from_checkpoint(&self, session_id: SessionId) -> Result<ResumeReport, SwarmError> {
+        self.restore_session(session_id).await?;
+        let key = Self::task_checkpoint_key(session_id);
+        let Some(payload) = self.state_manager.backend().get(&key).await? else {
+            return Ok(ResumeReport::default());
+        };
+        let TaskCheckpoint { tasks, mut completed, mut results } = serde_json::from_str(&payload)
+            .map_err(|e| SwarmError::state(&key, e))?;
+
+        let mut queued = vec![];
+        let mut finished_late = 0;
+        for task in tasks.into_iter().filter(|t| t.verifies.is_none()) {
+            match self.task_queue.logged_completion(task.id).await {
+                Some(done) => {
+                    completed.push(task.id);
+                    results.extend(done.result);
+                    finished_late += 1;
+                }
+                None => queued.push(task),
+            }
+        }
+        if let Some(session) = self.sessions.write().await.get_mut(&session_id) {
+            session.metrics.tasks_completed += finished_late;
+        }
+
+        let report = ResumeReport { queued: queued.len(), completed: completed.len() };
+        self.task_queue.adopt(completed, results, queued).await?;
+        info!(%session_id, queued = report.queued, completed = report.completed, "session resumed from checkpoint");
+        Ok(report)
+    }
+
+    /// Reload every `Active` or `Paused` checkpointed session, as listed by
+    /// the `sessions:{id}` index. A checkpoint that can't be read or
+    /// reloaded is logged and skipped, so it doesn't keep the rest down;
+    /// returns the sessions restored. Fails only if the index can't be read.
+    pub async fn restore_all(&self) -> Result<Vec<SessionId>, SwarmError> {
+        let backend = self.state_manager.backend();
+        self.index_checkpoints().await?;
+        let mut restored = vec![];
+
+        for key in backend.scan(SESSION_INDEX_PREFIX).await? {
+            let Some(Ok(session_id)) = key.strip_prefix(SESSION_INDEX_PREFIX).map(SessionId::parse_str) else {
+                continue;
+            };
+            let key = Self::checkpoint_key(session_id);
+            let session = match backend.get(&key).await {
+                Ok(Some(payload)) => serde_json::from_str::<Session>(&payload).map_err(|e| SwarmError::state(&key, e)),
+                Ok(None) => continue,
+                Err(e) => Err(e),
+            };
+            let outcome = match session {
+                Ok(session) if matches!(session.status, SessionStatus::Active | SessionStatus::Paused) => {
+                    self.rehydrate(session, false).await
+                }
+                Ok(_) => continue,
+                Err(e) => Err(e),
+            };
+            match outcome {
+                Ok(()) => restored.push(session_id),
+                Err(e) => warn!(%session_id, error = %e, "checkpoint not restored; skipped"),
+            }
+        }
+
+        Ok(restored)
+    }
+
+    /// Index checkpoints written before the `sessions:{id}` index existed.
+    /// Walks every `session:` key, but only until it has run once against
+    /// the backend.
+    async fn index_checkpoints(&self) -> Result<(), SwarmError> {
+        let backend = self.state_manager.backend();
+        if backend.get(SESSION_INDEX_READY).await?.is_some() {
+            return Ok(());
+        }
+        for key in backend.scan("session:").await? {
+            // Skip sub-keys such as `session:{id}:state:{key}`
+            if let Some(Ok(session_id)) = key.strip_prefix("session:").map(SessionId::parse_str) {
+                backend.set(&Self::index_key(session_id), String::new()).await?;
+            }
+        }
+        backend.set(SESSION_INDEX_READY, String::new()).await
+    }
+
+    /// A session as it stands, with its shared state, outstanding tasks and
+    /// completed ones; compare two with `SessionSnapshot::diff`
+    pub async fn snapshot(&self, session_id: SessionId) -> Result<SessionSnapshot, SwarmError> {
+        let session = self.sessions.read().await
+            .get(&session_id)
+            .cloned()
+            .ok_or(SwarmError::SessionNotFound)?;
+        Ok(SessionSnapshot {
+            state: session.shared_state.snapshot().await,
+            tasks: self.task_queue.outstanding_for(session_id).await,
+            completed: self.task_queue.completed_for(session_id).await,
+            session,
+        })
+    }
+
+    /// Encode a session and its shared state, for backup or for moving it to
+    /// another deployment with `import_snapshot`
+    pub async fn export_snapshot(
+        &self,
+        session_id: SessionId,
+        format: SnapshotFormat,
+    ) -> Result<Vec<u8>, SwarmError> {
+        format.encode(&self.snapshot(session_id).await?)
+    }
+
+    /// Bring back a session from `export_snapshot`: its shared state is
+    /// restored and its agents respawned, as with `restore_session`. Unlike
+    /// a restore, its agents count against the user's quota as a new
+    /// session's would.
+    pub async fn import_snapshot(
+        &self,
+        bytes: &[u8],
+        format: SnapshotFormat,
+    ) -> Result<SessionId, SwarmError> {
+        let SessionSnapshot { session, state, .. } = format.decode(bytes)?;
+        let session_id = session.id;
+
+        self.rehydrate(session, true).await?;
+        let shared_state = self.sessions.read().await
+            .get(&session_id)
+            .map(|s| s.shared_state.clone())
+            .ok_or(SwarmError::SessionNotFound)?;
+        shared_state.merge(&state).await?;
+        Ok(session_id)
+    }
+
+    fn migration_key(session_id: SessionId) -> String {
+        format!("session:{}:migration", session_id)
+    }
+
+    /// Move `session_id`'s ownership to the epoch it's handed over at: the
+    /// stored marker goes from `from` to `to`, atomically, so only one node
+    /// wins when several race to export or import the same session
+    async fn claim_epoch(
+        &self,
+        session_id: SessionId,
+        epoch: u64,
+        from: Option<&str>,
+        to: &str,
+    ) -> Result<(), SwarmError> {
+        let claimed = self.state_manager.backend()
+            .cas(&Self::migration_key(session_id), from, to.to_string())
+            .await?;
+        if !claimed {
+            return Err(SwarmError::StaleMigration { session_id, epoch });
+        }
+        Ok(())
+    }
+
+    /// Hand a session over to another node: stop dispatching to it, give its
+    /// agents up to `timeout` to finish what they hold, then take it off this
+    /// node together with its state and outstanding tasks.
+    ///
+    /// Tasks still running at the deadline travel in the bundle and run again
+    /// on the target; their late results here are discarded. Sub-sessions
+    /// stay behind. The session's epoch is bumped and claimed in the state
+    /// backend, so the bundle is the only one `import_migration` will accept.
+    pub async fn export_for_migration(
+        &self,
+        session_id: SessionId,
+        timeout: Duration,
+    ) -> Result<MigrationBundle, SwarmError> {
+        let (status, agent_ids) = {
+            let mut sessions = self.sessions.write().await;
+            let session = sessions.get_mut(&session_id)
+                .ok_or(SwarmError::SessionNotFound)?;
+            let status = session.status;
+            match status {
+                SessionStatus::Active => session.set_status(SessionStatus::Draining),
+                // Holding its agents; in-flight tasks are carried over instead
+                SessionStatus::Paused => {}
+                _ => return Err(SwarmError::SessionNotActive(status)),
+            }
+            (status, session.agents.iter().map(|a| a.id).collect::<Vec<_>>())
+        };
+
+        if status == SessionStatus::Active {
+            self.agent_pool
+                .wait_idle(&agent_ids, Instant::now() + timeout)
+                .await;
+        }
+
+        // Claimed without holding `sessions` across the backend call; the
+        // session's status keeps a second export out meanwhile
+        let epoch = self.sessions.read().await
+            .get(&session_id)
+            .ok_or(SwarmError::SessionNotFound)?
+            .epoch + 1;
+        let previous = (epoch > 1).then(|| format!("{}:imported", epoch - 1));
+        let claimed = self.claim_epoch(
+            session_id,
+            epoch,
+            previous.as_deref(),
+            &format!("{epoch}:exported"),
+        ).await;
+        let mut session = {
+            let mut sessions = self.sessions.write().await;
+            let session = sessions.get_mut(&session_id)
+                .ok_or(SwarmError::SessionNotFound)?;
+            if let Err(err) = claimed {
+                session.set_status(status);
+                return Err(err);
+            }
+            session.epoch = epoch;
+            sessions.remove(&session_id)
+                .ok_or(SwarmError::SessionNotFound)?
+        };
+
+        let (tasks, completed) = self.task_queue.hand_off(session_id).await;
+        let results = self.task_queue.results_for(session_id).await;
+        session.status = status;
+        let bundle = MigrationBundle {
+            state: session.shared_state.snapshot().await,
+            session: session.clone(),
+            tasks,
+            completed,
+            results,
+            epoch: session.epoch,
+        };
+        self.tear_down(session).await?;
+        self.admit_queued_sessions().await;
+        Ok(bundle)
+    }
+
+    /// Resume a session exported by `export_for_migration` on this node: its
+    /// agents are respawned, its state restored and its outstanding tasks
+    /// queued again, with the tasks it already finished counting as done for
+    /// their dependents. A bundle imported before, or overtaken by a later
+    /// export, fails with `StaleMigration`. Like a restore, it isn't checked
+    /// against the user's quota. An import that fails midway is undone and
+    /// hands the claim back, so the bundle can be imported again.
+    pub async fn import_migration(&self, bundle: MigrationBundle) -> Result<SessionId, SwarmError> {
+        let MigrationBundle { session, state, tasks, completed, results, epoch } = bundle;
+        let session_id = session.id;
+        // Checked again, atomically, by `rehydrate`
+        if self.sessions.read().await.contains_key(&session_id) {
+            return Err(SwarmError::SessionExists(session_id));
+        }
+        if session.epoch != epoch {
+            return Err(SwarmError::StaleMigration { session_id, epoch });
+        }
+        let (exported, imported) = (format!("{epoch}:exported"), format!("{epoch}:imported"));
+        self.claim_epoch(session_id, epoch, Some(&exported), &imported).await?;
+
+        let adopted = async {
+            self.rehydrate(session, false).await?;
+            let shared_state = self.sessions.read().await
+                .get(&session_id)
+                .map(|s| s.shared_state.clone())
+                .ok_or(SwarmError::SessionNotFound)?;
+            shared_state.merge(&state).await?;
+            self.task_queue.adopt(completed, results, tasks).await
+        }.await;
+        if let Err(e) = adopted {
+            let partial = self.sessions.write().await.remove(&session_id);
+            if let Some(partial) = partial {
+                let _ = self.tear_down(partial).await;
+            }
+            if let Err(release) = self.claim_epoch(session_id, epoch, Some(&imported), &exported).await {
+                warn!(%session_id, epoch, error = %release, "failed import left its migration claimed");
+            }
+            return Err(e);
+        }
+        Ok(session_id)
+    }
+
+    /// Bring `session` back on this node with its agents respawned. Its id
+    /// and its user's usage are reserved together under `sessions` first,
+    /// so of concurrent imports of one session only one gets through. With
+    /// `check_quota` the usage is checked against the user's quota; a
+    /// restored session was admitted already, so it's counted as is. One
+    /// failing partway is undone, agents spawned so far included.
+    async fn rehydrate(&self, mut session: Session, check_quota: bool) -> Result<(), SwarmError> {
+        let session_id = session.id;
+        let (user_id, reserved) = (session.user_id.clone(), session.agents.len());
+        {
+            let sessions = self.sessions.write().await;
+            if sessions.contains_key(&session_id) || !self.rehydrating().insert(session_id) {
+                return Err(SwarmError::SessionExists(session_id));
+            }
+            let counted = if check_quota {
+                self.reserve_quota(&user_id, 1, reserved).await
+            } else {
+                let mut usage = self.usage.write().await;
+                let user = usage.entry(user_id.clone()).or_default();
+                user.sessions += 1;
+                user.agents += reserved;
+                Ok(())
+            };
+            if let Err(e) = counted {
+                self.rehydrating().remove(&session_id);
+                return Err(e);
+            }
+            drop(sessions);
+        }
+
+        let respawned = self.respawn(&mut session).await;
+        let mut sessions = self.sessions.write().await;
+        self.rehydrating().remove(&session_id);
+        if let Err(e) = respawned {
+            drop(sessions);
+            self.release_quota(&user_id, 1, reserved).await;
+            return Err(e);
+        }
+        if let Some(deadline) = session.project_spec.deadline {
+            self.spawn_deadline_watch(session_id, deadline, session.span.clone());
+        }
+        sessions.insert(session_id, session);
+        Ok(())
+    }
+
+    /// Give a rehydrated session a fresh state space and the agents it had.
+    /// On failure, whatever was set up for it is taken down again.
+    async fn respawn(&self, session: &mut Session) -> Result<(), SwarmError> {
+        let session_id = session.id;
+        if let Some(requests_per_minute) = session.project_spec.max_requests_per_minute {
+            self.agent_pool.model_clients.set_session_rate(session_id, requests_per_minute)?;
+        }
+        session.shared_state = match self.state_manager.create_state_space(session_id).await {
+            Ok(shared_state) => shared_state,
+            Err(e) => {
+                self.agent_pool.model_clients.clear_session_rate(session_id);
+                return Err(e);
+            }
+        };
+        session.set_status(session.status);
+        session.span = Session::span_for(session_id, &session.user_id);
+        if session.project_spec.dedup_tasks {
+            self.task_queue.set_dedup(session_id, true).await;
+        }
+        self.agent_pool.set_session_template(session_id, session.project_spec.template);
+
+        let mut agents = Vec::with_capacity(session.agents.len());
+        for old in &session.agents {
+            let spawned = self.spawn_agent(
+                session_id,
+                old.role,
+                old.model,
+                old.skills.clone(),
+                session.shared_state.clone(),
+                session.control.clone(),
+                &session.span,
+            ).await;
+            match spawned {
+                Ok(mut agent) => {
+                    agent.tasks_completed = old.tasks_completed;
+                    agent.cost_incurred = old.cost_incurred;
+                    agent.models_used = old.models_used.clone();
+                    agents.push(agent);
+                }
+                Err(e) => {
+                    for agent in &agents {
+                        let _ = self.agent_pool.terminate_agent(agent.id).await;
+                    }
+                    let _ = self.state_manager.destroy_state_space(session_id).await;
+                    self.task_queue.set_dedup(session_id, false).await;
+                    self.agent_pool.forget_session(session_id);
+                    self.agent_pool.model_clients.clear_session_rate(session_id);
+                    return Err(e);
+                }
+            }
+        }
+        session.set_agents(agents);
+        Ok(())
+    }
+}
+
+/// Encoding of a session snapshot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SnapshotFormat {
+    Json,
+    /// Compact binary, with field names kept so older snapshots still load
+    MessagePack,
+}
+
+impl SnapshotFormat {
+    pub fn encode(self, snapshot: &SessionSnapshot) -> Result<Vec<u8>, SwarmError> {
+        let encoded = match self {
+            SnapshotFormat::Json => serde_json::to_vec(snapshot).map_err(BoxError::from),
+            SnapshotFormat::MessagePack => rmp_serde::to_vec_named(snapshot).map_err(BoxError::from),
+        };
+        encoded.map_err(|source| SwarmError::Snapshot { format: self, source })
+    }
+
+    /// Read back a snapshot from `export_snapshot`, to inspect it without
+    /// importing it
+    pub fn decode(self, bytes: &[u8]) -> Result<SessionSnapshot, SwarmError> {
+        let decoded = match self {
+            SnapshotFormat::Json => serde_json::from_slice(bytes).map_err(BoxError::from),
+            SnapshotFormat::MessagePack => rmp_serde::from_slice(bytes).map_err(BoxError::from),
+        };
+        decoded.map_err(|source| SwarmError::Snapshot { format: self, source })
+    }
+}
+
+/// A session together with the state its live handle points at
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionSnapshot {
+    pub session: Session,
+    pub state: StateSnapshot,
+    /// Its pending and in-progress tasks when the snapshot was taken; kept
+    /// for `diff`, and not queued again by `import_snapshot`
+    #[serde(default)]
+    pub tasks: Vec<Task>,
+    /// The tasks it had completed, with their results, in completion order;
+    /// kept for `diff` like `tasks`
+    #[serde(default)]
+    pub completed: Vec<Task>,
+}
+
+impl SessionSnapshot {
+    /// Where this snapshot and `other` disagree: metrics, outstanding and
+    /// completed tasks, completed tasks' outputs and shared-state values.
+    /// Tasks are matched by `dedup_key`, or else by description, since
+    /// replicated runs give the same step different ids; state is compared
+    /// by value, ignoring versions.
+    pub fn diff(&self, other: &SessionSnapshot) -> SnapshotDiff {
+        let as_fields = |metrics: &SessionMetrics| match serde_json::to_value(metrics) {
+            Ok(serde_json::Value::Object(fields)) => fields,
+            _ => serde_json::Map::new(),
+        };
+        let (left, right) = (as_fields(&self.session.metrics), as_fields(&other.session.metrics));
+        let mut metrics: Vec<FieldDiff> = left.iter()
+            .filter(|&(field, value)| right.get(field) != Some(value))
+            .map(|(field, value)| FieldDiff {
+                field: field.clone(),
+                left: value.clone(),
+                right: right.get(field).cloned().unwrap_or_default(),
+            })
+            .collect();
+        metrics.sort_by(|a, b| a.field.cmp(&b.field));
+
+        let (tasks_only_left, tasks_only_right) = Self::only_on_each_side(&self.tasks, &other.tasks);
+        let (completed_only_left, completed_only_right) = Self::only_on_each_side(&self.completed, &other.completed);
+
+        // Outputs of the tasks both sides completed, paired up in sorted
+        // order where a step completed more than once
+        let (left_outputs, mut right_outputs) = (Self::outputs(&self.completed), Self::outputs(&other.completed));
+        let mut results = vec![];
+        for (task, mut left) in left_outputs {
+            let Some(mut right) = right_outputs.remove(task) else { continue };
+            left.sort_unstable();
+            right.sort_unstable();
+            results.extend(left.into_iter().zip(right).filter(|(l, r)| l != r).map(|(l, r)| ResultDiff {
+                task: task.to_string(),
+                left: l.to_string(),
+                right: r.to_string(),
+            }));
+        }
+        results.sort_by(|a, b| (&a.task, &a.left).cmp(&(&b.task, &b.left)));
+
+        let value = |snapshot: &SessionSnapshot, key: &str| snapshot.state.entries.get(key).map(|e| e.value.clone());
+        let keys: HashSet<&String> = self.state.entries.keys().chain(other.state.entries.keys()).collect();
+        let mut state: Vec<StateDiff> = keys.into_iter()
+            .map(|key| StateDiff { key: key.clone(), left: value(self, key), right: value(other, key) })
+            .filter(|d| d.left != d.right)
+            .collect();
+        state.sort_by(|a, b| a.key.cmp(&b.key));
+
+        SnapshotDiff {
+            metrics,
+            tasks_only_left,
+            tasks_only_right,
+            completed_only_left,
+            completed_only_right,
+            results,
+            state,
+        }
+    }
+
+    /// What a task is matched by across runs
+    fn identity(task: &Task) -> &str {
+        task.dedup_key.as_deref().unwrap_or(&task.description)
+    }
+
+    /// Completed tasks' outputs by identity
+    fn outputs(tasks: &[Task]) -> HashMap<&str, Vec<&str>> {
+        let mut outputs: HashMap<&str, Vec<&str>> = HashMap::new();
+        for task in tasks {
+            let output = task.result.as_ref().map_or("", |r| r.output.as_str());
+            outputs.entry(Self::identity(task)).or_default().push(output);
+        }
+        outputs
+    }
+
+    /// The identities of `left` and `right`, as multisets, each side has
+    /// more of, sorted
+    fn only_on_each_side(left: &[Task], right: &[Task]) -> (Vec<String>, Vec<String>) {
+        let mut counts: HashMap<&str, isize> = HashMap::new();
+        for task in left {
+            *counts.entry(Self::identity(task)).or_default() += 1;
+        }
+        for task in right {
+            *counts.entry(Self::identity(task)).or_default() -= 1;
+        }
+        let (mut only_left, mut only_right) = (vec![], vec![]);
+        for (task, count) in counts {
+            let side = if count > 0 { &mut only_left } else { &mut only_right };
+            side.extend(std::iter::repeat_n(task.to_string(), count.unsigned_abs()));
+        }
+        only_left.sort();
+        only_right.sort();
+        (only_left, only_right)
+    }
+}
+
+/// What `SessionSnapshot::diff` found, each part sorted; printed one
+/// difference per line
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotDiff {
+    pub metrics: Vec<FieldDiff>,
+    /// Outstanding tasks, by identity, only the left snapshot has
+    pub tasks_only_left: Vec<String>,
+    /// Outstanding tasks, by identity, only the right snapshot has
+    pub tasks_only_right: Vec<String>,
+    /// Completed tasks, by identity, only the left snapshot has
+    #[serde(default)]
+    pub completed_only_left: Vec<String>,
+    /// Completed tasks, by identity, only the right snapshot has
+    #[serde(default)]
+    pub completed_only_right: Vec<String>,
+    /// Tasks both completed, with different outputs
+    #[serde(default)]
+    pub results: Vec<ResultDiff>,
+    pub state: Vec<StateDiff>,
+}
+
+/// A `SessionMetrics` field with different values on each side
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldDiff {
+    pub field: String,
+    pub left: serde_json::Value,
+    pub right: serde_json::Value,
+}
+
+/// A task, by identity, both sides completed with different outputs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResultDiff {
+    pub task: String,
+    pub left: String,
+    pub right: String,
+}
+
+/// A shared-state key with different values on each side; `None` where the
+/// key isn't set
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateDiff {
+    pub key: String,
+    pub left: Option<String>,
+    pub right: Option<String>,
+}
+
+impl SnapshotDiff {
+    pub fn is_empty(&self) -> bool {
+        self.metrics.is_empty()
+            && self.tasks_only_left.is_empty()
+            && self.tasks_only_right.is_empty()
+            && self.completed_only_left.is_empty()
+            && self.completed_only_right.is_empty()
+            && self.results.is_empty()
+            && self.state.is_empty()
+    }
+}
+
+impl std::fmt::Display for SnapshotDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "no differences");
+        }
+        for FieldDiff { field, left, right } in &self.metrics {
+            writeln!(f, "metrics.{field}: {left} != {right}")?;
+        }
+        for task in &self.tasks_only_left {
+            writeln!(f, "task only in left: {task:?}")?;
+        }
+        for task in &self.tasks_only_right {
+            writeln!(f, "task only in right: {task:?}")?;
+        }
+        for task in &self.completed_only_left {
+            writeln!(f, "completed only in left: {task:?}")?;
+        }
+        for task in &self.completed_only_right {
+            writeln!(f, "completed only in right: {task:?}")?;
+        }
+        for ResultDiff { task, left, right } in &self.results {
+            writeln!(f, "output of {task:?}: {left:?} != {right:?}")?;
+        }
+        let show = |value: &Option<String>| value.as_ref().map_or("(unset)".to_string(), |v| format!("{v:?}"));
+        for StateDiff { key, left, right } in &self.state {
+            writeln!(f, "state {key:?}: {} != {}", show(left), show(right))?;
+        }
+        Ok(())
+    }
+}
+
+/// Everything `import_migration` needs to resume a session on another node
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MigrationBundle {
+    pub session: Session,
+    pub state: StateSnapshot,
+    /// Pending and interrupted tasks, to be queued on the target
+    pub tasks: Vec<Task>,
+    /// Tasks the session already finished, satisfying the dependencies of
+    /// those in `tasks`
+    pub completed: Vec<TaskId>,
+    /// Results of the finished tasks, in completion order
+    #[serde(default)]
+    pub results: Vec<TaskResult>,
+    /// The session's epoch when it was exported
+    pub epoch: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AutoscaleConfig {
+    /// Spawn coders once pending tasks outnumber idle coders by more than this
+    pub scale_up_threshold: usize,
+    /// Terminate coders once idle coders outnumber pending tasks by more than this
+    pub scale_down_threshold: usize,
+    /// Most agents added or removed per `autoscale` call
+    pub max_step: usize,
+    pub max_agents: usize,
+}
+
+impl Default for AutoscaleConfig {
+    fn default() -> Self {
+        Self {
+            scale_up_threshold: 4,
+            scale_down_threshold: 4,
+            max_step: 100,
+            max_agents: MAX_AGENTS_PER_SESSION,
+        }
+    }
+}
+
+/// What `SessionManager::reap_idle_sessions` does with an idle session
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IdleAction {
+    /// Stop it taking work and hand its agents back to the pool, along
+    /// with their quota; `resume_session` spawns them again
+    Pause,
+    /// Destroy it, handing its agents back to the pool
+    Destroy,
+}
+
+/// What `create_session` does when the agent pool is at `max_agents`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SaturationPolicy {
+    /// Fail with `SwarmError::PoolSaturated`
+    #[default]
+    Reject,
+    /// Create the session as `Initializing`, without agents, and start it
+    /// once capacity frees up (see `SessionManager::admit_queued_sessions`)
+    Queue,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuotaConfig {
+    pub max_concurrent_sessions_per_user: usize,
+    /// Agents across all of a user's live sessions, including autoscaled ones
+    pub max_total_agents_per_user: usize,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent_sessions_per_user: usize::MAX,
+            max_total_agents_per_user: usize::MAX,
+        }
+    }
+}
+
+/// Verifier models come from the `ModelRouter`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct VerifierConfig {
+    /// Spawn one verifier per this many coders; `None` for one per session
+    pub coders_per_verifier: Option<usize>,
+    /// While a session's verifiers average more than this many checks
+    /// waiting or running, `dispatch_ready` gives its coders no new tasks, so
+    /// pool capacity goes to draining verification instead of adding to it,
+    /// and new checks go to idle testers, then idle coders, before queueing
+    /// on a busy verifier. `None` never holds coders back.
+    #[serde(default)]
+    pub max_backlog_per_verifier: Option<f64>,
+}
+
+/// Picks the model for each new agent from its role and the project's
+/// complexity. Unrouted pairs fall back to the role's default.
+#[derive(Debug, Clone, Default)]
+pub struct ModelRouter {
+    routes: HashMap<(AgentRole, Complexity), ModelPreference>,
+}
+
+impl ModelRouter {
+    /// Use `model` for `role` agents on projects of `complexity`
+    pub fn route(mut self, role: AgentRole, complexity: Complexity, model: ModelPreference) -> Self {
+        self.routes.insert((role, complexity), model);
+        self
+    }
+
+    pub fn model_for(&self, role: AgentRole, complexity: Complexity) -> ModelPreference {
+        self.routes
+            .get(&(role, complexity))
+            .copied()
+            .unwrap_or_else(|| Self::default_model(role))
+    }
+
+    fn default_model(role: AgentRole) -> ModelPreference {
+        match role {
+            AgentRole::Planner => ModelPreference::GPT51,
+            AgentRole::Coder => ModelPreference::ClaudeOpus45,
+            AgentRole::Tester => ModelPreference::Gemini3Pro,
+            AgentRole::Browser => ModelPreference::None,
+            AgentRole::Verifier => ModelPreference::Gemini3Pro,
+        }
+    }
+}
+
+/// Read-only view of a `SessionManager`'s sessions, from
+/// `SessionManager::observer`. It shares the manager's state but only takes
+/// read locks, and has no way to create, pause or destroy a session.
+#[derive(Clone)]
+pub struct SessionObserver {
+    sessions: Arc<RwLock<HashMap<SessionId, Session>>>,
+    task_queue: Arc<TaskQueue>,
+    agent_pool: Arc<AgentPool>,
+    events: broadcast::Sender<SwarmEvent>,
+}
+
+impl SessionObserver {
+    /// See `SessionManager::get_session_status`
+    pub async fn get_session_status(
+        &self,
+        session_id: SessionId,
+    ) -> Result<SessionStatusReport, SwarmError> {
+        let summary = {
+            let sessions = self.sessions.read().await;
+            let session = sessions.get(&session_id)
+                .ok_or(SwarmError::SessionNotFound)?;
+            SessionSummary::of(session)
+        };
+        Ok(self.status_report(summary).await)
+    }
+
+    /// See `SessionManager::get_rolled_up_status`
+    pub async fn get_rolled_up_status(
+        &self,
+        session_id: SessionId,
+    ) -> Result<SessionStatusReport, SwarmError> {
+        let summary = {
+            let sessions = self.sessions.read().await;
+            let session = sessions.get(&session_id)
+                .ok_or(SwarmError::SessionNotFound)?;
+            let mut summary = SessionSummary::of(session);
+            for id in descendants(&sessions, session_id) {
+                let child = &sessions[&id];
+                summary.metrics.absorb(&child.metrics);
+                summary.agents.absorb(&child.agent_counts);
+                summary.agents_starting += child.agents_starting;
+                summary.verifiers.extend(child.verifiers());
+                summary.descendants.push(id);
+            }
+            summary
+        };
+        Ok(self.status_report(summary).await)
+    }
+
+    /// Status of every session matching `filter`, newest first
+    pub async fn list_sessions(&self, filter: SessionFilter) -> Vec<SessionStatusReport> {
+        // Copy out what the reports need; build them without the lock
+        let mut summaries: Vec<SessionSummary> = self.sessions.read().await
+            .values()
+            .filter(|s| filter.matches(s))
+            .map(SessionSummary::of)
+            .collect();
+        summaries.sort_by_key(|s| std::cmp::Reverse(s.created_at));
+
+        let mut reports = Vec::with_capacity(summaries.len());
+        for summary in summaries {
+            reports.push(self.status_report(summary).await);
+        }
+        reports
+    }
+
+    /// See `SessionManager::aggregate_stats`. One pass over the sessions'
+    /// running counters under a read lock; agents and tasks aren't visited.
+    pub async fn aggregate_stats(&self, top_n: usize) -> OrchestratorStats {
+        let mut stats = OrchestratorStats {
+            tasks_per_sec: self.agent_pool.metrics.tasks_per_sec(),
+            ..OrchestratorStats::default()
+        };
+        let mut costs = Vec::new();
+        for session in self.sessions.read().await.values() {
+            stats.sessions += 1;
+            stats.active_sessions += usize::from(session.status == SessionStatus::Active);
+            stats.agents.absorb(&session.agent_counts);
+            stats.metrics.absorb(&session.metrics);
+            costs.push(SessionCost {
+                session_id: session.id,
+                user_id: session.user_id.clone(),
+                cost: session.metrics.total_cost,
+            });
+        }
+
+        let costliest = |a: &SessionCost, b: &SessionCost| {
+            b.cost.total_cmp(&a.cost).then_with(|| a.session_id.cmp(&b.session_id))
+        };
+        if top_n < costs.len() {
+            costs.select_nth_unstable_by(top_n, costliest);
+            costs.truncate(top_n);
+        }
+        costs.sort_by(costliest);
+        stats.top_sessions = costs;
+        stats
+    }
+
+    async fn status_report(&self, summary: SessionSummary) -> SessionStatusReport {
+        let SessionSummary {
+            id, user_id, created_at, status, parent_id, metrics, eta, agents, agents_starting, deadline, verifiers,
+            descendants, tags,
+        } = summary;
+
+        let progress_pct = if metrics.tasks_assigned == 0 {
+            0.0
+        } else {
+            (100.0 * metrics.tasks_completed as f64 / metrics.tasks_assigned as f64).min(100.0)
+        };
+        // Kept by the queue as tasks move, so none of this walks it
+        let (mut remaining_tasks, mut outstanding, mut chain) = (0, 0, 0);
+        for &session_id in std::iter::once(&id).chain(&descendants) {
+            let load = self.task_queue.load_for(session_id).await;
+            remaining_tasks += load.pending;
+            outstanding += load.pending + load.in_progress;
+            // Sessions' DAGs are separate, so the longest chain is one of theirs
+            chain = chain.max(load.critical_path);
+        }
+        let estimated_remaining_sec = metrics
+            .avg_task_duration_sec()
+            .map(|avg| avg * remaining_tasks as f64);
+        let agents_working = agents.working;
+        let eta = eta
+            .project(outstanding, chain, agents_working)
+            .map(|sec| Utc::now() + chrono::Duration::milliseconds((sec * 1000.0) as i64));
+
+        SessionStatusReport {
+            session_id: id,
+            user_id,
+            created_at,
+            status,
+            parent_id,
+            descendants,
+            agent_count: agents.total(),
+            agents_idle: agents.idle,
+            agents_working,
+            agents_unhealthy: agents.failed,
+            agents_starting,
+            verification_backlog: self.agent_pool.backlog(&verifiers).await,
+            metrics,
+            progress_pct,
+            estimated_remaining_sec,
+            eta,
+            deadline_remaining_sec: deadline.map(|at| (at - Utc::now()).num_milliseconds().max(0) as f64 / 1000.0),
+            tags,
+        }
+    }
+
+    /// See `SessionManager::subscribe`
+    pub fn subscribe(&self) -> broadcast::Receiver<SwarmEvent> {
+        self.events.subscribe()
+    }
+}
+
+/// Fleet-wide figures from `SessionManager::aggregate_stats`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct OrchestratorStats {
+    /// Sessions held, whatever their status
+    pub sessions: usize,
+    pub active_sessions: usize,
+    pub agents: StatusCounts,
+    /// Every session's metrics summed, sub-sessions included once each
+    pub metrics: SessionMetrics,
+    /// Tasks run per second, verification checks included, over the last
+    /// `metrics::THROUGHPUT_WINDOW_SECS`; sessions since destroyed count too
+    pub tasks_per_sec: f64,
+    /// Costliest sessions first
+    pub top_sessions: Vec<SessionCost>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionCost {
+    pub session_id: SessionId,
+    pub user_id: UserId,
+    /// USD spent so far
+    pub cost: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct UserUsage {
+    pub sessions: usize,
+    pub agents: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct AutoscaleOutcome {
+    pub spawned: usize,
+    pub terminated: usize,
+}
+
+/// What `apply_resource_pressure` decided for one session, to carry out
+/// once `sessions` is released
+struct Resize {
+    session_id: SessionId,
+    user_id: String,
+    spawn: Vec<(AgentRole, ModelPreference)>,
+    terminate: Vec<AgentId>,
+    shared_state: Arc<SharedState>,
+    control: Arc<SessionControl>,
+    span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DrainReport {
+    /// Tasks that finished while draining
+    pub completed: usize,
+    /// Tasks still queued or running when the timeout hit
+    pub abandoned: usize,
+    pub metrics: SessionMetrics,
+}
+
+/// A session's plan as `SessionManager::checkpoint_session` saves it
+#[derive(Serialize, Deserialize)]
+struct TaskCheckpoint {
+    /// Pending and in-progress tasks, unassigned
+    tasks: Vec<Task>,
+    completed: Vec<TaskId>,
+    /// Results of the completed tasks that recorded one, in completion order
+    #[serde(default)]
+    results: Vec<TaskResult>,
+}
+
+/// How `SessionManager::resume_from_checkpoint` picked a plan back up
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeReport {
+    /// Tasks queued to run again
+    pub queued: usize,
+    /// Tasks counted as done without running again
+    pub completed: usize,
+}
+
+/// What `SessionManager::shutdown` managed to save
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ShutdownReport {
+    /// Sessions checkpointed, paused
+    pub saved: Vec<SessionId>,
+    /// Sessions that couldn't be checkpointed
+    pub abandoned: Vec<SessionId>,
+    /// Tasks that finished within the timeout
+    pub tasks_completed: usize,
+    /// Tasks still running or queued on an agent at the timeout; their
+    /// sessions are saved without them
+    pub tasks_abandoned: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CostEstimate {
+    /// USD
+    pub low: f64,
+    pub expected: f64,
+    pub high: f64,
+    pub estimated_duration: Duration,
+}
+
+/// A task DAG proposed by `SessionManager::plan_only`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlanReport {
+    /// Dependencies reference other tasks in the plan
+    pub tasks: Vec<Task>,
+    /// Longest chain of dependent tasks, first to last
+    pub critical_path: Vec<TaskId>,
+    /// Minimum wall time with unlimited agents
+    pub critical_path_min: f64,
+    /// Sum over all tasks; the time for a single agent
+    pub total_estimated_min: f64,
+    pub model: ModelPreference,
+    /// USD spent on the planning call
+    pub cost: f64,
+}
+
+/// Selects sessions for `SessionManager::list_sessions`; unset fields match all
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SessionFilter {
+    pub user_id: Option<UserId>,
+    pub status: Option<SessionStatus>,
+    /// Only sessions created strictly after this
+    pub created_after: Option<DateTime<Utc>>,
+    /// Only sessions carrying every one of these tags, with the same values
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+}
+
+impl SessionFilter {
+    fn matches(&self, session: &Session) -> bool {
+        self.user_id.as_ref().is_none_or(|user| *user == session.user_id)
+            && self.status.is_none_or(|status| status == session.status)
+            && self.created_after.is_none_or(|after| session.created_at > after)
+            && self.tags.iter().all(|(key, value)| session.tags.get(key) == Some(value))
+    }
+}
+
+/// The parts of a session its status report is built from
+struct SessionSummary {
+    id: SessionId,
+    user_id: UserId,
+    created_at: DateTime<Utc>,
+    status: SessionStatus,
+    parent_id: Option<SessionId>,
+    metrics: SessionMetrics,
+    eta: EtaEstimator,
+    agents: StatusCounts,
+    agents_starting: usize,
+    deadline: Option<DateTime<Utc>>,
+    verifiers: Vec<AgentId>,
+    /// Sub-sessions whose figures are folded into the above
+    descendants: Vec<SessionId>,
+    tags: HashMap<String, String>,
+}
+
+impl SessionSummary {
+    fn of(session: &Session) -> Self {
+        Self {
+            id: session.id,
+            user_id: session.user_id.clone(),
+            created_at: session.created_at,
+            status: session.status,
+            parent_id: session.parent_id,
+            metrics: session.metrics.clone(),
+            eta: session.eta,
+            agents: session.agent_counts,
+            agents_starting: session.agents_starting,
+            deadline: session.project_spec.deadline,
+            verifiers: session.verifiers(),
+            descendants: vec![],
+            tags: session.tags.clone(),
+        }
+    }
+}
+
+/// Sub-sessions below `session_id` at any depth, parents before their children
+fn descendants(sessions: &HashMap<SessionId, Session>, session_id: SessionId) -> Vec<SessionId> {
+    let mut found = vec![];
+    let mut frontier = vec![session_id];
+    while let Some(parent) = frontier.pop() {
+        for child in sessions.values().filter(|s| s.parent_id == Some(parent)) {
+            found.push(child.id);
+            frontier.push(child.id);
+        }
+    }
+    found
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionStatusReport {
+    pub session_id: SessionId,
+    pub user_id: UserId,
+    pub created_at: DateTime<Utc>,
+    pub status: SessionStatus,
+    #[serde(default)]
+    pub parent_id: Option<SessionId>,
+    /// Sub-sessions counted in this report, from `get_rolled_up_status`
+    #[serde(default)]
+    pub descendants: Vec<SessionId>,
+    pub metrics: SessionMetrics,
+    pub agent_count: usize,
+    pub agents_idle: usize,
+    pub agents_working: usize,
+    /// Agents marked `Failed` by a health sweep or a stale-task reclaim
+    pub agents_unhealthy: usize,
+    /// Agents of the initial roster not yet online, under
+    /// `SessionManager::with_spawn_rate`
+    #[serde(default)]
+    pub agents_starting: usize,
+    /// Checks waiting or running per verifier; see
+    /// `VerifierConfig::max_backlog_per_verifier`
+    #[serde(default)]
+    pub verification_backlog: f64,
+    /// Completed share of assigned tasks, 0-100
+    pub progress_pct: f64,
+    /// Average task duration times the session's pending tasks; `None`
+    /// until a task has completed
+    pub estimated_remaining_sec: Option<f64>,
+    /// Smoothed completion time from `EtaEstimator`; `None` until a task has
+    /// completed
+    #[serde(default)]
+    pub eta: Option<DateTime<Utc>>,
+    /// Time left before `ProjectSpec::deadline`, 0 once it has passed;
+    /// `None` without one
+    #[serde(default)]
+    pub deadline_remaining_sec: Option<f64>,
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+}
+
+/// Weight of the latest task duration in `EtaEstimator`'s moving average
+pub const ETA_SMOOTHING: f64 = 0.3;
+
+/// Exponentially weighted moving average of a session's task durations,
+/// projected over the work it has left. Steadier than the plain average
+/// behind `estimated_remaining_sec`, and aware that dependent tasks can't
+/// run side by side.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct EtaEstimator {
+    alpha: f64,
+    avg_sec: Option<f64>,
+}
+
+impl Default for EtaEstimator {
+    fn default() -> Self {
+        Self::new(ETA_SMOOTHING)
+    }
+}
+
+impl EtaEstimator {
+    /// `alpha` in (0, 1]: higher follows recent durations more closely
+    pub fn new(alpha: f64) -> Self {
+        Self { alpha: alpha.clamp(f64::EPSILON, 1.0), avg_sec: None }
+    }
+
+    /// Fold in one completed task's duration
+    pub fn observe(&mut self, duration_sec: f64) {
+        self.avg_sec = Some(match self.avg_sec {
+            Some(avg) => avg + self.alpha * (duration_sec - avg),
+            None => duration_sec,
+        });
+    }
+
+    /// Smoothed task duration, once a task has been observed
+    pub fn average_sec(&self) -> Option<f64> {
+        self.avg_sec
+    }
+
+    /// How long `task` should take: the longer of its own estimate and the
+    /// smoothed duration, so neither a task planned as long nor a session
+    /// running slow is assumed to speed up
+    pub fn expected_sec(&self, task: &Task) -> f64 {
+        (task.estimated_time_min * 60.0).max(self.avg_sec.unwrap_or(0.0))
+    }
+
+    /// Seconds to finish `remaining` tasks with `parallelism` agents on
+    /// them, when the longest chain of dependent tasks among them is
+    /// `critical_path` long: the tasks spread over the agents in waves, but
+    /// never fewer waves than the chain has links.
+    pub fn project(&self, remaining: usize, critical_path: usize, parallelism: usize) -> Option<f64> {
+        let waves = remaining.div_ceil(parallelism.max(1)).max(critical_path.min(remaining));
+        self.avg_sec.map(|avg| avg * waves as f64)
+    }
+}
+
+// ============================================================================
+// AGENT POOL
+// ============================================================================
+
+/// Tasks each agent may have queued before `assign_task` waits
+const AGENT_INBOX_CAPACITY: usize = 16;
+
+/// How long a drain or shutdown waits for reports already sent to be
+/// processed, even past its own deadline
+const REPORT_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Default period between agent heartbeats
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Share of dispatch slots a session gets unless `set_session_weight` says otherwise
+pub const DEFAULT_SESSION_WEIGHT: u32 = 1;
+
+/// Start-time fair queueing over sessions: each dispatch advances the
+/// session's virtual time by 1/weight, and the session with the lowest
+/// virtual time goes next. Sessions start (or return from idle) at the
+/// current clock, so sitting out earns no burst of credit.
+#[derive(Debug, Default)]
+struct FairShare {
+    weights: HashMap<SessionId, u32>,
+    virtual_time: HashMap<SessionId, f64>,
+    clock: f64,
+}
+
+impl FairShare {
+    fn weight(&self, session_id: SessionId) -> u32 {
+        self.weights.get(&session_id).copied().unwrap_or(DEFAULT_SESSION_WEIGHT)
+    }
+
+    fn start(&self, session_id: SessionId) -> f64 {
+        self.virtual_time.get(&session_id).copied().unwrap_or(0.0).max(self.clock)
+    }
+
+    fn charge(&mut self, session_id: SessionId) {
+        let start = self.start(session_id);
+        self.clock = start;
+        self.virtual_time.insert(session_id, start + 1.0 / self.weight(session_id) as f64);
+    }
+}
+
+pub struct AgentPool {
+    agents: Arc<RwLock<HashMap<AgentId, AgentHandle>>>,
+    running: Arc<RwLock<HashMap<AgentId, AgentTask>>>,
+    /// Cancellation handle and owner of every assigned, unsettled task
+    assigned: RwLock<HashMap<TaskId, (AgentId, CancellationToken)>>,
+    fair_share: std::sync::Mutex<FairShare>,
+    /// Signalled whenever an assigned task settles
+    task_settled: Notify,
+    model_clients: Arc<ModelClients>,
+    metrics: MetricsRegistry,
+    bus: Arc<MessageBus>,
+    /// Applied to coder agents only
+    batcher: Option<TaskBatcher>,
+    heartbeat_interval: Duration,
+    /// Idle agents kept alive between sessions, for `spawn_agent` to reuse
+    warm: std::sync::Mutex<Vec<WarmAgent>>,
+    /// Most warm agents kept per role
+    warm_per_role: usize,
+    ids: Arc<dyn IdGenerator>,
+    /// Most agents attached to sessions at once, across all of them
+    max_agents: usize,
+    /// Held from the capacity check until a new agent is counted
+    spawn_gate: Mutex<()>,
+    reports_tx: mpsc::UnboundedSender<AgentReport>,
+    reports_rx: std::sync::Mutex<Option<mpsc::UnboundedReceiver<AgentReport>>>,
+    prompts: Arc<PromptTemplates>,
+    /// Project template of each session, for choosing its agents' prompts
+    session_templates: std::sync::Mutex<HashMap<SessionId, TemplateType>>,
+}
+
+impl AgentPool {
+    pub fn new(model_clients: Arc<ModelClients>) -> Self {
+        let (reports_tx, reports_rx) = mpsc::unbounded_channel();
+        let metrics = MetricsRegistry::new();
+        model_clients.report_to(metrics.clone());
+        Self {
+            agents: Arc::new(RwLock::new(HashMap::new())),
+            running: Arc::new(RwLock::new(HashMap::new())),
+            assigned: RwLock::new(HashMap::new()),
+            fair_share: std::sync::Mutex::new(FairShare::default()),
+            task_settled: Notify::new(),
+            model_clients,
+            metrics,
+            bus: Arc::new(MessageBus::local()),
+            batcher: None,
+            heartbeat_interval: HEARTBEAT_INTERVAL,
+            warm: std::sync::Mutex::new(Vec::new()),
+            warm_per_role: 0,
+            ids: Arc::new(RandomIdGenerator),
+            max_agents: usize::MAX,
+            spawn_gate: Mutex::new(()),
+            reports_tx,
+            reports_rx: std::sync::Mutex::new(Some(reports_rx)),
+            prompts: Arc::new(PromptTemplates::default()),
+            session_templates: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Report into an existing registry instead of a private one
+    pub fn with_metrics(mut self, metrics: MetricsRegistry) -> Self {
+        self.model_clients.report_to(metrics.clone());
+        if let Some(controller) = self.batcher.as_ref().and_then(|b| b.controller.as_ref()) {
+            controller.report_to(metrics.clone());
+        }
+        self.metrics = metrics;
+        self
+    }
+
+    /// Publish agent status over a shared bus (e.g. NATS) instead of in-process
+    pub fn with_message_bus(mut self, bus: Arc<MessageBus>) -> Self {
+        self.bus = bus;
+        self
+    }
+
+    /// Let coders combine queued tasks into a single model call
+    pub fn with_task_batcher(mut self, batcher: TaskBatcher) -> Self {
+        if let Some(controller) = &batcher.controller {
+            controller.report_to(self.metrics.clone());
+        }
+        self.batcher = Some(batcher);
+        self
+    }
+
+    /// How often agents report a heartbeat, idle or busy. Fails with
+    /// `InvalidSpec` for a zero `interval`.
+    pub fn with_heartbeat_interval(mut self, interval: Duration) -> Result<Self, SwarmError> {
+        if interval.is_zero() {
+            return Err(SwarmError::InvalidSpec("heartbeat interval must be positive".to_string()));
+        }
+        self.heartbeat_interval = interval;
+        Ok(self)
+    }
+
+    /// Keep up to `per_role` idle agents of each role alive once their
+    /// session ends (see `release_agent`), and hand them to new sessions
+    /// instead of starting fresh ones. Off (0) by default.
+    pub fn with_warm_pool(mut self, per_role: usize) -> Self {
+        self.warm_per_role = per_role;
+        self
+    }
+
+    /// Draw new agents' ids from `ids` instead of at random
+    pub fn with_id_generator(mut self, ids: Arc<dyn IdGenerator>) -> Self {
+        self.ids = ids;
+        self
+    }
+
+    /// Refuse to spawn past `max_agents` agents across all sessions, with
+    /// `SwarmError::PoolSaturated`. Parked warm agents don't count.
+    pub fn with_max_agents(mut self, max_agents: usize) -> Self {
+        self.max_agents = max_agents;
+        self
+    }
+
+    pub fn max_agents(&self) -> usize {
+        self.max_agents
+    }
+
+    /// Have agents render their prompts from `prompts` instead of the
+    /// defaults
+    pub fn with_prompt_templates(mut self, prompts: PromptTemplates) -> Self {
+        self.prompts = Arc::new(prompts);
+        self
+    }
+
+    /// Prompt `session_id`'s agents spawned from now on with `template`'s
+    /// texts; `SessionManager` sets it before a session's first agent
+    pub fn set_session_template(&self, session_id: SessionId, template: TemplateType) {
+        self.session_templates().insert(session_id, template);
+    }
+
+    fn session_templates(&self) -> std::sync::MutexGuard<'_, HashMap<SessionId, TemplateType>> {
+        self.session_templates.lock().expect("session template lock poisoned")
+    }
+
+    /// Agents that can still be spawned before the pool is saturated
+    pub async fn available_capacity(&self) -> usize {
+        self.max_agents.saturating_sub(self.running.read().await.len())
+    }
+
+    fn warm(&self) -> std::sync::MutexGuard<'_, Vec<WarmAgent>> {
+        self.warm.lock().expect("warm pool lock poisoned")
+    }
+
+    /// Agents parked in the warm pool
+    pub fn warm_len(&self) -> usize {
+        self.warm().len()
+    }
+
+    /// Relative share of dispatch slots for `session_id` when sessions
+    /// compete for the pool; a weight-3 session gets three tasks started for
+    /// every one of a weight-1 session. Zero is treated as 1.
+    pub fn set_session_weight(&self, session_id: SessionId, weight: u32) {
+        self.fair_share().weights.insert(session_id, weight.max(1));
+    }
+
+    pub fn session_weight(&self, session_id: SessionId) -> u32 {
+        self.fair_share().weight(session_id)
+    }
+
+    fn fair_share(&self) -> std::sync::MutexGuard<'_, FairShare> {
+        self.fair_share.lock().expect("fair share lock poisoned")
+    }
+
+    fn forget_session(&self, session_id: SessionId) {
+        let mut fair_share = self.fair_share();
+        fair_share.weights.remove(&session_id);
+        fair_share.virtual_time.remove(&session_id);
+        self.session_templates().remove(&session_id);
+    }
+
+    /// The most under-served of `candidates` (session, agent to run on):
+    /// lowest virtual time, then fewest tasks in flight per unit of weight
+    async fn pick_session(&self, candidates: &[(SessionId, AgentId)]) -> Option<(SessionId, AgentId)> {
+        let occupancy = self.occupancy().await;
+        let fair_share = self.fair_share();
+        candidates.iter().copied().min_by(|(a, _), (b, _)| {
+            let load = |id: &SessionId| {
+                occupancy.get(id).copied().unwrap_or(0) as f64 / fair_share.weight(*id) as f64
+            };
+            fair_share.start(*a).total_cmp(&fair_share.start(*b))
+                .then(load(a).total_cmp(&load(b)))
+                .then(fair_share.weight(*b).cmp(&fair_share.weight(*a)))
+        })
+    }
+
+    /// Record that `session_id` was given a dispatch slot
+    fn charge_session(&self, session_id: SessionId) {
+        self.fair_share().charge(session_id);
+    }
+
+    /// Unsettled tasks per session, across its local agents
+    async fn occupancy(&self) -> HashMap<SessionId, usize> {
+        let mut occupancy = HashMap::new();
+        for task in self.running.read().await.values() {
+            *occupancy.entry(task.session_id).or_default() += task.in_flight.load(AtomicOrdering::SeqCst);
+        }
+        occupancy
+    }
+
+    async fn in_flight_total(&self) -> usize {
+        self.running.read().await
+            .values()
+            .map(|t| t.in_flight.load(AtomicOrdering::SeqCst))
+            .sum()
+    }
+
+    /// Tasks in flight on each of `agents`, or `None` where it isn't running locally
+    async fn loads(&self, agents: &[AgentId]) -> Vec<Option<usize>> {
+        let running = self.running.read().await;
+        agents.iter()
+            .map(|id| running.get(id).map(|t| t.in_flight.load(AtomicOrdering::SeqCst)))
+            .collect()
+    }
+
+    /// Tasks in flight per agent across `agents`; 0 for no agents
+    async fn backlog(&self, agents: &[AgentId]) -> f64 {
+        if agents.is_empty() {
+            return 0.0;
+        }
+        let in_flight: usize = self.loads(agents).await.into_iter().flatten().sum();
+        in_flight as f64 / agents.len() as f64
+    }
+
+    async fn is_local(&self, agent_id: AgentId) -> bool {
+        self.running.read().await.contains_key(&agent_id)
+    }
+
+    /// Whether the agent's task is running here and hasn't ended
+    async fn is_alive(&self, agent_id: AgentId) -> bool {
+        self.running.read().await.get(&agent_id).is_some_and(|task| !task.join.is_finished())
+    }
+
+    /// Hand the report stream to its single consumer (the `SessionManager`)
+    fn take_reports(&self) -> Option<mpsc::UnboundedReceiver<AgentReport>> {
+        self.reports_rx.lock().expect("reports lock poisoned").take()
+    }
+
+    async fn update_agent(&self, agent_id: AgentId, update: impl FnOnce(&mut AgentHandle)) {
+        if let Some(agent) = self.agents.write().await.get_mut(&agent_id) {
+            let before = agent.status;
+            update(agent);
+            self.metrics.agent_status_changed(before, agent.status);
+        }
+    }
+
+    pub async fn spawn_agent(
+        &self,
+        session_id: SessionId,
+        role: AgentRole,
+        model: ModelPreference,
+        shared_state: Arc<SharedState>,
+        control: Arc<SessionControl>,
+    ) -> Result<AgentHandle, SwarmError> {
+        self.spawn_agent_with_skills(session_id, role, model, vec![], shared_state, control).await
+    }
+
+    /// `spawn_agent` for a specialist: tasks whose `required_skills` fall
+    /// within `skills` go to it ahead of generalists of the same role
+    pub async fn spawn_agent_with_skills(
+        &self,
+        session_id: SessionId,
+        role: AgentRole,
+        model: ModelPreference,
+        skills: Vec<String>,
+        shared_state: Arc<SharedState>,
+        control: Arc<SessionControl>,
+    ) -> Result<AgentHandle, SwarmError> {
+        let _gate = self.spawn_gate.lock().await;
+        if self.running.read().await.len() >= self.max_agents {
+            return Err(SwarmError::PoolSaturated { limit: self.max_agents });
+        }
+
+        // A warm agent of the same role and model starts with a clean slate
+        let warm = {
+            let mut warm = self.warm();
+            let found = warm.iter().position(|w| w.role == role && w.model == model);
+            found.map(|index| warm.swap_remove(index))
+        };
+        let handle = AgentHandle {
+            id: warm.as_ref().map_or_else(|| self.ids.next_id(), |w| w.id),
+            role,
+            model,
+            status: AgentStatus::Idle,
+            tasks_completed: 0,
+            cost_incurred: 0.0,
+            models_used: HashMap::new(),
+            last_heartbeat: Utc::now(),
+            skills,
+            blocked_on: None,
+            affinity: VecDeque::new(),
+            capabilities: self.model_clients.capabilities(model),
+            scratch: HashMap::new(),
+        };
+        let agent_id = handle.id;
+
+        let (bindings, heartbeat, join) = match warm {
+            Some(agent) => {
+                agent.heartbeat.beat();
+                (agent.bindings, agent.heartbeat, agent.join)
+            }
+            None => {
+                // Spawn async task for this agent
+                let (bindings, binding_rx) = mpsc::channel(1);
+                let heartbeat = Arc::new(Heartbeat::new(self.heartbeat_interval));
+                let join = tokio::spawn(Self::agent_loop(
+                    handle.clone(),
+                    self.model_clients.clone(),
+                    self.batcher.clone().filter(|_| role == AgentRole::Coder),
+                    binding_rx,
+                    self.reports_tx.clone(),
+                    self.bus.clone(),
+                    heartbeat.clone(),
+                ));
+                self.metrics.agent_cold_started();
+                (bindings, heartbeat, join)
+            }
+        };
+
+        let (inbox_tx, inbox) = mpsc::channel(AGENT_INBOX_CAPACITY);
+        let prompts = SessionPrompts {
+            templates: self.prompts.clone(),
+            template: self.session_templates().get(&session_id).copied(),
+        };
+        let binding = AgentBinding {
+            session_id,
+            shared_state,
+            control,
+            prompts,
+            inbox,
+            // Child of the caller's span (the session's, via `SessionManager`);
+            // spawned tasks don't inherit it on their own
+            span: info_span!(parent: Span::current(), "agent", %agent_id, ?role, ?model),
+        };
+        if bindings.send(binding).await.is_err() {
+            join.abort();
+            return Err(SwarmError::AgentSpawnFailed);
+        }
+
+        self.agents.write().await.insert(agent_id, handle.clone());
+        self.running.write().await.insert(agent_id, AgentTask {
+            session_id,
+            inbox: inbox_tx,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            heartbeat,
+            bindings,
+            join,
+        });
+        self.metrics.agent_spawned();
+
+        Ok(handle)
+    }
+
+    /// Push a task onto an agent's inbox, waiting if the inbox is full
+    pub async fn assign_task(
+        &self,
+        agent_id: AgentId,
+        task: Task,
+    ) -> Result<(), SwarmError> {
+        let claimed = self.claim(agent_id, task).await?;
+        self.hand_over(claimed).await
+    }
+
+    /// Count `task` as in flight on `agent_id`, ahead of `hand_over` putting
+    /// it in the agent's inbox
+    async fn claim(&self, agent_id: AgentId, mut task: Task) -> Result<ClaimedTask, SwarmError> {
+        let (inbox, in_flight) = self.running.read().await
+            .get(&agent_id)
+            .map(|t| (t.inbox.clone(), t.in_flight.clone()))
+            .ok_or(SwarmError::AgentNotFound)?;
+
+        task.assigned_to = Some(agent_id);
+        self.assigned.write().await.insert(task.id, (agent_id, task.cancellation.clone()));
+        in_flight.fetch_add(1, AtomicOrdering::SeqCst);
+        Ok(ClaimedTask { task, inbox, in_flight })
+    }
+
+    /// Push a claimed task onto its agent's inbox, waiting if the inbox is
+    /// full; an agent gone meanwhile no longer counts it
+    async fn hand_over(&self, claimed: ClaimedTask) -> Result<(), SwarmError> {
+        let ClaimedTask { task, inbox, in_flight } = claimed;
+        inbox.send(task).await.map_err(|_| {
+            in_flight.fetch_sub(1, AtomicOrdering::SeqCst);
+            self.task_settled.notify_waiters();
+            SwarmError::AgentNotFound
+        })
+    }
+
+    /// Wait until every report sent so far has been processed, so what it
+    /// reported shows in session state. Bounded by `REPORT_FLUSH_TIMEOUT`, in
+    /// case nothing is processing reports.
+    async fn flush_reports(&self) {
+        let flushed = Arc::new(Notify::new());
+        if self.reports_tx.send(AgentReport::Flushed(flushed.clone())).is_ok() {
+            let _ = tokio::time::timeout(REPORT_FLUSH_TIMEOUT, flushed.notified()).await;
+        }
+    }
+
+    /// Signal the agent holding `task_id` to abandon it, if one of `agents`
+    /// does. Returns whether such an agent was found.
+    async fn cancel_task(&self, task_id: TaskId, agents: &[AgentId]) -> bool {
+        match self.assigned.read().await.get(&task_id) {
+            Some((agent_id, token)) if agents.contains(agent_id) => {
+                token.cancel();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Mark one of an agent's assigned tasks as done (completed, failed or
+    /// cancelled)
+    async fn task_settled(&self, agent_id: AgentId, task_id: TaskId) {
+        self.assigned.write().await.remove(&task_id);
+        if let Some(task) = self.running.read().await.get(&agent_id) {
+            // Saturate: a report can outlive a re-spawn after restore
+            let _ = task.in_flight.fetch_update(AtomicOrdering::SeqCst, AtomicOrdering::SeqCst, |n| {
+                n.checked_sub(1)
+            });
+        }
+        self.task_settled.notify_waiters();
+    }
+
+    /// Forget the tasks assigned to an agent whose task has died, so they stop
+    /// counting as in flight; they're reclaimed through the `TaskQueue`
+    async fn abandon(&self, agent_id: AgentId) {
+        self.assigned.write().await.retain(|_, (owner, _)| *owner != agent_id);
+        if let Some(task) = self.running.read().await.get(&agent_id) {
+            task.in_flight.store(0, AtomicOrdering::SeqCst);
+        }
+        self.task_settled.notify_waiters();
+    }
+
+    /// Tasks assigned to `agents` that haven't settled yet
+    async fn in_flight(&self, agents: &[AgentId]) -> usize {
+        let running = self.running.read().await;
+        agents
+            .iter()
+            .filter_map(|id| running.get(id))
+            .map(|t| t.in_flight.load(AtomicOrdering::SeqCst))
+            .sum()
+    }
+
+    /// Wait until `agents` have no unsettled tasks or `deadline` passes;
+    /// returns how many were still outstanding
+    async fn wait_idle(&self, agents: &[AgentId], deadline: Instant) -> usize {
+        loop {
+            // Register before checking so a settle in between isn't missed
+            let settled = self.task_settled.notified();
+            let outstanding = self.in_flight(agents).await;
+            if outstanding == 0 {
+                return 0;
+            }
+            if tokio::time::timeout_at(deadline, settled).await.is_err() {
+                return self.in_flight(agents).await;
+            }
+        }
+    }
+
+    /// An agent's whole life: serve one session at a time, each handed over
+    /// as an `AgentBinding`, waiting in the warm pool in between
+    async fn agent_loop(
+        agent: AgentHandle,
+        model_clients: Arc<ModelClients>,
+        batcher: Option<TaskBatcher>,
+        mut bindings: mpsc::Receiver<AgentBinding>,
+        reports: mpsc::UnboundedSender<AgentReport>,
+        bus: Arc<MessageBus>,
+        heartbeat: Arc<Heartbeat>,
+    ) {
+        while let Some(binding) = bindings.recv().await {
+            let AgentBinding { session_id, shared_state, control, prompts, inbox, span } = binding;
+            let inbox = AgentInbox { rx: inbox, batcher: batcher.clone(), model: agent.model, control };
+            let served = std::panic::AssertUnwindSafe(Self::serve_session(
+                agent.clone(),
+                session_id,
+                model_clients.clone(),
+                shared_state,
+                prompts,
+                inbox,
+                reports.clone(),
+                bus.clone(),
+                heartbeat.clone(),
+            ).instrument(span.clone()));
+
+            // A panic ends the agent, but the orchestrator hears about it
+            // rather than waiting on a task that will never report
+            if let Err(panic) = futures::FutureExt::catch_unwind(served).await {
+                let message = panic_message(panic.as_ref());
+                span.in_scope(|| error!(%message, "agent panicked"));
+                let _ = reports.send(AgentReport::Panicked { session_id, agent_id: agent.id, message });
+                return;
+            }
+        }
+    }
+
+    /// Run a session's tasks until its inbox is closed.
+    ///
+    /// With instant model calls (mocks, cache hits) and a full inbox, no
+    /// await in the loop has to suspend, so the loop yields to the runtime
+    /// itself: once per batch received and once per task reported. Keep
+    /// that invariant when adding work here, or one agent can hold a worker
+    /// thread while the others starve.
+    #[allow(clippy::too_many_arguments)]
+    async fn serve_session(
+        mut agent: AgentHandle,
+        session_id: SessionId,
+        model_clients: Arc<ModelClients>,
+        shared_state: Arc<SharedState>,
+        prompts: SessionPrompts,
+        mut inbox: AgentInbox,
+        reports: mpsc::UnboundedSender<AgentReport>,
+        bus: Arc<MessageBus>,
+        heartbeat: Arc<Heartbeat>,
+    ) {
+        let agent_id = agent.id;
+        let publish_status = |status| {
+            let bus = bus.clone();
+            async move {
+                // Best effort: a missed update is corrected by the next one
+                let _ = bus.publish_status(AgentStatusUpdate {
+                    session_id,
+                    agent_id,
+                    status,
+                }).await;
+            }
+        };
+
+        // Exits once the inbox sender is dropped by `terminate_agent`
+        while let Some(mut batch) = heartbeat.during(inbox.next_batch()).await {
+            tokio::task::yield_now().await;
+            heartbeat.beat();
+            // Tasks cancelled while waiting in the inbox never start
+            batch.retain(|task| {
+                if !task.cancellation.is_cancelled() {
+                    return true;
+                }
+                Self::report_cancelled(&reports, session_id, agent.id, task.clone());
+                false
+            });
+            if batch.is_empty() {
+                continue;
+            }
+            for task in &batch {
+                // Send failures mean the orchestrator is gone; keep draining the inbox
+                let _ = reports.send(AgentReport::Started {
+                    session_id,
+                    agent_id: agent.id,
+                    verification: task.verifies.is_some(),
+                });
+            }
+            publish_status(AgentStatus::Working).await;
+            let started = Instant::now();
+
+            // Keeps beating through long model calls
+            let results = heartbeat.during(async {
+                if batch.len() > 1 {
+                    TaskBatcher::execute(&model_clients, session_id, agent.id, agent.model, batch).await
+                } else {
+                    let task = batch.remove(0);
+                    // A shared batch call can't be aborted for one task, but
+                    // a single task's call can
+                    let response = tokio::select! {
+                        response = Self::execute(session_id, &mut agent, &model_clients, &shared_state, &prompts, &task) => response,
+                        () = task.cancellation.cancelled() => Err(SwarmError::TaskCancelled),
+                    };
+                    vec![(task, response)]
+                }
+            }).await;
+
+            let latency = started.elapsed();
+            // A cancelled call says nothing about the model
+            let cancelled = results.iter().any(|(task, _)| task.cancellation.is_cancelled());
+            if let Some(controller) = inbox.batcher.as_ref().and_then(|b| b.controller.as_ref()) {
+                if !cancelled {
+                    controller.observe(agent.model, latency, results.iter().any(|(_, r)| r.is_ok()));
+                }
+            }
+            // Tasks in a batch share the call's wall time
+            let duration_sec = latency.as_secs_f64() / results.len() as f64;
+            for (task, response) in results {
+                if task.cancellation.is_cancelled() {
+                    Self::report_cancelled(&reports, session_id, agent.id, task);
+                    continue;
+                }
+                // Also left on the task, whose check is charged to it
+                let verifies = task.verifies.clone();
+                let passed = match response {
+                    Ok(CachedResponse { response, hit, throttled }) => {
+                        let passed = verification_passed(&response.text);
+                        let _ = shared_state
+                            .set_from(agent.id, &format!("task:{}:output", task.id), response.text.clone())
+                            .await;
+                        // A failed attempt's partial text is kept for its retry
+                        let _ = shared_state.remove(&format!("task:{}:partial", task.id)).await;
+                        let _ = reports.send(AgentReport::Completed {
+                            session_id,
+                            agent_id: agent.id,
+                            task,
+                            output: response.text,
+                            model: response.model,
+                            usage: response.usage,
+                            cache_hit: hit,
+                            throttled_sec: throttled.as_secs_f64(),
+                            duration_sec,
+                        });
+                        passed
+                    }
+                    Err(e) => {
+                        let _ = reports.send(AgentReport::Failed {
+                            session_id,
+                            agent_id: agent.id,
+                            task_id: task.id,
+                            retriable: model_clients.is_retriable(&e),
+                        });
+                        // An unverifiable result isn't accepted
+                        false
+                    }
+                };
+                if let Some(original) = verifies {
+                    let _ = reports.send(AgentReport::Verified {
+                        session_id,
+                        task: *original,
+                        passed,
+                    });
+                }
+                tokio::task::yield_now().await;
+            }
+            agent.clear_scratch();
+            publish_status(AgentStatus::Idle).await;
+        }
+    }
+
+    fn report_cancelled(
+        reports: &mpsc::UnboundedSender<AgentReport>,
+        session_id: SessionId,
+        agent_id: AgentId,
+        task: Task,
+    ) {
+        let _ = reports.send(AgentReport::Cancelled { session_id, agent_id, task_id: task.id });
+        // An unchecked result isn't accepted, so the original goes back for
+        // another attempt rather than waiting on a verdict that won't come
+        if let Some(original) = task.verifies {
+            let _ = reports.send(AgentReport::Verified { session_id, task: *original, passed: false });
+        }
+    }
+
+    /// Run a single task according to the agent's role, prompting the model
+    /// with the role's template for the session. Role logic may keep notes
+    /// in the agent's scratch space until the task finishes.
+    #[instrument(
+        name = "task",
+        skip_all,
+        fields(task_id = %task.id, agent_id = %agent.id, model = ?agent.model, cost = tracing::field::Empty),
+    )]
+    async fn execute(
+        session_id: SessionId,
+        agent: &mut AgentHandle,
+        model_clients: &ModelClients,
+        shared_state: &SharedState,
+        prompts: &SessionPrompts,
+        task: &Task,
+    ) -> Result<CachedResponse, SwarmError> {
+        let model = task.quality
+            .map_or(agent.model, |tier| model_clients.select_model(tier, agent.role));
+        // Asked at spawn, unless the task's quality tier picked another model
+        let capabilities = if model == agent.model { agent.capabilities } else { model_clients.capabilities(model) };
+        let prompt = prompts.render(agent.role, task);
+
+        // Execute task based on role
+        let result = match agent.role {
+            AgentRole::Planner => {
+                // Planning logic
+                model_clients.complete_for(session_id, agent.id, model, &prompt).await
+            }
+            AgentRole::Coder if capabilities.supports_streaming => {
+                // Coding logic: long outputs, so streamed
+                Self::stream_code(session_id, agent.id, model, model_clients, shared_state, task, &prompt).await
+            }
+            AgentRole::Coder => {
+                model_clients.complete_for(session_id, agent.id, model, &prompt).await
+            }
+            AgentRole::Tester => {
+                // Testing logic
+                model_clients.complete_for(session_id, agent.id, model, &prompt).await
+            }
+            AgentRole::Browser => {
+                // Browser automation logic
+                Ok(CachedResponse {
+                    response: ModelResponse::default(),
+                    hit: false,
+                    throttled: Duration::ZERO,
+                })
+            }
+            AgentRole::Verifier => {
+                // Verification logic
+                model_clients.complete_for(session_id, agent.id, model, &prompt).await
+            }
+        };
+
+        match &result {
+            Ok(CachedResponse { response, hit, .. }) => {
+                // The fallback chain may have served another model
+                let span = Span::current();
+                span.record("model", tracing::field::debug(response.model));
+                span.record("cost", if *hit { 0.0 } else { ModelClients::cost_of(response.model, &response.usage) });
+            }
+            Err(e) => warn!(error = %e, "model call failed"),
+        }
+        let response = result?;
+        let cost = if response.hit { 0.0 } else { ModelClients::cost_of(response.response.model, &response.response.usage) };
+        // Past its ceiling the task is aborted on this report, whatever the
+        // output; a stream cut short there wouldn't pass anyway
+        if task.over_cost_ceiling_after(cost) {
+            return Ok(response);
+        }
+        if let Err(e) = task.check_result(&response.response.text) {
+            warn!(error = %e, "result rejected");
+            // Or the retry would be served the same answer
+            model_clients.forget_cached(model, &prompt).await;
+            return Err(e);
+        }
+        Ok(response)
+    }
+
+    /// Stream a coder's answer, publishing the text so far under
+    /// `task:{id}:partial` as each chunk arrives. A stream cut off by the
+    /// model's timeout leaves what it delivered there (and in the error) for
+    /// the retry or the caller to pick up. One whose running cost takes the
+    /// task past `max_cost_usd` is abandoned, and what it got so far is
+    /// returned to be charged, and the task aborted, like any answer.
+    async fn stream_code(
+        session_id: SessionId,
+        agent_id: AgentId,
+        model: ModelPreference,
+        model_clients: &ModelClients,
+        shared_state: &SharedState,
+        task: &Task,
+        prompt: &str,
+    ) -> Result<CachedResponse, SwarmError> {
+        let mut stream = model_clients.open_stream_for(session_id, agent_id, model, prompt).await?;
+        let key = format!("task:{}:partial", task.id);
+        while let Some(chunk) = stream.next_chunk().await {
+            chunk?;
+            let _ = shared_state.set_from(agent_id, &key, stream.text().to_string()).await;
+            if task.over_cost_ceiling_after(stream.cost()) {
+                warn!(cost = task.cost_incurred + stream.cost(), "stream cut off at the task's cost ceiling");
+                let response = stream.abandon().await;
+                model_clients.audit_call(session_id, agent_id, prompt, &response).await;
+                return Ok(response);
+            }
+            // A buffered stream never suspends; see `serve_session`
+            tokio::task::yield_now().await;
+        }
+        let response = stream.finish().await;
+        model_clients.audit_call(session_id, agent_id, prompt, &response).await;
+        Ok(response)
+    }
+
+    /// Refresh every agent's `last_heartbeat` and mark those silent for
+    /// longer than `max_silence` as `Failed`. Returns the silent agents.
+    ///
+    /// Agents beat while idle and during model calls alike, so silence means
+    /// the agent's task died or is stuck off the runtime, not a long task.
+    pub async fn sweep_unhealthy(&self, max_silence: Duration) -> Vec<AgentId> {
+        let cutoff = Utc::now() - chrono::Duration::from_std(max_silence).unwrap_or(chrono::Duration::MAX);
+        let running = self.running.read().await;
+        let mut agents = self.agents.write().await;
+
+        let mut silent = vec![];
+        for (agent_id, task) in running.iter() {
+            let Some(agent) = agents.get_mut(agent_id) else { continue };
+            agent.last_heartbeat = task.heartbeat.last();
+            // A finished task (e.g. a panic) stops beating too
+            if agent.last_heartbeat >= cutoff && !task.join.is_finished() {
+                continue;
+            }
+            if agent.status != AgentStatus::Failed {
+                self.metrics.agent_status_changed(agent.status, AgentStatus::Failed);
+                agent.transition_or_log(AgentStatus::Failed);
+            }
+            silent.push(*agent_id);
+        }
+        silent
+    }
+
+    pub async fn terminate_agent(
+        &self,
+        agent_id: AgentId,
+    ) -> Result<(), SwarmError> {
+        if let Some(agent) = self.agents.write().await.remove(&agent_id) {
+            self.metrics.agent_terminated(agent.status);
+        }
+
+        // Abort rather than wait for the inbox to drain; an in-flight model
+        // call would otherwise keep the task alive
+        if let Some(task) = self.running.write().await.remove(&agent_id) {
+            task.join.abort();
+            info!(%agent_id, "agent terminated");
+        }
+        Ok(())
+    }
+
+    /// Detach an agent whose session is over and park it in the warm pool
+    /// (see `with_warm_pool`), or terminate it when the pool already holds
+    /// enough of its role. Busy and failed agents are always terminated.
+    pub async fn release_agent(&self, agent_id: AgentId) -> Result<(), SwarmError> {
+        {
+            let mut running = self.running.write().await;
+            let mut agents = self.agents.write().await;
+            let mut warm = self.warm();
+            let parkable = match (agents.get(&agent_id), running.get(&agent_id)) {
+                (Some(agent), Some(task)) => agent.status == AgentStatus::Idle
+                    && task.in_flight.load(AtomicOrdering::SeqCst) == 0
+                    && !task.join.is_finished()
+                    && warm.iter().filter(|w| w.role == agent.role).count() < self.warm_per_role,
+                _ => false,
+            };
+            if parkable {
+                if let (Some(agent), Some(task)) = (agents.remove(&agent_id), running.remove(&agent_id)) {
+                    self.metrics.agent_terminated(agent.status);
+                    // Dropping the inbox ends the session; the task waits for its next binding
+                    let AgentTask { bindings, heartbeat, join, .. } = task;
+                    warm.push(WarmAgent { id: agent_id, role: agent.role, model: agent.model, bindings, heartbeat, join });
+                    info!(%agent_id, "agent parked in the warm pool");
+                    return Ok(());
+                }
+            }
+        }
+        self.terminate_agent(agent_id).await
+    }
+}
+
+/// Background task backing a spawned agent
+struct AgentTask {
+    session_id: SessionId,
+    /// Dropping it ends the agent's session, not the agent
+    inbox: mpsc::Sender<Task>,
+    /// Assigned tasks not yet completed or failed, including queued ones
+    in_flight: Arc<AtomicUsize>,
+    heartbeat: Arc<Heartbeat>,
+    bindings: mpsc::Sender<AgentBinding>,
+    join: tokio::task::JoinHandle<()>,
+}
+
+/// The session an agent's task serves, until `inbox` closes
+struct AgentBinding {
+    session_id: SessionId,
+    shared_state: Arc<SharedState>,
+    control: Arc<SessionControl>,
+    prompts: SessionPrompts,
+    inbox: mpsc::Receiver<Task>,
+    span: Span,
+}
+
+/// An idle agent between sessions, waiting for its next `AgentBinding`
+struct WarmAgent {
+    id: AgentId,
+    role: AgentRole,
+    model: ModelPreference,
+    bindings: mpsc::Sender<AgentBinding>,
+    heartbeat: Arc<Heartbeat>,
+    join: tokio::task::JoinHandle<()>,
+}
+
+/// Liveness signal written by an agent's task, read by `sweep_unhealthy`
+struct Heartbeat {
+    /// Unix time in milliseconds
+    last: AtomicI64,
+    interval: Duration,
+}
+
+impl Heartbeat {
+    fn new(interval: Duration) -> Self {
+        Self {
+            last: AtomicI64::new(Utc::now().timestamp_millis()),
+            interval,
+        }
+    }
+
+    fn beat(&self) {
+        self.last.store(Utc::now().timestamp_millis(), AtomicOrdering::Relaxed);
+    }
+
+    fn last(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp_millis(self.last.load(AtomicOrdering::Relaxed)).unwrap_or_default()
+    }
+
+    /// Drive `work` to completion, beating every `interval` meanwhile
+    async fn during<F: std::future::Future>(&self, work: F) -> F::Output {
+        let mut ticks = tokio::time::interval(self.interval);
+        tokio::pin!(work);
+        loop {
+            tokio::select! {
+                output = &mut work => return output,
+                _ = ticks.tick() => self.beat(),
+            }
+        }
+    }
+}
+
+/// An agent's task feed, grouped into batches when batching is enabled
+struct AgentInbox {
+    rx: mpsc::Receiver<Task>,
+    batcher: Option<TaskBatcher>,
+    /// The agent's model, which batched calls go to
+    model: ModelPreference,
+    control: Arc<SessionControl>,
+}
+
+impl AgentInbox {
+    /// Next tasks to run, held back while the session is paused
+    async fn next_batch(&mut self) -> Option<Vec<Task>> {
+        let first = self.rx.recv().await?;
+        let batch = match &self.batcher {
+            Some(batcher) => batcher.collect(first, &mut self.rx, self.model).await,
+            None => vec![first],
+        };
+        // Checked after receiving, so a task that arrives mid-pause waits too
+        self.control.wait_until_running().await;
+        Some(batch)
+    }
+}
+
+/// Run/pause switch shared by a session's agents, and the signal that it
+/// has ended
+#[derive(Debug, Default)]
+pub struct SessionControl {
+    paused: AtomicBool,
+    resumed: Notify,
+    ended: CancellationToken,
+}
+
+impl SessionControl {
+    pub fn pause(&self) {
+        self.paused.store(true, AtomicOrdering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, AtomicOrdering::SeqCst);
+        self.resumed.notify_waiters();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(AtomicOrdering::SeqCst)
+    }
+
+    /// Cancelled once the session completes, fails or is destroyed, for work
+    /// on its behalf (such as a `spawn_task_source` feed) to stop with it
+    pub fn ended(&self) -> CancellationToken {
+        self.ended.clone()
+    }
+
+    fn end(&self) {
+        self.ended.cancel();
+    }
+
+    async fn wait_until_running(&self) {
+        loop {
+            // Register before checking so a resume in between isn't missed
+            let resumed = self.resumed.notified();
+            if !self.is_paused() {
+                return;
+            }
+            resumed.await;
+        }
+    }
+}
+
+/// A task counted against its agent's load, not yet in its inbox
+struct ClaimedTask {
+    task: Task,
+    inbox: mpsc::Sender<Task>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+/// Messages from agents back to the orchestrator
+#[derive(Debug, Clone)]
+enum AgentReport {
+    Started {
+        session_id: SessionId,
+        agent_id: AgentId,
+        /// The task is checking another's result
+        verification: bool,
+    },
+    Completed {
+        session_id: SessionId,
+        agent_id: AgentId,
+        task: Task,
+        output: String,
+        model: ModelPreference,
+        usage: TokenUsage,
+        /// Served from the prompt cache, so not billed
+        cache_hit: bool,
+        /// Time spent waiting on the model rate limiter
+        throttled_sec: f64,
+        duration_sec: f64,
+    },
+    Failed {
+        session_id: SessionId,
+        agent_id: AgentId,
+        task_id: TaskId,
+        /// By `ModelClients::is_retriable`; a terminal failure is
+        /// dead-lettered without using up the task's retries
+        retriable: bool,
+    },
+    /// The agent dropped a task cancelled by `SessionManager::cancel_task`
+    Cancelled {
+        session_id: SessionId,
+        agent_id: AgentId,
+        task_id: TaskId,
+    },
+    /// A verifier's verdict on a coder task, sent after its own report
+    Verified {
+        session_id: SessionId,
+        task: Task,
+        passed: bool,
+    },
+    /// The agent's task panicked while serving the session and has ended
+    Panicked {
+        session_id: SessionId,
+        agent_id: AgentId,
+        message: String,
+    },
+    /// Sent by the pool itself (see `AgentPool::flush_reports`): notified
+    /// once every report sent before it has been processed
+    Flushed(Arc<Notify>),
+}
+
+/// The text a panic was raised with, if it was given one
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+        (Some(message), _) => message.to_string(),
+        (_, Some(message)) => message.clone(),
+        _ => "panic without a message".to_string(),
+    }
+}
+
+/// A verifier passes a task only if the last line of its answer starts with
+/// PASS; an empty answer, or one without a verdict, fails it
+fn verification_passed(text: &str) -> bool {
+    let Some(verdict) = text.lines().rev().find(|l| !l.trim().is_empty()) else { return false };
+    verdict.trim_start().to_ascii_uppercase().starts_with("PASS")
+}
+
+// ============================================================================
+// PROMPT TEMPLATES
+// ============================================================================
+
+/// Prompt text for one role, or for one role on one kind of project
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PromptTemplate {
+    pub role: AgentRole,
+    /// Project template this text is for; `None` covers every template the
+    /// role has no specific text for
+    #[serde(default)]
+    pub template: Option<TemplateType>,
+    pub text: String,
+}
+
+/// How agents word their model calls, by role and project template.
+/// Placeholders in `{braces}` are filled from the task at hand:
+/// `{description}`, `{task_id}`, `{estimated_time_min}`, `{priority}`,
+/// `{skills}`, `{role}` and `{template}`. Anything else in braces is left
+/// as written.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PromptTemplates {
+    templates: Vec<PromptTemplate>,
+}
+
+impl Default for PromptTemplates {
+    /// A short brief per role ahead of the task itself, with
+    /// domain-specific briefs for hospital integrations. The task comes
+    /// last, so a verifier's verdict is still the answer's final line.
+    fn default() -> Self {
+        const HOSPITAL: &str = "This is a hospital integration: messages are HL7 v2 or FHIR, and \
+                                patient data must never be logged, echoed or sent anywhere but \
+                                its destination.";
+        let hospital = |role, brief: &str| PromptTemplate {
+            role,
+            template: Some(TemplateType::HospitalIntegration),
+            text: format!("{brief} {HOSPITAL}\n\n{{description}}"),
+        };
+        let role_wide = |role, brief: &str| PromptTemplate {
+            role,
+            template: None,
+            text: format!("{brief}\n\n{{description}}"),
+        };
+        Self {
+            templates: vec![
+                role_wide(AgentRole::Planner, "You plan the work of a team of agents."),
+                role_wide(AgentRole::Coder, "You write the code a task asks for, complete and ready to run."),
+                role_wide(AgentRole::Tester, "You write and run tests for the task below, reporting what fails."),
+                role_wide(AgentRole::Browser, "You carry out the task below in a web browser."),
+                role_wide(AgentRole::Verifier, "You check another agent's work, strictly."),
+                hospital(AgentRole::Coder, "You write integration code between clinical systems."),
+                hospital(AgentRole::Tester, "You test integrations between clinical systems, including malformed messages."),
+                hospital(AgentRole::Verifier, "You check integration code between clinical systems, strictly."),
+            ],
+        }
+    }
+}
+
+impl PromptTemplates {
+    /// No templates: every prompt is the task description alone
+    pub fn empty() -> Self {
+        Self { templates: vec![] }
+    }
+
+    /// The defaults, overridden by the JSON list of `PromptTemplate`s in
+    /// `json`, e.g. read from a config file
+    pub fn from_json(json: &str) -> Result<Self, SwarmError> {
+        let overrides: Vec<PromptTemplate> = serde_json::from_str(json)
+            .map_err(|e| SwarmError::InvalidSpec(format!("prompt templates: {e}")))?;
+        let mut templates = Self::default();
+        for PromptTemplate { role, template, text } in overrides {
+            templates.set(role, template, text);
+        }
+        Ok(templates)
+    }
+
+    /// Use `text` for `role`, on `template` projects or (with `None`) on any
+    pub fn set(&mut self, role: AgentRole, template: Option<TemplateType>, text: impl Into<String>) {
+        let text = text.into();
+        match self.templates.iter_mut().find(|t| t.role == role && t.template == template) {
+            Some(existing) => existing.text = text,
+            None => self.templates.push(PromptTemplate { role, template, text }),
+        }
+    }
+
+    /// Text for `role` on a `template` project: its own, else the role's
+    /// template-wide text, else just the description
+    pub fn get(&self, role: AgentRole, template: Option<TemplateType>) -> &str {
+        let find = |template| self.templates.iter().find(|t| t.role == role && t.template == template);
+        template.and_then(|_| find(template))
+            .or_else(|| find(None))
+            .map_or("{description}", |t| t.text.as_str())
+    }
+
+    /// The prompt `role` sends for `task` on a `template` project
+    pub fn render(&self, role: AgentRole, template: Option<TemplateType>, task: &Task) -> String {
+        let text = self.get(role, template);
+        let mut prompt = String::with_capacity(text.len() + task.description.len());
+        let mut rest = text;
+        while let Some(open) = rest.find('{') {
+            prompt.push_str(&rest[..open]);
+            let after = &rest[open + 1..];
+            let Some(close) = after.find('}') else {
+                rest = &rest[open..];
+                break;
+            };
+            let value = match &after[..close] {
+                "description" => task.description.clone(),
+                "task_id" => task.id.to_string(),
+                "estimated_time_min" => task.estimated_time_min.to_string(),
+                "priority" => format!("{:?}", task.priority),
+                "skills" => task.required_skills.join(", "),
+                "role" => format!("{role:?}"),
+                "template" => template.map_or_else(String::new, |t| format!("{t:?}")),
+                _ => rest[open..open + close + 2].to_string(),
+            };
+            prompt.push_str(&value);
+            rest = &after[close + 1..];
+        }
+        prompt.push_str(rest);
+        prompt
+    }
+}
+
+/// What a session's agents render their prompts with
+#[derive(Clone)]
+struct SessionPrompts {
+    templates: Arc<PromptTemplates>,
+    template: Option<TemplateType>,
+}
+
+impl SessionPrompts {
+    fn render(&self, role: AgentRole, task: &Task) -> String {
+        self.templates.render(role, self.template, task)
+    }
+}
+
+// ============================================================================
+// TASK BATCHING
+// ============================================================================
+
+/// Section marker tying each part of a batched prompt and its answer to a task
+const BATCH_TASK_MARKER: &str = "<<<task:";
+
+/// Weight of the newest call in a model's smoothed batch error rate
+const BATCH_ERROR_WEIGHT: f64 = 0.2;
+
+/// Default `BatchController` error rate above which batches shrink
+pub const DEFAULT_MAX_BATCH_ERROR_RATE: f64 = 0.25;
+
+/// Combines many small tasks into one model call.
+///
+/// An agent's inbox only holds tasks for its own role and model, so a batch
+/// is whatever arrives within `max_wait` of the first task, up to `max_batch`
+/// (or, when adaptive, the controller's current size for the agent's model).
+#[derive(Clone)]
+pub struct TaskBatcher {
+    max_batch: usize,
+    max_wait: Duration,
+    controller: Option<Arc<BatchController>>,
+}
+
+impl TaskBatcher {
+    pub fn new(max_batch: usize, max_wait: Duration) -> Self {
+        Self { max_batch: max_batch.max(1), max_wait, controller: None }
+    }
+
+    /// Batch up to `controller`'s size for each model, which it adjusts as
+    /// the agents' calls report back
+    pub fn adaptive(controller: Arc<BatchController>, max_wait: Duration) -> Self {
+        Self { max_batch: controller.max_batch, max_wait, controller: Some(controller) }
+    }
+
+    fn limit(&self, model: ModelPreference) -> usize {
+        self.controller.as_ref().map_or(self.max_batch, |c| c.batch_size(model))
+    }
+
+    async fn collect(&self, first: Task, inbox: &mut mpsc::Receiver<Task>, model: ModelPreference) -> Vec<Task> {
+        let limit = self.limit(model);
+        let deadline = Instant::now() + self.max_wait;
+        let mut batch = vec![first];
+        while batch.len() < limit {
+            match tokio::time::timeout_at(deadline, inbox.recv()).await {
+                Ok(Some(task)) => batch.push(task),
+                // Closed inbox or flush deadline
+                Ok(None) | Err(_) => break,
+            }
+        }
+        batch
+    }
+
+    fn combine(batch: &[Task]) -> String {
+        let mut prompt = String::from(
+            "Complete each task below. Answer every task in its own section, \
+             starting with the task's marker line exactly as given.\n\n",
+        );
+        for task in batch {
+            prompt.push_str(&format!("{}{}>>>\n{}\n\n", BATCH_TASK_MARKER, task.id, task.description));
+        }
+        prompt
+    }
+
+    fn split(text: &str) -> HashMap<TaskId, &str> {
+        text.split(BATCH_TASK_MARKER)
+            .skip(1)
+            .filter_map(|section| {
+                let (id, body) = section.split_once(">>>")?;
+                Some((id.parse().ok()?, body.trim()))
+            })
+            .collect()
+    }
+
+    /// Run `batch` as one completion and split the answer back per task,
+    /// sharing the call's usage evenly. A task whose section is missing,
+    /// malformed or against its `result_schema` fails alone; a failed call
+    /// fails the whole batch.
+    #[instrument(name = "batch", skip_all, fields(tasks = batch.len(), ?model))]
+    async fn execute(
+        model_clients: &ModelClients,
+        session_id: SessionId,
+        agent_id: AgentId,
+        model: ModelPreference,
+        batch: Vec<Task>,
+    ) -> Vec<(Task, Result<CachedResponse, SwarmError>)> {
+        let prompt = Self::combine(&batch);
+        let CachedResponse { response, hit, throttled } =
+            match model_clients.complete_for(session_id, agent_id, model, &prompt).await {
+                Ok(response) => response,
+                Err(_) => {
+                    return batch
+                        .into_iter()
+                        .map(|task| (task, Err(SwarmError::TaskExecutionFailed)))
+                        .collect();
+                }
+            };
+
+        let sections = Self::split(&response.text);
+        let n = batch.len() as u64;
+        let share = |total: u64, i: u64| total / n + u64::from(i < total % n);
+
+        batch
+            .into_iter()
+            .zip(0..)
+            .map(|(task, i)| {
+                let result = sections
+                    .get(&task.id)
+                    .map(|text| CachedResponse {
+                        response: ModelResponse {
+                            text: text.to_string(),
+                            usage: TokenUsage {
+                                input_tokens: share(response.usage.input_tokens, i),
+                                output_tokens: share(response.usage.output_tokens, i),
+                            },
+                            model: response.model,
+                        },
+                        hit,
+                        throttled: throttled / n as u32,
+                    })
+                    .ok_or(SwarmError::TaskExecutionFailed)
+                    .and_then(|cached| task.check_result(&cached.response.text).map(|()| cached));
+                (task, result)
+            })
+            .collect()
+    }
+}
+
+/// Sizes batches per model from how their calls go, additive-increase /
+/// multiplicative-decrease: one task more after a call that finished within
+/// `target_latency`, half as many after one that ran over it or while the
+/// model's smoothed error rate is above the limit. Sizes start at
+/// `min_batch` and stay within `min_batch..=max_batch`.
+pub struct BatchController {
+    min_batch: usize,
+    max_batch: usize,
+    target_latency: Duration,
+    max_error_rate: f64,
+    models: std::sync::Mutex<HashMap<ModelPreference, BatchState>>,
+    /// Set by the `AgentPool` batching with this controller
+    metrics: std::sync::RwLock<MetricsRegistry>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BatchState {
+    size: usize,
+    error_rate: f64,
+}
+
+impl BatchController {
+    pub fn new(min_batch: usize, max_batch: usize, target_latency: Duration) -> Self {
+        let min_batch = min_batch.max(1);
+        Self {
+            min_batch,
+            max_batch: max_batch.max(min_batch),
+            target_latency,
+            max_error_rate: DEFAULT_MAX_BATCH_ERROR_RATE,
+            models: std::sync::Mutex::new(HashMap::new()),
+            metrics: std::sync::RwLock::new(MetricsRegistry::new()),
+        }
+    }
+
+    /// Shrink batches while more than `rate` of a model's recent calls fail
+    pub fn with_max_error_rate(mut self, rate: f64) -> Self {
+        self.max_error_rate = rate;
+        self
+    }
+
+    /// Tasks `model`'s next batch may hold
+    pub fn batch_size(&self, model: ModelPreference) -> usize {
+        self.models.lock().expect("batch sizes lock poisoned").get(&model).map_or(self.min_batch, |s| s.size)
+    }
+
+    /// Adjust `model`'s batch size after a call that took `latency` and
+    /// succeeded for at least one of its tasks, or not
+    pub fn observe(&self, model: ModelPreference, latency: Duration, succeeded: bool) -> usize {
+        let size = {
+            let mut models = self.models.lock().expect("batch sizes lock poisoned");
+            let state = models.entry(model).or_insert(BatchState { size: self.min_batch, error_rate: 0.0 });
+            let failure = if succeeded { 0.0 } else { 1.0 };
+            state.error_rate += BATCH_ERROR_WEIGHT * (failure - state.error_rate);
+            state.size = if latency > self.target_latency || state.error_rate > self.max_error_rate {
+                (state.size / 2).max(self.min_batch)
+            } else {
+                (state.size + 1).min(self.max_batch)
+            };
+            state.size
+        };
+        self.metrics.read().expect("metrics lock poisoned").batch_size_changed(model, size);
+        size
+    }
+
+    fn report_to(&self, metrics: MetricsRegistry) {
+        for (&model, state) in self.models.lock().expect("batch sizes lock poisoned").iter() {
+            metrics.batch_size_changed(model, state.size);
+        }
+        *self.metrics.write().expect("metrics lock poisoned") = metrics;
+    }
+}
+
+// ============================================================================
+// MESSAGE BUS (NATS)
+// ============================================================================
+
+/// Subjects agents publish their status on: `swarm.{session_id}.agent.{agent_id}.status`
+const AGENT_STATUS_SUBJECTS: &str = "swarm.*.agent.*.status";
+
+/// Buffered messages per in-process subscriber
+const LOCAL_BUS_CAPACITY: usize = 4096;
+
+/// Pub/sub for agent coordination.
+///
+/// Backed by NATS when built with the `nats` feature and given a URL; otherwise
+/// an in-process broadcast channel with the same subject semantics, so a single
+/// node (and the tests) run without a NATS server.
+pub struct MessageBus {
+    backend: BusBackend,
+}
+
+enum BusBackend {
+    #[cfg(feature = "nats")]
+    Nats(async_nats::Client),
+    Local(broadcast::Sender<BusMessage>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BusMessage {
+    pub subject: String,
+    pub payload: Vec<u8>,
+}
+
+impl MessageBus {
+    pub fn local() -> Self {
+        Self { backend: BusBackend::Local(broadcast::channel(LOCAL_BUS_CAPACITY).0) }
+    }
+
+    /// Connect to NATS at `url`, or fall back to the in-process bus when no
+    /// URL is configured
+    pub async fn connect(url: Option<&str>) -> Result<Self, SwarmError> {
+        match url {
+            #[cfg(feature = "nats")]
+            Some(url) => {
+                let client = async_nats::connect(url)
+                    .await
+                    .map_err(|e| SwarmError::MessageBus(e.into()))?;
+                Ok(Self { backend: BusBackend::Nats(client) })
+            }
+            #[cfg(not(feature = "nats"))]
+            Some(_) => Err(SwarmError::MessageBus("built without the `nats` feature".into())),
+            None => Ok(Self::local()),
+        }
+    }
+
+    pub async fn publish(&self, subject: &str, payload: Vec<u8>) -> Result<(), SwarmError> {
+        match &self.backend {
+            #[cfg(feature = "nats")]
+            BusBackend::Nats(client) => client
+                .publish(subject.to_string(), payload.into())
+                .await
+                .map_err(|e| SwarmError::MessageBus(e.into())),
+            BusBackend::Local(tx) => {
+                // No subscribers is not an error on a pub/sub bus
+                let _ = tx.send(BusMessage { subject: subject.to_string(), payload });
+                Ok(())
+            }
+        }
+    }
+
+    /// Subscribe with NATS wildcards: `*` matches one token, a trailing `>`
+    /// matches the rest
+    pub async fn subscribe(&self, subject: &str) -> Result<Subscriber, SwarmError> {
+        let inner = match &self.backend {
+            #[cfg(feature = "nats")]
+            BusBackend::Nats(client) => SubscriberInner::Nats(
+                client
+                    .subscribe(subject.to_string())
+                    .await
+                    .map_err(|e| SwarmError::MessageBus(e.into()))?,
+            ),
+            BusBackend::Local(tx) => SubscriberInner::Local {
+                pattern: subject.to_string(),
+                rx: tx.subscribe(),
+            },
+        };
+        Ok(Subscriber { inner })
+    }
+
+    async fn publish_status(&self, update: AgentStatusUpdate) -> Result<(), SwarmError> {
+        let subject = format!("swarm.{}.agent.{}.status", update.session_id, update.agent_id);
+        let payload = serde_json::to_vec(&update)
+            .map_err(|e| SwarmError::MessageBus(e.into()))?;
+        self.publish(&subject, payload).await
+    }
+}
+
+pub struct Subscriber {
+    inner: SubscriberInner,
+}
+
+enum SubscriberInner {
+    #[cfg(feature = "nats")]
+    Nats(async_nats::Subscriber),
+    Local {
+        pattern: String,
+        rx: broadcast::Receiver<BusMessage>,
+    },
+}
+
+impl Subscriber {
+    /// Next matching message, or `None` once the bus is closed
+    pub async fn next(&mut self) -> Option<BusMessage> {
+        match &mut self.inner {
+            #[cfg(feature = "nats")]
+            SubscriberInner::Nats(subscriber) => {
+                use futures::StreamExt;
+                subscriber.next().await.map(|m| BusMessage {
+                    subject: m.subject.to_string(),
+                    payload: m.payload.to_vec(),
+                })
+            }
+            SubscriberInner::Local { pattern, rx } => loop {
+                match rx.recv().await {
+                    Ok(message) if subject_matches(pattern, &message.subject) => {
+                        return Some(message);
+                    }
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            },
+        }
+    }
+}
+
+fn subject_matches(pattern: &str, subject: &str) -> bool {
+    let mut subject_tokens = subject.split('.');
+    for token in pattern.split('.') {
+        match (token, subject_tokens.next()) {
+            (">", Some(_)) => return true,
+            ("*", Some(_)) => {}
+            (expected, Some(actual)) if expected == actual => {}
+            _ => return false,
+        }
+    }
+    subject_tokens.next().is_none()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AgentStatusUpdate {
+    pub session_id: SessionId,
+    pub agent_id: AgentId,
+    pub status: AgentStatus,
+}
+
+// ============================================================================
+// EVENTS
+// ============================================================================
+
+/// Observable orchestrator activity, as broadcast by `SessionManager::subscribe`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum SwarmEvent {
+    SessionCreated {
+        session_id: SessionId,
+        user_id: UserId,
+    },
+    AgentSpawned {
+        session_id: SessionId,
+        agent_id: AgentId,
+        role: AgentRole,
+        model: ModelPreference,
+    },
+    /// An agent picked up an assigned task; `role` is unset for an agent
+    /// the session no longer lists
+    TaskStarted {
+        session_id: SessionId,
+        agent_id: AgentId,
+        role: Option<AgentRole>,
+        /// Checking another task's result, whatever the agent's role
+        #[serde(default)]
+        verification: bool,
+    },
+    TaskCompleted {
+        session_id: SessionId,
+        task_id: TaskId,
+        agent_id: AgentId,
+        cost: f64,
+        #[serde(default)]
+        cost_saved: f64,
+        #[serde(default)]
+        duration_sec: f64,
+        #[serde(default)]
+        throttled_sec: f64,
+        #[serde(default)]
+        outcome: CompletionOutcome,
+        /// Role of the agent that ran the task, if it is still in the session
+        #[serde(default)]
+        role: Option<AgentRole>,
+        /// Model that served the task
+        #[serde(default)]
+        model: Option<ModelPreference>,
+    },
+    TaskFailed {
+        session_id: SessionId,
+        task_id: TaskId,
+        agent_id: AgentId,
+        will_retry: bool,
+        /// Out of retries; counted in `tasks_failed`
+        #[serde(default)]
+        dead_lettered: bool,
+    },
+    /// An agent abandoned a task cancelled mid-flight
+    TaskCancelled {
+        session_id: SessionId,
+        task_id: TaskId,
+        agent_id: AgentId,
+    },
+    /// A verifier passed or rejected a coder task's result
+    TaskVerified {
+        session_id: SessionId,
+        task_id: TaskId,
+        passed: bool,
+        /// The rejected task was out of retries
+        #[serde(default)]
+        dead_lettered: bool,
+    },
+    SessionCompleted {
+        session_id: SessionId,
+        metrics: SessionMetrics,
+    },
+    /// Spend crossed the session's budget. `metrics` are as of the breach,
+    /// or final if `policy` destroyed the session.
+    BudgetBreached {
+        session_id: SessionId,
+        policy: BudgetPolicy,
+        metrics: SessionMetrics,
+    },
+    /// The session sat idle past `SessionManager::reap_idle_sessions`'s
+    /// timeout and `action` was applied to it
+    SessionIdle {
+        session_id: SessionId,
+        idle_sec: f64,
+        action: IdleAction,
+    },
+    /// The session's `ProjectSpec::deadline` passed before it finished, and
+    /// it failed; `metrics` hold what it got done
+    DeadlineExceeded {
+        session_id: SessionId,
+        metrics: SessionMetrics,
+    },
+    /// A ready task needs `capability`, which none of the session's coders
+    /// has, so it was dead-lettered; counted in `tasks_failed`
+    TaskUnsupported {
+        session_id: SessionId,
+        task_id: TaskId,
+        capability: Capability,
+    },
+    /// Every agent is blocked while tasks are ready; see
+    /// `SessionManager::detect_deadlock`
+    Deadlocked {
+        session_id: SessionId,
+        blocked: Vec<BlockedAgent>,
+    },
+    /// An agent's task panicked; the agent is `Failed` and its tasks went
+    /// back to the queue. `respawned` is its replacement, with
+    /// `SessionManager::with_respawn_on_panic`.
+    AgentPanicked {
+        session_id: SessionId,
+        agent_id: AgentId,
+        message: String,
+        respawned: Option<AgentId>,
+    },
+}
+
+impl SwarmEvent {
+    pub fn session_id(&self) -> SessionId {
+        match *self {
+            SwarmEvent::SessionCreated { session_id, .. }
+            | SwarmEvent::AgentSpawned { session_id, .. }
+            | SwarmEvent::TaskStarted { session_id, .. }
+            | SwarmEvent::TaskCompleted { session_id, .. }
+            | SwarmEvent::TaskFailed { session_id, .. }
+            | SwarmEvent::TaskCancelled { session_id, .. }
+            | SwarmEvent::TaskVerified { session_id, .. }
+            | SwarmEvent::TaskUnsupported { session_id, .. }
+            | SwarmEvent::SessionCompleted { session_id, .. }
+            | SwarmEvent::BudgetBreached { session_id, .. }
+            | SwarmEvent::SessionIdle { session_id, .. }
+            | SwarmEvent::DeadlineExceeded { session_id, .. }
+            | SwarmEvent::Deadlocked { session_id, .. }
+            | SwarmEvent::AgentPanicked { session_id, .. } => session_id,
+        }
+    }
+}
+
+/// How a `TaskCompleted` result counted toward session metrics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CompletionOutcome {
+    /// Counted as a completed task
+    #[default]
+    Accepted,
+    /// Counts once a verifier passes it
+    AwaitingVerification,
+    /// A verifier's own run, or a late result for a reclaimed task
+    Uncounted,
+}
+
+/// Append-only record of a session's events, in emission order
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EventLog {
+    events: Vec<SwarmEvent>,
+}
+
+impl EventLog {
+    pub fn append(&mut self, event: SwarmEvent) {
+        self.events.push(event);
+    }
+
+    pub fn events(&self) -> &[SwarmEvent] {
+        &self.events
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+}
+
+// ============================================================================
+// WEBHOOKS
+// ============================================================================
+
+/// Tries per webhook delivery, the first included
+pub const WEBHOOK_ATTEMPTS: usize = 3;
+
+/// Wait before the first webhook retry; doubled before each one after
+pub const WEBHOOK_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Header with `sha256=` and the hex HMAC-SHA256 of the body under
+/// `ProjectSpec::webhook_secret`
+pub const WEBHOOK_SIGNATURE_HEADER: &str = "X-Swarm-Signature";
+
+/// Header naming the session a webhook is about
+pub const WEBHOOK_SESSION_HEADER: &str = "X-Swarm-Session";
+
+/// Where completion webhooks may be delivered, and how long one may take
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    pub connect_timeout: Duration,
+    /// For the whole delivery, response included
+    pub request_timeout: Duration,
+    /// Hosts webhooks may go to although they are, or resolve to, loopback,
+    /// private or link-local addresses. Any other such webhook is refused,
+    /// so clients can't aim the orchestrator at internal services.
+    pub internal_hosts: HashSet<String>,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(10),
+            internal_hosts: HashSet::new(),
+        }
+    }
+}
+
+/// The client every delivery shares, built from a `WebhookConfig`
+#[derive(Clone)]
+struct Webhooks {
+    internal_hosts: Arc<HashSet<String>>,
+    client: reqwest::Client,
+}
+
+impl Webhooks {
+    fn new(config: WebhookConfig) -> Self {
+        let internal_hosts = Arc::new(config.internal_hosts);
+        let client = reqwest::Client::builder()
+            .connect_timeout(config.connect_timeout)
+            .timeout(config.request_timeout)
+            // A redirect could lead anywhere the checks below would refuse
+            .redirect(reqwest::redirect::Policy::none())
+            .dns_resolver(Arc::new(ExternalResolver { internal_hosts: internal_hosts.clone() }))
+            .build()
+            .expect("webhook client settings are valid");
+        Self { internal_hosts, client }
+    }
+
+    /// Refuse `url` if its host is an internal address, or `localhost`, not
+    /// in `internal_hosts`. Other names are checked as they resolve.
+    fn check(&self, url: &str) -> Result<(), SwarmError> {
+        let host = reqwest::Url::parse(url).ok().and_then(|u| u.host_str().map(str::to_string)).unwrap_or_default();
+        let internal = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            Ok(ip) => is_internal(ip),
+            Err(_) => host.is_empty() || host == "localhost" || host.ends_with(".localhost"),
+        };
+        if internal && !self.internal_hosts.contains(&host) {
+            return Err(SwarmError::InvalidSpec(format!("completion_webhook {url} points at an internal address")));
+        }
+        Ok(())
+    }
+}
+
+/// Resolves webhook hosts, dropping internal addresses unless the host is
+/// allowed them, so a public name can't be pointed inside later
+struct ExternalResolver {
+    internal_hosts: Arc<HashSet<String>>,
+}
+
+impl reqwest::dns::Resolve for ExternalResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let allowed = self.internal_hosts.contains(name.as_str());
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| allowed || !is_internal(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{host} resolves to no external address").into());
+            }
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// Loopback, private, shared (CGNAT), link-local or unspecified
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || (a == 100 && b & 0xc0 == 64)
+        }
+        IpAddr::V6(ip) => {
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+                || ip.to_ipv4_mapped().is_some_and(|v4| is_internal(v4.into()))
+        }
+    }
+}
+
+/// POST a finished session's `metrics` to `url`, retrying failed or non-2xx
+/// deliveries up to `WEBHOOK_ATTEMPTS` times. The final status (`Completed`
+/// or `Failed`) goes in the `X-Swarm-Status` header. Failures are logged,
+/// not returned: the session is already over.
+async fn deliver_webhook(
+    webhooks: &Webhooks,
+    url: &str,
+    secret: Option<&str>,
+    session_id: SessionId,
+    status: SessionStatus,
+    metrics: &SessionMetrics,
+) {
+    use hmac::Mac;
+
+    // Checked again for sessions restored from elsewhere
+    if let Err(e) = webhooks.check(url) {
+        return error!(%session_id, url, error = %e, "completion webhook refused");
+    }
+    let body = match serde_json::to_vec(metrics) {
+        Ok(body) => body,
+        Err(e) => return error!(%session_id, error = %e, "webhook body unencodable"),
+    };
+    let signature = secret.map(|secret| {
+        let mut mac = hmac::Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes any key length");
+        mac.update(&body);
+        format!("sha256={:x}", mac.finalize().into_bytes())
+    });
+
+    let mut delay = WEBHOOK_RETRY_DELAY;
+    for attempt in 1..=WEBHOOK_ATTEMPTS {
+        let mut request = webhooks.client.post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(WEBHOOK_SESSION_HEADER, session_id.to_string())
+            .header("X-Swarm-Status", format!("{:?}", status))
+            .body(body.clone());
+        if let Some(signature) = &signature {
+            request = request.header(WEBHOOK_SIGNATURE_HEADER, signature);
+        }
+        match request.send().await.and_then(|r| r.error_for_status()) {
+            Ok(_) => return info!(%session_id, url, "completion webhook delivered"),
+            Err(e) if attempt < WEBHOOK_ATTEMPTS => {
+                warn!(%session_id, url, attempt, error = %e, "completion webhook failed, retrying");
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            Err(e) => error!(%session_id, url, error = %e, "completion webhook undelivered"),
+        }
+    }
+}
+
+// ============================================================================
+// READINESS PROBES
+// ============================================================================
+
+/// Check on an external system a session depends on; see `ReadinessGate`
+#[async_trait]
+pub trait ReadinessProbe: Send + Sync + std::fmt::Debug {
+    /// Whether the dependency can take requests now. Errors count as not
+    /// ready; the probe is simply asked again.
+    async fn is_ready(&self) -> bool;
+}
+
+/// How often a `ReadinessGate` asks its probe by default
+pub const DEFAULT_READINESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A `ReadinessProbe` a session must pass, every `interval`, before its
+/// agents start; the session fails if `timeout` passes first
+#[derive(Debug, Clone)]
+pub struct ReadinessGate {
+    pub probe: Arc<dyn ReadinessProbe>,
+    pub timeout: Duration,
+    pub interval: Duration,
+}
+
+impl ReadinessGate {
+    pub fn new(probe: Arc<dyn ReadinessProbe>, timeout: Duration) -> Self {
+        Self { probe, timeout, interval: DEFAULT_READINESS_INTERVAL }
+    }
+
+    /// Fails with `InvalidSpec` for a zero `interval`
+    pub fn with_interval(mut self, interval: Duration) -> Result<Self, SwarmError> {
+        if interval.is_zero() {
+            return Err(SwarmError::InvalidSpec("readiness interval must be positive".to_string()));
+        }
+        self.interval = interval;
+        Ok(self)
+    }
+}
+
+/// Ready once a GET of `url` (a health endpoint) answers 2xx
+#[derive(Debug, Clone)]
+pub struct HttpReadinessProbe {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl HttpReadinessProbe {
+    /// Each check gives up after `request_timeout`. Fails with
+    /// `InvalidSpec` if no HTTP client can be built with it.
+    pub fn new(url: impl Into<String>, request_timeout: Duration) -> Result<Self, SwarmError> {
+        let client = reqwest::Client::builder()
+            .timeout(request_timeout)
+            .build()
+            .map_err(|e| SwarmError::InvalidSpec(format!("readiness probe client: {e}")))?;
+        Ok(Self { url: url.into(), client })
+    }
+}
+
+#[async_trait]
+impl ReadinessProbe for HttpReadinessProbe {
+    async fn is_ready(&self) -> bool {
+        self.client.get(&self.url).send().await.is_ok_and(|r| r.status().is_success())
+    }
+}
+
+// ============================================================================
+// STATE MANAGER (CRDT-based)
+// ============================================================================
+
+/// Key-value store behind a `StateManager`: session state spaces,
+/// checkpoints and migration markers. Values are opaque strings and `cas`
+/// is the only atomic step needed, so any store with a conditional write
+/// (Postgres, DynamoDB) can back the orchestrator.
+#[async_trait]
+pub trait StateBackend: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<String>, SwarmError>;
+
+    async fn set(&self, key: &str, value: String) -> Result<(), SwarmError>;
+
+    /// Remove `key`; removing a missing key is not an error
+    async fn delete(&self, key: &str) -> Result<(), SwarmError>;
+
+    /// Every key starting with `prefix`, in no particular order
+    async fn scan(&self, prefix: &str) -> Result<Vec<String>, SwarmError>;
+
+    /// Set `key` to `new` only if it holds `expected` (`None`: only if
+    /// unset), as one atomic step. Returns whether it was set.
+    async fn cas(&self, key: &str, expected: Option<&str>, new: String) -> Result<bool, SwarmError>;
+
+    /// Notifications whenever a key starting with `prefix` changes, by any
+    /// writer. A backend without change feeds returns `None`, and state
+    /// spaces on it read through on every `get` instead of caching.
+    async fn watch(&self, _prefix: &str) -> Result<Option<KeyWatch>, SwarmError> {
+        Ok(None)
+    }
+
+    /// Every field of the hash at `key`, for state written before one
+    /// backend key per state key (see `SharedState::migrate_legacy`). A
+    /// backend that never stored hashes has none.
+    async fn hash_fields(&self, _key: &str) -> Result<Vec<(String, String)>, SwarmError> {
+        Ok(vec![])
+    }
+}
+
+/// `StateBackend` over a `RedisClient`, in-process or a server
+#[derive(Debug, Clone)]
+pub struct RedisBackend {
+    redis: Arc<RedisClient>,
+}
+
+impl RedisBackend {
+    pub fn new(redis: Arc<RedisClient>) -> Self {
+        Self { redis }
+    }
+
+    pub fn redis(&self) -> &Arc<RedisClient> {
+        &self.redis
+    }
+}
+
+#[async_trait]
+impl StateBackend for RedisBackend {
+    async fn get(&self, key: &str) -> Result<Option<String>, SwarmError> {
+        self.redis.get(key).await
+    }
+
+    async fn set(&self, key: &str, value: String) -> Result<(), SwarmError> {
+        self.redis.set(key, value).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), SwarmError> {
+        self.redis.del(key).await
+    }
+
+    async fn scan(&self, prefix: &str) -> Result<Vec<String>, SwarmError> {
+        self.redis.keys(&format!("{prefix}*")).await
+    }
+
+    async fn cas(&self, key: &str, expected: Option<&str>, new: String) -> Result<bool, SwarmError> {
+        self.redis.cas(key, expected, new).await
+    }
+
+    async fn hash_fields(&self, key: &str) -> Result<Vec<(String, String)>, SwarmError> {
+        self.redis.hgetall(key).await
+    }
+
+    async fn watch(&self, prefix: &str) -> Result<Option<KeyWatch>, SwarmError> {
+        self.redis.watch(&format!("{prefix}*")).await.map(Some)
+    }
+}
+
+/// `StateBackend` in process memory, with change notifications, for tests
+/// and single-node runs; state goes with the process
+#[derive(Debug)]
+pub struct MemoryBackend {
+    entries: RwLock<HashMap<String, String>>,
+    /// Names of modified keys
+    changes: broadcast::Sender<String>,
+}
+
+impl Default for MemoryBackend {
+    fn default() -> Self {
+        Self {
+            entries: RwLock::new(HashMap::new()),
+            changes: broadcast::channel(KEYSPACE_NOTIFY_CAPACITY).0,
+        }
+    }
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl StateBackend for MemoryBackend {
+    async fn get(&self, key: &str) -> Result<Option<String>, SwarmError> {
+        Ok(self.entries.read().await.get(key).cloned())
+    }
+
+    async fn set(&self, key: &str, value: String) -> Result<(), SwarmError> {
+        self.entries.write().await.insert(key.to_string(), value);
+        let _ = self.changes.send(key.to_string());
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), SwarmError> {
+        if self.entries.write().await.remove(key).is_some() {
+            let _ = self.changes.send(key.to_string());
+        }
+        Ok(())
+    }
+
+    async fn scan(&self, prefix: &str) -> Result<Vec<String>, SwarmError> {
+        Ok(self.entries.read().await.keys().filter(|k| k.starts_with(prefix)).cloned().collect())
+    }
+
+    async fn cas(&self, key: &str, expected: Option<&str>, new: String) -> Result<bool, SwarmError> {
+        let mut entries = self.entries.write().await;
+        if entries.get(key).map(String::as_str) != expected {
+            return Ok(false);
+        }
+        entries.insert(key.to_string(), new);
+        let _ = self.changes.send(key.to_string());
+        Ok(true)
+    }
+
+    async fn watch(&self, prefix: &str) -> Result<Option<KeyWatch>, SwarmError> {
+        Ok(Some(KeyWatch::in_process(format!("{prefix}*"), self.changes.subscribe())))
+    }
+}
+
+pub struct StateManager {
+    backend: Arc<dyn StateBackend>,
+}
+
+impl StateManager {
+    /// State kept in Redis; see `RedisBackend`
+    pub fn new(redis: Arc<RedisClient>) -> Self {
+        Self::with_backend(Arc::new(RedisBackend::new(redis)))
+    }
+
+    pub fn with_backend(backend: Arc<dyn StateBackend>) -> Self {
+        Self { backend }
+    }
+
+    pub fn backend(&self) -> &Arc<dyn StateBackend> {
+        &self.backend
+    }
+
+    /// Open a session's state space, one backend key per state key, taking
+    /// in anything an earlier release left in its `session:{id}:state`
+    /// hash. If the backend reports changes, those made by other
+    /// orchestrator instances invalidate the local read cache.
+    pub async fn create_state_space(
+        &self,
+        session_id: SessionId,
+    ) -> Result<Arc<SharedState>, SwarmError> {
+        let Some(mut watch) = self.backend.watch(&SharedState::state_prefix(session_id)).await? else {
+            let state = Arc::new(SharedState::with_backend(session_id, self.backend.clone(), false));
+            state.migrate_legacy().await?;
+            return Ok(state);
+        };
+        let state = Arc::new(SharedState::with_backend(session_id, self.backend.clone(), true));
+        state.migrate_legacy().await?;
+
+        let replica = Arc::downgrade(&state);
+        tokio::spawn(async move {
+            // Ends once the state space is dropped and one of its keys next
+            // changes (`destroy_state_space` deletes them)
+            while watch.changed().await.is_some() {
+                let Some(state) = replica.upgrade() else { break };
+                state.invalidate();
+            }
+        });
+
+        Ok(state)
+    }
+
+    pub async fn destroy_state_space(
+        &self,
+        session_id: SessionId,
+    ) -> Result<(), SwarmError> {
+        for key in self.backend.scan(&SharedState::state_prefix(session_id)).await? {
+            self.backend.delete(&key).await?;
+        }
+        self.backend.delete(&SharedState::legacy_key(session_id)).await
+    }
+}
+
+/// Per-session shared state: a map of last-writer-wins registers.
+///
+/// Every write carries a `Version` of (Lamport timestamp, writing agent); a
+/// write only lands if its version is newer than the stored one, so replicas
+/// that see the same writes in any order converge to the same value.
+///
+/// With a `StateBackend`, each key is stored as its JSON `LwwEntry` under
+/// `session:{id}:state:{key}`, and writes go through with the same LWW rule
+/// (a `cas` loop). The local map then acts as a read cache: a key is served
+/// locally only if it was confirmed against the backend since the space last
+/// changed, otherwise it's re-read. On a backend without change
+/// notifications every read goes through.
+pub struct SharedState {
+    session_id: SessionId,
+    data: Arc<RwLock<HashMap<String, LwwEntry>>>,
+    clock: AtomicU64,
+    backend: Option<Arc<dyn StateBackend>>,
+    /// Whether change notifications invalidate the cache; see `generation`
+    watched: bool,
+    /// Bumped on every change notification for the state space
+    generation: AtomicU64,
+    /// Generation at which each cached key was last confirmed against the backend
+    fresh: RwLock<HashMap<String, u64>>,
+}
+
+impl std::fmt::Debug for SharedState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedState")
+            .field("session_id", &self.session_id)
+            .field("backed", &self.backend.is_some())
+            .field("watched", &self.watched)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SharedState {
+    /// Writer id used by the plain `set`, i.e. the orchestrator itself
+    pub const ORCHESTRATOR: AgentId = AgentId::nil();
+
+    fn new(session_id: SessionId) -> Self {
+        Self {
+            session_id,
+            data: Arc::new(RwLock::new(HashMap::new())),
+            clock: AtomicU64::new(0),
+            backend: None,
+            watched: false,
+            generation: AtomicU64::new(0),
+            fresh: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn with_backend(session_id: SessionId, backend: Arc<dyn StateBackend>, watched: bool) -> Self {
+        Self { backend: Some(backend), watched, ..Self::new(session_id) }
+    }
+
+    /// Backend keys of a session's state space all start with this
+    fn state_prefix(session_id: SessionId) -> String {
+        format!("session:{}:state:", session_id)
+    }
+
+    fn backend_key(&self, key: &str) -> String {
+        format!("{}{}", Self::state_prefix(self.session_id), key)
+    }
+
+    /// The hash earlier releases kept a session's whole state space in, a
+    /// field per key holding its JSON `LwwEntry`
+    fn legacy_key(session_id: SessionId) -> String {
+        format!("session:{}:state", session_id)
+    }
+
+    /// Move what's left in the session's `legacy_key` hash to a backend key
+    /// per state key, merged by version as a replica's writes are, then drop
+    /// the hash. Returns how many keys were moved.
+    async fn migrate_legacy(&self) -> Result<usize, SwarmError> {
+        let Some(backend) = &self.backend else { return Ok(0) };
+        let legacy_key = Self::legacy_key(self.session_id);
+        let fields = backend.hash_fields(&legacy_key).await?;
+        if fields.is_empty() {
+            return Ok(0);
+        }
+        for (key, raw) in &fields {
+            let entry: LwwEntry = serde_json::from_str(raw).map_err(|e| SwarmError::state(&legacy_key, e))?;
+            self.set_versioned(key, entry.value, entry.version).await?;
+        }
+        backend.delete(&legacy_key).await?;
+        info!(session_id = %self.session_id, keys = fields.len(), "state moved out of the legacy hash");
+        Ok(fields.len())
+    }
+
+    /// The entry stored for `key` and the raw value it was read from, for a
+    /// following `cas`
+    async fn load(&self, backend: &dyn StateBackend, key: &str) -> Result<(Option<LwwEntry>, Option<String>), SwarmError> {
+        let backend_key = self.backend_key(key);
+        let Some(raw) = backend.get(&backend_key).await? else { return Ok((None, None)) };
+        let entry: LwwEntry = serde_json::from_str(&raw).map_err(|e| SwarmError::state(&backend_key, e))?;
+        self.clock.fetch_max(entry.version.timestamp, AtomicOrdering::SeqCst);
+        Ok((Some(entry), Some(raw)))
+    }
+
+    /// Treat every cached key as possibly stale
+    fn invalidate(&self) {
+        self.generation.fetch_add(1, AtomicOrdering::SeqCst);
+    }
+
+    /// Re-read `key` from the backend into the local map
+    async fn refresh(&self, backend: &dyn StateBackend, key: &str) -> Result<(), SwarmError> {
+        let generation = self.generation.load(AtomicOrdering::SeqCst);
+        match self.load(backend, key).await? {
+            (Some(entry), _) => {
+                Self::apply(&mut *self.data.write().await, key, entry);
+            }
+            // Removed through another replica
+            (None, _) => {
+                self.data.write().await.remove(key);
+            }
+        }
+        // Recorded with the generation read up front, so a change that lands
+        // mid-refresh leaves the key stale
+        self.fresh.write().await.insert(key.to_string(), generation);
+        Ok(())
+    }
+
+    /// Placeholder attached to deserialized sessions until the real state
+    /// space is reattached by `SessionManager::restore_session`
+    fn detached() -> Arc<Self> {
+        Arc::new(Self::new(SessionId::nil()))
+    }
+
+    pub async fn set(&self, key: &str, value: String) -> Result<(), SwarmError> {
+        self.set_from(Self::ORCHESTRATOR, key, value).await
+    }
+
+    /// Write on behalf of `agent_id`, stamped with the next local timestamp
+    pub async fn set_from(
+        &self,
+        agent_id: AgentId,
+        key: &str,
+        value: String,
+    ) -> Result<(), SwarmError> {
+        let version = self.next_version(agent_id);
+        self.set_versioned(key, value, version).await?;
+        Ok(())
+    }
+
+    /// Drop `key`; removing a missing key is not an error. A removal isn't
+    /// versioned, so a concurrent write may land either side of it.
+    pub async fn remove(&self, key: &str) -> Result<(), SwarmError> {
+        if let Some(backend) = &self.backend {
+            backend.delete(&self.backend_key(key)).await?;
+        }
+        self.data.write().await.remove(key);
+        self.fresh.write().await.remove(key);
+        Ok(())
+    }
+
+    fn next_version(&self, agent_id: AgentId) -> Version {
+        Version {
+            timestamp: self.clock.fetch_add(1, AtomicOrdering::SeqCst) + 1,
+            agent_id,
+        }
+    }
+
+    /// Set `key` to `new` only if it currently holds `expected` (`None`:
+    /// only if unset). Returns whether the swap happened.
+    ///
+    /// The check and write are one atomic step: under the local write lock,
+    /// or a backend `cas` on the stored entry.
+    pub async fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<String>,
+        new: String,
+    ) -> Result<bool, SwarmError> {
+        let Some(backend) = &self.backend else {
+            let mut data = self.data.write().await;
+            if data.get(key).map(|e| &e.value) != expected.as_ref() {
+                return Ok(false);
+            }
+            let version = self.next_version(Self::ORCHESTRATOR);
+            data.insert(key.to_string(), LwwEntry { value: new, version });
+            return Ok(true);
+        };
+
+        loop {
+            let (current, raw) = self.load(backend.as_ref(), key).await?;
+            if current.as_ref().map(|c| &c.value) != expected.as_ref() {
+                if let Some(current) = current {
+                    Self::apply(&mut *self.data.write().await, key, current);
+                }
+                return Ok(false);
+            }
+            // Stamped after `load` caught the clock up with the stored entry
+            let entry = LwwEntry { value: new.clone(), version: self.next_version(Self::ORCHESTRATOR) };
+            let payload = serde_json::to_string(&entry).map_err(|e| SwarmError::state(key, e))?;
+            if backend.cas(&self.backend_key(key), raw.as_deref(), payload).await? {
+                Self::apply(&mut *self.data.write().await, key, entry);
+                return Ok(true);
+            }
+            // Another writer got in between; look again
+        }
+    }
+
+    /// Add `delta` to the integer counter at `key` (unset counts as 0) and
+    /// return the new value. Backed state retries `compare_and_swap` until
+    /// no other writer got in between.
+    pub async fn increment(&self, key: &str, delta: i64) -> Result<i64, SwarmError> {
+        let next = |current: Option<&str>| -> Result<i64, SwarmError> {
+            let current = match current {
+                Some(value) => value.parse::<i64>().map_err(|e| SwarmError::state(key, e))?,
+                None => 0,
+            };
+            current.checked_add(delta).ok_or_else(|| SwarmError::state(key, "counter overflow"))
+        };
+
+        if self.backend.is_none() {
+            let mut data = self.data.write().await;
+            let value = next(data.get(key).map(|e| e.value.as_str()))?;
+            let version = self.next_version(Self::ORCHESTRATOR);
+            data.insert(key.to_string(), LwwEntry { value: value.to_string(), version });
+            return Ok(value);
+        }
+
+        loop {
+            let current = self.get(key).await?;
+            let value = next(current.as_deref())?;
+            if self.compare_and_swap(key, current, value.to_string()).await? {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// Store `value` as JSON under `key`
+    pub async fn set_typed<T: Serialize>(&self, key: &str, value: &T) -> Result<(), SwarmError> {
+        let json = serde_json::to_string(value)
+            .map_err(|source| SwarmError::Serialization { key: key.to_string(), source })?;
+        self.set(key, json).await
+    }
+
+    /// Read a value stored by `set_typed`
+    pub async fn get_typed<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, SwarmError> {
+        let Some(json) = self.get(key).await? else {
+            return Ok(None);
+        };
+        serde_json::from_str(&json)
+            .map(Some)
+            .map_err(|source| SwarmError::Serialization { key: key.to_string(), source })
+    }
+
+    /// Apply a write with an explicit version (e.g. from another replica).
+    /// Returns whether it was newer than the stored value and took effect.
+    pub async fn set_versioned(
+        &self,
+        key: &str,
+        value: String,
+        version: Version,
+    ) -> Result<bool, SwarmError> {
+        self.clock.fetch_max(version.timestamp, AtomicOrdering::SeqCst);
+        let entry = LwwEntry { value, version };
+
+        let Some(backend) = &self.backend else {
+            return Ok(Self::apply(&mut *self.data.write().await, key, entry));
+        };
+
+        let payload = serde_json::to_string(&entry).map_err(|e| SwarmError::state(key, e))?;
+        loop {
+            let (current, raw) = self.load(backend.as_ref(), key).await?;
+            if let Some(current) = current.filter(|c| c.version >= entry.version) {
+                // Another instance holds a newer value; pick it up
+                let mut data = self.data.write().await;
+                Self::apply(&mut data, key, entry);
+                Self::apply(&mut data, key, current);
+                return Ok(false);
+            }
+            if backend.cas(&self.backend_key(key), raw.as_deref(), payload.clone()).await? {
+                Self::apply(&mut *self.data.write().await, key, entry);
+                return Ok(true);
+            }
+        }
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<String>, SwarmError> {
+        if let Some(backend) = &self.backend {
+            let generation = self.generation.load(AtomicOrdering::SeqCst);
+            let confirmed = self.watched && self.fresh.read().await.get(key) == Some(&generation);
+            if !confirmed {
+                self.refresh(backend.as_ref(), key).await?;
+            }
+        }
+        Ok(self.data.read().await.get(key).map(|e| e.value.clone()))
+    }
+
+    /// Entries held by this replica (not re-read from the backend)
+    pub async fn snapshot(&self) -> StateSnapshot {
+        StateSnapshot {
+            session_id: self.session_id,
+            entries: self.data.read().await.clone(),
+        }
+    }
+
+    /// Reconcile with a divergent replica, keeping the newer side of every key
+    pub async fn merge(&self, other: &StateSnapshot) -> Result<(), SwarmError> {
+        if self.backend.is_some() {
+            for (key, entry) in &other.entries {
+                self.set_versioned(key, entry.value.clone(), entry.version).await?;
+            }
+            return Ok(());
+        }
+
+        let mut data = self.data.write().await;
+        for (key, entry) in &other.entries {
+            self.clock.fetch_max(entry.version.timestamp, AtomicOrdering::SeqCst);
+            Self::apply(&mut data, key, entry.clone());
+        }
+        Ok(())
+    }
+
+    fn apply(data: &mut HashMap<String, LwwEntry>, key: &str, entry: LwwEntry) -> bool {
+        match data.get(key) {
+            Some(current) if current.version >= entry.version => false,
+            _ => {
+                data.insert(key.to_string(), entry);
+                true
+            }
+        }
+    }
+}
+
+/// LWW ordering key; ties on timestamp are broken by agent id
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Version {
+    pub timestamp: u64,
+    pub agent_id: AgentId,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LwwEntry {
+    pub value: String,
+    pub version: Version,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    pub session_id: SessionId,
+    pub entries: HashMap<String, LwwEntry>,
+}
+
+// ============================================================================
+// TASK QUEUE (Priority DAG)
+// ============================================================================
+
+pub struct TaskQueue {
+    pending: Arc<RwLock<BinaryHeap<QueuedTask>>>,
+    in_progress: Arc<RwLock<HashMap<TaskId, Task>>>,
+    completed: Arc<RwLock<CompletedTasks>>,
+    dead_letter: Arc<RwLock<Vec<Task>>>,
+    cancelled: Arc<RwLock<HashSet<TaskId>>>,
+    dedup: Arc<RwLock<Dedup>>,
+    /// Remote dependencies linked to tasks not enqueued yet; locked last
+    links: Arc<RwLock<Links>>,
+    /// Most results kept published for remote dependencies; see
+    /// `with_published_capacity`
+    max_published: usize,
+    enqueue_seq: AtomicU64,
+    max_pending: usize,
+    space_available: Notify,
+    /// Ids for the tasks the orchestrator creates: plan steps and verifications
+    ids: Arc<dyn IdGenerator>,
+    /// Wait that raises a pending task one priority level; see
+    /// `with_priority_aging`
+    priority_aging: Option<Duration>,
+    /// Where every completion is recorded as it's accepted; see
+    /// `with_completion_log`
+    completion_log: Option<Arc<dyn CompletedStore>>,
+    /// Each session's share of `pending` and `in_progress`; see `moved`
+    loads: std::sync::Mutex<HashMap<SessionId, Load>>,
+}
+
+/// How many results a `TaskQueue` keeps published for remote
+/// dependencies by default
+pub const DEFAULT_MAX_PUBLISHED: usize = 10_000;
+
+/// Remote dependencies linked to tasks not enqueued yet, for `TaskQueue`
+#[derive(Default)]
+struct Links {
+    dependencies: HashMap<TaskId, Vec<SessionTask>>,
+    /// Linked tasks one of whose upstream tasks has failed; dead-lettered
+    /// as they're enqueued
+    failed: HashSet<TaskId>,
+}
+
+/// How much of the queue is one session's, from `TaskQueue::load_for`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionLoad {
+    pub pending: usize,
+    pub in_progress: usize,
+    /// Tasks along the longest dependency chain among the outstanding ones
+    pub critical_path: usize,
+}
+
+/// A session's plan by depth, from `TaskQueue::execution_waves`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExecutionWaves {
+    pub waves: Vec<Vec<TaskId>>,
+    /// Index of the earliest wave with a task not yet completed; `None`
+    /// once every task is
+    pub current: Option<usize>,
+}
+
+/// A session's counts as the queue keeps them
+#[derive(Debug, Default)]
+struct Load {
+    pending: usize,
+    in_progress: usize,
+    /// Worked out on the first read after the session's tasks change
+    critical_path: Option<usize>,
+}
+
+/// Where in the queue a task is held, for `TaskQueue::moved`
+#[derive(Debug, Clone, Copy)]
+enum Held {
+    Pending,
+    InProgress,
+}
+
+impl Load {
+    fn count(&mut self, held: Held) -> &mut usize {
+        match held {
+            Held::Pending => &mut self.pending,
+            Held::InProgress => &mut self.in_progress,
+        }
+    }
+}
+
+/// Sessions that deduplicate their tasks, and the task standing in for each
+/// set of equivalent ones
+#[derive(Default)]
+struct Dedup {
+    sessions: HashSet<SessionId>,
+    canonical: HashMap<(SessionId, [u8; 32]), TaskId>,
+    /// Tasks folded into each canonical one; they count as done when it is
+    folded: HashMap<TaskId, Vec<TaskId>>,
+    aliases: HashMap<TaskId, TaskId>,
+}
+
+impl Dedup {
+    /// The live task `task` duplicates, recording the fold. Otherwise `task`
+    /// becomes the one later equivalents fold into.
+    fn duplicate_of(&mut self, task: &Task, live: impl Fn(TaskId) -> bool) -> Option<TaskId> {
+        let hash = task.dedup_hash().filter(|(session_id, _)| self.sessions.contains(session_id))?;
+        match self.canonical.get(&hash) {
+            Some(&existing) if existing == task.id => None,
+            Some(&existing) if live(existing) => {
+                self.folded.entry(existing).or_default().push(task.id);
+                self.aliases.insert(task.id, existing);
+                Some(existing)
+            }
+            // Never enqueued, dead-lettered or cancelled: let this one run
+            _ => {
+                self.canonical.insert(hash, task.id);
+                None
+            }
+        }
+    }
+
+    fn folded_into(&self, task_id: TaskId) -> &[TaskId] {
+        self.folded.get(&task_id).map_or(&[], Vec::as_slice)
+    }
+
+    fn resolve(&self, task_id: TaskId) -> TaskId {
+        self.aliases.get(&task_id).copied().unwrap_or(task_id)
+    }
+}
+
+impl TaskQueue {
+    pub fn new(max_pending: usize) -> Self {
+        Self {
+            pending: Arc::new(RwLock::new(BinaryHeap::new())),
+            in_progress: Arc::new(RwLock::new(HashMap::new())),
+            completed: Arc::new(RwLock::new(CompletedTasks::default())),
+            dead_letter: Arc::new(RwLock::new(Vec::new())),
+            cancelled: Arc::new(RwLock::new(HashSet::new())),
+            dedup: Arc::new(RwLock::new(Dedup::default())),
+            links: Arc::new(RwLock::new(Links::default())),
+            max_published: DEFAULT_MAX_PUBLISHED,
+            enqueue_seq: AtomicU64::new(0),
+            max_pending,
+            space_available: Notify::new(),
+            ids: Arc::new(RandomIdGenerator),
+            priority_aging: None,
+            completion_log: None,
+            loads: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Raise a pending task's priority by one level for every `per_level`
+    /// it has waited eligible to run, so a steady stream of higher-priority
+    /// work can't starve it: a `Low` task waiting `3 * per_level` competes
+    /// as a fresh `Critical` one. The clock starts once its dependencies are
+    /// met and any retry backoff is over, as first seen by a dequeue if it
+    /// wasn't ready on enqueue. Aged levels change as time passes, so each
+    /// dequeue then scans and rebuilds the whole queue: O(n) in pending
+    /// tasks rather than O(log n).
+    pub fn with_priority_aging(mut self, per_level: Duration) -> Self {
+        self.priority_aging = Some(per_level);
+        self
+    }
+
+    /// Keep only the `capacity` most recent completed tasks in memory,
+    /// moving older ones to `store`. Their results stay available through
+    /// `get_result` and `results_for`, read back from the store.
+    pub fn with_completed_store(mut self, capacity: usize, store: Arc<dyn CompletedStore>) -> Self {
+        self.completed = Arc::new(RwLock::new(CompletedTasks {
+            spill: Some((capacity, store)),
+            ..CompletedTasks::default()
+        }));
+        self
+    }
+
+    /// Keep at most `capacity` results published for remote dependencies
+    /// (see `link_dependency`). Past it the oldest ones no task still waits
+    /// on are forgotten, down to three quarters of `capacity`, and linking
+    /// to them fails as for an unknown task.
+    pub fn with_published_capacity(mut self, capacity: usize) -> Self {
+        self.max_published = capacity;
+        self
+    }
+
+    /// Also write each task to `log` once its completion is accepted, so a
+    /// node resuming the session (`SessionManager::resume_from_checkpoint`)
+    /// knows it finished. Written after the fact: a crash in between leaves
+    /// the task to run again.
+    pub fn with_completion_log(mut self, log: Arc<dyn CompletedStore>) -> Self {
+        self.completion_log = Some(log);
+        self
+    }
+
+    /// Draw ids for plan steps and verifications from `ids` instead of at random
+    pub fn with_id_generator(mut self, ids: Arc<dyn IdGenerator>) -> Self {
+        self.ids = ids;
+        self
+    }
+
+    /// Where the orchestrator's own tasks get their ids
+    pub fn ids(&self) -> &dyn IdGenerator {
+        self.ids.as_ref()
+    }
+
+    /// Have `session_id`'s equivalent tasks (same `dedup_key`, or else same
+    /// description) run once: enqueuing one while an earlier one is queued,
+    /// running or completed returns the earlier one's id instead, and its
+    /// result serves both. Off by default; see `ProjectSpec::dedup_tasks`.
+    pub async fn set_dedup(&self, session_id: SessionId, enabled: bool) {
+        let mut dedup = self.dedup.write().await;
+        if enabled {
+            dedup.sessions.insert(session_id);
+            return;
+        }
+        dedup.sessions.remove(&session_id);
+        let mut dropped = vec![];
+        dedup.canonical.retain(|(session, _), task_id| {
+            let keep = *session != session_id;
+            if !keep {
+                dropped.push(*task_id);
+            }
+            keep
+        });
+        for task_id in dropped {
+            for duplicate in dedup.folded.remove(&task_id).unwrap_or_default() {
+                dedup.aliases.remove(&duplicate);
+            }
+        }
+    }
+
+    /// Enqueue without waiting, failing with `QueueFull` at capacity, or
+    /// `InvalidSpec` if the task's `result_schema` doesn't compile. Returns
+    /// the task's id, or that of the task it was deduplicated into.
+    pub async fn enqueue(&self, mut task: Task) -> Result<TaskId, SwarmError> {
+        task.compile_result_schema()?;
+        self.try_enqueue(task).await.map_err(|_| SwarmError::QueueFull)
+    }
+
+    /// Enqueue without waiting, handing the task back if the queue is full
+    pub async fn try_enqueue(&self, mut task: Task) -> Result<TaskId, Task> {
+        let mut pending = self.pending.write().await;
+        let in_progress = self.in_progress.read().await;
+        let mut completed = self.completed.write().await;
+
+        // A duplicate takes no space, so it's accepted even when full
+        let mut dedup = self.dedup.write().await;
+        let live = |id| {
+            completed.ids.contains(&id)
+                || in_progress.contains_key(&id)
+                || pending.iter().any(|q| q.task.id == id)
+        };
+        if let Some(existing) = dedup.duplicate_of(&task, live) {
+            if completed.ids.contains(&existing) {
+                completed.ids.insert(task.id);
+            }
+            return Ok(existing);
+        }
+        drop(dedup);
+
+        if pending.len() >= self.max_pending {
+            return Err(task);
+        }
+
+        // A re-enqueued task is no longer done, so its dependents must block again
+        completed.forget(task.id);
+        let task_id = task.id;
+        if Self::attach_links(&mut *self.links.write().await, &mut task) {
+            let failed = task.session_id.map(|session_id| (session_id, task_id));
+            warn!(%task_id, "upstream task failed; dependent dead-lettered");
+            self.dead_letter.write().await.push(task);
+            self.fail_dependents(&mut pending, failed.into_iter().collect()).await;
+            return Ok(task_id);
+        }
+
+        let seq = self.enqueue_seq.fetch_add(1, AtomicOrdering::Relaxed);
+        let ready_since = completed.satisfies(&task).then(Instant::now);
+        self.moved(&task, None, Some(Held::Pending));
+        pending.push(QueuedTask { task, seq, eligible_at: None, ready_since });
+        Ok(task_id)
+    }
+
+    /// Enqueue, waiting for a dequeue to free space if the queue is full.
+    /// Dropped while it waits, the task is left out.
+    pub async fn enqueue_blocking(&self, mut task: Task) -> Result<TaskId, SwarmError> {
+        task.compile_result_schema()?;
+        loop {
+            // Register before checking so a dequeue in between isn't missed
+            let space = self.space_available.notified();
+            match self.try_enqueue(task).await {
+                Ok(task_id) => return Ok(task_id),
+                Err(rejected) => task = rejected,
+            }
+            space.await;
+        }
+    }
+
+    pub async fn pending_len(&self) -> usize {
+        self.pending.read().await.len()
+    }
+
+    /// Pending tasks (ready or blocked) from `session_id`'s plan
+    pub async fn pending_len_for(&self, session_id: SessionId) -> usize {
+        self.loads().get(&session_id).map_or(0, |load| load.pending)
+    }
+
+    /// `session_id`'s pending and in-progress tasks, counted as they move
+    /// rather than by walking the queue. The critical path is worked out
+    /// again only after the session's tasks changed.
+    pub async fn load_for(&self, session_id: SessionId) -> SessionLoad {
+        if let Some(load) = self.loads().get(&session_id) {
+            if let Some(critical_path) = load.critical_path {
+                return SessionLoad { pending: load.pending, in_progress: load.in_progress, critical_path };
+            }
+        } else {
+            return SessionLoad::default();
+        }
+
+        // Moves count themselves under a write lock, so none lands while
+        // these are held
+        let pending = self.pending.read().await;
+        let in_progress = self.in_progress.read().await;
+        let tasks: Vec<&Task> = outstanding(&pending, &in_progress, &[])
+            .into_values()
+            .filter(|t| t.session_id == Some(session_id))
+            .collect();
+        let (chain, _) = critical_path_by(&tasks, |_| 1.0);
+        let mut loads = self.loads();
+        let Some(load) = loads.get_mut(&session_id) else { return SessionLoad::default() };
+        load.critical_path = Some(chain.len());
+        SessionLoad { pending: load.pending, in_progress: load.in_progress, critical_path: chain.len() }
+    }
+
+    fn loads(&self) -> std::sync::MutexGuard<'_, HashMap<SessionId, Load>> {
+        self.loads.lock().expect("queue loads lock poisoned")
+    }
+
+    /// Count `task` moving into the queue (`from: None`), out of it (`to:
+    /// None`) or between `pending` and `in_progress`. Called with a write
+    /// lock held on the collection it moved in or out of.
+    fn moved(&self, task: &Task, from: Option<Held>, to: Option<Held>) {
+        let Some(session_id) = task.session_id else { return };
+        let mut loads = self.loads();
+        let load = loads.entry(session_id).or_default();
+        if let Some(from) = from {
+            let count = load.count(from);
+            *count = count.saturating_sub(1);
+        }
+        if let Some(to) = to {
+            *load.count(to) += 1;
+        }
+        load.critical_path = None;
+        if load.pending + load.in_progress == 0 {
+            loads.remove(&session_id);
+        }
+    }
+
+    /// How many pending and in-progress tasks `session_id` has, as counted
+    /// by `load_for`
+    pub async fn outstanding_len_for(&self, session_id: SessionId) -> usize {
+        self.loads().get(&session_id).map_or(0, |load| load.pending + load.in_progress)
+    }
+
+    /// `session_id`'s pending and in-progress tasks
+    pub async fn outstanding_for(&self, session_id: SessionId) -> Vec<Task> {
+        let pending = self.pending.read().await;
+        let in_progress = self.in_progress.read().await;
+        outstanding(&pending, &in_progress, &[])
+            .into_values()
+            .filter(|t| t.session_id == Some(session_id))
+            .cloned()
+            .collect()
+    }
+
+    pub async fn in_progress_len(&self) -> usize {
+        self.in_progress.read().await.len()
+    }
+
+    /// Pop the highest-priority task whose dependencies have all completed.
+    /// Blocked tasks stay in `pending`; the returned task moves to `in_progress`.
+    pub async fn dequeue(&self) -> Option<Task> {
+        self.dequeue_matching(|_| true).await
+    }
+
+    /// `dequeue`, limited to tasks from `session_id`'s plan
+    pub async fn dequeue_for(&self, session_id: SessionId) -> Option<Task> {
+        self.dequeue_matching(|task| task.session_id == Some(session_id)).await
+    }
+
+    /// `dequeue_for`, further limited to the tasks `wanted` accepts
+    pub async fn dequeue_for_where(&self, session_id: SessionId, wanted: impl Fn(&Task) -> bool) -> Option<Task> {
+        self.dequeue_matching(|task| task.session_id == Some(session_id) && wanted(task)).await
+    }
+
+    /// `dequeue` for one particular task of `session_id`'s plan, failing if
+    /// it is unknown, already in progress, or still waiting on dependencies
+    pub async fn dequeue_task(&self, session_id: SessionId, task_id: TaskId) -> Result<Task, SwarmError> {
+        let wanted = |task: &Task| task.id == task_id && task.session_id == Some(session_id);
+        if let Some(task) = self.dequeue_matching(wanted).await {
+            return Ok(task);
+        }
+        if self.in_progress.read().await.get(&task_id).is_some_and(wanted) {
+            Err(SwarmError::TaskStarted(task_id))
+        } else if self.pending.read().await.iter().any(|q| wanted(&q.task)) {
+            Err(SwarmError::TaskBlocked(task_id))
+        } else {
+            Err(SwarmError::TaskNotFound(task_id))
+        }
+    }
+
+    async fn dequeue_matching(&self, wanted: impl Fn(&Task) -> bool) -> Option<Task> {
+        let mut pending = self.pending.write().await;
+        let mut in_progress = self.in_progress.write().await;
+        let completed = self.completed.read().await;
+
+        let now = Instant::now();
+        let found = match self.priority_aging {
+            // Aged priorities change as time passes, so no heap order holds
+            Some(per_level) => {
+                let mut queued = std::mem::take(&mut *pending).into_vec();
+                for q in queued.iter_mut().filter(|q| q.ready_since.is_none()) {
+                    if q.is_ready(&completed, now) {
+                        q.ready_since = Some(now);
+                    }
+                }
+                let best = queued.iter()
+                    .enumerate()
+                    .filter(|(_, q)| wanted(&q.task) && q.is_ready(&completed, now))
+                    .max_by(|(_, a), (_, b)| a.aged_cmp(b, now, per_level))
+                    .map(|(i, _)| i);
+                let found = best.map(|i| queued.swap_remove(i).task);
+                *pending = queued.into();
+                found
+            }
+            None => {
+                let mut blocked = Vec::new();
+                let mut found = None;
+                while let Some(queued) = pending.pop() {
+                    if wanted(&queued.task) && queued.is_ready(&completed, now) {
+                        found = Some(queued.task);
+                        break;
+                    }
+                    blocked.push(queued);
+                }
+                pending.extend(blocked);
+                found
+            }
+        };
+
+        let mut task = found?;
+        task.started_at = Some(Utc::now());
+        self.moved(&task, Some(Held::Pending), Some(Held::InProgress));
+        in_progress.insert(task.id, task.clone());
+        self.space_available.notify_waiters();
+        Some(task)
+    }
+
+    /// Mark an in-progress task as completed, unblocking its dependents.
+    /// Returns `false` if the task was not in progress.
+    pub async fn complete(&self, task_id: TaskId) -> bool {
+        let mut in_progress = self.in_progress.write().await;
+        let Some(task) = in_progress.remove(&task_id) else {
+            return false;
+        };
+        self.moved(&task, Some(Held::InProgress), None);
+        drop(in_progress);
+        let logged = self.completion_log.is_some().then(|| task.clone());
+        let spilled = self.completed.write().await.record(task, self.dedup.read().await.folded_into(task_id));
+        self.spill(spilled).await;
+        self.trim_published().await;
+        if let Some(task) = logged {
+            self.log_completion(&task).await;
+        }
+        true
+    }
+
+    /// Forget the oldest published results once there are more than
+    /// `max_published`, sparing those a task here is still linked to
+    async fn trim_published(&self) {
+        if self.completed.read().await.published.len() <= self.max_published {
+            return;
+        }
+        let pending = self.pending.read().await;
+        let in_progress = self.in_progress.read().await;
+        let mut completed = self.completed.write().await;
+        let links = self.links.read().await;
+        let wanted: HashSet<SessionTask> = pending.iter()
+            .map(|q| &q.task)
+            .chain(in_progress.values())
+            .flat_map(|t| t.remote_dependencies.iter().copied())
+            .chain(links.dependencies.values().flatten().copied())
+            .collect();
+        completed.trim_published(self.max_published - self.max_published / 4, &wanted);
+    }
+
+    /// Write tasks `CompletedTasks::record` picked out to the store, without
+    /// holding the queue's locks across the writes, then drop them from
+    /// memory. One that fails to write stays in memory, and a later
+    /// completion tries again.
+    async fn spill(&self, tasks: Vec<Task>) {
+        if tasks.is_empty() {
+            return;
+        }
+        let Some((_, store)) = self.completed.read().await.spill.clone() else { return };
+        for task in tasks {
+            let stored = store.put(&task).await;
+            let mut completed = self.completed.write().await;
+            match stored {
+                Ok(()) => completed.evict(task.id),
+                Err(e) => {
+                    warn!(task_id = %task.id, error = %e, "completed task not spilled");
+                    completed.spilling.remove(&task.id);
+                }
+            }
+        }
+    }
+
+    /// A completed task, from memory or else the store, read without holding
+    /// the queue's locks
+    async fn completed_task(&self, task_id: TaskId) -> Option<Task> {
+        let store = {
+            let completed = self.completed.read().await;
+            if let Some(task) = completed.in_memory(task_id) {
+                return Some(task);
+            }
+            completed.store_for(task_id)?
+        };
+        read_spilled(store.as_ref(), task_id).await
+    }
+
+    async fn log_completion(&self, task: &Task) {
+        let Some(log) = &self.completion_log else { return };
+        if let Err(e) = log.put(task).await {
+            warn!(task_id = %task.id, error = %e, "completion not logged");
+        }
+    }
+
+    /// `task_id` as written to the completion log, if it completed; see
+    /// `with_completion_log`
+    pub async fn logged_completion(&self, task_id: TaskId) -> Option<Task> {
+        let log = self.completion_log.as_ref()?;
+        log.get(task_id).await
+            .inspect_err(|e| warn!(%task_id, error = %e, "completion log unreadable"))
+            .ok()
+            .flatten()
+    }
+
+    /// Record a failed attempt of an in-progress task.
+    ///
+    /// The task goes back to `pending`, held back by exponential backoff, until
+    /// `retry_policy.max_attempts` is used up or another attempt wouldn't fit
+    /// under `max_cost_usd`; then it moves to the dead-letter set. Retries
+    /// bypass `max_pending` so a full queue can't drop them.
+    pub async fn fail(&self, task_id: TaskId) -> Option<FailureOutcome> {
+        self.record_failure(task_id, true).await
+    }
+
+    /// Record an attempt that failed in a way no retry can fix: the task is
+    /// dead-lettered whatever retries it has left
+    pub async fn fail_terminal(&self, task_id: TaskId) -> Option<FailureOutcome> {
+        self.record_failure(task_id, false).await
+    }
+
+    async fn record_failure(&self, task_id: TaskId, retriable: bool) -> Option<FailureOutcome> {
+        let mut pending = self.pending.write().await;
+        let mut task = self.in_progress.write().await.remove(&task_id)?;
+        task.attempts += 1;
+
+        if !retriable || task.attempts >= task.retry_policy.max_attempts || !task.can_afford_retry() {
+            self.moved(&task, Some(Held::InProgress), None);
+            let failed = task.session_id.map(|session_id| (session_id, task_id));
+            self.dead_letter.write().await.push(task);
+            self.fail_dependents(&mut pending, failed.into_iter().collect()).await;
+            return Some(FailureOutcome::DeadLettered);
+        }
+
+        let previous = task.last_retry_delay_ms.map(Duration::from_millis);
+        let delay = task.retry_policy.retry_delay(task.attempts, previous);
+        task.last_retry_delay_ms = Some(delay.as_millis() as u64);
+        let seq = self.enqueue_seq.fetch_add(1, AtomicOrdering::Relaxed);
+        let attempt = task.attempts;
+        let now = Instant::now();
+        self.moved(&task, Some(Held::InProgress), Some(Held::Pending));
+        // It ran, so its dependencies are met: ready once the backoff ends
+        pending.push(QueuedTask { task, seq, eligible_at: Some(now + delay), ready_since: Some(now + delay) });
+
+        Some(FailureOutcome::Retrying { attempt, delay })
+    }
+
+    /// Send a task back for another attempt: through `fail` if it's in
+    /// progress here, otherwise as a fresh enqueue. A task the queue already
+    /// holds elsewhere (requeued, completed or dead-lettered) is left alone.
+    pub async fn requeue(&self, task: Task) -> Option<FailureOutcome> {
+        if let Some(outcome) = self.fail(task.id).await {
+            return Some(outcome);
+        }
+        if !self.tracks(task.id).await {
+            // Over capacity the task is dropped, as with any enqueue
+            let _ = self.enqueue(task).await;
+        }
+        None
+    }
+
+    /// Add `cost` to an in-progress task's `cost_incurred`, returning the new
+    /// total; `None` if the task isn't in progress here
+    pub async fn charge(&self, task_id: TaskId, cost: f64) -> Option<f64> {
+        let mut in_progress = self.in_progress.write().await;
+        let task = in_progress.get_mut(&task_id)?;
+        task.cost_incurred += cost;
+        Some(task.cost_incurred)
+    }
+
+    /// Dead-letter a task outright, however many retries it has left, on
+    /// behalf of the agent that ran it. Same ownership rules as `complete_as`;
+    /// a task the queue never saw is dead-lettered as given.
+    pub async fn abort_as(&self, task: Task, agent_id: AgentId) -> bool {
+        let held = {
+            let mut in_progress = self.in_progress.write().await;
+            match in_progress.get(&task.id) {
+                Some(held) if held.assigned_to.is_none_or(|owner| owner == agent_id) => {
+                    Some(in_progress.remove(&task.id).inspect(|t| self.moved(t, Some(Held::InProgress), None)))
+                }
+                Some(_) => Some(None),
+                None => None,
+            }
+        };
+        let aborted = match held {
+            Some(held) => held,
+            None if self.tracks(task.id).await => None,
+            None => Some(task),
+        };
+        let Some(task) = aborted else { return false };
+
+        let mut pending = self.pending.write().await;
+        let failed = task.session_id.map(|session_id| (session_id, task.id));
+        self.dead_letter.write().await.push(task);
+        self.fail_dependents(&mut pending, failed.into_iter().collect()).await;
+        true
+    }
+
+    /// Record which agent an in-progress task was handed to
+    pub async fn claim(&self, task_id: TaskId, agent_id: AgentId) {
+        if let Some(task) = self.in_progress.write().await.get_mut(&task_id) {
+            task.assigned_to = Some(agent_id);
+        }
+    }
+
+    /// Whether a result from `agent_id` for `task_id` may still land: the
+    /// task is in progress and unclaimed or claimed by that agent, or the
+    /// queue has never seen it. `false` once it was reclaimed or completed.
+    pub async fn owned_by(&self, task_id: TaskId, agent_id: AgentId) -> bool {
+        let in_progress = self.in_progress.read().await;
+        match in_progress.get(&task_id) {
+            Some(task) => task.assigned_to.is_none_or(|owner| owner == agent_id),
+            None => {
+                drop(in_progress);
+                !self.tracks(task_id).await
+            }
+        }
+    }
+
+    /// Complete a task on behalf of the agent that ran it. Returns `false`,
+    /// leaving the queue untouched, unless `owned_by` holds; the check and the
+    /// completion are atomic, so a task can't complete twice across a reclaim.
+    pub async fn complete_as(&self, task_id: TaskId, agent_id: AgentId) -> bool {
+        self.finish(task_id, agent_id, None).await
+    }
+
+    /// `complete_as`, keeping `task.result` for `get_result`. A task the
+    /// queue never saw (assigned directly) is recorded as completed too.
+    pub async fn complete_with(&self, task: Task, agent_id: AgentId) -> bool {
+        self.finish(task.id, agent_id, Some(task)).await
+    }
+
+    async fn finish(&self, task_id: TaskId, agent_id: AgentId, done: Option<Task>) -> bool {
+        let (mut logged, mut spilled) = (None, vec![]);
+        let accepted = {
+            let pending = self.pending.read().await;
+            let mut in_progress = self.in_progress.write().await;
+            let mut completed = self.completed.write().await;
+            let mut record = |task: Task| {
+                logged = self.completion_log.is_some().then(|| task.clone());
+                task
+            };
+
+            match in_progress.get(&task_id) {
+                Some(task) if task.assigned_to.is_none_or(|owner| owner == agent_id) => {
+                    if let Some(mut task) = in_progress.remove(&task_id) {
+                        self.moved(&task, Some(Held::InProgress), None);
+                        task.result = done.and_then(|t| t.result);
+                        spilled = completed.record(record(task), self.dedup.read().await.folded_into(task_id));
+                    }
+                    true
+                }
+                Some(_) => false,
+                None => {
+                    let requeued = pending.iter().any(|q| q.task.id == task_id)
+                        || self.dead_letter.read().await.iter().any(|t| t.id == task_id);
+                    let accepted = !requeued
+                        && !completed.ids.contains(&task_id)
+                        && !self.cancelled.read().await.contains(&task_id);
+                    if let Some(task) = done.filter(|_| accepted) {
+                        spilled = completed.record(record(task), self.dedup.read().await.folded_into(task_id));
+                    }
+                    accepted
+                }
+            }
+        };
+        // Off the queue's locks
+        self.spill(spilled).await;
+        self.trim_published().await;
+        if let Some(task) = logged {
+            self.log_completion(&task).await;
+        }
+        accepted
+    }
+
+    /// Output of a completed task, if it recorded one. A deduplicated task
+    /// shares the result of the one it was folded into.
+    pub async fn get_result(&self, task_id: TaskId) -> Option<TaskResult> {
+        let task_id = self.dedup.read().await.resolve(task_id);
+        self.completed_task(task_id).await?.result
+    }
+
+    /// Recorded results of `session_id`'s completed tasks, in completion order
+    pub async fn results_for(&self, session_id: SessionId) -> Vec<TaskResult> {
+        self.completed_for(session_id).await
+            .into_iter()
+            .filter_map(|t| t.result)
+            .filter(|r| r.session_id == session_id)
+            .collect()
+    }
+
+    /// `session_id`'s completed tasks, with their results, in completion
+    /// order; those spilled to the store are read back
+    pub async fn completed_for(&self, session_id: SessionId) -> Vec<Task> {
+        let (evicted, store, recent) = {
+            let completed = self.completed.read().await;
+            let (evicted, store) = completed.evicted_of(session_id);
+            let recent: Vec<Task> = completed.tasks
+                .values()
+                .filter(|t| {
+                    t.session_id == Some(session_id) || t.result.as_ref().is_some_and(|r| r.session_id == session_id)
+                })
+                .cloned()
+                .collect();
+            (evicted, store, recent)
+        };
+        let mut tasks = vec![];
+        if let Some(store) = store {
+            for task_id in evicted {
+                tasks.extend(read_spilled(store.as_ref(), task_id).await);
+            }
+        }
+        tasks.extend(recent);
+        tasks
+    }
+
+    /// Result of `task_id` as completed in `session_id`, even once that
+    /// session is destroyed
+    pub async fn published_result(&self, session_id: SessionId, task_id: TaskId) -> Option<TaskResult> {
+        let holder = *self.completed.read().await.published.get(&(session_id, task_id))?;
+        self.completed_task(holder).await?.result
+    }
+
+    /// Hold `dependent` (a session's task) back until `dependency`'s result
+    /// is published. The dependent may be pending or not enqueued yet; once
+    /// it has started, linking fails with `TaskStarted`. The dependency must
+    /// be queued, running or published, else `TaskNotFound`; should it fail
+    /// instead, the dependent is dead-lettered. Cycles through remote
+    /// dependencies are not detected.
+    pub async fn link_dependency(
+        &self,
+        dependent: SessionTask,
+        dependency: SessionTask,
+    ) -> Result<(), SwarmError> {
+        let (session_id, task_id) = dependent;
+        let mut pending = self.pending.write().await;
+        let in_progress = self.in_progress.read().await;
+        let completed = self.completed.read().await;
+        if in_progress.contains_key(&task_id) || completed.ids.contains(&task_id) {
+            return Err(SwarmError::TaskStarted(task_id));
+        }
+        let upstream = self.dedup.read().await.resolve(dependency.1);
+        let ours = |task: &Task| task.id == upstream && task.session_id == Some(dependency.0);
+        let known = completed.published.contains_key(&dependency)
+            || in_progress.get(&upstream).is_some_and(ours)
+            || pending.iter().any(|q| ours(&q.task));
+        if !known {
+            return Err(SwarmError::TaskNotFound(dependency.1));
+        }
+        drop((in_progress, completed));
+
+        if let Some(queued) = pending.iter().find(|q| q.task.id == task_id) {
+            if queued.task.session_id != Some(session_id) {
+                return Err(SwarmError::TaskNotFound(task_id));
+            }
+            // The heap has no `iter_mut`; rebuilding it keeps the order
+            let mut queued = std::mem::take(&mut *pending).into_vec();
+            for q in queued.iter_mut().filter(|q| q.task.id == task_id) {
+                if !q.task.remote_dependencies.contains(&dependency) {
+                    q.task.remote_dependencies.push(dependency);
+                }
+            }
+            *pending = queued.into();
+            return Ok(());
+        }
+
+        let mut links = self.links.write().await;
+        let linked = links.dependencies.entry(task_id).or_default();
+        if !linked.contains(&dependency) {
+            linked.push(dependency);
+        }
+        Ok(())
+    }
+
+    /// Move links made before `task` was enqueued onto it. Returns whether
+    /// one of them has failed since, dooming the task.
+    fn attach_links(links: &mut Links, task: &mut Task) -> bool {
+        for dependency in links.dependencies.remove(&task.id).unwrap_or_default() {
+            if !task.remote_dependencies.contains(&dependency) {
+                task.remote_dependencies.push(dependency);
+            }
+        }
+        links.failed.remove(&task.id)
+    }
+
+    /// Dead-letter the pending tasks linked to one of `failed`, upstream
+    /// tasks that won't publish a result, then those linked to them in turn,
+    /// so no dependent waits forever. Linked tasks not enqueued yet are
+    /// marked to be dead-lettered as they are. Called with `pending`
+    /// write-locked.
+    async fn fail_dependents(&self, pending: &mut BinaryHeap<QueuedTask>, mut failed: Vec<SessionTask>) {
+        if failed.is_empty() {
+            return;
+        }
+        let mut dead_letter = self.dead_letter.write().await;
+        let mut links = self.links.write().await;
+        let mut dropped = false;
+        while !failed.is_empty() {
+            let waits = |dependencies: &[SessionTask]| dependencies.iter().any(|dep| failed.contains(dep));
+            let doomed: Vec<TaskId> = links.dependencies.iter()
+                .filter(|(_, dependencies)| waits(dependencies))
+                .map(|(task_id, _)| *task_id)
+                .collect();
+            links.failed.extend(doomed);
+            if !pending.iter().any(|q| waits(&q.task.remote_dependencies)) {
+                break;
+            }
+
+            let (orphaned, kept): (Vec<QueuedTask>, Vec<QueuedTask>) = std::mem::take(pending)
+                .into_vec()
+                .into_iter()
+                .partition(|q| waits(&q.task.remote_dependencies));
+            *pending = kept.into();
+            failed = vec![];
+            for QueuedTask { task, .. } in orphaned {
+                warn!(task_id = %task.id, "upstream task failed; dependent dead-lettered");
+                self.moved(&task, Some(Held::Pending), None);
+                task.cancellation.cancel();
+                failed.extend(task.session_id.map(|session_id| (session_id, task.id)));
+                dead_letter.push(task);
+            }
+            dropped = true;
+        }
+        if dropped {
+            self.space_available.notify_waiters();
+        }
+    }
+
+    /// Remove a pending or in-progress task belonging to `session_id` and
+    /// remember it as cancelled, so late results for it are discarded; tasks
+    /// linked to it are dead-lettered. Returns the removed task.
+    pub async fn cancel(&self, task_id: TaskId, session_id: SessionId) -> Option<Task> {
+        let mut pending = self.pending.write().await;
+        let mut in_progress = self.in_progress.write().await;
+        let belongs = |task: &Task| task.id == task_id && task.session_id.is_none_or(|id| id == session_id);
+
+        let task = if in_progress.get(&task_id).is_some_and(belongs) {
+            in_progress.remove(&task_id).inspect(|t| self.moved(t, Some(Held::InProgress), None))
+        } else {
+            let mut queued = std::mem::take(&mut *pending).into_vec();
+            let index = queued.iter().position(|q| belongs(&q.task));
+            let task = index.map(|i| queued.swap_remove(i).task);
+            *pending = queued.into();
+            task.inspect(|t| self.moved(t, Some(Held::Pending), None))
+        }?;
+
+        drop(in_progress);
+        self.cancelled.write().await.insert(task_id);
+        let failed = task.session_id.map(|session_id| (session_id, task_id));
+        self.fail_dependents(&mut pending, failed.into_iter().collect()).await;
+        self.space_available.notify_waiters();
+        Some(task)
+    }
+
+    /// Take `session_id`'s pending and in-progress tasks out of the queue
+    /// for `SessionManager::export_for_migration`, unassigned, along with the
+    /// ids of its completed tasks. Removed in-progress tasks are remembered
+    /// as cancelled, so their results here are discarded.
+    pub async fn hand_off(&self, session_id: SessionId) -> (Vec<Task>, Vec<TaskId>) {
+        let mut pending = self.pending.write().await;
+        let mut in_progress = self.in_progress.write().await;
+        let completed = self.completed.read().await;
+        let ours = |task: &Task| task.session_id == Some(session_id);
+
+        let (taken, kept): (Vec<QueuedTask>, Vec<QueuedTask>) = std::mem::take(&mut *pending)
+            .into_vec()
+            .into_iter()
+            .partition(|q| ours(&q.task));
+        *pending = kept.into();
+        let mut tasks: Vec<Task> = taken.into_iter().map(|q| q.task).collect();
+
+        let running: Vec<TaskId> = in_progress.values()
+            .filter(|t| ours(t))
+            .map(|t| t.id)
+            .collect();
+        for task_id in &running {
+            tasks.extend(in_progress.remove(task_id));
+        }
+        // Every task the session had here is gone
+        self.loads().remove(&session_id);
+        for task in &mut tasks {
+            task.assigned_to = None;
+            task.started_at = None;
+        }
+
+        let done = completed.ids_of(session_id);
+        self.cancelled.write().await.extend(running);
+        self.space_available.notify_waiters();
+        (tasks, done)
+    }
+
+    /// Drop everything `session_id` still has in the queue: its pending and
+    /// in-progress tasks, whose tokens are cancelled so running agents stop
+    /// and late results are discarded, and its dedup entries. Other
+    /// sessions' tasks linked to the dropped ones are dead-lettered. Returns
+    /// how many tasks were dropped.
+    pub async fn purge_session(&self, session_id: SessionId) -> usize {
+        let (tasks, _) = self.hand_off(session_id).await;
+        for task in &tasks {
+            task.cancellation.cancel();
+        }
+        let failed = tasks.iter().map(|t| (session_id, t.id)).collect();
+        self.fail_dependents(&mut *self.pending.write().await, failed).await;
+        self.set_dedup(session_id, false).await;
+        tasks.len()
+    }
+
+    /// `hand_off` without taking anything: copies of `session_id`'s
+    /// outstanding tasks, unassigned, and the ids of its completed ones
+    pub async fn progress_of(&self, session_id: SessionId) -> (Vec<Task>, Vec<TaskId>) {
+        let pending = self.pending.read().await;
+        let in_progress = self.in_progress.read().await;
+        let completed = self.completed.read().await;
+        let tasks = outstanding(&pending, &in_progress, &[])
+            .into_values()
+            .filter(|t| t.session_id == Some(session_id))
+            .map(|t| Task { assigned_to: None, started_at: None, ..t.clone() })
+            .collect();
+        (tasks, completed.ids_of(session_id))
+    }
+
+    /// Queue a migrated session's `tasks` as a batch, after counting
+    /// `completed` as done so the tasks that depend on them are ready.
+    /// `results` of completed tasks are served by `get_result` and
+    /// `results_for` again, in the order given.
+    pub async fn adopt(&self, completed: Vec<TaskId>, results: Vec<TaskResult>, tasks: Vec<Task>) -> Result<(), SwarmError> {
+        let mut spilled = vec![];
+        {
+            let mut done = self.completed.write().await;
+            done.ids.extend(completed);
+            for result in results {
+                // Only the result survives a hand-off; it's all a completed
+                // task is read back for
+                let task = Task {
+                    id: result.task_id,
+                    session_id: Some(result.session_id),
+                    result: Some(result),
+                    ..Task::new("", 0.0)
+                };
+                spilled.extend(done.record(task, &[]));
+            }
+        }
+        self.spill(spilled).await;
+        self.trim_published().await;
+        self.enqueue_batch(tasks).await
+    }
+
+    /// Move tasks in progress for longer than `timeout` back to `pending`,
+    /// counting an attempt; like `fail`, exhausted ones are dead-lettered.
+    /// Reclaimed tasks are eligible again immediately.
+    pub async fn reclaim_stale(&self, timeout: Duration) -> Vec<ReclaimedTask> {
+        let cutoff = Utc::now() - chrono::Duration::from_std(timeout).unwrap_or(chrono::Duration::MAX);
+        self.reclaim_where(|t| t.started_at.is_some_and(|started| started < cutoff)).await
+    }
+
+    /// Like `reclaim_stale`, for the tasks in progress on `agent_id`, e.g.
+    /// once it has died
+    pub async fn reclaim_from(&self, agent_id: AgentId) -> Vec<ReclaimedTask> {
+        self.reclaim_where(|t| t.assigned_to == Some(agent_id)).await
+    }
+
+    async fn reclaim_where(&self, reclaim: impl Fn(&Task) -> bool) -> Vec<ReclaimedTask> {
+        let mut pending = self.pending.write().await;
+        let mut in_progress = self.in_progress.write().await;
+
+        let stale: Vec<TaskId> = in_progress
+            .values()
+            .filter(|t| reclaim(t))
+            .map(|t| t.id)
+            .collect();
+
+        let mut reclaimed = Vec::with_capacity(stale.len());
+        let mut failed = vec![];
+        for task_id in stale {
+            let Some(mut task) = in_progress.remove(&task_id) else { continue };
+            task.attempts += 1;
+            task.started_at = None;
+            // The stale owner stays recorded until the next `claim`, so its
+            // late result is still recognised as superseded
+            let agent_id = task.assigned_to;
+
+            let outcome = if task.attempts >= task.retry_policy.max_attempts {
+                self.moved(&task, Some(Held::InProgress), None);
+                failed.extend(task.session_id.map(|session_id| (session_id, task_id)));
+                self.dead_letter.write().await.push(task);
+                FailureOutcome::DeadLettered
+            } else {
+                let seq = self.enqueue_seq.fetch_add(1, AtomicOrdering::Relaxed);
+                let attempt = task.attempts;
+                self.moved(&task, Some(Held::InProgress), Some(Held::Pending));
+                pending.push(QueuedTask { task, seq, eligible_at: None, ready_since: Some(Instant::now()) });
+                FailureOutcome::Retrying { attempt, delay: Duration::ZERO }
+            };
+            reclaimed.push(ReclaimedTask { task_id, agent_id, outcome });
+        }
+
+        drop(in_progress);
+        self.fail_dependents(&mut pending, failed).await;
+        reclaimed
+    }
+
+    /// Whether the queue holds `task_id` anywhere other than `in_progress`
+    async fn tracks(&self, task_id: TaskId) -> bool {
+        let pending = self.pending.read().await;
+        let completed = self.completed.read().await;
+        pending.iter().any(|q| q.task.id == task_id)
+            || completed.ids.contains(&task_id)
+            || self.dead_letter.read().await.iter().any(|t| t.id == task_id)
+            || self.cancelled.read().await.contains(&task_id)
+    }
+
+    /// Tasks that exhausted their retry policy
+    pub async fn dead_letter(&self) -> Vec<Task> {
+        self.dead_letter.read().await.clone()
+    }
+
+    /// Sessions with at least one task in `ready_tasks`
+    pub async fn ready_sessions(&self) -> HashSet<SessionId> {
+        let pending = self.pending.read().await;
+        let completed = self.completed.read().await;
+        let now = Instant::now();
+
+        pending
+            .iter()
+            .filter(|q| q.is_ready(&completed, now))
+            .filter_map(|q| q.task.session_id)
+            .collect()
+    }
+
+    /// IDs of pending tasks that can run now: dependencies completed and any
+    /// retry backoff elapsed
+    pub async fn ready_tasks(&self) -> Vec<TaskId> {
+        let pending = self.pending.read().await;
+        let completed = self.completed.read().await;
+        let now = Instant::now();
+
+        pending
+            .iter()
+            .filter(|q| q.is_ready(&completed, now))
+            .map(|q| q.task.id)
+            .collect()
+    }
+
+    /// Priority of the task at the head of the queue, ready or not
+    pub async fn peek_priority(&self) -> Option<TaskPriority> {
+        self.pending.read().await.peek().map(|q| q.task.priority)
+    }
+
+    /// Check that the outstanding (pending + in-progress) tasks form a DAG.
+    ///
+    /// Dependencies on completed or not-yet-enqueued tasks are ignored; only
+    /// cycles among outstanding tasks can deadlock the queue.
+    pub async fn is_dag_valid(&self) -> Result<(), SwarmError> {
+        self.validate_dag().await.map_err(SwarmError::CyclicDependency)
+    }
+
+    /// Like `is_dag_valid`, but returns the offending cycle: each task in the
+    /// list depends on the next, and the last depends on the first.
+    pub async fn validate_dag(&self) -> Result<(), Vec<TaskId>> {
+        let pending = self.pending.read().await;
+        let in_progress = self.in_progress.read().await;
+        match find_cycle(&outstanding(&pending, &in_progress, &[])) {
+            Some(cycle) => Err(cycle),
+            None => Ok(()),
+        }
+    }
+
+    /// Every task of `session_id`'s plan the queue knows of (pending,
+    /// running, completed or dead-lettered) grouped by depth: wave 0 holds
+    /// the tasks with no dependencies, and each other task sits one wave past
+    /// its deepest dependency, so a wave can run once the waves before it are
+    /// done. Tasks in a wave are in id order; dependencies on tasks the queue
+    /// doesn't know of don't count, and remote ones belong to another
+    /// session's DAG. The waves and `ExecutionWaves::current` come from one
+    /// look at the queue, taken before the waves are worked out.
+    pub async fn execution_waves(&self, session_id: SessionId) -> ExecutionWaves {
+        let ours = |t: &&Task| t.session_id == Some(session_id);
+        let (mut dependencies, mut done, evicted, store) = {
+            let pending = self.pending.read().await;
+            let in_progress = self.in_progress.read().await;
+            let completed = self.completed.read().await;
+            let dead_letter = self.dead_letter.read().await;
+            let dedup = self.dedup.read().await;
+
+            // Dependents of a deduplicated task wait on the one it was folded into
+            let entry = |t: &Task| (t.id, t.dependencies.iter().map(|d| dedup.resolve(*d)).collect::<Vec<_>>());
+            let finished: HashMap<TaskId, Vec<TaskId>> = completed.tasks.values().filter(ours).map(entry).collect();
+            let done: HashSet<TaskId> = finished.keys().copied().collect();
+            let mut dependencies = finished;
+            dependencies.extend(in_progress.values().filter(ours).map(entry));
+            dependencies.extend(pending.iter().map(|q| &q.task).filter(ours).map(entry));
+            dependencies.extend(dead_letter.iter().filter(ours).map(entry));
+            let (evicted, store) = completed.evicted_of(session_id);
+            (dependencies, done, evicted, store)
+        };
+        // Read back off the queue's locks
+        if let Some(store) = store {
+            let mut spilled = vec![];
+            for task_id in evicted {
+                spilled.extend(read_spilled(store.as_ref(), task_id).await);
+            }
+            let dedup = self.dedup.read().await;
+            for task in spilled {
+                dependencies.insert(task.id, task.dependencies.iter().map(|d| dedup.resolve(*d)).collect());
+                done.insert(task.id);
+            }
+        }
+
+        let waves = execution_waves(&dependencies);
+        let current = waves.iter().position(|wave| wave.iter().any(|id| !done.contains(id)));
+        ExecutionWaves { waves, current }
+    }
+
+    /// `ExecutionWaves::current` of `session_id`'s plan
+    pub async fn current_wave(&self, session_id: SessionId) -> Option<usize> {
+        self.execution_waves(session_id).await.current
+    }
+
+    /// Enqueue a whole plan atomically; see `enqueue_batch`
+    pub async fn enqueue_plan(&self, tasks: Vec<Task>) -> Result<(), SwarmError> {
+        self.enqueue_batch(tasks).await
+    }
+
+    /// Enqueue many tasks at once, all or nothing: none is enqueued if the
+    /// batch (together with outstanding tasks) contains a cycle, doesn't
+    /// fit under `max_pending`, or has a `result_schema` that doesn't
+    /// compile. The DAG is checked once for the whole batch
+    /// and each lock taken once, so large templates don't pay per task.
+    /// Tasks deduplicated into others (see `set_dedup`) are dropped, and
+    /// their dependents wait on the task they were folded into.
+    pub async fn enqueue_batch(&self, mut tasks: Vec<Task>) -> Result<(), SwarmError> {
+        for task in &mut tasks {
+            task.compile_result_schema()?;
+        }
+        let mut pending = self.pending.write().await;
+        let in_progress = self.in_progress.read().await;
+
+        if let Some(cycle) = find_cycle(&outstanding(&pending, &in_progress, &tasks)) {
+            return Err(SwarmError::CyclicDependency(cycle));
+        }
+        if pending.len() + tasks.len() > self.max_pending {
+            return Err(SwarmError::QueueFull);
+        }
+
+        let mut completed = self.completed.write().await;
+        let mut dedup = self.dedup.write().await;
+        let mut links = self.links.write().await;
+        let mut kept: HashSet<TaskId> = HashSet::new();
+        let mut batch = Vec::with_capacity(tasks.len());
+        let mut orphans = vec![];
+        for mut task in tasks {
+            let live = |id| {
+                kept.contains(&id)
+                    || completed.ids.contains(&id)
+                    || in_progress.contains_key(&id)
+                    || pending.iter().any(|q| q.task.id == id)
+            };
+            if let Some(existing) = dedup.duplicate_of(&task, live) {
+                if completed.ids.contains(&existing) {
+                    completed.ids.insert(task.id);
+                }
+                continue;
+            }
+
+            completed.forget(task.id);
+            if Self::attach_links(&mut links, &mut task) {
+                orphans.push(task);
+                continue;
+            }
+            kept.insert(task.id);
+            let seq = self.enqueue_seq.fetch_add(1, AtomicOrdering::Relaxed);
+            let ready_since = completed.satisfies(&task).then(Instant::now);
+            self.moved(&task, None, Some(Held::Pending));
+            batch.push(QueuedTask { task, seq, eligible_at: None, ready_since });
+        }
+        pending.extend(batch);
+        drop((completed, dedup, links));
+        if !orphans.is_empty() {
+            let failed = orphans.iter().filter_map(|t| t.session_id.map(|session_id| (session_id, t.id))).collect();
+            for task in &orphans {
+                warn!(task_id = %task.id, "upstream task failed; dependent dead-lettered");
+            }
+            self.dead_letter.write().await.extend(orphans);
+            self.fail_dependents(&mut pending, failed).await;
+        }
+        Ok(())
+    }
+}
+
+/// Outstanding tasks by id, with `extra` (a plan being submitted) on top
+fn outstanding<'a>(
+    pending: &'a BinaryHeap<QueuedTask>,
+    in_progress: &'a HashMap<TaskId, Task>,
+    extra: &'a [Task],
+) -> HashMap<TaskId, &'a Task> {
+    pending
+        .iter()
+        .map(|q| &q.task)
+        .chain(in_progress.values())
+        .chain(extra)
+        .map(|t| (t.id, t))
+        .collect()
+}
+
+/// Prompt asking the planner for a task DAG in the shape `parse_plan` reads
+fn planning_prompt(project_spec: &ProjectSpec) -> String {
+    format!(
+        "Break this project into tasks for a team of agents. Answer with a JSON array only; \
+         each element is {{\"id\": <integer>, \"description\": <string>, \
+         \"estimated_time_min\": <number>, \"depends_on\": [<ids>]}}.\n\n\
+         Project: {}\nTemplate: {:?}\nComplexity: {:?}\nInstances: {}\nBrowser automation: {}",
+        project_spec.name,
+        project_spec.template,
+        project_spec.estimated_complexity,
+        project_spec.replication_count,
+        project_spec.requires_browser,
+    )
+}
+
+/// One element of the planner's JSON answer
+#[derive(Deserialize)]
+struct PlanStep {
+    id: u64,
+    description: String,
+    #[serde(default)]
+    estimated_time_min: f64,
+    #[serde(default)]
+    depends_on: Vec<u64>,
+}
+
+/// Turn a planner's answer into tasks. The JSON array may be surrounded by
+/// prose; step ids become task dependencies, and the plan must be acyclic.
+fn parse_plan(text: &str, id_source: &dyn IdGenerator) -> Result<Vec<Task>, SwarmError> {
+    let invalid = SwarmError::InvalidPlan;
+    let json = match (text.find('['), text.rfind(']')) {
+        (Some(start), Some(end)) if start < end => &text[start..=end],
+        _ => return Err(invalid("no JSON array in the planner's answer".into())),
+    };
+    let steps: Vec<PlanStep> = serde_json::from_str(json).map_err(|e| invalid(e.to_string()))?;
+    if steps.is_empty() {
+        return Err(invalid("the plan has no tasks".into()));
+    }
+
+    let mut ids = HashMap::with_capacity(steps.len());
+    for step in &steps {
+        if ids.insert(step.id, id_source.next_id()).is_some() {
+            return Err(invalid(format!("step {} appears twice", step.id)));
+        }
+    }
+
+    let mut tasks = Vec::with_capacity(steps.len());
+    for step in steps {
+        let mut task = Task::new(step.description, step.estimated_time_min.max(0.0));
+        task.id = ids[&step.id];
+        for dep in step.depends_on {
+            let Some(&dep_id) = ids.get(&dep) else {
+                return Err(invalid(format!("step {} depends on unknown step {}", step.id, dep)));
+            };
+            task.dependencies.push(dep_id);
+        }
+        tasks.push(task);
+    }
+
+    if let Some(cycle) = find_cycle(&tasks.iter().map(|t| (t.id, t)).collect()) {
+        return Err(SwarmError::CyclicDependency(cycle));
+    }
+    Ok(tasks)
+}
+
+/// JSON Schema for `PlanDocument`, as served by `PlanDocument::schema`
+const PLAN_DOCUMENT_SCHEMA: &str = r#"{
+  "$schema": "http://json-schema.org/draft-07/schema#",
+  "title": "PlanDocument",
+  "type": "object",
+  "required": ["tasks"],
+  "additionalProperties": false,
+  "properties": {
+    "metadata": {
+      "type": "object",
+      "additionalProperties": { "type": "string" }
+    },
+    "tasks": {
+      "type": "array",
+      "minItems": 1,
+      "items": {
+        "type": "object",
+        "required": ["id", "description"],
+        "additionalProperties": false,
+        "properties": {
+          "id": { "type": "string", "minLength": 1 },
+          "description": { "type": "string" },
+          "estimated_time_min": { "type": "number", "minimum": 0 },
+          "depends_on": { "type": "array", "items": { "type": "string" } },
+          "priority": { "enum": ["Low", "Normal", "High", "Critical"] }
+        }
+      }
+    }
+  }
+}"#;
+
+/// A task plan as users write it, for `SessionManager::submit_plan_document`.
+/// Tasks name each other by ids local to the document, which become fresh
+/// task ids on submission.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlanDocument {
+    /// Free-form labels, e.g. the plan's author or source
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+    pub tasks: Vec<PlanTask>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlanTask {
+    pub id: String,
+    pub description: String,
+    #[serde(default)]
+    pub estimated_time_min: f64,
+    /// Ids of tasks in the same document
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    #[serde(default)]
+    pub priority: TaskPriority,
+}
+
+/// One problem with a `PlanDocument`, naming tasks by their document ids
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, thiserror::Error)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PlanError {
+    #[error("{path}: {message}")]
+    Schema { path: String, message: String },
+    #[error("the plan has no tasks")]
+    Empty,
+    #[error("task id `{id}` appears more than once")]
+    DuplicateId { id: String },
+    #[error("task `{task}` depends on unknown task `{dependency}`")]
+    UnknownDependency { task: String, dependency: String },
+    #[error("task `{task}` has a negative estimate of {estimated_time_min} min")]
+    NegativeTime { task: String, estimated_time_min: f64 },
+    /// Each task depends on the next, and the last on the first
+    #[error("tasks {tasks:?} depend on each other in a cycle")]
+    Cycle { tasks: Vec<String> },
+}
+
+impl PlanDocument {
+    pub fn schema() -> serde_json::Value {
+        serde_json::from_str(PLAN_DOCUMENT_SCHEMA).expect("plan document schema is valid JSON")
+    }
+
+    /// Parse a submitted plan, checking it against `schema` and then
+    /// `validate`. Schema violations are reported alone, one per offending
+    /// value, since the rest can't be checked without a well-formed plan.
+    pub fn from_json(json: &str) -> Result<Self, Vec<PlanError>> {
+        static SCHEMA: std::sync::OnceLock<jsonschema::JSONSchema> = std::sync::OnceLock::new();
+        let malformed = |e: serde_json::Error| vec![PlanError::Schema { path: String::new(), message: e.to_string() }];
+
+        let value: serde_json::Value = serde_json::from_str(json).map_err(malformed)?;
+        let schema = SCHEMA.get_or_init(|| {
+            jsonschema::JSONSchema::compile(&Self::schema()).expect("plan document schema compiles")
+        });
+        if let Err(errors) = schema.validate(&value) {
+            return Err(errors
+                .map(|e| PlanError::Schema { path: e.instance_path.to_string(), message: e.to_string() })
+                .collect());
+        }
+        let document: Self = serde_json::from_value(value).map_err(malformed)?;
+        document.validate()?;
+        Ok(document)
+    }
+
+    /// Every problem that would stop the plan from running: duplicate ids,
+    /// dependencies on tasks not in the document, negative estimates and
+    /// dependency cycles
+    pub fn validate(&self) -> Result<(), Vec<PlanError>> {
+        self.to_tasks(&RandomIdGenerator).map(|_| ())
+    }
+
+    /// The plan as tasks, with ids drawn from `ids` and dependencies between
+    /// them, once it passes `validate`
+    pub fn to_tasks(&self, ids: &dyn IdGenerator) -> Result<Vec<Task>, Vec<PlanError>> {
+        let mut errors = vec![];
+        if self.tasks.is_empty() {
+            errors.push(PlanError::Empty);
+        }
+        let mut task_ids: HashMap<&str, TaskId> = HashMap::with_capacity(self.tasks.len());
+        for step in &self.tasks {
+            if task_ids.insert(&step.id, ids.next_id()).is_some() {
+                errors.push(PlanError::DuplicateId { id: step.id.clone() });
+            }
+        }
+        let unique = errors.is_empty();
+
+        let mut tasks = Vec::with_capacity(self.tasks.len());
+        for step in &self.tasks {
+            if step.estimated_time_min.is_nan() || step.estimated_time_min < 0.0 {
+                let estimated_time_min = step.estimated_time_min;
+                errors.push(PlanError::NegativeTime { task: step.id.clone(), estimated_time_min });
+            }
+            let mut task = Task::new(step.description.clone(), step.estimated_time_min);
+            task.id = task_ids[step.id.as_str()];
+            task.priority = step.priority;
+            for dependency in &step.depends_on {
+                match task_ids.get(dependency.as_str()) {
+                    Some(&id) => task.dependencies.push(id),
+                    None => errors.push(PlanError::UnknownDependency {
+                        task: step.id.clone(),
+                        dependency: dependency.clone(),
+                    }),
+                }
+            }
+            tasks.push(task);
+        }
+
+        // With duplicate ids, tasks share a TaskId and cycles are ambiguous
+        if unique {
+            if let Some(cycle) = find_cycle(&tasks.iter().map(|t| (t.id, t)).collect()) {
+                let names: HashMap<TaskId, &str> = task_ids.iter().map(|(&name, &id)| (id, name)).collect();
+                let tasks = cycle.iter().map(|id| names[id].to_string()).collect();
+                errors.push(PlanError::Cycle { tasks });
+            }
+        }
+
+        if errors.is_empty() { Ok(tasks) } else { Err(errors) }
+    }
+}
+
+/// Longest chain of dependent tasks by estimated time, and its length in
+/// minutes. `tasks` must be acyclic; dependencies outside it are ignored.
+fn critical_path(tasks: &[Task]) -> (Vec<TaskId>, f64) {
+    critical_path_by(&tasks.iter().collect::<Vec<_>>(), |t| t.estimated_time_min)
+}
+
+/// `critical_path`, with each task weighing `weight` instead of its estimate
+fn critical_path_by(tasks: &[&Task], weight: impl Fn(&Task) -> f64) -> (Vec<TaskId>, f64) {
+    let by_id: HashMap<TaskId, &Task> = tasks.iter().map(|&t| (t.id, t)).collect();
+    // Earliest finish of each task, and the dependency it waits on longest
+    let mut finish: HashMap<TaskId, (f64, Option<TaskId>)> = HashMap::with_capacity(tasks.len());
+
+    for task in tasks {
+        let mut stack = vec![task.id];
+        while let Some(&id) = stack.last() {
+            if finish.contains_key(&id) {
+                stack.pop();
+                continue;
+            }
+            let deps: Vec<TaskId> = by_id[&id].dependencies
+                .iter()
+                .copied()
+                .filter(|d| by_id.contains_key(d))
+                .collect();
+            let unresolved: Vec<TaskId> = deps.iter().copied().filter(|d| !finish.contains_key(d)).collect();
+            if !unresolved.is_empty() {
+                stack.extend(unresolved);
+                continue;
+            }
+            let before = deps
+                .into_iter()
+                .map(|d| (finish[&d].0, Some(d)))
+                .max_by(|a, b| a.0.total_cmp(&b.0))
+                .unwrap_or((0.0, None));
+            finish.insert(id, (before.0 + weight(by_id[&id]), before.1));
+            stack.pop();
+        }
+    }
+
+    let Some((&last, &(total, _))) = finish.iter().max_by(|a, b| a.1.0.total_cmp(&b.1.0)) else {
+        return (vec![], 0.0);
+    };
+    let mut path = vec![last];
+    let mut cursor = last;
+    while let Some(prev) = finish[&cursor].1 {
+        path.push(prev);
+        cursor = prev;
+    }
+    path.reverse();
+    (path, total)
+}
+
+/// Tasks, given as their dependencies by id, grouped by longest dependency
+/// chain from a root; see `TaskQueue::execution_waves`. An edge closing a
+/// cycle (possible with tasks enqueued one by one) is ignored rather than
+/// followed forever.
+fn execution_waves(by_id: &HashMap<TaskId, Vec<TaskId>>) -> Vec<Vec<TaskId>> {
+    let mut depth: HashMap<TaskId, usize> = HashMap::with_capacity(by_id.len());
+    let mut visiting: HashSet<TaskId> = HashSet::new();
+
+    for &task_id in by_id.keys() {
+        let mut stack = vec![task_id];
+        while let Some(&id) = stack.last() {
+            if depth.contains_key(&id) {
+                stack.pop();
+                continue;
+            }
+            visiting.insert(id);
+            let deps = by_id[&id].iter().filter(|d| by_id.contains_key(d));
+            let unresolved: Vec<TaskId> = deps
+                .clone()
+                .copied()
+                .filter(|d| !depth.contains_key(d) && !visiting.contains(d))
+                .collect();
+            if !unresolved.is_empty() {
+                stack.extend(unresolved);
+                continue;
+            }
+            depth.insert(id, deps.filter_map(|d| depth.get(d)).map(|d| d + 1).max().unwrap_or(0));
+            visiting.remove(&id);
+            stack.pop();
+        }
+    }
+
+    let mut waves: Vec<Vec<TaskId>> = vec![vec![]; depth.values().max().map_or(0, |d| d + 1)];
+    for (id, d) in depth {
+        waves[d].push(id);
+    }
+    for wave in &mut waves {
+        wave.sort();
+    }
+    waves
+}
+
+/// Depth-first search for a dependency cycle among `tasks`, ignoring edges
+/// to tasks outside the map. Iterative, so deep plans can't overflow the stack.
+fn find_cycle(tasks: &HashMap<TaskId, &Task>) -> Option<Vec<TaskId>> {
+    #[derive(PartialEq)]
+    enum Mark {
+        OnPath,
+        Done,
+    }
+
+    let mut marks: HashMap<TaskId, Mark> = HashMap::new();
+    for &root in tasks.keys() {
+        if marks.contains_key(&root) {
+            continue;
+        }
+
+        // The current DFS path, each entry with the next dependency to explore
+        let mut path: Vec<(TaskId, usize)> = vec![(root, 0)];
+        marks.insert(root, Mark::OnPath);
+
+        while let Some(&(id, next)) = path.last() {
+            let Some(&dep) = tasks[&id].dependencies.get(next) else {
+                marks.insert(id, Mark::Done);
+                path.pop();
+                continue;
+            };
+            if let Some(top) = path.last_mut() {
+                top.1 += 1;
+            }
+            if !tasks.contains_key(&dep) {
+                continue;
+            }
+
+            match marks.get(&dep) {
+                Some(Mark::OnPath) => {
+                    let start = path.iter().position(|(t, _)| *t == dep)?;
+                    return Some(path[start..].iter().map(|(t, _)| *t).collect());
+                }
+                Some(Mark::Done) => {}
+                None => {
+                    marks.insert(dep, Mark::OnPath);
+                    path.push((dep, 0));
+                }
+            }
+        }
+    }
+    None
+}
+
+#[derive(Default)]
+struct CompletedTasks {
+    /// The most recent completed tasks, by completion order
+    tasks: BTreeMap<u64, Task>,
+    /// Where each task in `tasks` sits
+    order: HashMap<TaskId, u64>,
+    next_seq: u64,
+    ids: HashSet<TaskId>,
+    /// Task holding the result of each (session, task) pair, for remote
+    /// dependencies. Kept after the session is gone, so a task linked later
+    /// is still satisfied.
+    published: HashMap<SessionTask, TaskId>,
+    /// `published` keys, oldest first
+    published_order: VecDeque<SessionTask>,
+    /// The `published` keys each holder was recorded under
+    publishes: HashMap<TaskId, Vec<SessionTask>>,
+    /// Most tasks kept in `tasks`, and where older ones go
+    spill: Option<(usize, Arc<dyn CompletedStore>)>,
+    /// Tasks in `tasks` handed out to be written to the store
+    spilling: HashSet<TaskId>,
+    /// Tasks moved out to the store, by session, in completion order
+    evicted: HashMap<Option<SessionId>, BTreeMap<u64, TaskId>>,
+    /// Completion order and session of each evicted task
+    evicted_at: HashMap<TaskId, (u64, Option<SessionId>)>,
+}
+
+impl CompletedTasks {
+    /// Completed tasks of `session_id`, spilled ones included
+    fn ids_of(&self, session_id: SessionId) -> Vec<TaskId> {
+        let evicted = self.evicted.get(&Some(session_id)).into_iter().flat_map(|e| e.values().copied());
+        evicted
+            .chain(self.tasks.values().filter(|t| t.session_id == Some(session_id)).map(|t| t.id))
+            .collect()
+    }
+
+    fn satisfies(&self, task: &Task) -> bool {
+        task.dependencies.iter().all(|dep| self.ids.contains(dep))
+            && task.remote_dependencies.iter().all(|dep| self.published.contains_key(dep))
+    }
+
+    /// Record `task` as done, along with the tasks deduplicated into it.
+    /// Returns the oldest tasks over capacity, for `TaskQueue::spill` to
+    /// write to the store once the queue's locks are released; they stay
+    /// readable here meanwhile.
+    fn record(&mut self, task: Task, folded: &[TaskId]) -> Vec<Task> {
+        self.ids.insert(task.id);
+        self.ids.extend(folded);
+        if let Some(result) = &task.result {
+            for &task_id in std::iter::once(&task.id).chain(folded) {
+                let key = (result.session_id, task_id);
+                if self.published.insert(key, task.id).is_none() {
+                    self.published_order.push_back(key);
+                }
+                self.publishes.entry(task.id).or_default().push(key);
+            }
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.order.insert(task.id, seq);
+        self.tasks.insert(seq, task);
+
+        let Some((capacity, _)) = &self.spill else { return vec![] };
+        let excess = (self.tasks.len() - self.spilling.len()).saturating_sub(*capacity);
+        let oldest: Vec<Task> = self.tasks.values()
+            .filter(|t| !self.spilling.contains(&t.id))
+            .take(excess)
+            .cloned()
+            .collect();
+        self.spilling.extend(oldest.iter().map(|t| t.id));
+        oldest
+    }
+
+    /// Drop `task_id` from memory once the store holds it
+    fn evict(&mut self, task_id: TaskId) {
+        self.spilling.remove(&task_id);
+        // Unless it was forgotten while being written
+        let Some(seq) = self.order.remove(&task_id) else { return };
+        let Some(task) = self.tasks.remove(&seq) else { return };
+        self.evicted.entry(task.session_id).or_default().insert(seq, task_id);
+        self.evicted_at.insert(task_id, (seq, task.session_id));
+    }
+
+    fn in_memory(&self, task_id: TaskId) -> Option<Task> {
+        self.order.get(&task_id).and_then(|seq| self.tasks.get(seq)).cloned()
+    }
+
+    /// The store to read `task_id` from, if it was evicted there
+    fn store_for(&self, task_id: TaskId) -> Option<Arc<dyn CompletedStore>> {
+        let (_, store) = self.spill.as_ref().filter(|_| self.evicted_at.contains_key(&task_id))?;
+        Some(store.clone())
+    }
+
+    /// `session_id`'s evicted tasks, in completion order, and the store
+    /// holding them
+    fn evicted_of(&self, session_id: SessionId) -> (Vec<TaskId>, Option<Arc<dyn CompletedStore>>) {
+        let ids = self.evicted.get(&Some(session_id)).map(|e| e.values().copied().collect()).unwrap_or_default();
+        (ids, self.spill.as_ref().map(|(_, store)| store.clone()))
+    }
+
+    fn forget(&mut self, task_id: TaskId) -> bool {
+        if !self.ids.remove(&task_id) {
+            return false;
+        }
+        if let Some(seq) = self.order.remove(&task_id) {
+            self.tasks.remove(&seq);
+        }
+        if let Some((seq, session_id)) = self.evicted_at.remove(&task_id) {
+            if let Some(evicted) = self.evicted.get_mut(&session_id) {
+                evicted.remove(&seq);
+            }
+        }
+        for key in self.publishes.remove(&task_id).unwrap_or_default() {
+            if self.published.get(&key) == Some(&task_id) {
+                self.published.remove(&key);
+                self.published_order.retain(|k| *k != key);
+            }
+        }
+        true
+    }
+
+    /// Forget the oldest published results not in `wanted` until at most
+    /// `keep` are left
+    fn trim_published(&mut self, keep: usize, wanted: &HashSet<SessionTask>) {
+        let mut spared = VecDeque::new();
+        while self.published.len() > keep {
+            let Some(key) = self.published_order.pop_front() else { break };
+            if wanted.contains(&key) {
+                spared.push_back(key);
+                continue;
+            }
+            let Some(holder) = self.published.remove(&key) else { continue };
+            if let Some(keys) = self.publishes.get_mut(&holder) {
+                keys.retain(|k| *k != key);
+                if keys.is_empty() {
+                    self.publishes.remove(&holder);
+                }
+            }
+        }
+        spared.append(&mut self.published_order);
+        self.published_order = spared;
+    }
+}
+
+/// A spilled task read back from `store`, a failed read logged and missing
+async fn read_spilled(store: &dyn CompletedStore, task_id: TaskId) -> Option<Task> {
+    store.get(task_id).await
+        .inspect_err(|e| warn!(%task_id, error = %e, "completed task unreadable"))
+        .ok()
+        .flatten()
+}
+
+/// Where a `TaskQueue` keeps completed tasks evicted from memory; see
+/// `TaskQueue::with_completed_store`
+#[async_trait]
+pub trait CompletedStore: Send + Sync {
+    async fn put(&self, task: &Task) -> Result<(), SwarmError>;
+    async fn get(&self, task_id: TaskId) -> Result<Option<Task>, SwarmError>;
+}
+
+fn completed_key(task_id: TaskId) -> String {
+    format!("task:{}:completed", task_id)
+}
+
+fn store_error(key: String, source: impl Into<BoxError>) -> SwarmError {
+    SwarmError::StateError { key, source: source.into() }
+}
+
+/// Completed tasks as JSON strings in Redis, under `task:{id}:completed`
+pub struct RedisCompletedStore {
+    redis: Arc<RedisClient>,
+}
+
+impl RedisCompletedStore {
+    pub fn new(redis: Arc<RedisClient>) -> Self {
+        Self { redis }
+    }
+}
+
+#[async_trait]
+impl CompletedStore for RedisCompletedStore {
+    async fn put(&self, task: &Task) -> Result<(), SwarmError> {
+        let key = completed_key(task.id);
+        let json = serde_json::to_string(task).map_err(|e| store_error(key.clone(), e))?;
+        self.redis.set(&key, json).await
+    }
+
+    async fn get(&self, task_id: TaskId) -> Result<Option<Task>, SwarmError> {
+        let key = completed_key(task_id);
+        let Some(json) = self.redis.get(&key).await? else { return Ok(None) };
+        serde_json::from_str(&json).map(Some).map_err(|e| store_error(key, e))
+    }
+}
+
+/// Completed tasks as JSON files in a directory, one `{id}.json` per task
+pub struct FileCompletedStore {
+    dir: std::path::PathBuf,
+}
+
+impl FileCompletedStore {
+    /// Store under `dir`, creating it if needed
+    pub async fn open(dir: impl Into<std::path::PathBuf>) -> Result<Self, SwarmError> {
+        let dir = dir.into();
+        tokio::fs::create_dir_all(&dir).await
+            .map_err(|e| store_error(dir.display().to_string(), e))?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, task_id: TaskId) -> std::path::PathBuf {
+        self.dir.join(format!("{}.json", task_id))
+    }
+}
+
+#[async_trait]
+impl CompletedStore for FileCompletedStore {
+    async fn put(&self, task: &Task) -> Result<(), SwarmError> {
+        let path = self.path(task.id);
+        let error = |e: BoxError| store_error(path.display().to_string(), e);
+        let json = serde_json::to_vec(task).map_err(|e| error(e.into()))?;
+        tokio::fs::write(&path, json).await.map_err(|e| error(e.into()))
+    }
+
+    async fn get(&self, task_id: TaskId) -> Result<Option<Task>, SwarmError> {
+        let path = self.path(task_id);
+        let error = |e: BoxError| store_error(path.display().to_string(), e);
+        let json = match tokio::fs::read(&path).await {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(error(e.into())),
+        };
+        serde_json::from_slice(&json).map(Some).map_err(|e| error(e.into()))
+    }
+}
+
+/// Heap entry ordering tasks by priority, then shortest estimate, then FIFO
+struct QueuedTask {
+    task: Task,
+    seq: u64,
+    // Retry backoff: not handed out before this instant
+    eligible_at: Option<Instant>,
+    // When it became ready to run, for priority aging; `None` until a
+    // dequeue finds it is
+    ready_since: Option<Instant>,
+}
+
+impl QueuedTask {
+    fn is_ready(&self, completed: &CompletedTasks, now: Instant) -> bool {
+        self.eligible_at.is_none_or(|at| at <= now) && completed.satisfies(&self.task)
+    }
+
+    /// Priority level plus one for every `per_level` waited since the task
+    /// became ready to run
+    fn aged_level(&self, now: Instant, per_level: Duration) -> u128 {
+        let waited = self.ready_since.map_or(Duration::ZERO, |since| now.saturating_duration_since(since));
+        self.task.priority as u128 + waited.as_nanos() / per_level.as_nanos().max(1)
+    }
+
+    /// `Ord` with aged levels in place of priorities
+    fn aged_cmp(&self, other: &Self, now: Instant, per_level: Duration) -> Ordering {
+        self.aged_level(now, per_level)
+            .cmp(&other.aged_level(now, per_level))
+            .then_with(|| self.cmp_within_level(other))
+    }
+
+    fn cmp_within_level(&self, other: &Self) -> Ordering {
+        other.task.estimated_time_min
+            .total_cmp(&self.task.estimated_time_min)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl Ord for QueuedTask {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.task.priority
+            .cmp(&other.task.priority)
+            .then_with(|| self.cmp_within_level(other))
+    }
+}
+
+impl PartialOrd for QueuedTask {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for QueuedTask {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for QueuedTask {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Task {
+    pub id: TaskId,
+    pub description: String,
+    pub estimated_time_min: f64,
+    pub dependencies: Vec<TaskId>,
+    pub assigned_to: Option<AgentId>,
+    /// Session whose plan this task belongs to, set by `submit_plan`
+    #[serde(default)]
+    pub session_id: Option<SessionId>,
+    #[serde(default)]
+    pub priority: TaskPriority,
+    #[serde(default)]
+    pub retry_policy: RetryPolicy,
+    // Failed attempts so far
+    #[serde(default)]
+    pub attempts: usize,
+    /// Delay before the task's latest retry, for `JitterStrategy::Decorrelated`
+    #[serde(default)]
+    pub last_retry_delay_ms: Option<u64>,
+    /// For a verification task, the coder task whose result it checks
+    #[serde(default)]
+    pub verifies: Option<Box<Task>>,
+    /// When the current attempt moved to `in_progress`
+    #[serde(default)]
+    pub started_at: Option<DateTime<Utc>>,
+    /// Fired by `SessionManager::cancel_task`; shared by clones of the task
+    #[serde(skip)]
+    pub cancellation: CancellationToken,
+    /// Work product, once an agent has completed the task
+    #[serde(default)]
+    pub result: Option<TaskResult>,
+    /// What makes two tasks equivalent for deduplication, instead of their
+    /// description
+    #[serde(default)]
+    pub dedup_key: Option<String>,
+    /// Tasks in other sessions whose results this one waits for, as
+    /// `(session, task)` pairs; see `SessionManager::link_dependency`
+    #[serde(default)]
+    pub remote_dependencies: Vec<SessionTask>,
+    /// Skills the agent running this task should have; `dispatch_ready`
+    /// prefers coders covering them over other coders
+    #[serde(default)]
+    pub required_skills: Vec<String>,
+    /// Model features the task can't run without; only coders whose
+    /// `capabilities` include them are given it
+    #[serde(default)]
+    pub required_capabilities: Vec<Capability>,
+    /// Most the task may spend across all its attempts, verifications
+    /// included. A streamed attempt is cut off once it passes it, and the
+    /// task is dead-lettered; it's never retried once another attempt,
+    /// costing what the earlier ones did on average, wouldn't fit.
+    #[serde(default)]
+    pub max_cost_usd: Option<f64>,
+    /// Spent on the task so far, over all attempts and their verifications
+    #[serde(default)]
+    pub cost_incurred: f64,
+    /// Tasks sharing expensive context (a file, a research corpus) share a
+    /// key; `dispatch_ready` sends each to the coder that last ran that key
+    /// while it's idle
+    #[serde(default)]
+    pub affinity_key: Option<String>,
+    /// Quality the answer needs; when set, the task runs on the cheapest
+    /// model meeting it (see `ModelClients::select_model`) rather than its
+    /// agent's own model
+    #[serde(default)]
+    pub quality: Option<QualityTier>,
+    /// JSON Schema the output must satisfy, as a JSON document (optionally
+    /// in a ``` fence); an output that doesn't counts as a retriable
+    /// failure, `SwarmError::ResultSchemaViolation`. Compiled on enqueue,
+    /// where a schema that doesn't compile is an `InvalidSpec`.
+    #[serde(default)]
+    pub result_schema: Option<serde_json::Value>,
+    /// `result_schema`, compiled; shared by clones of the task
+    #[serde(skip)]
+    compiled_schema: Option<Arc<jsonschema::JSONSchema>>,
+}
+
+/// What a completed task produced, and where
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TaskResult {
+    pub task_id: TaskId,
+    pub session_id: SessionId,
+    pub agent_id: AgentId,
+    /// Model that actually served the call, after any fallback
+    pub model: ModelPreference,
+    pub output: String,
+    /// Cost of producing the output, excluding verification
+    pub cost: f64,
+    pub completed_at: DateTime<Utc>,
+}
+
+impl Task {
+    pub fn new(description: impl Into<String>, estimated_time_min: f64) -> Self {
+        Self {
+            id: TaskId::new_v4(),
+            description: description.into(),
+            estimated_time_min,
+            dependencies: vec![],
+            assigned_to: None,
+            session_id: None,
+            priority: TaskPriority::Normal,
+            retry_policy: RetryPolicy::default(),
+            attempts: 0,
+            last_retry_delay_ms: None,
+            verifies: None,
+            started_at: None,
+            cancellation: CancellationToken::new(),
+            result: None,
+            dedup_key: None,
+            remote_dependencies: vec![],
+            required_skills: vec![],
+            required_capabilities: vec![],
+            max_cost_usd: None,
+            cost_incurred: 0.0,
+            affinity_key: None,
+            quality: None,
+            result_schema: None,
+            compiled_schema: None,
+        }
+    }
+
+    /// Compile `result_schema`, unless it already is
+    fn compile_result_schema(&mut self) -> Result<(), SwarmError> {
+        if let (Some(schema), None) = (&self.result_schema, &self.compiled_schema) {
+            self.compiled_schema = Some(Arc::new(Self::compile_schema(self.id, schema)?));
+        }
+        Ok(())
+    }
+
+    fn compile_schema(task_id: TaskId, schema: &serde_json::Value) -> Result<jsonschema::JSONSchema, SwarmError> {
+        jsonschema::JSONSchema::compile(schema)
+            .map_err(|e| SwarmError::InvalidSpec(format!("result_schema of task {task_id} doesn't compile: {e}")))
+    }
+
+    /// Check `output` against `result_schema`, collecting every violation.
+    /// A task that didn't come through `TaskQueue::enqueue` has its schema
+    /// compiled here.
+    fn check_result(&self, output: &str) -> Result<(), SwarmError> {
+        let Some(schema) = &self.result_schema else { return Ok(()) };
+        let violation = |errors| SwarmError::ResultSchemaViolation { task_id: self.id, errors };
+
+        let text = output.trim();
+        let text = text
+            .strip_prefix("```")
+            .and_then(|fenced| fenced.strip_suffix("```"))
+            .map_or(text, |fenced| fenced.trim_start_matches("json").trim());
+        let value: serde_json::Value = serde_json::from_str(text)
+            .map_err(|e| violation(vec![format!("output is not JSON: {e}")]))?;
+        let compiled = match &self.compiled_schema {
+            Some(compiled) => compiled.clone(),
+            None => Arc::new(Self::compile_schema(self.id, schema)?),
+        };
+        compiled.validate(&value).map_err(|errors| {
+            violation(errors.map(|e| format!("{}: {e}", e.instance_path)).collect())
+        })
+    }
+
+    fn over_cost_ceiling(&self) -> bool {
+        self.over_cost_ceiling_after(0.0)
+    }
+
+    /// Whether spending `cost` more takes the task over `max_cost_usd`
+    fn over_cost_ceiling_after(&self, cost: f64) -> bool {
+        self.max_cost_usd.is_some_and(|limit| self.cost_incurred + cost > limit)
+    }
+
+    /// Whether another attempt fits under `max_cost_usd`, expecting it to
+    /// cost what the failed ones did on average
+    fn can_afford_retry(&self) -> bool {
+        !self.over_cost_ceiling_after(self.cost_incurred / self.attempts.max(1) as f64)
+    }
+
+    /// Identity for deduplication within a session, if the task can be shared.
+    /// Verifications never are: each checks its own original.
+    fn dedup_hash(&self) -> Option<(SessionId, [u8; 32])> {
+        let session_id = self.session_id.filter(|_| self.verifies.is_none())?;
+        let (tag, content) = match &self.dedup_key {
+            Some(key) => (1, key),
+            None => (0, &self.description),
+        };
+        let mut hasher = Sha256::new();
+        hasher.update([tag]);
+        hasher.update(content.as_bytes());
+        Some((session_id, hasher.finalize().into()))
+    }
+
+    /// A task asking a verifier to check `original`'s `output`
+    fn verification(id: TaskId, original: Task, output: &str) -> Self {
+        let mut check = Task::new(
+            format!(
+                "Verify the result of this task. End your answer with a line reading PASS or FAIL.\n\n\
+                 Task:\n{}\n\nResult:\n{}",
+                original.description, output,
+            ),
+            original.estimated_time_min,
+        );
+        check.id = id;
+        check.session_id = original.session_id;
+        check.priority = original.priority;
+        check.verifies = Some(Box::new(original));
+        check
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /// Total attempts including the first; 1 disables retries
+    pub max_attempts: usize,
+    pub base_delay_ms: u64,
+    /// Randomizes each delay, so tasks failed by the same outage don't all
+    /// retry at once
+    #[serde(default)]
+    pub jitter: JitterStrategy,
+}
+
+/// How `RetryPolicy::retry_delay` randomizes the exponential backoff
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JitterStrategy {
+    /// Exactly the backoff
+    None,
+    /// Anywhere from zero to the backoff
+    #[default]
+    Full,
+    /// At least half the backoff, the rest at random
+    Equal,
+    /// Between the base delay and three times the previous delay, up to the
+    /// longest backoff
+    Decorrelated,
+}
+
+impl RetryPolicy {
+    /// Delay before retry number `attempt` (1-based), before jitter:
+    /// base * 2^(attempt - 1)
+    pub fn backoff(&self, attempt: usize) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(16) as u32;
+        Duration::from_millis(self.base_delay_ms.saturating_mul(1 << exponent))
+    }
+
+    /// `backoff(attempt)` with `jitter` applied. `previous` is the delay
+    /// before the last retry, which `Decorrelated` grows from.
+    pub fn retry_delay(&self, attempt: usize, previous: Option<Duration>) -> Duration {
+        let backoff = self.backoff(attempt).as_millis() as u64;
+        let millis = match self.jitter {
+            JitterStrategy::None => backoff,
+            JitterStrategy::Full => random_between(0, backoff),
+            JitterStrategy::Equal => backoff / 2 + random_between(0, backoff - backoff / 2),
+            JitterStrategy::Decorrelated => {
+                let previous = previous.map_or(self.base_delay_ms, |d| d.as_millis() as u64);
+                let cap = self.backoff(usize::MAX).as_millis() as u64;
+                random_between(self.base_delay_ms, previous.saturating_mul(3).max(self.base_delay_ms)).min(cap)
+            }
+        };
+        Duration::from_millis(millis)
+    }
+}
+
+/// Uniform in `low..=high`
+fn random_between(low: u64, high: u64) -> u64 {
+    rand::Rng::gen_range(&mut rand::thread_rng(), low..=high)
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self { max_attempts: 3, base_delay_ms: 500, jitter: JitterStrategy::Full }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FailureOutcome {
+    Retrying { attempt: usize, delay: Duration },
+    DeadLettered,
+}
+
+/// A task taken back from an agent by `TaskQueue::reclaim_stale` or
+/// `TaskQueue::reclaim_from`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReclaimedTask {
+    pub task_id: TaskId,
+    /// Agent it was claimed by, if any
+    pub agent_id: Option<AgentId>,
+    pub outcome: FailureOutcome,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum TaskPriority {
+    Low,
+    #[default]
+    Normal,
+    High,
+    Critical,  // Critical path, jumps the queue
+}
+
+/// External feed of tasks, e.g. a Kafka topic or a database outbox; see
+/// `SessionManager::spawn_task_source`
+#[async_trait]
+pub trait TaskSource: Send + Sync {
+    /// Wait for the next task; `None` once the source is exhausted
+    async fn next_task(&self) -> Option<Task>;
+}
+
+/// Hook around every task a `SessionManager` runs, e.g. scrubbing PII from
+/// a description before it reaches a model, or validating a result; see
+/// `SessionManager::with_task_interceptor`. Both hooks default to doing
+/// nothing.
+pub trait TaskInterceptor: Send + Sync {
+    /// Called on each attempt (verifications included) before the task goes
+    /// to its agent; the agent sees the task as changed here
+    fn before(&self, _task: &mut Task) {}
+
+    /// Called on each completed attempt's result before it is verified or
+    /// stored
+    fn after(&self, _task: &Task, _result: &mut TaskResult) {}
+}
+
+/// Hands out a fixed list of tasks, in order
+#[derive(Default)]
+pub struct VecTaskSource {
+    tasks: Mutex<VecDeque<Task>>,
+}
+
+impl VecTaskSource {
+    pub fn new(tasks: Vec<Task>) -> Self {
+        Self { tasks: Mutex::new(tasks.into()) }
+    }
+}
+
+#[async_trait]
+impl TaskSource for VecTaskSource {
+    async fn next_task(&self) -> Option<Task> {
+        self.tasks.lock().await.pop_front()
+    }
+}
+
+// ============================================================================
+// MODEL CLIENTS
+// ============================================================================
+
+/// A model API backend (OpenAI, Anthropic, Google, or a test double)
+#[async_trait]
+pub trait ModelProvider: Send + Sync {
+    async fn complete(
+        &self,
+        model: ModelPreference,
+        prompt: &str,
+    ) -> Result<ModelResponse, SwarmError>;
+
+    /// Stream a completion as it is generated. Providers that can't stream
+    /// answer with the whole completion as one chunk.
+    async fn complete_stream(
+        &self,
+        model: ModelPreference,
+        prompt: &str,
+    ) -> Result<ChunkStream, SwarmError> {
+        let ModelResponse { text, usage, .. } = self.complete(model, prompt).await?;
+        Ok(Box::pin(futures::stream::once(async move { Ok(ModelChunk { text, usage: Some(usage) }) })))
+    }
+
+    /// What `model` supports through this provider, asked once per agent
+    /// at spawn. Defaults to `Capabilities::of`.
+    fn capabilities(&self, model: ModelPreference) -> Capabilities {
+        Capabilities::of(model)
+    }
+}
+
+/// A model feature a task may depend on; see `Task::required_capabilities`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Capability {
+    Streaming,
+    Tools,
+    Vision,
+}
+
+/// What a model backend supports, discovered when an agent spawns
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub supports_streaming: bool,
+    pub max_context_tokens: u64,
+    pub supports_tools: bool,
+    pub supports_vision: bool,
+}
+
+impl Capabilities {
+    /// The published feature set of `model`. `ModelPreference::None`
+    /// supports nothing.
+    pub fn of(model: ModelPreference) -> Self {
+        let max_context_tokens = match model {
+            ModelPreference::GPT51 => 400_000,
+            ModelPreference::ClaudeOpus45 => 200_000,
+            ModelPreference::Gemini3Pro => 1_000_000,
+            ModelPreference::None => return Self::default(),
+        };
+        Self { supports_streaming: true, max_context_tokens, supports_tools: true, supports_vision: true }
+    }
+
+    pub fn has(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::Streaming => self.supports_streaming,
+            Capability::Tools => self.supports_tools,
+            Capability::Vision => self.supports_vision,
+        }
+    }
+
+    /// The first of `required` these capabilities lack
+    pub fn missing(&self, required: &[Capability]) -> Option<Capability> {
+        required.iter().copied().find(|c| !self.has(*c))
+    }
+
+    /// What both these and `other` support
+    pub fn common(self, other: Self) -> Self {
+        Self {
+            supports_streaming: self.supports_streaming && other.supports_streaming,
+            max_context_tokens: self.max_context_tokens.min(other.max_context_tokens),
+            supports_tools: self.supports_tools && other.supports_tools,
+            supports_vision: self.supports_vision && other.supports_vision,
+        }
+    }
+}
+
+/// Part of a streamed completion
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModelChunk {
+    pub text: String,
+    /// Usage for the whole call, on the chunk that reports it (usually the
+    /// last). Estimated from the text when no chunk does.
+    pub usage: Option<TokenUsage>,
+}
+
+/// A provider's streamed completion
+/// Decides whether a failed call is worth retrying; see `ModelClients::with_retry_classifier`
+pub type RetryClassifier = Arc<dyn Fn(&SwarmError) -> bool + Send + Sync>;
+
+pub type ChunkStream = std::pin::Pin<Box<dyn futures::Stream<Item = Result<ModelChunk, SwarmError>> + Send>>;
+
+/// Placeholder provider: echoes the prompt and estimates usage at ~4 chars/token.
+pub struct EchoProvider;
+
+#[async_trait]
+impl ModelProvider for EchoProvider {
+    async fn complete(
+        &self,
+        model: ModelPreference,
+        prompt: &str,
+    ) -> Result<ModelResponse, SwarmError> {
+        let tokens = estimate_tokens(prompt);
+        Ok(ModelResponse {
+            text: format!("[{:?}] {}", model, prompt),
+            usage: TokenUsage { input_tokens: tokens, output_tokens: tokens },
+            model,
+        })
+    }
+}
+
+/// Rough token count at ~4 chars/token, for budgeting before a call
+fn estimate_tokens(text: &str) -> u64 {
+    (text.len() as u64).div_ceil(4).max(1)
+}
+
+/// How long a cached completion stays servable by default
+pub const DEFAULT_PROMPT_CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Longest a provider call may take before it's abandoned with
+/// `SwarmError::ModelTimeout`, for models without their own
+/// `ModelClients::with_timeout`
+pub const DEFAULT_MODEL_TIMEOUT: Duration = Duration::from_secs(120);
+
+pub struct ModelClients {
+    provider: Arc<dyn ModelProvider>,
+    cache: PromptCache,
+    limiter: RateLimiter,
+    /// Each capped session's own request budget, spent before the global one
+    session_rates: std::sync::RwLock<HashMap<SessionId, Arc<SessionRate>>>,
+    concurrency: HashMap<ModelPreference, ConcurrencyLimit>,
+    breakers: HashMap<ModelPreference, CircuitBreaker>,
+    audit: Arc<dyn AuditSink>,
+    /// Keep prompts and responses in audit records, not just their hashes
+    audit_full_text: bool,
+    /// Set by the `AgentPool` using these clients
+    metrics: std::sync::RwLock<MetricsRegistry>,
+    retriable: RetryClassifier,
+    /// Quality of each model `select_model` may pick
+    quality: HashMap<ModelPreference, QualityTier>,
+    /// Per-model overrides of `DEFAULT_MODEL_TIMEOUT`
+    timeouts: HashMap<ModelPreference, Duration>,
+    /// Per-model overrides of the provider's `capabilities`
+    capabilities: HashMap<ModelPreference, Capabilities>,
+}
+
+/// Cap on simultaneous calls to one model's provider
+struct ConcurrencyLimit {
+    max: usize,
+    permits: Semaphore,
+}
+
+/// Counts a provider call as in flight until dropped, including when the
+/// calling task is cancelled mid-call
+struct InFlightCall {
+    metrics: MetricsRegistry,
+    model: ModelPreference,
+}
+
+impl Drop for InFlightCall {
+    fn drop(&mut self) {
+        self.metrics.model_call_finished(self.model);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CircuitState {
+    /// Calls go through; failures are being counted
+    Closed,
+    /// Calls are refused until the cooldown ends
+    Open,
+    /// One probe call is deciding whether to close again
+    HalfOpen,
+}
+
+/// Stops calling a model's provider after `failure_threshold` consecutive
+/// retriable failures. Calls are refused with `SwarmError::CircuitOpen` for
+/// `cooldown`, after which a single probe is let through: success closes the
+/// breaker, failure opens it for another cooldown.
+pub struct CircuitBreaker {
+    failure_threshold: usize,
+    cooldown: Duration,
+    state: std::sync::Mutex<BreakerState>,
+}
+
+enum BreakerState {
+    Closed { failures: usize },
+    Open { since: Instant },
+    // A probe that never reports back (e.g. cancelled) is replaced after a cooldown
+    HalfOpen { probe_started: Instant },
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: usize, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold: failure_threshold.max(1),
+            cooldown,
+            state: std::sync::Mutex::new(BreakerState::Closed { failures: 0 }),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        match *self.lock() {
+            BreakerState::Closed { .. } => CircuitState::Closed,
+            BreakerState::Open { .. } => CircuitState::Open,
+            BreakerState::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BreakerState> {
+        self.state.lock().expect("circuit breaker lock poisoned")
+    }
+
+    /// Whether a call may go ahead now; past the cooldown, the caller becomes the probe
+    fn allow(&self) -> bool {
+        let mut state = self.lock();
+        match *state {
+            BreakerState::Closed { .. } => true,
+            BreakerState::Open { since: started } | BreakerState::HalfOpen { probe_started: started } => {
+                if started.elapsed() < self.cooldown {
+                    return false;
+                }
+                *state = BreakerState::HalfOpen { probe_started: Instant::now() };
+                true
+            }
+        }
+    }
+
+    fn record_success(&self) {
+        *self.lock() = BreakerState::Closed { failures: 0 };
+    }
+
+    /// Count a failed call. Returns whether it opened the breaker.
+    fn record_failure(&self) -> bool {
+        let mut state = self.lock();
+        match *state {
+            BreakerState::Closed { failures } if failures + 1 < self.failure_threshold => {
+                *state = BreakerState::Closed { failures: failures + 1 };
+                false
+            }
+            BreakerState::Closed { .. } | BreakerState::HalfOpen { .. } => {
+                *state = BreakerState::Open { since: Instant::now() };
+                true
+            }
+            // A call from before the breaker opened; keep the cooldown as is
+            BreakerState::Open { .. } => false,
+        }
+    }
+}
+
+impl Default for ModelClients {
+    fn default() -> Self {
+        Self::with_provider(Arc::new(EchoProvider))
+    }
+}
+
+impl ModelClients {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_provider(provider: Arc<dyn ModelProvider>) -> Self {
+        Self {
+            provider,
+            cache: PromptCache::new(DEFAULT_PROMPT_CACHE_TTL),
+            limiter: RateLimiter::unlimited(),
+            session_rates: std::sync::RwLock::new(HashMap::new()),
+            concurrency: HashMap::new(),
+            breakers: HashMap::new(),
+            audit: Arc::new(NoopAuditSink),
+            audit_full_text: false,
+            metrics: std::sync::RwLock::new(MetricsRegistry::new()),
+            retriable: Arc::new(SwarmError::is_retriable),
+            quality: HashMap::from([
+                (ModelPreference::GPT51, QualityTier::Standard),
+                (ModelPreference::ClaudeOpus45, QualityTier::High),
+                (ModelPreference::Gemini3Pro, QualityTier::Standard),
+            ]),
+            timeouts: HashMap::new(),
+            capabilities: HashMap::new(),
+        }
+    }
+
+    /// Report `capabilities` for `model` instead of asking the provider,
+    /// e.g. for a deployment with streaming turned off
+    pub fn with_capabilities(mut self, model: ModelPreference, capabilities: Capabilities) -> Self {
+        self.capabilities.insert(model, capabilities);
+        self
+    }
+
+    /// What `model` supports, for an agent spawning on it: only what every
+    /// model along `model.fallback_chain()` supports, since any of them may
+    /// end up serving the agent's calls
+    pub fn capabilities(&self, model: ModelPreference) -> Capabilities {
+        let of = |m: ModelPreference| self.capabilities.get(&m).copied().unwrap_or_else(|| self.provider.capabilities(m));
+        model.fallback_chain().iter().map(|&m| of(m)).reduce(Capabilities::common).unwrap_or_default()
+    }
+
+    /// Give up on calls to `model` after `timeout` instead of
+    /// `DEFAULT_MODEL_TIMEOUT`, e.g. longer for a slow reasoning model. A
+    /// stream's timeout covers the whole stream, not each chunk.
+    pub fn with_timeout(mut self, model: ModelPreference, timeout: Duration) -> Self {
+        self.timeouts.insert(model, timeout);
+        self
+    }
+
+    /// How long a call to `model` may take
+    pub fn timeout(&self, model: ModelPreference) -> Duration {
+        self.timeouts.get(&model).copied().unwrap_or(DEFAULT_MODEL_TIMEOUT)
+    }
+
+    /// `call`, failed with `ModelTimeout` if it outlasts `model`'s timeout
+    async fn timed<T>(
+        &self,
+        model: ModelPreference,
+        call: impl std::future::Future<Output = Result<T, SwarmError>>,
+    ) -> Result<T, SwarmError> {
+        let after = self.timeout(model);
+        tokio::time::timeout(after, call)
+            .await
+            .unwrap_or(Err(SwarmError::ModelTimeout { model, after, partial: String::new() }))
+    }
+
+    /// Rate `model`'s answers at `tier` for `select_model`, adding it to the
+    /// models it picks from
+    pub fn with_model_quality(mut self, model: ModelPreference, tier: QualityTier) -> Self {
+        self.quality.insert(model, tier);
+        self
+    }
+
+    /// The cheapest model rated at least `required_quality`, by its cost for
+    /// an average action; ties go to `role`'s usual model. Falls back to the
+    /// best-rated model when none is good enough. Browser agents never call a
+    /// model, so get `ModelPreference::None`.
+    pub fn select_model(&self, required_quality: QualityTier, role: AgentRole) -> ModelPreference {
+        if role == AgentRole::Browser {
+            return ModelPreference::None;
+        }
+        let usual = ModelRouter::default_model(role);
+        let cost = |model: ModelPreference| Self::cost_of(model, &AVG_USAGE_PER_ACTION);
+        let cheaper = |a: &ModelPreference, b: &ModelPreference| {
+            cost(*a).total_cmp(&cost(*b)).then_with(|| (*b == usual).cmp(&(*a == usual)))
+        };
+
+        let mut candidates: Vec<(ModelPreference, QualityTier)> = self.quality.iter()
+            .filter(|(model, _)| **model != ModelPreference::None)
+            .map(|(model, tier)| (*model, *tier))
+            .collect();
+        // HashMap order varies; keep picks deterministic
+        candidates.sort_by(|a, b| cheaper(&a.0, &b.0));
+        candidates.iter()
+            .find(|(_, tier)| *tier >= required_quality)
+            .or_else(|| candidates.iter().max_by_key(|(_, tier)| *tier))
+            .map_or(usual, |(model, _)| *model)
+    }
+
+    /// Replace `SwarmError::is_retriable` as the judge of which failures
+    /// fall back to the next model and which tasks get another attempt, e.g.
+    /// for a provider whose errors carry no HTTP status
+    pub fn with_retry_classifier(
+        mut self,
+        classifier: impl Fn(&SwarmError) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.retriable = Arc::new(classifier);
+        self
+    }
+
+    /// Whether `err` is worth retrying, by the configured classifier
+    pub fn is_retriable(&self, err: &SwarmError) -> bool {
+        (self.retriable)(err)
+    }
+
+    /// Allow at most `max_concurrent` calls to `model`'s provider at once,
+    /// however many agents use it; further calls wait their turn
+    pub fn with_max_concurrent(mut self, model: ModelPreference, max_concurrent: usize) -> Self {
+        let max = max_concurrent.max(1);
+        self.concurrency.insert(model, ConcurrencyLimit { max, permits: Semaphore::new(max) });
+        self
+    }
+
+    /// Calls to `model` currently holding a concurrency permit, if it has a limit
+    pub fn permits_in_use(&self, model: ModelPreference) -> Option<usize> {
+        self.concurrency.get(&model).map(|limit| limit.max - limit.permits.available_permits())
+    }
+
+    /// Trip a circuit breaker on `model` after `failure_threshold` consecutive
+    /// failures, refusing calls to it for `cooldown` (see `CircuitBreaker`)
+    pub fn with_circuit_breaker(
+        mut self,
+        model: ModelPreference,
+        failure_threshold: usize,
+        cooldown: Duration,
+    ) -> Self {
+        self.breakers.insert(model, CircuitBreaker::new(failure_threshold, cooldown));
+        self
+    }
+
+    /// Record every agent model call to `sink` (see `complete_for`)
+    pub fn with_audit_sink(mut self, sink: Arc<dyn AuditSink>) -> Self {
+        self.audit = sink;
+        self
+    }
+
+    /// Store full prompts and responses in audit records. Off by default,
+    /// so records only carry hashes.
+    pub fn with_audit_full_text(mut self, full_text: bool) -> Self {
+        self.audit_full_text = full_text;
+        self
+    }
+
+    /// State of `model`'s circuit breaker, if it has one
+    pub fn circuit_state(&self, model: ModelPreference) -> Option<CircuitState> {
+        self.breakers.get(&model).map(CircuitBreaker::state)
+    }
+
+    fn report_to(&self, metrics: MetricsRegistry) {
+        for (&model, breaker) in &self.breakers {
+            metrics.circuit_state_changed(model, breaker.state());
+        }
+        *self.metrics.write().expect("metrics lock poisoned") = metrics;
+    }
+
+    fn metrics(&self) -> MetricsRegistry {
+        self.metrics.read().expect("metrics lock poisoned").clone()
+    }
+
+    fn call_started(&self, model: ModelPreference) -> InFlightCall {
+        let metrics = self.metrics();
+        metrics.model_call_started(model);
+        InFlightCall { metrics, model }
+    }
+
+    /// Feed a provider call's outcome to `model`'s breaker, if it has one
+    fn record_outcome(&self, model: ModelPreference, failed: bool) {
+        let Some(breaker) = self.breakers.get(&model) else {
+            return;
+        };
+        if !failed {
+            breaker.record_success();
+        } else if breaker.record_failure() {
+            warn!(?model, "circuit breaker opened");
+        }
+        self.metrics().circuit_state_changed(model, breaker.state());
+    }
+
+    /// Throttle calls per model to stay under provider rate limits
+    pub fn with_rate_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.limiter = limiter;
+        self
+    }
+
+    /// Replace the prompt cache, e.g. to change its TTL
+    pub fn with_prompt_cache(mut self, cache: PromptCache) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Drop the cached answer to `prompt`, so the next call asks `model`
+    /// again; for answers found unusable after the fact
+    pub async fn forget_cached(&self, model: ModelPreference, prompt: &str) {
+        self.cache.remove(model, prompt).await;
+    }
+
+    /// (input, output) price in USD per token
+    pub fn cost_per_token(model: ModelPreference) -> (f64, f64) {
+        match model {
+            ModelPreference::GPT51 => (1.25e-6, 10.0e-6),
+            ModelPreference::ClaudeOpus45 => (5.0e-6, 25.0e-6),
+            ModelPreference::Gemini3Pro => (2.0e-6, 12.0e-6),
+            ModelPreference::None => (0.0, 0.0),
+        }
+    }
+
+    pub fn cost_of(model: ModelPreference, usage: &TokenUsage) -> f64 {
+        let (input, output) = Self::cost_per_token(model);
+        usage.input_tokens as f64 * input + usage.output_tokens as f64 * output
+    }
+
+    /// Run a single completion against `model`, serving identical recent
+    /// prompts from the cache and otherwise falling back along
+    /// `model.fallback_chain()` on retriable errors and open circuit
+    /// breakers. The response records which model served it. If every model
+    /// in the chain has an open breaker, fails fast with `CircuitOpen`.
+    ///
+    /// A provider call outlasting its model's `timeout` is abandoned and
+    /// counts as a retriable failure, so it falls back too.
+    pub async fn complete(
+        &self,
+        model: ModelPreference,
+        prompt: &str,
+    ) -> Result<CachedResponse, SwarmError> {
+        self.complete_as(None, model, prompt).await
+    }
+
+    /// `complete`, auditing each failed attempt on behalf of `caller`
+    async fn complete_as(
+        &self,
+        caller: Option<(SessionId, AgentId)>,
+        model: ModelPreference,
+        prompt: &str,
+    ) -> Result<CachedResponse, SwarmError> {
+        if let Some(response) = self.cache.get(model, prompt).await {
+            return Ok(CachedResponse { response, hit: true, throttled: Duration::ZERO });
+        }
+
+        let served = self.first_served(model, prompt, caller, |candidate| self.provider.complete(candidate, prompt)).await?;
+        self.record_outcome(served.model, false);
+        let Served { model: candidate, value: mut response, throttled, .. } = served;
+        let used = response.usage.input_tokens + response.usage.output_tokens;
+        self.limiter.settle(candidate, estimate_tokens(prompt), used).await;
+        response.model = candidate;
+        self.cache.insert(model, prompt, response.clone()).await;
+        Ok(CachedResponse { response, hit: false, throttled })
+    }
+
+    /// Make `call` to the first model along `model.fallback_chain()` that
+    /// takes it: models with an open breaker are skipped, the rate limiter
+    /// reserves `prompt`'s estimated tokens, and the call holds a
+    /// concurrency permit and counts as in flight until the returned
+    /// `Served` drops. A retriable failure counts against the model's
+    /// breaker and falls back; any other fails the call. Each failed attempt
+    /// is audited on behalf of `caller`, if there is one. The caller records
+    /// the served model's success once it's sure of it.
+    async fn first_served<'a, T, F, Fut>(
+        &'a self,
+        model: ModelPreference,
+        prompt: &str,
+        caller: Option<(SessionId, AgentId)>,
+        mut call: F,
+    ) -> Result<Served<'a, T>, SwarmError>
+    where
+        F: FnMut(ModelPreference) -> Fut,
+        Fut: std::future::Future<Output = Result<T, SwarmError>>,
+    {
+        let estimate = estimate_tokens(prompt);
+        let chain = model.fallback_chain();
+        let mut throttled = Duration::ZERO;
+        let mut last_error = None;
+        let mut short_circuited = 0;
+
+        for &candidate in chain {
+            if let Some(breaker) = self.breakers.get(&candidate) {
+                let allowed = breaker.allow();
+                self.metrics().circuit_state_changed(candidate, breaker.state());
+                if !allowed {
+                    short_circuited += 1;
+                    last_error = Some(Box::new(SwarmError::CircuitOpen(candidate)));
+                    continue;
+                }
+            }
+
+            match self.limiter.acquire(candidate, estimate).await {
+                Ok(waited) => throttled += waited,
+                Err(e) => {
+                    last_error = Some(Box::new(e));
+                    continue;
+                }
+            }
+
+            // Released before falling back
+            let permit = match self.concurrency.get(&candidate) {
+                Some(limit) => Some(limit.permits.acquire().await.expect("concurrency semaphore is never closed")),
+                None => None,
+            };
+            let in_flight = self.call_started(candidate);
+
+            let result = self.timed(candidate, call(candidate)).await;
+            if let (Err(e), Some((session_id, agent_id))) = (&result, caller) {
+                self.audit_failure(session_id, agent_id, candidate, prompt, e).await;
+            }
+            match result {
+                Ok(value) => return Ok(Served { model: candidate, value, throttled, _permit: permit, _in_flight: in_flight }),
+                Err(e) if self.is_retriable(&e) => {
+                    self.record_outcome(candidate, true);
+                    last_error = Some(Box::new(e));
+                }
+                // Not the model's fault, so no outcome for its breaker either way
+                Err(e) => return Err(e),
+            }
+        }
+
+        if short_circuited == chain.len() {
+            return Err(SwarmError::CircuitOpen(model));
+        }
+        Err(SwarmError::AllModelsFailed {
+            tried: chain.to_vec(),
+            source: last_error,
+        })
+    }
+
+    /// `complete`, yielding the answer's text in chunks as the provider
+    /// produces them. Falls back along the chain only until the first chunk
+    /// arrives; a stream that breaks later ends with its error.
+    pub fn complete_stream<'a>(
+        &'a self,
+        model: ModelPreference,
+        prompt: &'a str,
+    ) -> impl futures::Stream<Item = Result<String, SwarmError>> + Send + 'a {
+        enum State<'a> {
+            Opening,
+            Open(ModelStream<'a>),
+            Done,
+        }
+
+        futures::stream::unfold(State::Opening, move |state| async move {
+            let mut stream = match state {
+                State::Opening => match self.open_stream(model, prompt).await {
+                    Ok(stream) => stream,
+                    Err(e) => return Some((Err(e), State::Done)),
+                },
+                State::Open(stream) => stream,
+                State::Done => return None,
+            };
+            match stream.next_chunk().await {
+                Some(Ok(chunk)) => Some((Ok(chunk), State::Open(stream))),
+                Some(Err(e)) => Some((Err(e), State::Done)),
+                None => {
+                    stream.finish().await;
+                    None
+                }
+            }
+        })
+    }
+
+    /// Start a streamed completion: a cache hit, or the first model along
+    /// `model.fallback_chain()` that accepts the call
+    pub async fn open_stream<'a>(
+        &'a self,
+        model: ModelPreference,
+        prompt: &'a str,
+    ) -> Result<ModelStream<'a>, SwarmError> {
+        self.open_stream_as(None, model, prompt).await
+    }
+
+    /// `open_stream`, auditing each failed attempt on behalf of `caller`
+    async fn open_stream_as<'a>(
+        &'a self,
+        caller: Option<(SessionId, AgentId)>,
+        model: ModelPreference,
+        prompt: &'a str,
+    ) -> Result<ModelStream<'a>, SwarmError> {
+        let estimate = estimate_tokens(prompt);
+        let started = Instant::now();
+        let stream = |served, chunks, hit, throttled, permit| ModelStream {
+            clients: self,
+            deadline: started + self.timeout(served),
+            requested: model,
+            prompt,
+            served,
+            chunks,
+            hit,
+            throttled,
+            estimate,
+            text: String::new(),
+            usage: None,
+            settled: hit,
+            _permit: permit,
+        };
+        if let Some(ModelResponse { text, usage, model: served }) = self.cache.get(model, prompt).await {
+            let cached: ChunkStream = Box::pin(futures::stream::once(async move { Ok(ModelChunk { text, usage: Some(usage) }) }));
+            return Ok(stream(served, cached, true, Duration::ZERO, None));
+        }
+
+        // The permit and in-flight count are held until the stream ends
+        let Served { model: candidate, value: chunks, throttled, _permit, _in_flight } =
+            self.first_served(model, prompt, caller, |candidate| self.provider.complete_stream(candidate, prompt)).await?;
+        Ok(stream(candidate, chunks, false, throttled, Some((_permit, _in_flight))))
+    }
+
+    /// `complete` on behalf of one of a session's agents, recording each
+    /// model it tries to the audit sink, failed fallbacks included. Cache
+    /// hits reach no model and aren't recorded; a failed audit write is
+    /// logged without failing the call.
+    pub async fn complete_for(
+        &self,
+        session_id: SessionId,
+        agent_id: AgentId,
+        model: ModelPreference,
+        prompt: &str,
+    ) -> Result<CachedResponse, SwarmError> {
+        let waited = self.session_wait(session_id, model, prompt).await;
+        let mut result = self.complete_as(Some((session_id, agent_id)), model, prompt).await;
+        if let Ok(response) = &mut result {
+            response.throttled += waited;
+            self.audit_call(session_id, agent_id, prompt, response).await;
+        }
+        result
+    }
+
+    /// `open_stream` on behalf of one of a session's agents, under the
+    /// session's request cap like `complete_for`. Models that fail to open
+    /// are audited here; the caller audits the finished stream.
+    pub async fn open_stream_for<'a>(
+        &'a self,
+        session_id: SessionId,
+        agent_id: AgentId,
+        model: ModelPreference,
+        prompt: &'a str,
+    ) -> Result<ModelStream<'a>, SwarmError> {
+        let waited = self.session_wait(session_id, model, prompt).await;
+        let mut stream = self.open_stream_as(Some((session_id, agent_id)), model, prompt).await?;
+        stream.throttled += waited;
+        Ok(stream)
+    }
+
+    /// Wait for the session's request cap to allow one more call, if it
+    /// has one. Cache hits send no request, so they don't count against it.
+    async fn session_wait(&self, session_id: SessionId, model: ModelPreference, prompt: &str) -> Duration {
+        let rate = self.session_rates.read().expect("session rates lock poisoned").get(&session_id).cloned();
+        match rate {
+            Some(rate) if !self.cache.contains(model, prompt).await => rate.acquire().await,
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Cap `session_id`'s calls through `complete_for` and `open_stream_for`
+    /// at `requests_per_minute` across all models
+    pub fn set_session_rate(&self, session_id: SessionId, requests_per_minute: u32) -> Result<(), SwarmError> {
+        if requests_per_minute == 0 {
+            return Err(SwarmError::InvalidSpec("max_requests_per_minute must be positive".to_string()));
+        }
+        self.session_rates
+            .write()
+            .expect("session rates lock poisoned")
+            .insert(session_id, Arc::new(SessionRate::new(requests_per_minute)));
+        Ok(())
+    }
+
+    /// Lift `session_id`'s request cap, if it has one
+    pub fn clear_session_rate(&self, session_id: SessionId) {
+        self.session_rates.write().expect("session rates lock poisoned").remove(&session_id);
+    }
+
+    /// Record a call made for one of a session's agents; see `complete_for`
+    async fn audit_call(&self, session_id: SessionId, agent_id: AgentId, prompt: &str, result: &CachedResponse) {
+        if let CachedResponse { response, hit: false, .. } = result {
+            let record = AuditRecord {
+                session_id,
+                agent_id,
+                model: response.model,
+                prompt_hash: audit_hash(prompt),
+                response_hash: audit_hash(&response.text),
+                timestamp: Utc::now(),
+                cost: Self::cost_of(response.model, &response.usage),
+                prompt: self.audit_full_text.then(|| prompt.to_string()),
+                response: self.audit_full_text.then(|| response.text.clone()),
+                error: None,
+            };
+            self.write_audit(record).await;
+        }
+    }
+
+    /// Record a model's failed attempt at a call for one of a session's
+    /// agents. Nothing came back, so nothing is billed.
+    async fn audit_failure(
+        &self,
+        session_id: SessionId,
+        agent_id: AgentId,
+        model: ModelPreference,
+        prompt: &str,
+        error: &SwarmError,
+    ) {
+        self.write_audit(AuditRecord {
+            session_id,
+            agent_id,
+            model,
+            prompt_hash: audit_hash(prompt),
+            response_hash: audit_hash(""),
+            timestamp: Utc::now(),
+            cost: 0.0,
+            prompt: self.audit_full_text.then(|| prompt.to_string()),
+            response: None,
+            error: Some(error.to_string()),
+        })
+        .await;
+    }
+
+    async fn write_audit(&self, record: AuditRecord) {
+        let (session_id, agent_id) = (record.session_id, record.agent_id);
+        if let Err(e) = self.audit.record(record).await {
+            error!(%session_id, %agent_id, error = %e, "audit record lost");
+        }
+    }
+}
+
+/// A completion arriving in chunks, from `ModelClients::open_stream`. Holds
+/// the model's concurrency permit until dropped.
+pub struct ModelStream<'a> {
+    clients: &'a ModelClients,
+    /// Model asked for, which keys the cache
+    requested: ModelPreference,
+    prompt: &'a str,
+    /// Model serving the stream, after any fallback
+    served: ModelPreference,
+    /// End of the served model's timeout, counted from the call
+    deadline: Instant,
+    chunks: ChunkStream,
+    hit: bool,
+    throttled: Duration,
+    /// Tokens reserved with the rate limiter
+    estimate: u64,
+    text: String,
+    usage: Option<TokenUsage>,
+    /// Whether the reservation with the rate limiter has been settled
+    settled: bool,
+    _permit: Option<(Option<tokio::sync::SemaphorePermit<'a>>, InFlightCall)>,
+}
+
+/// A call `ModelClients::first_served` got through, holding its model's
+/// concurrency permit and in-flight count
+struct Served<'a, T> {
+    model: ModelPreference,
+    value: T,
+    throttled: Duration,
+    _permit: Option<tokio::sync::SemaphorePermit<'a>>,
+    _in_flight: InFlightCall,
+}
+
+impl ModelStream<'_> {
+    /// The next chunk of text; `None` once the completion is done. Past the
+    /// model's timeout it's a `ModelTimeout` carrying the text received so
+    /// far, after which the stream holds nothing more.
+    pub async fn next_chunk(&mut self) -> Option<Result<String, SwarmError>> {
+        let next = tokio::time::timeout_at(self.deadline, futures::StreamExt::next(&mut self.chunks));
+        let chunk = match next.await {
+            Ok(chunk) => chunk?,
+            Err(_) => {
+                // A hung stream counts against its model like a hung call
+                if !self.hit {
+                    self.clients.record_outcome(self.served, true);
+                }
+                self.chunks = Box::pin(futures::stream::empty());
+                self.settle().await;
+                let after = self.clients.timeout(self.served);
+                Err(SwarmError::ModelTimeout { model: self.served, after, partial: self.text.clone() })
+            }
+        };
+        match chunk {
+            Ok(ModelChunk { text, usage }) => {
+                self.text.push_str(&text);
+                self.usage = usage.or(self.usage);
+                Some(Ok(text))
+            }
+            Err(e) => {
+                if !self.hit && self.clients.is_retriable(&e) {
+                    self.clients.record_outcome(self.served, true);
+                }
+                self.settle().await;
+                Some(Err(e))
+            }
+        }
+    }
+
+    /// Tokens used so far: the provider's count, or an estimate from the
+    /// prompt and text received
+    fn usage(&self) -> TokenUsage {
+        self.usage.unwrap_or(TokenUsage {
+            input_tokens: self.estimate,
+            output_tokens: estimate_tokens(&self.text),
+        })
+    }
+
+    /// Give back the rate limiter reservation, charging what was used, at
+    /// most once
+    async fn settle(&mut self) {
+        if std::mem::replace(&mut self.settled, true) {
+            return;
+        }
+        let usage = self.usage();
+        self.clients.limiter.settle(self.served, self.estimate, usage.input_tokens + usage.output_tokens).await;
+    }
+
+    /// Text received so far
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// What the stream has cost so far; nothing if served from the cache
+    pub fn cost(&self) -> f64 {
+        if self.hit {
+            return 0.0;
+        }
+        ModelClients::cost_of(self.served, &self.usage())
+    }
+
+    /// Stop reading partway, settling with the rate limiter for what was
+    /// used. The partial text isn't cached.
+    pub async fn abandon(mut self) -> CachedResponse {
+        let usage = self.usage();
+        self.settle().await;
+        let response = ModelResponse { text: std::mem::take(&mut self.text), usage, model: self.served };
+        CachedResponse { response, hit: self.hit, throttled: self.throttled }
+    }
+
+    /// The assembled completion, once `next_chunk` has returned `None`.
+    /// A fresh one is cached and settled with the rate limiter, like a
+    /// `complete` response.
+    pub async fn finish(mut self) -> CachedResponse {
+        let usage = self.usage();
+        self.settle().await;
+        let response = ModelResponse { text: self.text, usage, model: self.served };
+        if !self.hit {
+            let clients = self.clients;
+            clients.record_outcome(self.served, false);
+            clients.cache.insert(self.requested, self.prompt, response.clone()).await;
+        }
+        CachedResponse { response, hit: self.hit, throttled: self.throttled }
+    }
+}
+
+/// One attempt at a model call, as kept for audit
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditRecord {
     pub session_id: SessionId,
-    pub status: SessionStatus,
-    pub metrics: SessionMetrics,
-    pub agent_count: usize,
-    pub agents_idle: usize,
-    pub agents_working: usize,
+    pub agent_id: AgentId,
+    /// Model tried; a fallback gets a record of its own
+    pub model: ModelPreference,
+    /// Hex SHA-256 of the prompt sent
+    pub prompt_hash: String,
+    /// Hex SHA-256 of the response text received
+    pub response_hash: String,
+    pub timestamp: DateTime<Utc>,
+    pub cost: f64,
+    /// Full text, only with `ModelClients::with_audit_full_text`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<String>,
+    /// Why the model failed the attempt; `None` when it answered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+fn audit_hash(text: &str) -> String {
+    format!("{:x}", Sha256::digest(text.as_bytes()))
+}
+
+/// Destination for audit records of model calls
+#[async_trait]
+pub trait AuditSink: Send + Sync {
+    async fn record(&self, record: AuditRecord) -> Result<(), SwarmError>;
+}
+
+/// Default sink: auditing off
+pub struct NoopAuditSink;
+
+#[async_trait]
+impl AuditSink for NoopAuditSink {
+    async fn record(&self, _record: AuditRecord) -> Result<(), SwarmError> {
+        Ok(())
+    }
+}
+
+/// Appends records to a file as JSON lines
+pub struct FileAuditSink {
+    file: Mutex<tokio::fs::File>,
+}
+
+impl FileAuditSink {
+    /// Open `path` for appending, creating it if needed
+    pub async fn open(path: impl AsRef<std::path::Path>) -> Result<Self, SwarmError> {
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .map_err(|e| SwarmError::Audit(e.into()))?;
+        Ok(Self { file: Mutex::new(file) })
+    }
+}
+
+#[async_trait]
+impl AuditSink for FileAuditSink {
+    async fn record(&self, record: AuditRecord) -> Result<(), SwarmError> {
+        use tokio::io::AsyncWriteExt;
+
+        let mut line = serde_json::to_vec(&record).map_err(|e| SwarmError::Audit(e.into()))?;
+        line.push(b'\n');
+        // One write per record, so concurrent calls don't interleave lines
+        let mut file = self.file.lock().await;
+        file.write_all(&line).await.map_err(|e| SwarmError::Audit(e.into()))?;
+        file.flush().await.map_err(|e| SwarmError::Audit(e.into()))
+    }
+}
+
+/// Completions keyed on a hash of (model, prompt), so identical prompts
+/// across replicated projects are billed once per TTL
+pub struct PromptCache {
+    ttl: Duration,
+    entries: RwLock<CacheEntries>,
+}
+
+struct CacheEntry {
+    response: ModelResponse,
+    expires_at: Instant,
+}
+
+/// Cached completions, and their keys in expiry order (one TTL for all, so
+/// that's insertion order) so expired ones go without a scan
+#[derive(Default)]
+struct CacheEntries {
+    by_key: HashMap<[u8; 32], CacheEntry>,
+    by_expiry: VecDeque<(Instant, [u8; 32])>,
+}
+
+impl PromptCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self { ttl, entries: RwLock::new(CacheEntries::default()) }
+    }
+
+    fn key(model: ModelPreference, prompt: &str) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(format!("{:?}", model).as_bytes());
+        hasher.update([0]);
+        hasher.update(prompt.as_bytes());
+        hasher.finalize().into()
+    }
+
+    pub async fn get(&self, model: ModelPreference, prompt: &str) -> Option<ModelResponse> {
+        let entries = self.entries.read().await;
+        entries.by_key
+            .get(&Self::key(model, prompt))
+            .filter(|e| e.expires_at > Instant::now())
+            .map(|e| e.response.clone())
+    }
+
+    /// Whether `get` would hit, without cloning the response
+    pub async fn contains(&self, model: ModelPreference, prompt: &str) -> bool {
+        self.entries.read().await.by_key
+            .get(&Self::key(model, prompt))
+            .is_some_and(|e| e.expires_at > Instant::now())
+    }
+
+    pub async fn insert(&self, model: ModelPreference, prompt: &str, response: ModelResponse) {
+        let mut entries = self.entries.write().await;
+        // Read under the lock so `by_expiry` stays in order
+        let now = Instant::now();
+        // Sweep on write so expired prompts don't accumulate
+        while let Some(&(expires_at, key)) = entries.by_expiry.front() {
+            if expires_at > now {
+                break;
+            }
+            entries.by_expiry.pop_front();
+            // An entry inserted again since expires later
+            if entries.by_key.get(&key).is_some_and(|e| e.expires_at == expires_at) {
+                entries.by_key.remove(&key);
+            }
+        }
+        let (key, expires_at) = (Self::key(model, prompt), now + self.ttl);
+        entries.by_key.insert(key, CacheEntry { response, expires_at });
+        entries.by_expiry.push_back((expires_at, key));
+    }
+
+    pub async fn remove(&self, model: ModelPreference, prompt: &str) {
+        self.entries.write().await.by_key.remove(&Self::key(model, prompt));
+    }
+
+    pub async fn len(&self) -> usize {
+        self.entries.read().await.by_key.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+}
+
+/// A completion and whether it came from the prompt cache
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    pub response: ModelResponse,
+    pub hit: bool,
+    /// Time spent queued on the rate limiter before the call
+    pub throttled: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub requests_per_minute: u32,
+    /// Input plus output tokens
+    pub tokens_per_minute: u64,
+}
+
+/// Per-model token buckets for requests and tokens.
+///
+/// Each bucket holds one second of capacity, so load is spread across the
+/// minute instead of bursting. Callers reserve capacity up front, going into
+/// debt if needed, and sleep it off; later callers queue behind them. Only a
+/// reservation that would wait longer than `max_wait` fails.
+pub struct RateLimiter {
+    limits: HashMap<ModelPreference, RateLimit>,
+    buckets: tokio::sync::Mutex<HashMap<ModelPreference, Buckets>>,
+    max_wait: Duration,
+}
+
+struct Buckets {
+    requests: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimit {
+    fn requests_per_sec(&self) -> f64 {
+        self.requests_per_minute as f64 / 60.0
+    }
+
+    fn tokens_per_sec(&self) -> f64 {
+        self.tokens_per_minute as f64 / 60.0
+    }
+}
+
+impl Buckets {
+    fn full(limit: &RateLimit, now: Instant) -> Self {
+        Self {
+            requests: limit.requests_per_sec().max(1.0),
+            tokens: limit.tokens_per_sec().max(1.0),
+            refilled_at: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        let full = Self::full(limit, now);
+        self.requests = (self.requests + elapsed * limit.requests_per_sec()).min(full.requests);
+        self.tokens = (self.tokens + elapsed * limit.tokens_per_sec()).min(full.tokens);
+        self.refilled_at = now;
+    }
+}
+
+/// One session's request budget, a single bucket like `RateLimiter`'s
+/// spread over every model. Callers queue for capacity however long it takes.
+struct SessionRate {
+    limit: RateLimit,
+    bucket: tokio::sync::Mutex<Buckets>,
+}
+
+impl SessionRate {
+    fn new(requests_per_minute: u32) -> Self {
+        // Tokens aren't limited per session
+        let limit = RateLimit { requests_per_minute, tokens_per_minute: 0 };
+        Self { limit, bucket: tokio::sync::Mutex::new(Buckets::full(&limit, Instant::now())) }
+    }
+
+    /// Reserve one request, returning how long it had to wait
+    async fn acquire(&self) -> Duration {
+        let wait = {
+            let mut bucket = self.bucket.lock().await;
+            bucket.refill(&self.limit, Instant::now());
+            let wait = (1.0 - bucket.requests).max(0.0) / self.limit.requests_per_sec();
+            bucket.requests -= 1.0;
+            Duration::from_secs_f64(wait)
+        };
+        tokio::time::sleep(wait).await;
+        wait
+    }
+}
+
+impl RateLimiter {
+    pub fn new(max_wait: Duration) -> Self {
+        Self {
+            limits: HashMap::new(),
+            buckets: tokio::sync::Mutex::new(HashMap::new()),
+            max_wait,
+        }
+    }
+
+    /// No limits on any model
+    pub fn unlimited() -> Self {
+        Self::new(Duration::MAX)
+    }
+
+    /// Limit `model` to `limit`; a zero rate would never let a call through
+    pub fn with_limit(mut self, model: ModelPreference, limit: RateLimit) -> Result<Self, SwarmError> {
+        if limit.requests_per_minute == 0 || limit.tokens_per_minute == 0 {
+            return Err(SwarmError::InvalidSpec(format!("rate limit for {model:?} must be positive")));
+        }
+        self.limits.insert(model, limit);
+        Ok(self)
+    }
+
+    /// Wait for capacity to send one request of about `tokens` to `model`,
+    /// returning how long that took
+    pub async fn acquire(&self, model: ModelPreference, tokens: u64) -> Result<Duration, SwarmError> {
+        let Some(limit) = self.limits.get(&model) else {
+            return Ok(Duration::ZERO);
+        };
+
+        let wait = {
+            let mut buckets = self.buckets.lock().await;
+            let now = Instant::now();
+            let bucket = buckets.entry(model).or_insert_with(|| Buckets::full(limit, now));
+            bucket.refill(limit, now);
+
+            let request_wait = (1.0 - bucket.requests).max(0.0) / limit.requests_per_sec();
+            let token_wait = (tokens as f64 - bucket.tokens).max(0.0) / limit.tokens_per_sec();
+            let wait = Duration::from_secs_f64(request_wait.max(token_wait));
+            if wait > self.max_wait {
+                return Err(SwarmError::RateLimited { model, wait });
+            }
+
+            bucket.requests -= 1.0;
+            bucket.tokens -= tokens as f64;
+            wait
+        };
+
+        tokio::time::sleep(wait).await;
+        Ok(wait)
+    }
+
+    /// Correct a reservation once the call's actual token usage is known
+    async fn settle(&self, model: ModelPreference, estimated: u64, actual: u64) {
+        if let Some(bucket) = self.buckets.lock().await.get_mut(&model) {
+            bucket.tokens -= actual as f64 - estimated as f64;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ModelResponse {
+    pub text: String,
+    pub usage: TokenUsage,
+    /// Model that served the request
+    pub model: ModelPreference,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+}
+
+/// Server-side compare-and-swap on a string key: set it to ARGV[3] only if
+/// it holds ARGV[2] (ARGV[1] = '0' expects no key). Replies 1 if set.
+#[cfg(feature = "redis")]
+const CAS_SCRIPT: &str = r#"
+local current = redis.call('GET', KEYS[1])
+if ARGV[1] == '1' then
+    if current ~= ARGV[2] then return 0 end
+elseif current then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[3])
+return 1
+"#;
+
+/// Buffered change notifications per in-process watcher
+const KEYSPACE_NOTIFY_CAPACITY: usize = 1024;
+
+/// Minimal Redis command surface used by the orchestrator.
+///
+/// `connect` talks to a Redis server (with the `redis` feature); `new` keeps an
+/// in-process keyspace with the same semantics, including change
+/// notifications, for tests and single-node runs.
+pub struct RedisClient {
+    connection: Connection,
+}
+
+enum Connection {
+    InProcess {
+        strings: RwLock<HashMap<String, String>>,
+        /// Names of modified keys, like Redis keyspace notifications
+        changes: broadcast::Sender<String>,
+    },
+    #[cfg(feature = "redis")]
+    Server {
+        client: redis::Client,
+        conn: redis::aio::ConnectionManager,
+    },
+}
+
+impl Default for RedisClient {
+    fn default() -> Self {
+        Self {
+            connection: Connection::InProcess {
+                strings: RwLock::new(HashMap::new()),
+                changes: broadcast::channel(KEYSPACE_NOTIFY_CAPACITY).0,
+            },
+        }
+    }
+}
+
+impl std::fmt::Debug for RedisClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let connection = match &self.connection {
+            Connection::InProcess { .. } => "in-process",
+            #[cfg(feature = "redis")]
+            Connection::Server { .. } => "server",
+        };
+        f.debug_struct("RedisClient").field("connection", &connection).finish()
+    }
+}
+
+impl RedisClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connect to a Redis server, e.g. `redis://127.0.0.1/`.
+    ///
+    /// Cache invalidation relies on keyspace notifications for string and
+    /// generic commands (`notify-keyspace-events` containing `Kg$`); they're enabled
+    /// here when the server allows `CONFIG SET`.
+    #[cfg(feature = "redis")]
+    pub async fn connect(url: &str) -> Result<Self, SwarmError> {
+        let client = redis::Client::open(url)?;
+        let mut conn = redis::aio::ConnectionManager::new(client.clone()).await?;
+
+        let current: Vec<String> = redis::cmd("CONFIG")
+            .arg("GET")
+            .arg("notify-keyspace-events")
+            .query_async(&mut conn)
+            .await
+            .unwrap_or_default();
+        let mut flags = current.get(1).cloned().unwrap_or_default();
+        // `A` covers every event class (but not the `K` channel prefix)
+        for flag in ['K', 'g', '$'] {
+            let covered = flags.contains(flag) || (flag != 'K' && flags.contains('A'));
+            if !covered {
+                flags.push(flag);
+            }
+        }
+        // Managed Redis may forbid CONFIG; then it has to be set server-side
+        let _: redis::RedisResult<()> = redis::cmd("CONFIG")
+            .arg("SET")
+            .arg("notify-keyspace-events")
+            .arg(flags)
+            .query_async(&mut conn)
+            .await;
+
+        Ok(Self { connection: Connection::Server { client, conn } })
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<String>, SwarmError> {
+        match &self.connection {
+            Connection::InProcess { strings, .. } => Ok(strings.read().await.get(key).cloned()),
+            #[cfg(feature = "redis")]
+            Connection::Server { conn, .. } => {
+                Ok(redis::AsyncCommands::get(&mut conn.clone(), key).await?)
+            }
+        }
+    }
+
+    pub async fn set(&self, key: &str, value: String) -> Result<(), SwarmError> {
+        match &self.connection {
+            Connection::InProcess { strings, changes } => {
+                strings.write().await.insert(key.to_string(), value);
+                let _ = changes.send(key.to_string());
+                Ok(())
+            }
+            #[cfg(feature = "redis")]
+            Connection::Server { conn, .. } => {
+                Ok(redis::AsyncCommands::set(&mut conn.clone(), key, value).await?)
+            }
+        }
+    }
+
+    pub async fn del(&self, key: &str) -> Result<(), SwarmError> {
+        match &self.connection {
+            Connection::InProcess { strings, changes } => {
+                if strings.write().await.remove(key).is_some() {
+                    let _ = changes.send(key.to_string());
+                }
+                Ok(())
+            }
+            #[cfg(feature = "redis")]
+            Connection::Server { conn, .. } => {
+                Ok(redis::AsyncCommands::del(&mut conn.clone(), key).await?)
+            }
+        }
+    }
+
+    /// `KEYS` with support for a trailing `*` wildcard (`SCAN` on a server)
+    pub async fn keys(&self, pattern: &str) -> Result<Vec<String>, SwarmError> {
+        match &self.connection {
+            Connection::InProcess { strings, .. } => {
+                let strings = strings.read().await;
+                let all = strings.keys();
+                let matches = match pattern.strip_suffix('*') {
+                    Some(prefix) => all.filter(|k| k.starts_with(prefix)).cloned().collect(),
+                    None => all.filter(|k| *k == pattern).cloned().collect(),
+                };
+                Ok(matches)
+            }
+            #[cfg(feature = "redis")]
+            Connection::Server { conn, .. } => {
+                let mut conn = conn.clone();
+                let mut iter: redis::AsyncIter<String> =
+                    redis::AsyncCommands::scan_match(&mut conn, pattern).await?;
+                let mut keys = Vec::new();
+                while let Some(key) = iter.next_item().await {
+                    keys.push(key);
+                }
+                Ok(keys)
+            }
+        }
+    }
+
+    /// Set `key` to `value` only if it holds `expected` (`None`: only if it
+    /// doesn't exist), atomically. Returns whether it was set.
+    pub async fn cas(&self, key: &str, expected: Option<&str>, value: String) -> Result<bool, SwarmError> {
+        match &self.connection {
+            Connection::InProcess { strings, changes } => {
+                let mut strings = strings.write().await;
+                if strings.get(key).map(String::as_str) != expected {
+                    return Ok(false);
+                }
+                strings.insert(key.to_string(), value);
+                let _ = changes.send(key.to_string());
+                Ok(true)
+            }
+            #[cfg(feature = "redis")]
+            Connection::Server { conn, .. } => {
+                let set: i32 = redis::Script::new(CAS_SCRIPT)
+                    .key(key)
+                    .arg(if expected.is_some() { "1" } else { "0" })
+                    .arg(expected.unwrap_or_default())
+                    .arg(value)
+                    .invoke_async(&mut conn.clone())
+                    .await?;
+                Ok(set == 1)
+            }
+        }
+    }
+
+    /// Every field of the hash at `key`. An in-process client holds no
+    /// hashes: nothing written before it was created survives anyway.
+    pub async fn hgetall(&self, key: &str) -> Result<Vec<(String, String)>, SwarmError> {
+        match &self.connection {
+            Connection::InProcess { .. } => {
+                let _ = key;
+                Ok(vec![])
+            }
+            #[cfg(feature = "redis")]
+            Connection::Server { conn, .. } => {
+                Ok(redis::AsyncCommands::hgetall(&mut conn.clone(), key).await?)
+            }
+        }
+    }
+
+    /// Notifications whenever a key matching `pattern` (a key, or a prefix
+    /// with a trailing `*`) is modified, by this client or any other
+    pub async fn watch(&self, pattern: &str) -> Result<KeyWatch, SwarmError> {
+        match &self.connection {
+            Connection::InProcess { changes, .. } => Ok(KeyWatch::in_process(pattern.to_string(), changes.subscribe())),
+            #[cfg(feature = "redis")]
+            Connection::Server { client, .. } => {
+                let mut pubsub = client.get_async_pubsub().await?;
+                pubsub.psubscribe(format!("__keyspace@*__:{}", pattern)).await?;
+                Ok(KeyWatch { inner: KeyWatchInner::Server(Box::pin(pubsub.into_on_message())) })
+            }
+        }
+    }
+}
+
+pub struct KeyWatch {
+    inner: KeyWatchInner,
+}
+
+enum KeyWatchInner {
+    InProcess {
+        pattern: String,
+        rx: broadcast::Receiver<String>,
+    },
+    #[cfg(feature = "redis")]
+    Server(std::pin::Pin<Box<dyn futures::Stream<Item = redis::Msg> + Send>>),
+}
+
+impl KeyWatch {
+    /// Watch the names of modified keys sent on `rx` for ones matching
+    /// `pattern`, as `RedisClient::watch` does
+    fn in_process(pattern: String, rx: broadcast::Receiver<String>) -> Self {
+        Self { inner: KeyWatchInner::InProcess { pattern, rx } }
+    }
+
+    /// Wait for the next change; `None` once notifications stop for good
+    pub async fn changed(&mut self) -> Option<()> {
+        match &mut self.inner {
+            KeyWatchInner::InProcess { pattern, rx } => loop {
+                let matches = |key: &str| match pattern.strip_suffix('*') {
+                    Some(prefix) => key.starts_with(prefix),
+                    None => key == pattern,
+                };
+                match rx.recv().await {
+                    Ok(changed) if matches(&changed) => return Some(()),
+                    Ok(_) => continue,
+                    // Missed notifications may have included this key
+                    Err(broadcast::error::RecvError::Lagged(_)) => return Some(()),
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            },
+            #[cfg(feature = "redis")]
+            KeyWatchInner::Server(messages) => {
+                futures::StreamExt::next(messages).await.map(|_| ())
+            }
+        }
+    }
+}
+
+// ============================================================================
+// ERROR TYPES
+// ============================================================================
+
+/// Boxed underlying cause carried by `SwarmError` variants
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SwarmError {
+    #[error("Session not found")]
+    SessionNotFound,
+    #[error("Session {0} already exists")]
+    SessionExists(SessionId),
+    #[error("Session is not accepting tasks ({0:?})")]
+    SessionNotActive(SessionStatus),
+    #[error("Sessions of user {0} are being cancelled")]
+    UserCancelling(UserId),
+    #[error("Agent not found")]
+    AgentNotFound,
+    #[error("Agent {0} is busy")]
+    AgentBusy(AgentId),
+    #[error("Agent {agent_id} is a {role:?}, but the task needs a {required:?}")]
+    WrongRole {
+        agent_id: AgentId,
+        role: AgentRole,
+        required: AgentRole,
+    },
+    #[error("Agent {agent_id} runs {model:?}, which lacks {capability:?} the task needs")]
+    UnsupportedCapability {
+        agent_id: AgentId,
+        model: ModelPreference,
+        capability: Capability,
+    },
+    #[error("Failed to spawn agent")]
+    AgentSpawnFailed,
+    #[error("Task execution failed")]
+    TaskExecutionFailed,
+    #[error("Tearing down session {session_id} failed: {}", .errors.iter().map(ToString::to_string).collect::<Vec<_>>().join("; "))]
+    TeardownFailed {
+        session_id: SessionId,
+        /// Everything that failed, across its sub-sessions too; what could
+        /// be released was
+        errors: Vec<SwarmError>,
+    },
+    #[error("Task {0} not found")]
+    TaskNotFound(TaskId),
+    #[error("Task {0} has already started")]
+    TaskStarted(TaskId),
+    #[error("Task {0} is waiting on its dependencies")]
+    TaskBlocked(TaskId),
+    #[error("Task was cancelled")]
+    TaskCancelled,
+    #[error("Result of task {task_id} doesn't match its schema: {}", .errors.join("; "))]
+    ResultSchemaViolation {
+        task_id: TaskId,
+        errors: Vec<String>,
+    },
+    #[error("State management error on `{key}`")]
+    StateError {
+        key: String,
+        #[source]
+        source: BoxError,
+    },
+    #[cfg(feature = "redis")]
+    #[error("Redis command failed")]
+    Redis(#[from] redis::RedisError),
+    #[error("Model API call to {model:?} failed")]
+    ModelApi {
+        model: ModelPreference,
+        /// HTTP status the provider answered with, if it got that far
+        status: Option<u16>,
+        #[source]
+        source: BoxError,
+    },
+    #[error("Model call to {model:?} timed out after {after:?}")]
+    ModelTimeout {
+        model: ModelPreference,
+        after: Duration,
+        /// Text a stream delivered before the deadline; empty for a
+        /// whole-response call
+        partial: String,
+    },
+    #[error("Rate limit for {model:?} needs a {wait:?} wait")]
+    RateLimited {
+        model: ModelPreference,
+        wait: Duration,
+    },
+    #[error("Every model in the fallback chain failed: {tried:?}")]
+    AllModelsFailed {
+        tried: Vec<ModelPreference>,
+        #[source]
+        source: Option<Box<SwarmError>>,
+    },
+    #[error("Circuit breaker for {0:?} is open")]
+    CircuitOpen(ModelPreference),
+    #[error("Planner produced an unusable plan: {0}")]
+    InvalidPlan(String),
+    #[error("Plan document failed validation with {} problem(s)", .0.len())]
+    InvalidPlanDocument(Vec<PlanError>),
+    #[error("Task dependencies contain a cycle: {0:?}")]
+    CyclicDependency(Vec<TaskId>),
+    #[error("Session budget exceeded")]
+    BudgetExceeded,
+    #[error("Task {task_id} cost ${cost:.4}, over its ${limit:.4} ceiling")]
+    TaskBudgetExceeded {
+        task_id: TaskId,
+        cost: f64,
+        limit: f64,
+    },
+    #[error("Session {session_id} is deadlocked: all agents are blocked ({blocked:?})")]
+    Deadlock {
+        session_id: SessionId,
+        blocked: Vec<BlockedAgent>,
+    },
+    #[error("Migration bundle for session {session_id} at epoch {epoch} was already imported or superseded")]
+    StaleMigration {
+        session_id: SessionId,
+        epoch: u64,
+    },
+    #[error("Agent {agent_id} can't move from {from:?} to {to:?}")]
+    InvalidTransition {
+        agent_id: AgentId,
+        from: AgentStatus,
+        to: AgentStatus,
+    },
+    #[error("Agent pool is saturated at {limit} agents")]
+    PoolSaturated {
+        limit: usize,
+    },
+    #[error("Task queue is full")]
+    QueueFull,
+    #[error("Quota exceeded for `{user_id}`: at most {limit} {resource}")]
+    QuotaExceeded {
+        user_id: UserId,
+        resource: &'static str,
+        limit: usize,
+    },
+    #[error("Message bus error")]
+    MessageBus(#[source] BoxError),
+    #[error("Invalid project spec: {0}")]
+    InvalidSpec(String),
+    #[error("Failed to write an audit record")]
+    Audit(#[source] BoxError),
+    #[error("Failed to encode or decode a {format:?} session snapshot")]
+    Snapshot {
+        format: SnapshotFormat,
+        #[source]
+        source: BoxError,
+    },
+    #[error("Failed to encode or decode `{key}` as JSON")]
+    Serialization {
+        key: String,
+        #[source]
+        source: serde_json::Error,
+    },
+}
+
+impl SwarmError {
+    pub fn state(key: impl Into<String>, source: impl Into<BoxError>) -> Self {
+        SwarmError::StateError { key: key.into(), source: source.into() }
+    }
+
+    /// Failures a retry may get past: outages, timeouts and rate limits,
+    /// but not a request the provider rejected (a 400 fails forever). The
+    /// default classification for `ModelClients::with_retry_classifier`.
+    pub fn is_retriable(&self) -> bool {
+        match self {
+            SwarmError::ModelApi { status, .. } => {
+                status.is_none_or(|code| matches!(code, 408 | 429 | 500..))
+            }
+            SwarmError::RateLimited { .. }
+            | SwarmError::ModelTimeout { .. }
+            | SwarmError::CircuitOpen(_)
+            | SwarmError::AllModelsFailed { .. }
+            | SwarmError::TaskExecutionFailed
+            | SwarmError::ResultSchemaViolation { .. } => true,
+            _ => false,
+        }
+    }
 }
 
-// ============================================================================
-// AGENT POOL
-// ============================================================================
+// ============================================================================
+// TESTS
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_session_creation() {
+        let redis = Arc::new(RedisClient::new());
+        let state_manager = Arc::new(StateManager::new(redis));
+        let model_clients = Arc::new(ModelClients::new());
+        let agent_pool = Arc::new(AgentPool::new(model_clients));
+        let task_queue = Arc::new(TaskQueue::new(1_000));
+        
+        let session_mgr = SessionManager::new(
+            agent_pool,
+            state_manager,
+            task_queue,
+        );
+
+        let project = ProjectSpec {
+            name: "Test Hospital Integration".to_string(),
+            template: TemplateType::HospitalIntegration,
+            replication_count: 100,
+            parallelization: ParallelizationMode::Turbo,
+            requires_browser: false,
+            estimated_complexity: Complexity::Medium,
+            budget_usd: None,
+            budget_policy: BudgetPolicy::default(),
+            dedup_tasks: false,
+            tags: HashMap::new(),
+            completion_webhook: None,
+            webhook_secret: None,
+            max_requests_per_minute: None,
+            readiness: None,
+            deadline: None,
+        };
+
+        let session_id = session_mgr
+            .create_session("user123".to_string(), project, None)
+            .await
+            .unwrap();
+
+        let status = session_mgr
+            .get_session_status(session_id)
+            .await
+            .unwrap();
+
+        assert_eq!(status.status, SessionStatus::Active);
+        assert!(status.agent_count > 0);
+    }
+
+    fn make_manager(redis: Arc<RedisClient>) -> SessionManager {
+        let state_manager = Arc::new(StateManager::new(redis));
+        let model_clients = Arc::new(ModelClients::with_provider(Arc::new(ScriptedProvider::default())));
+        let agent_pool = Arc::new(AgentPool::new(model_clients));
+        let task_queue = Arc::new(TaskQueue::new(1_000));
+
+        SessionManager::new(agent_pool, state_manager, task_queue)
+    }
+
+    /// Shared with the submodules' tests, which override what they need
+    pub(super) fn small_project() -> ProjectSpec {
+        ProjectSpec {
+            name: "Test Software Dev".to_string(),
+            template: TemplateType::SoftwareDev,
+            replication_count: 1,
+            parallelization: ParallelizationMode::Batch10,
+            requires_browser: false,
+            estimated_complexity: Complexity::Small,
+            budget_usd: None,
+            budget_policy: BudgetPolicy::default(),
+            dedup_tasks: false,
+            tags: HashMap::new(),
+            completion_webhook: None,
+            webhook_secret: None,
+            max_requests_per_minute: None,
+            readiness: None,
+            deadline: None,
+        }
+    }
+
+    /// `make_manager` over `clients`, with in-memory state
+    fn make_manager_with(clients: ModelClients) -> SessionManager {
+        make_manager_on(AgentPool::new(Arc::new(clients)))
+    }
+
+    /// `make_manager` over a configured `agent_pool`
+    fn make_manager_on(agent_pool: AgentPool) -> SessionManager {
+        SessionManager::new(
+            Arc::new(agent_pool),
+            Arc::new(StateManager::new(Arc::new(RedisClient::new()))),
+            Arc::new(TaskQueue::new(1_000)),
+        )
+    }
+
+    /// How `ScriptedProvider` answers a call
+    #[derive(Clone)]
+    enum Reply {
+        /// `EchoProvider`'s answer, with a PASS verdict for a verification
+        Echo,
+        /// The echo, rewritten
+        Map(Arc<dyn Fn(String) -> String + Send + Sync>),
+        /// This text, billed as `usage` or estimated from the text
+        Text(String, Option<TokenUsage>),
+        /// The model's API failing with this HTTP status
+        Status(u16),
+        /// The echo, after a delay
+        Slow(Duration),
+        /// The echo, once a permit is taken from the gate
+        Gated(Arc<Semaphore>),
+        Panic,
+        /// No answer ever; a stream sends its first word, then stalls
+        Hang,
+        /// A stream of these words 2ms apart, the last reporting `usage`;
+        /// an unstreamed call gets the echo
+        Words(Vec<&'static str>, TokenUsage),
+    }
+
+    impl Reply {
+        fn text(text: &str) -> Self {
+            Reply::Text(text.to_string(), None)
+        }
+
+        async fn answer(self, model: ModelPreference, prompt: &str) -> Result<ModelResponse, SwarmError> {
+            // An echo passes the verifications it's asked for
+            let echo = || async {
+                let mut response = EchoProvider.complete(model, prompt).await?;
+                if verifying(model, prompt) {
+                    response.text.push_str("\nPASS");
+                }
+                Ok::<_, SwarmError>(response)
+            };
+            match self {
+                Reply::Echo | Reply::Words(..) => echo().await,
+                Reply::Map(map) => {
+                    let mut response = echo().await?;
+                    response.text = map(response.text);
+                    Ok(response)
+                }
+                Reply::Text(text, usage) => {
+                    let usage = usage.unwrap_or(TokenUsage {
+                        input_tokens: estimate_tokens(prompt),
+                        output_tokens: estimate_tokens(&text),
+                    });
+                    Ok(ModelResponse { text, usage, model })
+                }
+                Reply::Status(status) => {
+                    Err(SwarmError::ModelApi { model, status: Some(status), source: format!("HTTP {status}").into() })
+                }
+                Reply::Slow(delay) => {
+                    tokio::time::sleep(delay).await;
+                    echo().await
+                }
+                Reply::Gated(gate) => {
+                    gate.acquire().await.expect("gate closed").forget();
+                    echo().await
+                }
+                Reply::Panic => panic!("malformed response"),
+                Reply::Hang => futures::future::pending().await,
+            }
+        }
+    }
+
+    type Matcher = Box<dyn Fn(ModelPreference, &str) -> bool + Send + Sync>;
+
+    /// A `ScriptedProvider` rule: the nth call it matches gets its nth
+    /// reply, the last one repeating
+    struct Rule {
+        when: Matcher,
+        replies: Vec<Reply>,
+        matched: AtomicUsize,
+    }
+
+    /// The test double: answers each call by the first rule matching it,
+    /// else as `EchoProvider`. Records every prompt, and the most calls in
+    /// flight at once.
+    #[derive(Default)]
+    struct ScriptedProvider {
+        rules: std::sync::Mutex<Vec<Arc<Rule>>>,
+        capabilities: std::sync::Mutex<HashMap<ModelPreference, Capabilities>>,
+        prompts: std::sync::Mutex<Vec<String>>,
+        in_flight: AtomicUsize,
+        peak: AtomicUsize,
+    }
+
+    impl ScriptedProvider {
+        fn on(self, when: impl Fn(ModelPreference, &str) -> bool + Send + Sync + 'static, reply: Reply) -> Self {
+            self.on_each(when, vec![reply])
+        }
+
+        fn on_each(
+            self,
+            when: impl Fn(ModelPreference, &str) -> bool + Send + Sync + 'static,
+            replies: Vec<Reply>,
+        ) -> Self {
+            let rule = Rule { when: Box::new(when), replies, matched: AtomicUsize::new(0) };
+            self.rules.lock().unwrap().push(Arc::new(rule));
+            self
+        }
+
+        /// Drop every rule, echoing from now on
+        fn clear(&self) {
+            self.rules.lock().unwrap().clear();
+        }
+
+        /// Report `capabilities` for `model` to agents spawned from now on
+        fn set_capabilities(&self, model: ModelPreference, capabilities: Capabilities) {
+            self.capabilities.lock().unwrap().insert(model, capabilities);
+        }
+
+        fn prompts(&self) -> Vec<String> {
+            self.prompts.lock().unwrap().clone()
+        }
+
+        fn calls(&self) -> usize {
+            self.prompts.lock().unwrap().len()
+        }
+
+        fn calls_where(&self, matches: impl Fn(&str) -> bool) -> usize {
+            self.prompts.lock().unwrap().iter().filter(|p| matches(p)).count()
+        }
+
+        fn peak(&self) -> usize {
+            self.peak.load(AtomicOrdering::SeqCst)
+        }
+
+        /// `reply.answer(..)`, counted as in flight meanwhile
+        async fn answer(&self, reply: Reply, model: ModelPreference, prompt: &str) -> Result<ModelResponse, SwarmError> {
+            let now = self.in_flight.fetch_add(1, AtomicOrdering::SeqCst) + 1;
+            self.peak.fetch_max(now, AtomicOrdering::SeqCst);
+            let result = reply.answer(model, prompt).await;
+            self.in_flight.fetch_sub(1, AtomicOrdering::SeqCst);
+            result
+        }
+
+        /// Record a call and pick its reply
+        fn reply_to(&self, model: ModelPreference, prompt: &str) -> Reply {
+            self.prompts.lock().unwrap().push(prompt.to_string());
+            let rule = self.rules.lock().unwrap().iter().find(|r| (r.when)(model, prompt)).cloned();
+            rule.map_or(Reply::Echo, |rule| {
+                let nth = rule.matched.fetch_add(1, AtomicOrdering::SeqCst);
+                rule.replies[nth.min(rule.replies.len() - 1)].clone()
+            })
+        }
+    }
+
+    #[async_trait]
+    impl ModelProvider for ScriptedProvider {
+        async fn complete(&self, model: ModelPreference, prompt: &str) -> Result<ModelResponse, SwarmError> {
+            let reply = self.reply_to(model, prompt);
+            self.answer(reply, model, prompt).await
+        }
+
+        async fn complete_stream(&self, model: ModelPreference, prompt: &str) -> Result<ChunkStream, SwarmError> {
+            let chunks: ChunkStream = match self.reply_to(model, prompt) {
+                Reply::Words(words, usage) => {
+                    let last = words.len().saturating_sub(1);
+                    let chunks = words.into_iter().enumerate().map(move |(i, word)| {
+                        Ok(ModelChunk { text: word.to_string(), usage: (i == last).then_some(usage) })
+                    });
+                    Box::pin(futures::StreamExt::then(futures::stream::iter(chunks), |chunk| async move {
+                        tokio::time::sleep(Duration::from_millis(2)).await;
+                        chunk
+                    }))
+                }
+                Reply::Hang => {
+                    let first = futures::stream::once(async { Ok(ModelChunk { text: "fn ".to_string(), usage: None }) });
+                    Box::pin(futures::StreamExt::chain(first, futures::stream::pending()))
+                }
+                reply => {
+                    let ModelResponse { text, usage, .. } = self.answer(reply, model, prompt).await?;
+                    Box::pin(futures::stream::once(async move { Ok(ModelChunk { text, usage: Some(usage) }) }))
+                }
+            };
+            Ok(chunks)
+        }
+
+        fn capabilities(&self, model: ModelPreference) -> Capabilities {
+            self.capabilities.lock().unwrap().get(&model).copied().unwrap_or_else(|| Capabilities::of(model))
+        }
+    }
+
+    /// Echoes every call after `delay`
+    fn slow(delay: Duration) -> Arc<ScriptedProvider> {
+        Arc::new(ScriptedProvider::default().on(|_, _| true, Reply::Slow(delay)))
+    }
+
+    /// Whether `prompt` asks a verifier to check a result
+    fn verifying(_model: ModelPreference, prompt: &str) -> bool {
+        prompt.contains("Verify the result")
+    }
+
+    /// Poll until `check` holds, failing the test after ~2s
+    async fn wait_until<F, Fut>(mut check: F)
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = bool>,
+    {
+        for _ in 0..200 {
+            if check().await {
+                return;
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        }
+        panic!("condition not reached in time");
+    }
+
+    #[tokio::test]
+    async fn test_destroy_purges_the_sessions_queued_work() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let other = session_mgr.create_session("user456".to_string(), small_project(), None).await.unwrap();
+        let first = make_task("write the parser", vec![]);
+        let second = make_task("test the parser", vec![first.id]);
+        session_mgr.submit_plan(session_id, vec![first.clone(), second]).await.unwrap();
+        session_mgr.submit_plan(other, vec![make_task("write the lexer", vec![])]).await.unwrap();
+
+        // One task running (taken by hand, so no agent finishes it), one blocked
+        let running = session_mgr.task_queue.dequeue_for(session_id).await.unwrap();
+        assert_eq!(running.id, first.id);
+        session_mgr.destroy_session(session_id).await.unwrap();
+
+        assert!(running.cancellation.is_cancelled());
+        assert_eq!(session_mgr.task_queue.pending_len_for(session_id).await, 0);
+        assert_eq!(session_mgr.task_queue.in_progress_len().await, 0);
+        // Other sessions' work is untouched
+        assert_eq!(session_mgr.task_queue.pending_len().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_cost_accumulates_and_pauses_over_budget() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let mut project = small_project();
+        project.budget_usd = Some(0.10);
+
+        let session_id = session_mgr
+            .create_session("user123".to_string(), project, None)
+            .await
+            .unwrap();
+        let coder = session_mgr.sessions.read().await[&session_id].agents
+            .iter()
+            .find(|a| a.role == AgentRole::Coder)
+            .unwrap()
+            .id;
+
+        // Each fake task: 2k tokens in, 2k out on Opus = $0.06
+        let usage = TokenUsage { input_tokens: 2_000, output_tokens: 2_000 };
+        let per_task = ModelClients::cost_of(ModelPreference::ClaudeOpus45, &usage);
+        for _ in 0..2 {
+            session_mgr.agent_pool.reports_tx.send(AgentReport::Completed {
+                session_id,
+                agent_id: coder,
+                task: Task::new("fake task", 1.0),
+                output: String::new(),
+                model: ModelPreference::ClaudeOpus45,
+                usage,
+                cache_hit: false,
+                throttled_sec: 0.0,
+                duration_sec: 1.0,
+            }).unwrap();
+        }
+
+        wait_until(|| async {
+            session_mgr.get_session_status(session_id).await.unwrap().status
+                == SessionStatus::Paused
+        }).await;
+
+        let status = session_mgr.get_session_status(session_id).await.unwrap();
+        assert!((status.metrics.total_cost - 2.0 * per_task).abs() < 1e-9);
+        assert!(status.metrics.total_cost > 0.10);
+        let agent_cost = session_mgr.sessions.read().await[&session_id].agents
+            .iter()
+            .find(|a| a.id == coder)
+            .unwrap()
+            .cost_incurred;
+        assert!((agent_cost - 2.0 * per_task).abs() < 1e-9);
+
+        assert!(matches!(
+            session_mgr.resume_session(session_id).await,
+            Err(SwarmError::BudgetExceeded)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_budget_policies_on_breach() {
+        // Run a plan under a budget the first task already breaks
+        async fn breach(policy: BudgetPolicy) -> (SessionManager, SessionId, Vec<SwarmEvent>) {
+            let session_mgr = make_manager(Arc::new(RedisClient::new()));
+            let mut events = session_mgr.subscribe();
+            let spec = ProjectSpec { budget_usd: Some(1e-9), budget_policy: policy, ..small_project() };
+            let session_id = session_mgr.create_session("user123".to_string(), spec, None).await.unwrap();
+            let plan = (0..6).map(|i| make_task(&format!("step {i}"), vec![])).collect();
+            session_mgr.submit_plan(session_id, plan).await.unwrap();
+
+            let dispatcher = session_mgr.spawn_dispatcher(1, Duration::from_millis(2)).unwrap();
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            dispatcher.abort();
+            let mut seen = vec![];
+            while let Ok(event) = events.try_recv() {
+                seen.push(event);
+            }
+            (session_mgr, session_id, seen)
+        }
+        let started_after_breach = |events: &[SwarmEvent]| {
+            let breach = events.iter()
+                .position(|e| matches!(e, SwarmEvent::BudgetBreached { .. }))
+                .expect("budget breached");
+            events[breach..].iter().filter(|e| matches!(e, SwarmEvent::TaskStarted { .. })).count()
+        };
+
+        let (session_mgr, session_id, events) = breach(BudgetPolicy::PauseOnExceed).await;
+        assert_eq!(started_after_breach(&events), 0);
+        assert_eq!(session_mgr.get_session_status(session_id).await.unwrap().status, SessionStatus::Paused);
+        assert_eq!(session_mgr.task_queue.pending_len_for(session_id).await, 5);
+
+        // Killed: gone, with the overshoot in the final metrics
+        let (session_mgr, session_id, events) = breach(BudgetPolicy::KillOnExceed).await;
+        assert_eq!(started_after_breach(&events), 0);
+        assert!(matches!(session_mgr.get_session_status(session_id).await, Err(SwarmError::SessionNotFound)));
+        assert!(session_mgr.agent_pool.agents.read().await.is_empty());
+        let metrics = events.iter().find_map(|e| match e {
+            SwarmEvent::BudgetBreached { policy: BudgetPolicy::KillOnExceed, metrics, .. } => Some(metrics),
+            _ => None,
+        }).unwrap();
+        assert!(metrics.total_cost > 1e-9);
+
+        // Warned once, and the plan runs to the end
+        let (session_mgr, session_id, events) = breach(BudgetPolicy::WarnOnly).await;
+        assert!(started_after_breach(&events) > 0);
+        let breaches = events.iter().filter(|e| matches!(e, SwarmEvent::BudgetBreached { .. })).count();
+        assert_eq!(breaches, 1);
+        let status = session_mgr.get_session_status(session_id).await.unwrap();
+        assert_eq!((status.status, status.metrics.tasks_completed), (SessionStatus::Active, 6));
+        session_mgr.pause_session(session_id).await.unwrap();
+        session_mgr.resume_session(session_id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint_and_restore_all() {
+        let redis = Arc::new(RedisClient::new());
+        let session_mgr = make_manager(redis.clone());
+
+        let active = session_mgr
+            .create_session("user123".to_string(), small_project(), None)
+            .await
+            .unwrap();
+        let paused = session_mgr
+            .create_session("user123".to_string(), small_project(), None)
+            .await
+            .unwrap();
+        session_mgr.pause_session(paused).await.unwrap();
+        session_mgr.checkpoint_session(active).await.unwrap();
+        session_mgr.checkpoint_session(paused).await.unwrap();
+
+        // Simulate a restart: a fresh manager pointed at the same Redis
+        let restarted = make_manager(redis);
+        let mut restored = restarted.restore_all().await.unwrap();
+        restored.sort();
+        let mut expected = vec![active, paused];
+        expected.sort();
+        assert_eq!(restored, expected);
+
+        let status = restarted.get_session_status(paused).await.unwrap();
+        assert_eq!(status.status, SessionStatus::Paused);
+        assert!(status.agent_count > 0);
+
+        // Destroyed sessions are not restored again
+        restarted.destroy_session(active).await.unwrap();
+        assert!(restarted.restore_session(active).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resume_from_checkpoint_skips_completed_tasks() {
+        let redis = Arc::new(RedisClient::new());
+        let with_provider = |provider: Arc<ScriptedProvider>| SessionManager::new(
+            Arc::new(AgentPool::new(Arc::new(ModelClients::with_provider(provider)))),
+            Arc::new(StateManager::new(redis.clone())),
+            Arc::new(TaskQueue::new(1_000).with_completion_log(Arc::new(RedisCompletedStore::new(redis.clone())))),
+        );
+        // Coding calls per step, leaving verifications out
+        let steps = |provider: &ScriptedProvider| provider.prompts()
+            .iter()
+            .filter(|p| !verifying(ModelPreference::None, p))
+            .fold(HashMap::<String, usize>::new(), |mut steps, p| {
+                *steps.entry(p.rsplit("\n\n").next().unwrap_or_default().to_string()).or_default() += 1;
+                steps
+            });
+        let gate = Arc::new(Semaphore::new(0));
+        let first = Arc::new(ScriptedProvider::default().on(
+            |model, p| !verifying(model, p) && p.ends_with("\n\nstep b"),
+            Reply::Gated(gate.clone()),
+        ));
+        let session_mgr = with_provider(first.clone());
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let (a, b) = (make_task("step a", vec![]), make_task("step b", vec![]));
+        let c = make_task("step c", vec![a.id, b.id]);
+        session_mgr.submit_plan(session_id, vec![a.clone(), b.clone(), c]).await.unwrap();
+
+        // Checkpointed with a done and b still running
+        let dispatcher = session_mgr.spawn_dispatcher(4, Duration::from_millis(5)).unwrap();
+        wait_until(|| async {
+            session_mgr.get_session_status(session_id).await.unwrap().metrics.tasks_completed == 1
+        }).await;
+        session_mgr.checkpoint_session(session_id).await.unwrap();
+        dispatcher.abort();
+        // b finishes after the checkpoint, then the node "crashes"
+        gate.add_permits(1);
+        wait_until(|| async { session_mgr.task_queue.get_result(b.id).await.is_some() }).await;
+        drop(session_mgr);
+
+        let second = Arc::new(ScriptedProvider::default());
+        let restarted = with_provider(second.clone());
+        let report = restarted.resume_from_checkpoint(session_id).await.unwrap();
+        assert_eq!(report, ResumeReport { queued: 1, completed: 2 });
+        // Results from before and after the checkpoint both carried over
+        for task_id in [a.id, b.id] {
+            assert!(restarted.task_queue.get_result(task_id).await.is_some_and(|r| r.session_id == session_id));
+        }
+        let dispatcher = restarted.spawn_dispatcher(4, Duration::from_millis(5)).unwrap();
+        wait_until(|| async {
+            restarted.get_session_status(session_id).await.unwrap().metrics.tasks_completed == 3
+        }).await;
+        dispatcher.abort();
+
+        // Each step ran exactly once across the two nodes
+        let once = |steps: &[&str]| steps.iter().map(|s| (s.to_string(), 1)).collect::<HashMap<_, _>>();
+        assert_eq!(steps(&first), once(&["step a", "step b"]));
+        assert_eq!(steps(&second), once(&["step c"]));
+    }
+
+    #[tokio::test]
+    async fn test_agent_status_transitions_follow_the_state_machine() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let mut agent = session_mgr.sessions.read().await[&session_id].agents[1].clone();
+
+        assert_eq!(agent.status, AgentStatus::Idle);
+        agent.transition(AgentStatus::Working).unwrap();
+        agent.transition(AgentStatus::Idle).unwrap();
+        agent.transition(AgentStatus::Failed).unwrap();
+        assert!(matches!(
+            agent.transition(AgentStatus::Working),
+            Err(SwarmError::InvalidTransition { from: AgentStatus::Failed, to: AgentStatus::Working, .. })
+        ));
+        assert_eq!(agent.status, AgentStatus::Failed);
+
+        // A failed agent's late `Started` report doesn't put it back to work
+        session_mgr.sessions.write().await.get_mut(&session_id).unwrap()
+            .transition_agent(agent.id, AgentStatus::Failed).unwrap();
+        session_mgr.agent_pool.reports_tx.send(AgentReport::Started { session_id, agent_id: agent.id, verification: false }).unwrap();
+        wait_until(|| async {
+            session_mgr.get_session_status(session_id).await.unwrap().metrics.tasks_assigned == 1
+        }).await;
+        assert_eq!(session_mgr.sessions.read().await[&session_id].agents[1].status, AgentStatus::Failed);
+        assert!(matches!(
+            session_mgr.set_blocked(session_id, agent.id, Some("schema".to_string())).await,
+            Err(SwarmError::InvalidTransition { to: AgentStatus::Blocked, .. })
+        ));
+        // Nor does a late `Completed` or `Failed` free it up
+        session_mgr.agent_pool.reports_tx.send(AgentReport::Failed {
+            session_id,
+            agent_id: agent.id,
+            task_id: TaskId::new_v4(),
+            retriable: true,
+        }).unwrap();
+        let mut events = session_mgr.subscribe();
+        session_mgr.agent_pool.reports_tx.send(AgentReport::Cancelled { session_id, agent_id: agent.id, task_id: TaskId::new_v4() }).unwrap();
+        while !matches!(events.recv().await.unwrap(), SwarmEvent::TaskCancelled { .. }) {}
+        assert_eq!(session_mgr.sessions.read().await[&session_id].agents[1].status, AgentStatus::Failed);
+        assert!(matches!(
+            session_mgr.set_blocked(session_id, agent.id, None).await,
+            Err(SwarmError::InvalidTransition { from: AgentStatus::Failed, to: AgentStatus::Idle, .. })
+        ));
+
+        // Only recovering it does, and only from `Failed`
+        session_mgr.recover_agent(session_id, agent.id).await.unwrap();
+        let counts = session_mgr.sessions.read().await[&session_id].agent_counts();
+        assert_eq!((counts.idle, counts.failed), (5, 0));
+        assert!(matches!(
+            session_mgr.recover_agent(session_id, agent.id).await,
+            Err(SwarmError::InvalidTransition { from: AgentStatus::Idle, .. })
+        ));
+        session_mgr.agent_pool.terminate_agent(agent.id).await.unwrap();
+        assert!(matches!(session_mgr.recover_agent(session_id, agent.id).await, Err(SwarmError::AgentNotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_running_agent_counts_match_a_recount() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let agents: Vec<AgentId> = session_mgr.sessions.read().await[&session_id].agents.iter().map(|a| a.id).collect();
+        let counts = || async {
+            let sessions = session_mgr.sessions.read().await;
+            (sessions[&session_id].agent_counts(), sessions[&session_id].recount())
+        };
+        let (running, recounted) = counts().await;
+        assert_eq!(running, recounted);
+        assert_eq!((running.idle, running.total()), (agents.len(), agents.len()));
+
+        let reports = &session_mgr.agent_pool.reports_tx;
+        reports.send(AgentReport::Started { session_id, agent_id: agents[0], verification: false }).unwrap();
+        reports.send(AgentReport::Started { session_id, agent_id: agents[1], verification: false }).unwrap();
+        wait_until(|| async { counts().await.0.working == 2 }).await;
+        session_mgr.set_blocked(session_id, agents[2], Some("schema".to_string())).await.unwrap();
+        session_mgr.sessions.write().await.get_mut(&session_id).unwrap()
+            .transition_agent(agents[3], AgentStatus::Failed).unwrap();
+        // Rejected moves leave the counts alone
+        session_mgr.sessions.write().await.get_mut(&session_id).unwrap()
+            .transition_agent_or_log(agents[3], AgentStatus::Working);
+        reports.send(AgentReport::Cancelled { session_id, agent_id: agents[1], task_id: TaskId::new_v4() }).unwrap();
+        session_mgr.add_agent(session_id, AgentRole::Coder, vec![]).await.unwrap();
+        wait_until(|| async { counts().await.0.working == 1 }).await;
+
+        let (running, recounted) = counts().await;
+        assert_eq!(running, recounted);
+        assert_eq!(running, StatusCounts { idle: 3, working: 1, blocked: 1, failed: 1 });
+        let status = session_mgr.get_session_status(session_id).await.unwrap();
+        assert_eq!((status.agent_count, status.agents_working, status.agents_unhealthy), (5 + 1, 1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_detects_deadlock_when_every_agent_is_blocked() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        session_mgr.submit_plan(session_id, vec![make_task("draft the schema", vec![])]).await.unwrap();
+        let agents: Vec<AgentId> = session_mgr.sessions.read().await[&session_id].agents.iter().map(|a| a.id).collect();
+        let mut events = session_mgr.subscribe();
+
+        for (i, agent_id) in agents.iter().enumerate() {
+            assert!(session_mgr.detect_deadlock(session_id).await.is_ok());
+            session_mgr.set_blocked(session_id, *agent_id, Some(format!("state key {i}"))).await.unwrap();
+        }
+        let Err(SwarmError::Deadlock { blocked, .. }) = session_mgr.detect_deadlock(session_id).await else {
+            panic!("expected a deadlock");
+        };
+        assert_eq!(blocked.len(), agents.len());
+        assert_eq!(blocked[0], BlockedAgent { agent_id: agents[0], waiting_on: Some("state key 0".to_string()) });
+        assert_eq!(events.recv().await.unwrap(), SwarmEvent::Deadlocked { session_id, blocked });
+
+        // One agent free to work breaks it
+        session_mgr.set_blocked(session_id, agents[1], None).await.unwrap();
+        assert!(session_mgr.detect_deadlock(session_id).await.is_ok());
+
+        // The monitor fails a deadlocked session outright, and stops when told
+        let stop = CancellationToken::new();
+        assert!(matches!(
+            session_mgr.spawn_deadlock_monitor(Duration::ZERO, true, stop.clone()),
+            Err(SwarmError::InvalidSpec(_))
+        ));
+        let monitor = session_mgr.spawn_deadlock_monitor(Duration::from_millis(5), true, stop.clone()).unwrap();
+        session_mgr.set_blocked(session_id, agents[1], Some("state key 1".to_string())).await.unwrap();
+        wait_until(|| async {
+            session_mgr.get_session_status(session_id).await.unwrap().status == SessionStatus::Failed
+        }).await;
+        assert_eq!(session_mgr.user_usage("user123").await.agents, 0);
+        assert!(session_mgr.sessions.read().await[&session_id].agents.is_empty());
+        stop.cancel();
+        monitor.await.unwrap();
+    }
+
+    /// A source that never has another task
+    struct StalledSource;
+
+    #[async_trait]
+    impl TaskSource for StalledSource {
+        async fn next_task(&self) -> Option<Task> {
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_task_source_stops_with_its_session() {
+        let session_mgr = SessionManager::new(
+            Arc::new(AgentPool::new(Arc::new(ModelClients::with_provider(Arc::new(ScriptedProvider::default()))))),
+            Arc::new(StateManager::new(Arc::new(RedisClient::new()))),
+            Arc::new(TaskQueue::new(1)),
+        );
+        let stalled = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let feed = session_mgr.spawn_task_source(stalled, Arc::new(StalledSource));
+        session_mgr.destroy_session(stalled).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), feed).await.unwrap().unwrap();
+
+        // Held back by a full queue, then the session goes
+        session_mgr.task_queue.enqueue(make_task("fills the queue", vec![])).await.unwrap();
+        let backed_up = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let tasks = (0..2).map(|i| make_task(&format!("ticket {i}"), vec![])).collect();
+        let feed = session_mgr.spawn_task_source(backed_up, Arc::new(VecTaskSource::new(tasks)));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        session_mgr.destroy_session(backed_up).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), feed).await.unwrap().unwrap();
+        session_mgr.task_queue.dequeue().await.unwrap();
+        assert_eq!(session_mgr.task_queue.pending_len().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_task_source_feeds_the_queue() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let tasks: Vec<Task> = (0..3).map(|i| make_task(&format!("ticket {i}"), vec![])).collect();
+        let feed = session_mgr.spawn_task_source(session_id, Arc::new(VecTaskSource::new(tasks.clone())));
+        let dispatcher = session_mgr.spawn_dispatcher(4, Duration::from_millis(5)).unwrap();
+
+        wait_until(|| async { session_mgr.collect_results(session_id).await.len() == 3 }).await;
+        dispatcher.abort();
+        // Exhausted, so the feed has stopped on its own
+        feed.await.unwrap();
+        for task in &tasks {
+            let result = session_mgr.task_queue.get_result(task.id).await.unwrap();
+            assert_eq!(result.session_id, session_id);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_pauses_and_checkpoints_sessions() {
+        let redis = Arc::new(RedisClient::new());
+        let session_mgr = make_manager(redis.clone());
+        let first = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let second = session_mgr.create_session("user456".to_string(), small_project(), None).await.unwrap();
+        // One task running on the first session when the signal arrives
+        session_mgr.submit_plan(first, vec![make_task("draft the schema", vec![])]).await.unwrap();
+        session_mgr.dispatch_ready(4).await.unwrap();
+
+        let mut report = session_mgr.shutdown(Duration::from_secs(5)).await;
+        report.saved.sort();
+        let mut expected = vec![first, second];
+        expected.sort();
+        assert_eq!(report.saved, expected);
+        assert!(report.abandoned.is_empty());
+        assert_eq!((report.tasks_completed, report.tasks_abandoned), (1, 0));
+
+        for session_id in [first, second] {
+            let status = session_mgr.get_session_status(session_id).await.unwrap();
+            assert_eq!(status.status, SessionStatus::Paused);
+        }
+        // The checkpoints hold the state as of shutdown
+        let restarted = make_manager(redis);
+        restarted.restore_all().await.unwrap();
+        assert_eq!(restarted.get_session_status(first).await.unwrap().status, SessionStatus::Paused);
+        // ...with the task through verification, not still waiting on it
+        let metrics = restarted.get_session_status(first).await.unwrap().metrics;
+        assert_eq!((metrics.tasks_assigned, metrics.tasks_completed), (1, 1));
+        assert_eq!(restarted.get_session_status(second).await.unwrap().status, SessionStatus::Paused);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_round_trips_in_both_formats() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let coder = session_mgr.sessions.read().await[&session_id].agents[1].id;
+        session_mgr.assign_task(session_id, coder, make_task("write the parser", vec![])).await.unwrap();
+        wait_until(|| async {
+            session_mgr.get_session_status(session_id).await.unwrap().metrics.tasks_completed == 1
+        }).await;
+        let shared_state = session_mgr.sessions.read().await[&session_id].shared_state.clone();
+        shared_state.set("schema", "v2".to_string()).await.unwrap();
+        let original = session_mgr.get_session_status(session_id).await.unwrap();
+
+        for format in [SnapshotFormat::Json, SnapshotFormat::MessagePack] {
+            let bytes = session_mgr.export_snapshot(session_id, format).await.unwrap();
+
+            // Another deployment, sharing nothing with the first
+            let migrated = make_manager(Arc::new(RedisClient::new()));
+            assert_eq!(migrated.import_snapshot(&bytes, format).await.unwrap(), session_id);
+            let status = migrated.get_session_status(session_id).await.unwrap();
+            assert_eq!(status.metrics, original.metrics);
+            assert_eq!((status.user_id.as_str(), status.agent_count), ("user123", original.agent_count));
+            let sessions = migrated.sessions.read().await;
+            let session = &sessions[&session_id];
+            assert_eq!(session.shared_state.get("schema").await.unwrap().as_deref(), Some("v2"));
+            assert_eq!(session.agents[1].tasks_completed, 1);
+            assert!(migrated.agent_pool.is_local(session.agents[1].id).await);
+            drop(sessions);
+
+            assert!(matches!(
+                migrated.import_snapshot(&bytes, format).await,
+                Err(SwarmError::SessionExists(id)) if id == session_id
+            ));
+        }
+        assert!(matches!(
+            session_mgr.import_snapshot(b"not a snapshot", SnapshotFormat::MessagePack).await,
+            Err(SwarmError::Snapshot { format: SnapshotFormat::MessagePack, .. })
+        ));
+
+        // Of two racing imports one wins, and only it is counted
+        let bytes = session_mgr.export_snapshot(session_id, SnapshotFormat::Json).await.unwrap();
+        let migrated = make_manager(Arc::new(RedisClient::new()));
+        let (a, b) = tokio::join!(
+            migrated.import_snapshot(&bytes, SnapshotFormat::Json),
+            migrated.import_snapshot(&bytes, SnapshotFormat::Json),
+        );
+        assert!(matches!(
+            (a, b),
+            (Ok(_), Err(SwarmError::SessionExists(_))) | (Err(SwarmError::SessionExists(_)), Ok(_))
+        ));
+        let usage = UserUsage { sessions: 1, agents: original.agent_count };
+        assert_eq!(migrated.user_usage("user123").await, usage);
+        assert_eq!(migrated.agent_pool.agents.read().await.len(), original.agent_count);
+
+        // The user's quota applies
+        let capped = make_manager(Arc::new(RedisClient::new())).with_quota_config(QuotaConfig {
+            max_total_agents_per_user: original.agent_count - 1,
+            ..QuotaConfig::default()
+        });
+        assert!(matches!(
+            capped.import_snapshot(&bytes, SnapshotFormat::Json).await,
+            Err(SwarmError::QuotaExceeded { resource: "agents", .. })
+        ));
+        assert_eq!(capped.user_usage("user123").await, UserUsage::default());
+
+        // A spawn failing partway takes down the agents spawned before it
+        let cramped = make_manager_on(
+            AgentPool::new(Arc::new(ModelClients::new())).with_max_agents(original.agent_count - 1)
+        );
+        assert!(matches!(
+            cramped.import_snapshot(&bytes, SnapshotFormat::Json).await,
+            Err(SwarmError::PoolSaturated { .. })
+        ));
+        assert!(cramped.sessions.read().await.is_empty());
+        assert_eq!(cramped.user_usage("user123").await, UserUsage::default());
+        assert_eq!(cramped.agent_pool.available_capacity().await, original.agent_count - 1);
+        assert!(cramped.rehydrating().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_idle_sessions_are_reaped() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let mut events = session_mgr.subscribe();
+        let idle = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        // Its task never runs, but it's still work outstanding
+        let busy = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        session_mgr.submit_plan(busy, vec![make_task("write the parser", vec![])]).await.unwrap();
+
+        assert!(matches!(
+            session_mgr.spawn_idle_session_reaper(Duration::from_millis(50), IdleAction::Destroy, Duration::ZERO),
+            Err(SwarmError::InvalidSpec(_))
+        ));
+        let reaper = session_mgr.spawn_idle_session_reaper(
+            Duration::from_millis(50),
+            IdleAction::Destroy,
+            Duration::from_millis(10),
+        ).unwrap();
+        let (session_id, idle_sec) = loop {
+            if let SwarmEvent::SessionIdle { session_id, idle_sec, action } = events.recv().await.unwrap() {
+                assert_eq!(action, IdleAction::Destroy);
+                break (session_id, idle_sec);
+            }
+        };
+        assert_eq!(session_id, idle);
+        assert!(idle_sec > 0.05, "{idle_sec}");
+        assert!(matches!(session_mgr.get_session_status(idle).await, Err(SwarmError::SessionNotFound)));
+        assert_eq!(session_mgr.user_usage("user123").await, UserUsage { sessions: 1, agents: 5 });
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        reaper.abort();
+        assert_eq!(session_mgr.get_session_status(busy).await.unwrap().status, SessionStatus::Active);
+
+        // Pausing instead keeps the session but hands its agents back
+        let parked = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        assert!(session_mgr.reap_idle_sessions(Duration::from_millis(50), IdleAction::Pause).await.is_empty());
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let capacity = session_mgr.agent_pool.available_capacity().await;
+        assert_eq!(session_mgr.reap_idle_sessions(Duration::from_millis(50), IdleAction::Pause).await, vec![parked]);
+        let report = session_mgr.get_session_status(parked).await.unwrap();
+        assert_eq!((report.status, report.agent_count), (SessionStatus::Paused, 0));
+        assert_eq!(session_mgr.agent_pool.available_capacity().await, capacity + 5);
+        assert_eq!(session_mgr.user_usage("user123").await, UserUsage { sessions: 2, agents: 5 });
+
+        session_mgr.resume_session(parked).await.unwrap();
+        let report = session_mgr.get_session_status(parked).await.unwrap();
+        assert_eq!((report.status, report.agent_count), (SessionStatus::Active, 5));
+        assert_eq!(session_mgr.user_usage("user123").await, UserUsage { sessions: 2, agents: 10 });
+    }
+
+    #[tokio::test]
+    async fn test_cancel_user_sessions_takes_down_only_that_user() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new())).with_cancel_settle_timeout(Duration::from_millis(100));
+        let mut alice = HashSet::new();
+        for _ in 0..3 {
+            alice.insert(session_mgr.create_session("alice".to_string(), small_project(), None).await.unwrap());
+        }
+        let bob = session_mgr.create_session("bob".to_string(), small_project(), None).await.unwrap();
+
+        // Alice keeps creating sessions while she's cancelled: the creates
+        // already under way finish and are then cancelled, later ones fail
+        let racing = (0..4).map(|_| session_mgr.create_session("alice".to_string(), small_project(), None));
+        let (raced, cancelled) = tokio::join!(
+            futures::future::join_all(racing),
+            session_mgr.cancel_user_sessions("alice"),
+        );
+        for result in raced {
+            match result {
+                Ok(session_id) => assert!(alice.insert(session_id)),
+                Err(err) => assert!(matches!(err, SwarmError::UserCancelling(ref user) if user == "alice"), "{err:?}"),
+            }
+        }
+
+        let ids: HashSet<SessionId> = cancelled.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, alice);
+        assert!(cancelled.iter().all(|(_, metrics)| metrics.as_ref().unwrap().agents_spawned == 5));
+        let remaining: Vec<_> = session_mgr.list_sessions(SessionFilter::default()).await
+            .into_iter()
+            .map(|r| r.session_id)
+            .collect();
+        assert_eq!(remaining, vec![bob]);
+        assert_eq!(session_mgr.user_usage("alice").await, UserUsage::default());
+
+        // Once the cancel is over she can start again
+        session_mgr.create_session("alice".to_string(), small_project(), None).await.unwrap();
+
+        // A cancel dropped midway lets the user back in too, and one
+        // waiting on quota no session holds gives up in the end
+        session_mgr.usage.write().await.entry("carol".to_string()).or_default().sessions = 1;
+        let abandoned = tokio::time::timeout(Duration::from_millis(20), session_mgr.cancel_user_sessions("carol")).await;
+        assert!(abandoned.is_err());
+        assert!(session_mgr.cancelling().is_empty());
+        assert!(session_mgr.cancel_user_sessions("carol").await.is_empty());
+        assert!(session_mgr.cancelling().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_diff_pinpoints_the_divergence() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let mut snapshots = vec![];
+        let runs = [("write the lexer", "1", "fn parse() {}", 1), ("write the printer", "2", "fn parse(&mut self) {}", 2)];
+        for (last_step, seed, parser, steps_done) in runs {
+            let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+            let plan = vec![make_task("write the parser", vec![]), make_task(last_step, vec![])];
+            session_mgr.submit_plan(session_id, plan).await.unwrap();
+            let shared_state = session_mgr.sessions.read().await[&session_id].shared_state.clone();
+            shared_state.set("schema", "v2".to_string()).await.unwrap();
+            shared_state.set("seed", seed.to_string()).await.unwrap();
+            let agent_id = AgentId::new_v4();
+            for output in [parser, "fn print() {}"].into_iter().take(steps_done) {
+                let mut done = session_mgr.task_queue.dequeue_for(session_id).await.unwrap();
+                session_mgr.task_queue.claim(done.id, agent_id).await;
+                done.result = Some(TaskResult {
+                    task_id: done.id,
+                    session_id,
+                    agent_id,
+                    model: ModelPreference::ClaudeOpus45,
+                    output: output.to_string(),
+                    cost: 0.0,
+                    completed_at: Utc::now(),
+                });
+                assert!(session_mgr.task_queue.complete_with(done, agent_id).await);
+            }
+            snapshots.push(session_mgr.snapshot(session_id).await.unwrap());
+        }
+
+        // The right run went on to finish its last step, and wrote the parser differently
+        let diff = snapshots[0].diff(&snapshots[1]);
+        assert_eq!(diff, SnapshotDiff {
+            metrics: vec![],
+            tasks_only_left: vec!["write the lexer".to_string()],
+            tasks_only_right: vec![],
+            completed_only_left: vec![],
+            completed_only_right: vec!["write the printer".to_string()],
+            results: vec![ResultDiff {
+                task: "write the parser".to_string(),
+                left: "fn parse() {}".to_string(),
+                right: "fn parse(&mut self) {}".to_string(),
+            }],
+            state: vec![StateDiff { key: "seed".to_string(), left: Some("1".to_string()), right: Some("2".to_string()) }],
+        });
+        assert_eq!(diff.to_string(), concat!(
+            "task only in left: \"write the lexer\"\n",
+            "completed only in right: \"write the printer\"\n",
+            "output of \"write the parser\": \"fn parse() {}\" != \"fn parse(&mut self) {}\"\n",
+            "state \"seed\": \"1\" != \"2\"\n",
+        ));
+        let round_trip: SnapshotDiff = serde_json::from_str(&serde_json::to_string(&diff).unwrap()).unwrap();
+        assert_eq!(round_trip, diff);
+        assert!(snapshots[0].diff(&snapshots[0]).is_empty());
+
+        // Metrics are compared field by field
+        let mut busier = snapshots[0].clone();
+        busier.session.metrics.tasks_completed = 3;
+        let diff = snapshots[0].diff(&busier);
+        assert_eq!(diff.metrics, vec![FieldDiff { field: "tasks_completed".to_string(), left: 0.into(), right: 3.into() }]);
+        assert_eq!(diff.to_string(), "metrics.tasks_completed: 0 != 3\n");
+    }
+
+    #[tokio::test]
+    async fn test_migration_hands_tasks_over_exactly_once() {
+        // Two nodes sharing a Redis, nothing else
+        let redis = Arc::new(RedisClient::new());
+        let source = make_manager(redis.clone());
+        let target = make_manager(redis);
+        let session_id = source.create_session("user123".to_string(), small_project(), None).await.unwrap();
+
+        let mut tasks: Vec<Task> = ["define the schema", "write the parser", "document it"]
+            .into_iter()
+            .map(|d| make_task(d, vec![]))
+            .collect();
+        let schema = tasks[0].id;
+        tasks[1].dependencies.push(schema);
+        for task in &mut tasks {
+            task.session_id = Some(session_id);
+            source.task_queue.enqueue(task.clone()).await.unwrap();
+        }
+        let done = source.task_queue.dequeue_for(session_id).await.unwrap();
+        assert!(source.task_queue.complete(done.id).await);
+        let interrupted = source.task_queue.dequeue_for(session_id).await.unwrap();
+        source.sessions.read().await[&session_id].shared_state.set("schema", "v2".to_string()).await.unwrap();
+
+        let bundle = source.export_for_migration(session_id, Duration::from_millis(50)).await.unwrap();
+        assert_eq!(bundle.epoch, 1);
+        assert!(matches!(source.get_session_status(session_id).await, Err(SwarmError::SessionNotFound)));
+        assert!(source.task_queue.outstanding_for(session_id).await.is_empty());
+        // The interrupted task's late result is ignored on the source
+        assert!(!source.task_queue.complete(interrupted.id).await);
+
+        // A node without room for the tasks backs out, leaving the bundle
+        // for another
+        let cramped = SessionManager::new(
+            Arc::new(AgentPool::new(Arc::new(ModelClients::new()))),
+            target.state_manager.clone(),
+            Arc::new(TaskQueue::new(1)),
+        );
+        assert!(matches!(cramped.import_migration(bundle.clone()).await, Err(SwarmError::QueueFull)));
+        assert!(matches!(cramped.get_session_status(session_id).await, Err(SwarmError::SessionNotFound)));
+        assert!(cramped.usage.read().await.get("user123").is_none_or(|u| u.sessions == 0 && u.agents == 0));
+
+        let encoded = serde_json::to_vec(&bundle).unwrap();
+        assert_eq!(target.import_migration(bundle.clone()).await.unwrap(), session_id);
+        let mut carried: Vec<TaskId> = target.task_queue.outstanding_for(session_id).await.iter().map(|t| t.id).collect();
+        let mut expected: Vec<TaskId> = tasks.iter().map(|t| t.id).filter(|&id| id != done.id).collect();
+        carried.sort();
+        expected.sort();
+        assert_eq!(carried, expected);
+        let status = target.get_session_status(session_id).await.unwrap();
+        assert_eq!((status.status, status.agent_count), (SessionStatus::Active, 5));
+        let shared_state = target.sessions.read().await[&session_id].shared_state.clone();
+        assert_eq!(shared_state.get("schema").await.unwrap().as_deref(), Some("v2"));
+
+        // The finished dependency carried over, so the parser is ready too
+        let mut ready = target.task_queue.ready_tasks().await;
+        ready.sort();
+        assert_eq!(ready, expected);
+
+        // Neither node takes the same bundle a second time
+        let decoded: MigrationBundle = serde_json::from_slice(&encoded).unwrap();
+        target.destroy_session(session_id).await.unwrap();
+        assert!(matches!(
+            target.import_migration(decoded).await,
+            Err(SwarmError::StaleMigration { epoch: 1, .. })
+        ));
+        assert!(matches!(
+            source.import_migration(bundle).await,
+            Err(SwarmError::StaleMigration { epoch: 1, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_assigned_task_is_executed_and_reported() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let session_id = session_mgr
+            .create_session("user123".to_string(), small_project(), None)
+            .await
+            .unwrap();
+        let coder = session_mgr.sessions.read().await[&session_id].agents
+            .iter()
+            .find(|a| a.role == AgentRole::Coder)
+            .unwrap()
+            .id;
+
+        let task = Task::new("implement login form", 5.0);
+        session_mgr.task_queue.enqueue(task.clone()).await.unwrap();
+        let task = session_mgr.task_queue.dequeue().await.unwrap();
+        session_mgr.agent_pool.assign_task(coder, task).await.unwrap();
+
+        wait_until(|| async {
+            session_mgr.get_session_status(session_id).await.unwrap().metrics.tasks_completed == 1
+        }).await;
+
+        let status = session_mgr.get_session_status(session_id).await.unwrap();
+        assert_eq!(status.metrics.tasks_assigned, 1);
+        assert!(status.metrics.total_cost > 0.0);
+        assert!(session_mgr.task_queue.ready_tasks().await.is_empty());
+
+        // A terminated agent's inbox is closed
+        session_mgr.agent_pool.terminate_agent(coder).await.unwrap();
+        assert!(matches!(
+            session_mgr.agent_pool.assign_task(coder, Task::new("late", 1.0)).await,
+            Err(SwarmError::AgentNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_completed_task_result_is_retrievable() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let session_id = session_mgr
+            .create_session("user123".to_string(), small_project(), None)
+            .await
+            .unwrap();
+        let coder = session_mgr.sessions.read().await[&session_id].agents
+            .iter()
+            .find(|a| a.role == AgentRole::Coder)
+            .unwrap()
+            .id;
+
+        session_mgr.submit_plan(session_id, vec![make_task("write the parser", vec![])]).await.unwrap();
+        let task = session_mgr.task_queue.dequeue().await.unwrap();
+        assert_eq!(session_mgr.task_queue.get_result(task.id).await, None);
+        session_mgr.assign_task(session_id, coder, task.clone()).await.unwrap();
+
+        // Recorded once the verifier has passed it
+        wait_until(|| async { session_mgr.task_queue.get_result(task.id).await.is_some() }).await;
+        let result = session_mgr.task_queue.get_result(task.id).await.unwrap();
+        assert_eq!((result.task_id, result.session_id, result.agent_id), (task.id, session_id, coder));
+        assert!(result.output.contains("write the parser"));
+        assert!(result.cost > 0.0);
+
+        assert_eq!(session_mgr.collect_results(session_id).await, vec![result]);
+        assert!(session_mgr.collect_results(SessionId::new_v4()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_evicted_completed_tasks_are_read_back_from_the_store() {
+        let dir = std::env::temp_dir().join(format!("completed-{}", Uuid::new_v4()));
+        let stores: Vec<Arc<dyn CompletedStore>> = vec![
+            Arc::new(RedisCompletedStore::new(Arc::new(RedisClient::new()))),
+            Arc::new(FileCompletedStore::open(&dir).await.unwrap()),
+        ];
+        for store in stores {
+            let queue = TaskQueue::new(100).with_completed_store(2, store);
+            let (session_id, agent_id) = (SessionId::new_v4(), AgentId::new_v4());
+            let mut results = vec![];
+            for i in 0..5 {
+                let mut task = make_task(&format!("step {i}"), vec![]);
+                task.session_id = Some(session_id);
+                queue.enqueue(task).await.unwrap();
+                let mut task = queue.dequeue().await.unwrap();
+                let result = TaskResult {
+                    task_id: task.id,
+                    session_id,
+                    agent_id,
+                    model: ModelPreference::ClaudeOpus45,
+                    output: format!("did step {i}"),
+                    cost: 0.01,
+                    completed_at: Utc::now(),
+                };
+                task.result = Some(result.clone());
+                assert!(queue.complete_with(task, agent_id).await);
+                results.push(result);
+            }
+
+            assert_eq!(queue.completed.read().await.tasks.len(), 2);
+            for result in &results {
+                assert_eq!(queue.get_result(result.task_id).await.as_ref(), Some(result));
+            }
+            assert_eq!(queue.results_for(session_id).await, results);
+            assert_eq!(queue.published_result(session_id, results[0].task_id).await.as_ref(), Some(&results[0]));
+            // Still done as far as dependents are concerned
+            let (_, done) = queue.hand_off(session_id).await;
+            assert_eq!(done, results.iter().map(|r| r.task_id).collect::<Vec<_>>());
+        }
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+
+    #[tokio::test]
+    async fn test_dedup_runs_equivalent_tasks_once() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let spec = ProjectSpec { dedup_tasks: true, ..small_project() };
+        let session_id = session_mgr.create_session("user123".to_string(), spec, None).await.unwrap();
+
+        let (first, second) = (make_task("write the parser", vec![]), make_task("write the parser", vec![]));
+        let dependent = make_task("wire up the parser", vec![second.id]);
+        let plan = vec![first.clone(), second.clone(), dependent.clone()];
+        session_mgr.submit_plan(session_id, plan).await.unwrap();
+        assert_eq!(session_mgr.task_queue.pending_len_for(session_id).await, 2);
+
+        let dispatcher = session_mgr.spawn_dispatcher(4, Duration::from_millis(2)).unwrap();
+        wait_until(|| async { session_mgr.task_queue.get_result(dependent.id).await.is_some() }).await;
+        dispatcher.abort();
+
+        // The parser ran once; its duplicate shares the result
+        let results = session_mgr.collect_results(session_id).await;
+        let ran: Vec<TaskId> = results.iter().map(|r| r.task_id).collect();
+        assert_eq!(ran, vec![first.id, dependent.id]);
+        let shared = session_mgr.task_queue.get_result(second.id).await.unwrap();
+        assert_eq!(shared, results[0]);
+
+        // Later equivalents fold into the completed task too, unless keyed apart
+        let mut again = make_task("write the parser", vec![]);
+        again.session_id = Some(session_id);
+        assert_eq!(session_mgr.task_queue.enqueue(again.clone()).await.unwrap(), first.id);
+        let keyed = Task { id: TaskId::new_v4(), dedup_key: Some("parser v2".to_string()), ..again };
+        assert_eq!(session_mgr.task_queue.enqueue(keyed.clone()).await.unwrap(), keyed.id);
+
+        // Opt-in: other sessions still run every task
+        let plain = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let mut copy = make_task("write the parser", vec![]);
+        copy.session_id = Some(plain);
+        assert_eq!(session_mgr.task_queue.enqueue(copy.clone()).await.unwrap(), copy.id);
+    }
+
+    #[tokio::test]
+    async fn test_cross_session_dependencies_wait_for_upstream_results() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let research = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let manufacturing = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let survey = make_task("survey heat-resistant alloys", vec![]);
+        let machine = make_task("machine the turbine blade", vec![]);
+
+        // Linked before the dependent is submitted, with research held back
+        session_mgr.submit_plan(research, vec![survey.clone()]).await.unwrap();
+        session_mgr.pause_session(research).await.unwrap();
+        session_mgr.link_dependency((manufacturing, machine.id), (research, survey.id)).await.unwrap();
+        session_mgr.submit_plan(manufacturing, vec![machine.clone()]).await.unwrap();
+        let dispatcher = session_mgr.spawn_dispatcher(4, Duration::from_millis(2)).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(session_mgr.task_queue.get_result(machine.id).await.is_none());
+        assert!(!session_mgr.task_queue.ready_tasks().await.contains(&machine.id));
+
+        session_mgr.resume_session(research).await.unwrap();
+        wait_until(|| async { session_mgr.task_queue.get_result(machine.id).await.is_some() }).await;
+        let upstream = session_mgr.task_queue.published_result(research, survey.id).await.unwrap();
+        let downstream = session_mgr.task_queue.get_result(machine.id).await.unwrap();
+        assert!(upstream.completed_at <= downstream.completed_at);
+
+        // Upstream finished and gone before the downstream task is enqueued
+        session_mgr.complete_session(research).await.unwrap();
+        session_mgr.destroy_session(research).await.unwrap();
+        let assemble = make_task("assemble the engine", vec![]);
+        session_mgr.link_dependency((manufacturing, assemble.id), (research, survey.id)).await.unwrap();
+        session_mgr.submit_plan(manufacturing, vec![assemble.clone()]).await.unwrap();
+        wait_until(|| async { session_mgr.task_queue.get_result(assemble.id).await.is_some() }).await;
+        dispatcher.abort();
+
+        // Nothing to wait for, or too late to wait
+        let unknown = (SessionId::new_v4(), TaskId::new_v4());
+        assert!(matches!(
+            session_mgr.link_dependency((manufacturing, TaskId::new_v4()), unknown).await,
+            Err(SwarmError::SessionNotFound)
+        ));
+        let missing = TaskId::new_v4();
+        assert!(matches!(
+            session_mgr.link_dependency((manufacturing, TaskId::new_v4()), (manufacturing, missing)).await,
+            Err(SwarmError::TaskNotFound(id)) if id == missing
+        ));
+        assert!(matches!(
+            session_mgr.link_dependency((manufacturing, machine.id), (research, survey.id)).await,
+            Err(SwarmError::TaskStarted(id)) if id == machine.id
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_upstream_failure_dead_letters_linked_tasks() {
+        let queue = TaskQueue::new(100);
+        let (research, manufacturing, assembly) = (SessionId::new_v4(), SessionId::new_v4(), SessionId::new_v4());
+        let in_session = |session_id, mut task: Task| {
+            task.session_id = Some(session_id);
+            task
+        };
+        let mut survey = in_session(research, make_task("survey heat-resistant alloys", vec![]));
+        survey.retry_policy.max_attempts = 1;
+        let machine = in_session(manufacturing, make_task("machine the turbine blade", vec![]));
+        let assemble = in_session(assembly, make_task("assemble the engine", vec![]));
+
+        // One dependent queued, the next in the chain not enqueued yet
+        queue.enqueue(survey.clone()).await.unwrap();
+        queue.enqueue(machine.clone()).await.unwrap();
+        queue.link_dependency((manufacturing, machine.id), (research, survey.id)).await.unwrap();
+        queue.link_dependency((assembly, assemble.id), (manufacturing, machine.id)).await.unwrap();
+        assert_eq!(queue.dequeue().await.map(|t| t.id), Some(survey.id));
+        assert_eq!(queue.fail(survey.id).await, Some(FailureOutcome::DeadLettered));
+        assert_eq!(queue.pending_len().await, 0);
+        assert!(queue.dead_letter().await.iter().any(|t| t.id == machine.id && t.cancellation.is_cancelled()));
+
+        queue.enqueue(assemble.clone()).await.unwrap();
+        assert_eq!(queue.pending_len().await, 0);
+        let dead: Vec<TaskId> = queue.dead_letter().await.iter().map(|t| t.id).collect();
+        assert_eq!(dead, vec![survey.id, machine.id, assemble.id]);
+        // A failed upstream can't be linked to any more
+        assert!(matches!(
+            queue.link_dependency((assembly, TaskId::new_v4()), (research, survey.id)).await,
+            Err(SwarmError::TaskNotFound(_))
+        ));
+
+        // Cancelling an upstream task fails its dependents the same way
+        let drill = in_session(research, make_task("drill the cooling holes", vec![]));
+        let coat = in_session(manufacturing, make_task("coat the blade", vec![]));
+        queue.enqueue(drill.clone()).await.unwrap();
+        queue.enqueue(coat.clone()).await.unwrap();
+        queue.link_dependency((manufacturing, coat.id), (research, drill.id)).await.unwrap();
+        queue.cancel(drill.id, research).await.unwrap();
+        assert_eq!(queue.pending_len().await, 0);
+        assert_eq!(queue.dead_letter().await.last().map(|t| t.id), Some(coat.id));
+    }
+
+    #[tokio::test]
+    async fn test_published_results_are_bounded_but_kept_while_linked() {
+        let queue = TaskQueue::new(100).with_published_capacity(4);
+        let (upstream, downstream) = (SessionId::new_v4(), SessionId::new_v4());
+        let mut waiting = make_task("needs the first result", vec![TaskId::new_v4()]);
+        waiting.session_id = Some(downstream);
+
+        let mut first = None;
+        for i in 0..8 {
+            let mut task = make_task(&format!("step {i}"), vec![]);
+            task.session_id = Some(upstream);
+            queue.enqueue(task.clone()).await.unwrap();
+            if i == 0 {
+                // Held back by a local dependency that never completes
+                queue.enqueue(waiting.clone()).await.unwrap();
+                queue.link_dependency((downstream, waiting.id), (upstream, task.id)).await.unwrap();
+                first = Some(task.id);
+            }
+            let mut done = queue.dequeue_for(upstream).await.unwrap();
+            done.result = Some(TaskResult {
+                task_id: done.id,
+                session_id: upstream,
+                agent_id: AgentId::nil(),
+                model: ModelPreference::ClaudeOpus45,
+                output: format!("result {i}"),
+                cost: 0.0,
+                completed_at: Utc::now(),
+            });
+            assert!(queue.complete_with(done, AgentId::nil()).await);
+        }
+
+        let published = queue.completed.read().await.published.len();
+        assert!(published <= 4, "{published} results still published");
+        assert!(queue.published_result(upstream, first.unwrap()).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_observer_reads_status_without_mutating() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        // Only read methods exist on the observer: `create_session`,
+        // `pause_session` or `destroy_session` on it wouldn't compile
+        let observer: SessionObserver = session_mgr.observer();
+        let mut events = observer.subscribe();
+
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        assert!(matches!(events.recv().await.unwrap(), SwarmEvent::AgentSpawned { session_id: id, .. } if id == session_id));
+        let seen = observer.get_session_status(session_id).await.unwrap();
+        assert_eq!(seen.status, SessionStatus::Active);
+        assert_eq!(seen.agent_count, session_mgr.get_session_status(session_id).await.unwrap().agent_count);
+        assert_eq!(observer.list_sessions(SessionFilter::default()).await.len(), 1);
+
+        // It follows the manager's changes live
+        session_mgr.pause_session(session_id).await.unwrap();
+        assert_eq!(observer.get_session_status(session_id).await.unwrap().status, SessionStatus::Paused);
+        session_mgr.destroy_session(session_id).await.unwrap();
+        assert!(matches!(observer.get_session_status(session_id).await, Err(SwarmError::SessionNotFound)));
+        assert!(observer.list_sessions(SessionFilter::default()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_create_session_is_idempotent_per_key() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()))
+            .with_idempotency_window(Duration::from_millis(100));
+        let key = || Some("req-42".to_string());
+
+        let first = session_mgr.create_session("user123".to_string(), small_project(), key()).await.unwrap();
+        let agents = session_mgr.agent_pool.agents.read().await.len();
+        let retried = session_mgr.create_session("user123".to_string(), small_project(), key()).await.unwrap();
+        assert_eq!(retried, first);
+        assert_eq!(session_mgr.sessions.read().await.len(), 1);
+        assert_eq!(session_mgr.agent_pool.agents.read().await.len(), agents);
+        assert_eq!(session_mgr.user_usage("user123").await.sessions, 1);
+
+        // Keys are scoped to the user, and expire
+        let other = session_mgr.create_session("user456".to_string(), small_project(), key()).await.unwrap();
+        assert_ne!(other, first);
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let later = session_mgr.create_session("user123".to_string(), small_project(), key()).await.unwrap();
+        assert_ne!(later, first);
+
+        // Racing calls under one key share a single creation
+        let racing = || session_mgr.create_session("user789".to_string(), small_project(), Some("req-7".to_string()));
+        let (a, b) = tokio::join!(racing(), racing());
+        assert_eq!(a.unwrap(), b.unwrap());
+        assert_eq!(session_mgr.user_usage("user789").await.sessions, 1);
+
+        // A failed creation leaves the key for the retry
+        let broken = ProjectSpec { replication_count: 0, ..small_project() };
+        let retry = || Some("req-9".to_string());
+        assert!(session_mgr.create_session("user789".to_string(), broken, retry()).await.is_err());
+        let fixed = session_mgr.create_session("user789".to_string(), small_project(), retry()).await.unwrap();
+        assert_eq!(session_mgr.create_session("user789".to_string(), small_project(), retry()).await.unwrap(), fixed);
+    }
+
+    #[tokio::test]
+    async fn test_subsessions_roll_up_and_cascade() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let parent = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let children = [
+            session_mgr.create_subsession(parent, small_project()).await.unwrap(),
+            session_mgr.create_subsession(parent, small_project()).await.unwrap(),
+        ];
+        let grandchild = session_mgr.create_subsession(children[0], small_project()).await.unwrap();
+        assert_eq!(session_mgr.sessions.read().await[&grandchild].parent_id, Some(children[0]));
+
+        let family = [parent, children[0], children[1], grandchild];
+        for (i, &session_id) in family.iter().enumerate() {
+            let coder = session_mgr.sessions.read().await[&session_id].agents[1].id;
+            let task = make_task(&format!("part {i} of the build"), vec![]);
+            session_mgr.assign_task(session_id, coder, task).await.unwrap();
+        }
+        let mut costs = vec![];
+        for &session_id in &family {
+            wait_until(|| async {
+                session_mgr.get_session_status(session_id).await.unwrap().metrics.tasks_completed == 1
+            }).await;
+            costs.push(session_mgr.get_session_status(session_id).await.unwrap().metrics.total_cost);
+        }
+
+        let own = session_mgr.get_session_status(parent).await.unwrap();
+        assert_eq!(own.metrics.total_cost, costs[0]);
+        assert!(own.descendants.is_empty());
+        let rolled_up = session_mgr.get_rolled_up_status(parent).await.unwrap();
+        assert!((rolled_up.metrics.total_cost - costs.iter().sum::<f64>()).abs() < 1e-12);
+        assert_eq!(rolled_up.metrics.tasks_completed, 4);
+        assert_eq!(rolled_up.agent_count, 4 * own.agent_count);
+        assert_eq!(rolled_up.descendants.len(), 3);
+        let branch = session_mgr.get_rolled_up_status(children[1]).await.unwrap();
+        assert_eq!((branch.parent_id, branch.metrics.tasks_completed), (Some(parent), 1));
+
+        // Destroying the parent takes the whole tree with it
+        session_mgr.destroy_session(parent).await.unwrap();
+        assert!(session_mgr.sessions.read().await.is_empty());
+        assert!(session_mgr.agent_pool.agents.read().await.is_empty());
+        assert_eq!(session_mgr.user_usage("user123").await, UserUsage::default());
+        assert!(matches!(
+            session_mgr.create_subsession(parent, small_project()).await,
+            Err(SwarmError::SessionNotFound)
+        ));
+    }
+
+    /// A `MemoryBackend` refusing to delete keys under one prefix
+    #[derive(Default)]
+    struct StickyBackend {
+        inner: MemoryBackend,
+        sticky: std::sync::Mutex<Option<String>>,
+    }
+
+    #[async_trait]
+    impl StateBackend for StickyBackend {
+        async fn get(&self, key: &str) -> Result<Option<String>, SwarmError> {
+            self.inner.get(key).await
+        }
+
+        async fn set(&self, key: &str, value: String) -> Result<(), SwarmError> {
+            self.inner.set(key, value).await
+        }
+
+        async fn delete(&self, key: &str) -> Result<(), SwarmError> {
+            let sticky = self.sticky.lock().unwrap().as_ref().is_some_and(|prefix| key.starts_with(prefix.as_str()));
+            if sticky {
+                return Err(SwarmError::StateError {
+                    key: key.to_string(),
+                    source: "backend unavailable".into(),
+                });
+            }
+            self.inner.delete(key).await
+        }
+
+        async fn scan(&self, prefix: &str) -> Result<Vec<String>, SwarmError> {
+            self.inner.scan(prefix).await
+        }
+
+        async fn cas(&self, key: &str, expected: Option<&str>, new: String) -> Result<bool, SwarmError> {
+            self.inner.cas(key, expected, new).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_destroy_tears_down_the_whole_tree_past_a_failure() {
+        let backend = Arc::new(StickyBackend::default());
+        let session_mgr = SessionManager::new(
+            Arc::new(AgentPool::new(Arc::new(ModelClients::with_provider(Arc::new(ScriptedProvider::default()))))),
+            Arc::new(StateManager::with_backend(backend.clone())),
+            Arc::new(TaskQueue::new(1_000)),
+        );
+        let parent = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let children = [
+            session_mgr.create_subsession(parent, small_project()).await.unwrap(),
+            session_mgr.create_subsession(parent, small_project()).await.unwrap(),
+        ];
+        for session_id in [parent, children[0], children[1]] {
+            session_mgr.checkpoint_session(session_id).await.unwrap();
+        }
+
+        // Nothing under the first child can be deleted
+        *backend.sticky.lock().unwrap() = Some(format!("session:{}", children[0]));
+        let err = session_mgr.destroy_session(parent).await.unwrap_err();
+        let SwarmError::TeardownFailed { session_id, errors } = err else {
+            panic!("expected a teardown failure, got {err:?}");
+        };
+        assert_eq!(session_id, parent);
+        assert!(!errors.is_empty());
+        assert!(errors.iter().all(|e| matches!(e, SwarmError::StateError { key, .. } if key.contains(&children[0].to_string()))));
+
+        // The rest of the tree went regardless
+        assert!(session_mgr.sessions.read().await.is_empty());
+        assert!(session_mgr.agent_pool.agents.read().await.is_empty());
+        assert_eq!(session_mgr.user_usage("user123").await, UserUsage::default());
+        for session_id in [parent, children[1]] {
+            assert_eq!(backend.get(&SessionManager::checkpoint_key(session_id)).await.unwrap(), None);
+            assert!(backend.scan(&format!("session:{session_id}")).await.unwrap().is_empty());
+        }
+        assert!(backend.get(&SessionManager::checkpoint_key(children[0])).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_terminate_agent_stops_its_task() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let shared_state = session_mgr.state_manager
+            .create_state_space(SessionId::new_v4())
+            .await
+            .unwrap();
+        let runtime = tokio::runtime::Handle::current();
+        let baseline = runtime.metrics().num_alive_tasks();
+        let agent = session_mgr.agent_pool
+            .spawn_agent(
+                SessionId::new_v4(),
+                AgentRole::Coder,
+                ModelPreference::ClaudeOpus45,
+                shared_state,
+                Arc::new(SessionControl::default()),
+            )
+            .await
+            .unwrap();
+        assert_eq!(runtime.metrics().num_alive_tasks(), baseline + 1);
+
+        session_mgr.agent_pool.terminate_agent(agent.id).await.unwrap();
+        wait_until(|| async { runtime.metrics().num_alive_tasks() == baseline }).await;
+    }
+
+    #[tokio::test]
+    async fn test_lww_out_of_order_writes_converge() {
+        let session_id = SessionId::new_v4();
+        let replica_a = SharedState::new(session_id);
+        let replica_b = SharedState::new(session_id);
+        let (agent_1, agent_2) = (AgentId::new_v4(), AgentId::new_v4());
+
+        let older = Version { timestamp: 1, agent_id: agent_1 };
+        let newer = Version { timestamp: 2, agent_id: agent_2 };
+
+        assert!(replica_a.set_versioned("plan", "v1".to_string(), older).await.unwrap());
+        assert!(replica_a.set_versioned("plan", "v2".to_string(), newer).await.unwrap());
+        assert!(replica_b.set_versioned("plan", "v2".to_string(), newer).await.unwrap());
+        assert!(!replica_b.set_versioned("plan", "v1".to_string(), older).await.unwrap());
+
+        assert_eq!(replica_a.get("plan").await.unwrap(), Some("v2".to_string()));
+        assert_eq!(replica_b.get("plan").await.unwrap(), Some("v2".to_string()));
+
+        // Divergent keys reconcile through merge in either direction
+        replica_a.set_from(agent_1, "a_only", "x".to_string()).await.unwrap();
+        replica_b.set_from(agent_2, "b_only", "y".to_string()).await.unwrap();
+        let snapshot_a = replica_a.snapshot().await;
+        let snapshot_b = replica_b.snapshot().await;
+        replica_a.merge(&snapshot_b).await.unwrap();
+        replica_b.merge(&snapshot_a).await.unwrap();
+
+        assert_eq!(replica_a.snapshot().await.entries, replica_b.snapshot().await.entries);
+
+        // Local writes after a merge are stamped past anything observed
+        replica_a.set("plan", "v3".to_string()).await.unwrap();
+        assert_eq!(replica_a.get("plan").await.unwrap(), Some("v3".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_bounded_queue_rejects_then_waits_for_space() {
+        let queue = Arc::new(TaskQueue::new(2));
+        queue.enqueue(make_task("a", vec![])).await.unwrap();
+        queue.enqueue(make_task("b", vec![])).await.unwrap();
+
+        assert!(matches!(
+            queue.enqueue(make_task("c", vec![])).await,
+            Err(SwarmError::QueueFull)
+        ));
+        assert!(queue.try_enqueue(make_task("c", vec![])).await.is_err());
+        assert_eq!(queue.pending_len().await, 2);
+
+        let blocked = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.enqueue_blocking(make_task("c", vec![])).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!blocked.is_finished());
+
+        queue.dequeue().await.unwrap();
+        blocked.await.unwrap().unwrap();
+        assert_eq!(queue.pending_len().await, 2);
+        assert_eq!(queue.in_progress_len().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_autoscale_follows_queue_depth() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()))
+            .with_autoscale_config(AutoscaleConfig {
+                scale_up_threshold: 2,
+                scale_down_threshold: 0,
+                max_step: 10,
+                max_agents: MAX_AGENTS_PER_SESSION,
+            });
+        let session_id = session_mgr
+            .create_session("user123".to_string(), small_project(), None)
+            .await
+            .unwrap();
+        let before = session_mgr.get_session_status(session_id).await.unwrap();
+
+        // Burst of 12 tasks against 2 idle coders: backlog of 10
+        for i in 0..12 {
+            let task = Task { session_id: Some(session_id), ..make_task(&format!("part {}", i), vec![]) };
+            session_mgr.task_queue.enqueue(task).await.unwrap();
+        }
+        // Another session's backlog isn't this one's
+        session_mgr.task_queue.enqueue(make_task("elsewhere", vec![])).await.unwrap();
+        let outcome = session_mgr.autoscale(session_id).await.unwrap();
+        assert_eq!(outcome.spawned, 10);
+
+        let after = session_mgr.get_session_status(session_id).await.unwrap();
+        assert_eq!(after.agent_count, before.agent_count + 10);
+        assert_eq!(after.metrics.agents_spawned, before.metrics.agents_spawned + 10);
+
+        // Backlog covered: nothing more to do
+        assert_eq!(session_mgr.autoscale(session_id).await.unwrap(), AutoscaleOutcome::default());
+
+        // Queue drained: shrink back down to a single coder
+        while session_mgr.task_queue.dequeue().await.is_some() {}
+        let outcome = session_mgr.autoscale(session_id).await.unwrap();
+        assert_eq!(outcome.terminated, 10);
+        let outcome = session_mgr.autoscale(session_id).await.unwrap();
+        assert_eq!(outcome.terminated, 1);
+        let coders = session_mgr.sessions.read().await[&session_id].agents
+            .iter()
+            .filter(|a| a.role == AgentRole::Coder)
+            .count();
+        assert_eq!(coders, 1);
+
+        session_mgr.pause_session(session_id).await.unwrap();
+        assert!(matches!(
+            session_mgr.autoscale(session_id).await,
+            Err(SwarmError::SessionNotActive(SessionStatus::Paused))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_autoscale_keeps_the_coders_spawned_before_a_failure() {
+        let sizing = make_manager(Arc::new(RedisClient::new()));
+        let sized = sizing.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let agents = sizing.get_session_status(sized).await.unwrap().agent_count;
+        // Room for the roster and three more
+        let session_mgr = make_manager_on(
+            AgentPool::new(Arc::new(ModelClients::new())).with_max_agents(agents + 3)
+        ).with_autoscale_config(AutoscaleConfig {
+            scale_up_threshold: 0,
+            scale_down_threshold: 0,
+            max_step: 10,
+            max_agents: MAX_AGENTS_PER_SESSION,
+        });
+        let session_id = session_mgr
+            .create_session("user123".to_string(), small_project(), None)
+            .await
+            .unwrap();
+        let before = session_mgr.get_session_status(session_id).await.unwrap();
+        for i in 0..12 {
+            let task = Task { session_id: Some(session_id), ..make_task(&format!("part {}", i), vec![]) };
+            session_mgr.task_queue.enqueue(task).await.unwrap();
+        }
+
+        assert!(matches!(session_mgr.autoscale(session_id).await, Err(SwarmError::PoolSaturated { .. })));
+        let after = session_mgr.get_session_status(session_id).await.unwrap();
+        assert_eq!(after.agent_count, before.agent_count + 3);
+        assert_eq!(after.metrics.agents_spawned, before.metrics.agents_spawned + 3);
+        assert_eq!(session_mgr.sessions.read().await[&session_id].agents_starting, 0);
+        assert_eq!(session_mgr.user_usage("user123").await.agents, after.agent_count);
+    }
+
+    #[tokio::test]
+    async fn test_jitter_spreads_out_simultaneous_retries() {
+        let queue = TaskQueue::new(1_000);
+        // Out of the box, without picking a strategy
+        let policy = RetryPolicy { max_attempts: 3, base_delay_ms: 1_000, ..RetryPolicy::default() };
+        let tasks: Vec<Task> = (0..50)
+            .map(|i| Task { retry_policy: policy, ..make_task(&format!("call vendor api #{i}"), vec![]) })
+            .collect();
+        for task in &tasks {
+            queue.enqueue(task.clone()).await.unwrap();
+            queue.dequeue().await.unwrap();
+        }
+
+        // The same outage fails them all at once
+        let mut delays = vec![];
+        for task in &tasks {
+            match queue.fail(task.id).await {
+                Some(FailureOutcome::Retrying { attempt: 1, delay }) => delays.push(delay),
+                other => panic!("unexpected outcome {other:?}"),
+            }
+        }
+        assert!(delays.iter().all(|d| *d <= Duration::from_secs(1)));
+        let distinct: HashSet<Duration> = delays.iter().copied().collect();
+        assert!(distinct.len() > 40, "only {} distinct delays", distinct.len());
+        let spread = *delays.iter().max().unwrap() - *delays.iter().min().unwrap();
+        assert!(spread > Duration::from_millis(500), "delays spread over only {spread:?}");
+
+        // Each strategy stays within its bounds
+        let delay = |jitter, attempt, previous| RetryPolicy { jitter, ..policy }.retry_delay(attempt, previous);
+        for _ in 0..100 {
+            assert_eq!(delay(JitterStrategy::None, 2, None), Duration::from_secs(2));
+            assert!(delay(JitterStrategy::Full, 2, None) <= Duration::from_secs(2));
+            let equal = delay(JitterStrategy::Equal, 2, None);
+            assert!((Duration::from_secs(1)..=Duration::from_secs(2)).contains(&equal));
+            let decorrelated = delay(JitterStrategy::Decorrelated, 2, Some(Duration::from_secs(4)));
+            assert!((Duration::from_secs(1)..=Duration::from_secs(12)).contains(&decorrelated));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_task_retries_with_backoff_then_dead_letters() {
+        let queue = TaskQueue::new(1_000);
+        let upstream = make_task("fetch specs", vec![]);
+        let mut flaky = make_task("call vendor api", vec![]);
+        flaky.retry_policy = RetryPolicy { max_attempts: 3, base_delay_ms: 20, jitter: JitterStrategy::None };
+
+        queue.enqueue(flaky.clone()).await.unwrap();
+        queue.dequeue().await.unwrap();
+        assert_eq!(
+            queue.fail(flaky.id).await,
+            Some(FailureOutcome::Retrying { attempt: 1, delay: Duration::from_millis(20) })
+        );
+
+        // Held back during the backoff window
+        assert!(queue.dequeue().await.is_none());
+        tokio::time::sleep(Duration::from_millis(25)).await;
+        let retried = queue.dequeue().await.unwrap();
+        assert_eq!(retried.attempts, 1);
+
+        assert_eq!(
+            queue.fail(flaky.id).await,
+            Some(FailureOutcome::Retrying { attempt: 2, delay: Duration::from_millis(40) })
+        );
+        tokio::time::sleep(Duration::from_millis(45)).await;
+        queue.dequeue().await.unwrap();
+        assert_eq!(queue.fail(flaky.id).await, Some(FailureOutcome::DeadLettered));
+
+        let dead = queue.dead_letter().await;
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].attempts, 3);
+        assert!(queue.dequeue().await.is_none());
+
+        // A dependency completing mid-backoff makes the task eligible right
+        // after the delay elapses
+        let mut dependent = make_task("integrate vendor", vec![upstream.id]);
+        dependent.retry_policy = RetryPolicy { max_attempts: 2, base_delay_ms: 20, jitter: JitterStrategy::None };
+        queue.enqueue(dependent.clone()).await.unwrap();
+        // Put it in flight directly, as if dispatched before its upstream was re-run
+        let queued = queue.pending.write().await.pop().unwrap();
+        queue.in_progress.write().await.insert(dependent.id, queued.task);
+        queue.fail(dependent.id).await.unwrap();
+
+        queue.enqueue(upstream.clone()).await.unwrap();
+        assert_eq!(queue.dequeue().await.unwrap().id, upstream.id);
+        assert!(queue.complete(upstream.id).await);
+        assert!(queue.ready_tasks().await.is_empty());
+
+        tokio::time::sleep(Duration::from_millis(25)).await;
+        assert_eq!(queue.ready_tasks().await, vec![dependent.id]);
+    }
+
+    #[tokio::test]
+    async fn test_events_are_broadcast() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let mut events = session_mgr.subscribe();
+
+        let session_id = session_mgr
+            .create_session("user123".to_string(), small_project(), None)
+            .await
+            .unwrap();
+        let agent_count = session_mgr.get_session_status(session_id).await.unwrap().agent_count;
+
+        for _ in 0..agent_count {
+            assert!(matches!(
+                events.recv().await.unwrap(),
+                SwarmEvent::AgentSpawned { session_id: id, .. } if id == session_id
+            ));
+        }
+        assert_eq!(
+            events.recv().await.unwrap(),
+            SwarmEvent::SessionCreated { session_id, user_id: "user123".to_string() }
+        );
+
+        let coder = session_mgr.sessions.read().await[&session_id].agents
+            .iter()
+            .find(|a| a.role == AgentRole::Coder)
+            .unwrap()
+            .id;
+        session_mgr.task_queue.enqueue(make_task("write tests", vec![])).await.unwrap();
+        let task = session_mgr.task_queue.dequeue().await.unwrap();
+        session_mgr.agent_pool.assign_task(coder, task.clone()).await.unwrap();
+        assert_eq!(
+            events.recv().await.unwrap(),
+            SwarmEvent::TaskStarted { session_id, agent_id: coder, role: Some(AgentRole::Coder), verification: false }
+        );
+        match events.recv().await.unwrap() {
+            SwarmEvent::TaskCompleted { task_id, agent_id, cost, outcome, .. } => {
+                assert_eq!((task_id, agent_id), (task.id, coder));
+                assert!(cost > 0.0);
+                assert_eq!(outcome, CompletionOutcome::AwaitingVerification);
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+        // Then the verifier checks it
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            SwarmEvent::TaskStarted { role: Some(AgentRole::Verifier), .. }
+        ));
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            SwarmEvent::TaskCompleted { outcome: CompletionOutcome::Uncounted, .. }
+        ));
+        assert_eq!(
+            events.recv().await.unwrap(),
+            SwarmEvent::TaskVerified { session_id, task_id: task.id, passed: true, dead_lettered: false }
+        );
+
+        let metrics = session_mgr.complete_session(session_id).await.unwrap();
+        assert_eq!(
+            events.recv().await.unwrap(),
+            SwarmEvent::SessionCompleted { session_id, metrics }
+        );
+
+        // Events serialize for forwarding over a websocket
+        let json = serde_json::to_string(&SwarmEvent::SessionCreated {
+            session_id,
+            user_id: "user123".to_string(),
+        }).unwrap();
+        assert!(json.contains("\"type\":\"SessionCreated\""));
+    }
+
+    #[tokio::test]
+    async fn test_cost_breaks_down_by_role_and_model() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let agents = session_mgr.sessions.read().await[&session_id].agents.clone();
+        let agent = |role| agents.iter().find(|a| a.role == role).unwrap().id;
+
+        let usage = TokenUsage { input_tokens: 2_000, output_tokens: 2_000 };
+        let runs = [
+            (agent(AgentRole::Coder), ModelPreference::ClaudeOpus45),
+            (agent(AgentRole::Tester), ModelPreference::Gemini3Pro),
+            (agent(AgentRole::Tester), ModelPreference::ClaudeOpus45),
+        ];
+        let mut events = session_mgr.subscribe();
+        for (agent_id, model) in runs {
+            session_mgr.agent_pool.reports_tx.send(AgentReport::Completed {
+                session_id,
+                agent_id,
+                task: Task::new("fake task", 1.0),
+                output: String::new(),
+                model,
+                usage,
+                cache_hit: false,
+                throttled_sec: 0.0,
+                duration_sec: 1.0,
+            }).unwrap();
+        }
+        // Every run reported, not just one per role
+        let mut reported = 0;
+        while reported < runs.len() {
+            if let SwarmEvent::TaskCompleted { agent_id, .. } = events.recv().await.unwrap() {
+                if runs.iter().any(|&(run, _)| run == agent_id) {
+                    reported += 1;
+                }
+            }
+        }
+
+        let metrics = session_mgr.get_session_status(session_id).await.unwrap().metrics;
+        let opus = ModelClients::cost_of(ModelPreference::ClaudeOpus45, &usage);
+        let gemini = ModelClients::cost_of(ModelPreference::Gemini3Pro, &usage);
+        assert!((metrics.cost_by_role[&AgentRole::Coder] - opus).abs() < 1e-12);
+        assert!((metrics.cost_by_role[&AgentRole::Tester] - (gemini + opus)).abs() < 1e-12);
+        // The coder's result may already be under verification, adding to both
+        assert!(metrics.cost_by_model[&ModelPreference::ClaudeOpus45] >= 2.0 * opus - 1e-12);
+        for breakdown in [metrics.cost_by_role.values().sum::<f64>(), metrics.cost_by_model.values().sum()] {
+            assert!((breakdown - metrics.total_cost).abs() < 1e-12);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_reproduces_final_metrics() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let session_id = session_mgr
+            .create_session("user123".to_string(), small_project(), None)
+            .await
+            .unwrap();
+        let plan: Vec<Task> = (0..6).map(|i| make_task(&format!("step {i}"), vec![])).collect();
+        session_mgr.submit_plan(session_id, plan).await.unwrap();
+
+        let dispatcher = session_mgr.spawn_dispatcher(2, Duration::from_millis(5)).unwrap();
+        wait_until(|| async {
+            session_mgr.get_session_status(session_id).await.unwrap().metrics.tasks_completed == 6
+        }).await;
+        dispatcher.abort();
+        // One that fails outright, without retries
+        let mut doomed = make_task("doomed", vec![]);
+        doomed.retry_policy.max_attempts = 1;
+        session_mgr.task_queue.enqueue(doomed.clone()).await.unwrap();
+        session_mgr.task_queue.dequeue().await.unwrap();
+        let coder = session_mgr.sessions.read().await[&session_id].agents[1].id;
+        session_mgr.agent_pool.reports_tx.send(AgentReport::Failed {
+            session_id,
+            agent_id: coder,
+            task_id: doomed.id,
+            retriable: true,
+        }).unwrap();
+        wait_until(|| async {
+            session_mgr.get_session_status(session_id).await.unwrap().metrics.tasks_failed == 1
+        }).await;
+        let metrics = session_mgr.complete_session(session_id).await.unwrap();
+
+        // As a customer would ship it: serialized, then replayed elsewhere
+        let log = session_mgr.event_log(session_id).unwrap();
+        let shipped: EventLog = serde_json::from_str(&serde_json::to_string(&log).unwrap()).unwrap();
+        assert!(shipped.len() > 6);
+        assert_eq!(SessionManager::replay(&shipped), metrics);
+
+        session_mgr.destroy_session(session_id).await.unwrap();
+        assert_eq!(session_mgr.event_log(session_id), None);
+    }
+
+    /// A webhook receiver that answers each request with the next of
+    /// `statuses` (200 once they run out), sending the captured headers and
+    /// body on
+    async fn mock_webhook(statuses: Vec<u16>) -> (String, mpsc::UnboundedReceiver<(HashMap<String, String>, Vec<u8>)>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut statuses = statuses.into_iter();
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut chunk = [0u8; 4096];
+                let (head_len, body_len) = loop {
+                    let n = stream.read(&mut chunk).await.unwrap();
+                    assert!(n > 0, "connection closed mid-request");
+                    request.extend_from_slice(&chunk[..n]);
+                    let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") else { continue };
+                    let head = String::from_utf8_lossy(&request[..end]).to_lowercase();
+                    let body_len = head.lines()
+                        .find_map(|line| line.strip_prefix("content-length:"))
+                        .map_or(0, |len| len.trim().parse().unwrap());
+                    break (end + 4, body_len);
+                };
+                while request.len() < head_len + body_len {
+                    let n = stream.read(&mut chunk).await.unwrap();
+                    request.extend_from_slice(&chunk[..n]);
+                }
+                let headers = String::from_utf8_lossy(&request[..head_len]).lines().skip(1)
+                    .filter_map(|line| line.split_once(": "))
+                    .map(|(name, value)| (name.to_lowercase(), value.to_string()))
+                    .collect();
+                tx.send((headers, request[head_len..].to_vec())).unwrap();
+                let status = statuses.next().unwrap_or(200);
+                let response = format!("HTTP/1.1 {status} X\r\ncontent-length: 0\r\nconnection: close\r\n\r\n");
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    #[tokio::test]
+    async fn test_session_activates_once_its_readiness_probe_passes() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let gate = |url: &str, timeout| {
+            let probe = Arc::new(HttpReadinessProbe::new(url, Duration::from_secs(1)).unwrap());
+            ReadinessGate::new(probe, timeout).with_interval(Duration::from_millis(20)).unwrap()
+        };
+
+        // A zero interval would spin, so it's refused however it's set
+        let probe = Arc::new(HttpReadinessProbe::new("http://example.com/health", Duration::from_secs(1)).unwrap());
+        assert!(matches!(
+            ReadinessGate::new(probe.clone(), Duration::from_secs(1)).with_interval(Duration::ZERO),
+            Err(SwarmError::InvalidSpec(_))
+        ));
+        let zero = ReadinessGate { interval: Duration::ZERO, ..ReadinessGate::new(probe, Duration::from_secs(1)) };
+        let spec = ProjectSpec { readiness: Some(zero), ..small_project() };
+        assert!(matches!(
+            session_mgr.create_session("user123".to_string(), spec, None).await,
+            Err(SwarmError::InvalidSpec(_))
+        ));
+        assert_eq!(session_mgr.user_usage("user123").await, UserUsage::default());
+
+        // The health endpoint is down for two checks, then up
+        let (url, mut checks) = mock_webhook(vec![503, 503]).await;
+        let spec = ProjectSpec { readiness: Some(gate(&url, Duration::from_secs(5))), ..small_project() };
+        let session_id = session_mgr.create_session("user123".to_string(), spec, None).await.unwrap();
+        for _ in 0..2 {
+            checks.recv().await.unwrap();
+        }
+        let status = session_mgr.get_session_status(session_id).await.unwrap();
+        assert_eq!((status.status, status.metrics.agents_spawned), (SessionStatus::Initializing, 0));
+        checks.recv().await.unwrap();
+        wait_until(|| async {
+            session_mgr.get_session_status(session_id).await.unwrap().status == SessionStatus::Active
+        }).await;
+        assert!(session_mgr.get_session_status(session_id).await.unwrap().metrics.agents_spawned > 0);
+
+        // One that never comes up fails the session without spawning agents
+        let (url, _checks) = mock_webhook(vec![503; 1_000]).await;
+        let spec = ProjectSpec { readiness: Some(gate(&url, Duration::from_millis(200))), ..small_project() };
+        let session_id = session_mgr.create_session("user123".to_string(), spec, None).await.unwrap();
+        wait_until(|| async {
+            session_mgr.get_session_status(session_id).await.unwrap().status == SessionStatus::Failed
+        }).await;
+        assert_eq!(session_mgr.get_session_status(session_id).await.unwrap().metrics.agents_spawned, 0);
+    }
+
+    #[tokio::test]
+    async fn test_completion_webhook_posts_signed_metrics_once() {
+        use hmac::Mac;
+
+        // Internal addresses are refused unless allowed
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        for internal in ["http://169.254.169.254/latest/meta-data", "http://[::1]:8080/hook", "http://localhost/hook", "http://10.0.0.7/"] {
+            let project = ProjectSpec { completion_webhook: Some(internal.into()), ..small_project() };
+            assert!(matches!(
+                session_mgr.create_session("user123".to_string(), project, None).await,
+                Err(SwarmError::InvalidSpec(_))
+            ));
+        }
+        let resolver = ExternalResolver { internal_hosts: Arc::new(HashSet::new()) };
+        assert!(reqwest::dns::Resolve::resolve(&resolver, "localhost".parse().unwrap()).await.is_err());
+
+        let session_mgr = session_mgr.with_webhook_config(WebhookConfig {
+            internal_hosts: HashSet::from(["127.0.0.1".to_string()]),
+            ..WebhookConfig::default()
+        });
+        // The first delivery is refused, so it lands on the retry
+        let (url, mut deliveries) = mock_webhook(vec![503]).await;
+        let project = ProjectSpec { completion_webhook: Some(url), webhook_secret: Some("s3cret".into()), ..small_project() };
+        // Never written out with the session
+        assert!(!serde_json::to_string(&project).unwrap().contains("s3cret"));
+        let session_id = session_mgr.create_session("user123".to_string(), project, None).await.unwrap();
+
+        let coder = session_mgr.sessions.read().await[&session_id].agents[1].id;
+        session_mgr.agent_pool.reports_tx.send(AgentReport::Completed {
+            session_id,
+            agent_id: coder,
+            task: Task::new("ship it", 1.0),
+            output: "done".to_string(),
+            model: ModelPreference::ClaudeOpus45,
+            usage: TokenUsage { input_tokens: 2_000, output_tokens: 2_000 },
+            cache_hit: false,
+            throttled_sec: 0.0,
+            duration_sec: 1.0,
+        }).unwrap();
+        wait_until(|| async {
+            session_mgr.get_session_status(session_id).await.unwrap().metrics.tasks_completed == 1
+        }).await;
+        let metrics = session_mgr.complete_session(session_id).await.unwrap();
+        // Finishing again doesn't refire
+        session_mgr.complete_session(session_id).await.unwrap();
+
+        let timeout = Duration::from_secs(5);
+        let (refused, _) = tokio::time::timeout(timeout, deliveries.recv()).await.unwrap().unwrap();
+        let (headers, body) = tokio::time::timeout(timeout, deliveries.recv()).await.unwrap().unwrap();
+        assert_eq!(refused, headers);
+        assert_eq!(body, serde_json::to_vec(&metrics).unwrap());
+        assert_eq!(metrics.tasks_completed, 1);
+        assert_eq!(headers[&WEBHOOK_SESSION_HEADER.to_lowercase()], session_id.to_string());
+        assert_eq!(headers["x-swarm-status"], "Completed");
+        let mut mac = hmac::Hmac::<Sha256>::new_from_slice(b"s3cret").unwrap();
+        mac.update(&body);
+        let signature = headers[&WEBHOOK_SIGNATURE_HEADER.to_lowercase()].strip_prefix("sha256=").unwrap();
+        mac.verify_slice(&hex::decode(signature).unwrap()).unwrap();
+
+        tokio::time::sleep(WEBHOOK_RETRY_DELAY * 3).await;
+        assert!(deliveries.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_slow_subscriber_lags_instead_of_blocking() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let mut events = session_mgr.subscribe();
+
+        // Over EVENT_CHANNEL_CAPACITY agent spawns without reading
+        let mut project = small_project();
+        project.parallelization = ParallelizationMode::Turbo;
+        project.replication_count = 120;
+        project.estimated_complexity = Complexity::XLarge;
+        session_mgr
+            .create_session("user123".to_string(), project, None)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            events.recv().await,
+            Err(broadcast::error::RecvError::Lagged(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_prometheus_export_tracks_sessions_and_agents() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let metrics = session_mgr.metrics();
+
+        let first = session_mgr
+            .create_session("user123".to_string(), small_project(), None)
+            .await
+            .unwrap();
+        session_mgr
+            .create_session("user456".to_string(), small_project(), None)
+            .await
+            .unwrap();
+        let agents = session_mgr.get_session_status(first).await.unwrap().agent_count;
+
+        let rendered = metrics.render_prometheus();
+        assert!(rendered.contains("# TYPE swarm_active_sessions gauge\n"));
+        assert!(rendered.contains("swarm_active_sessions 2\n"));
+        assert!(rendered.contains(&format!("swarm_agents_idle {}\n", agents * 2)));
+
+        session_mgr.destroy_session(first).await.unwrap();
+        let rendered = metrics.render_prometheus();
+        assert!(rendered.contains("swarm_active_sessions 1\n"));
+        assert!(rendered.contains(&format!("swarm_agents_idle {}\n", agents)));
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_checkpoint_reports_key_and_cause() {
+        use std::error::Error;
+
+        let redis = Arc::new(RedisClient::new());
+        let session_mgr = make_manager(redis.clone());
+        let session_id = SessionId::new_v4();
+        let key = format!("session:{}", session_id);
+        redis.set(&key, "{not json".to_string()).await.unwrap();
+
+        let err = session_mgr.restore_session(session_id).await.unwrap_err();
+        assert_eq!(err.to_string(), format!("State management error on `{}`", key));
+        assert!(err.source().unwrap().is::<serde_json::Error>());
+    }
+
+    #[tokio::test]
+    async fn test_local_bus_matches_wildcard_subjects() {
+        let bus = MessageBus::connect(None).await.unwrap();
+        let mut statuses = bus.subscribe("swarm.*.agent.*.status").await.unwrap();
+        let mut everything = bus.subscribe("swarm.>").await.unwrap();
+
+        bus.publish("swarm.s1.agent.a1.log", b"noise".to_vec()).await.unwrap();
+        bus.publish("swarm.s1.agent.a1.status", b"busy".to_vec()).await.unwrap();
+
+        assert_eq!(statuses.next().await.unwrap().payload, b"busy".to_vec());
+        assert_eq!(everything.next().await.unwrap().subject, "swarm.s1.agent.a1.log");
+        assert_eq!(everything.next().await.unwrap().subject, "swarm.s1.agent.a1.status");
+        assert!(!subject_matches("swarm.*.status", "swarm.s1.agent.a1.status"));
+    }
+
+    #[tokio::test]
+    async fn test_remote_agent_status_syncs_over_bus() {
+        let bus = Arc::new(MessageBus::local());
+        let state_manager = Arc::new(StateManager::new(Arc::new(RedisClient::new())));
+        let agent_pool = Arc::new(
+            AgentPool::new(Arc::new(ModelClients::new())).with_message_bus(bus.clone()),
+        );
+        let session_mgr = SessionManager::new(
+            agent_pool,
+            state_manager,
+            Arc::new(TaskQueue::new(1_000)),
+        );
+        let session_id = session_mgr
+            .create_session("user123".to_string(), small_project(), None)
+            .await
+            .unwrap();
+
+        // An agent owned by another orchestrator node
+        let remote = AgentHandle {
+            id: AgentId::new_v4(),
+            role: AgentRole::Coder,
+            model: ModelPreference::ClaudeOpus45,
+            status: AgentStatus::Idle,
+            tasks_completed: 0,
+            cost_incurred: 0.0,
+            models_used: HashMap::new(),
+            last_heartbeat: Utc::now(),
+            skills: vec![],
+            blocked_on: None,
+            affinity: VecDeque::new(),
+            capabilities: Capabilities::of(ModelPreference::ClaudeOpus45),
+            scratch: HashMap::new(),
+        };
+        session_mgr.sessions.write().await
+            .get_mut(&session_id).unwrap()
+            .push_agent(remote.clone());
+        tokio::task::yield_now().await;
+
+        bus.publish_status(AgentStatusUpdate {
+            session_id,
+            agent_id: remote.id,
+            status: AgentStatus::Working,
+        }).await.unwrap();
+
+        wait_until(|| async {
+            session_mgr.get_session_status(session_id).await.unwrap().agents_working == 1
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_saturated_pool_rejects_or_queues_sessions() {
+        let saturated_manager = |policy| {
+            make_manager_on(AgentPool::new(Arc::new(ModelClients::new())).with_max_agents(5))
+                .with_saturation_policy(policy)
+        };
+
+        let session_mgr = saturated_manager(SaturationPolicy::Reject);
+        let first = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        assert_eq!(session_mgr.agent_pool.available_capacity().await, 0);
+        assert!(matches!(
+            session_mgr.create_session("user456".to_string(), small_project(), None).await,
+            Err(SwarmError::PoolSaturated { limit: 5 })
+        ));
+        assert_eq!(session_mgr.user_usage("user456").await, UserUsage::default());
+        assert_eq!(session_mgr.get_session_status(first).await.unwrap().agent_count, 5);
+
+        let session_mgr = saturated_manager(SaturationPolicy::Queue);
+        let first = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let queued = session_mgr.create_session("user456".to_string(), small_project(), None).await.unwrap();
+        let status = session_mgr.get_session_status(queued).await.unwrap();
+        assert_eq!((status.status, status.agent_count), (SessionStatus::Initializing, 0));
+        assert!(session_mgr.admit_queued_sessions().await.is_empty());
+
+        // Freeing the first session's agents starts the queued one
+        session_mgr.destroy_session(first).await.unwrap();
+        let status = session_mgr.get_session_status(queued).await.unwrap();
+        assert_eq!((status.status, status.agent_count), (SessionStatus::Active, 5));
+        assert_eq!(session_mgr.user_usage("user456").await, UserUsage { sessions: 1, agents: 5 });
+        assert_eq!(session_mgr.agent_pool.available_capacity().await, 0);
+
+        // A roster bigger than the whole pool is never queued
+        let huge = ProjectSpec { estimated_complexity: Complexity::Large, ..small_project() };
+        assert!(matches!(
+            session_mgr.create_session("user789".to_string(), huge, None).await,
+            Err(SwarmError::InvalidSpec(_))
+        ));
+        assert_eq!(session_mgr.user_usage("user789").await, UserUsage::default());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_creates_respect_session_quota() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()))
+            .with_quota_config(QuotaConfig {
+                max_concurrent_sessions_per_user: 1,
+                ..QuotaConfig::default()
+            });
+
+        let (a, b) = tokio::join!(
+            session_mgr.create_session("user123".to_string(), small_project(), None),
+            session_mgr.create_session("user123".to_string(), small_project(), None),
+        );
+        let created = match (a, b) {
+            (Ok(id), Err(SwarmError::QuotaExceeded { .. }))
+            | (Err(SwarmError::QuotaExceeded { .. }), Ok(id)) => id,
+            other => panic!("expected exactly one session, got {other:?}"),
+        };
+
+        // Other users are unaffected
+        session_mgr.create_session("user456".to_string(), small_project(), None).await.unwrap();
+
+        session_mgr.destroy_session(created).await.unwrap();
+        assert_eq!(session_mgr.user_usage("user123").await, UserUsage::default());
+        session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_agent_quota_checked_before_spawning() {
+        // small_project starts 5 agents
+        let session_mgr = make_manager(Arc::new(RedisClient::new()))
+            .with_quota_config(QuotaConfig {
+                max_total_agents_per_user: 6,
+                ..QuotaConfig::default()
+            });
+
+        session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let spawned_before = session_mgr.agent_pool.agents.read().await.len();
+
+        let err = session_mgr
+            .create_session("user123".to_string(), small_project(), None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, SwarmError::QuotaExceeded { resource: "agents", limit: 6, .. }));
+        assert_eq!(session_mgr.agent_pool.agents.read().await.len(), spawned_before);
+        assert_eq!(
+            session_mgr.user_usage("user123").await,
+            UserUsage { sessions: 1, agents: 5 }
+        );
+
+        // Growing a session counts too, and stops at the per-session cap
+        let session_id = *session_mgr.sessions.read().await.keys().next().unwrap();
+        session_mgr.add_agent(session_id, AgentRole::Coder, vec![]).await.unwrap();
+        let err = session_mgr.add_agent(session_id, AgentRole::Coder, vec![]).await.unwrap_err();
+        assert!(matches!(err, SwarmError::QuotaExceeded { resource: "agents", limit: 6, .. }));
+        assert_eq!(session_mgr.user_usage("user123").await, UserUsage { sessions: 1, agents: 6 });
+
+        let roomy = make_manager(Arc::new(RedisClient::new()));
+        let session_id = roomy.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        roomy.sessions.write().await.get_mut(&session_id).unwrap().agents_starting = MAX_AGENTS_PER_SESSION - 5;
+        let err = roomy.add_agent(session_id, AgentRole::Coder, vec![]).await.unwrap_err();
+        assert!(matches!(err, SwarmError::QuotaExceeded { resource: "agents per session", .. }));
+        assert_eq!(roomy.agent_pool.agents.read().await.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_model_fallback_serves_from_next_in_chain() {
+        let outage = ScriptedProvider::default().on(|model, _| model == ModelPreference::ClaudeOpus45, Reply::Status(503));
+        let clients = ModelClients::with_provider(Arc::new(outage));
+        let response = clients
+            .complete(ModelPreference::ClaudeOpus45, "triage the outage")
+            .await
+            .unwrap()
+            .response;
+        assert_eq!(response.model, ModelPreference::GPT51);
+
+        // End to end: the agent records the serving model and is billed for it
+        let session_mgr = make_manager_with(clients);
+        let session_id = session_mgr
+            .create_session("user123".to_string(), small_project(), None)
+            .await
+            .unwrap();
+        let coder = session_mgr.sessions.read().await[&session_id]
+            .agents.iter()
+            .find(|a| a.model == ModelPreference::ClaudeOpus45)
+            .unwrap()
+            .id;
+        let task = make_task("fix the bug", vec![]);
+        let task_id = task.id;
+        session_mgr.assign_task(session_id, coder, task.clone()).await.unwrap();
+
+        wait_until(|| async {
+            session_mgr.get_session_status(session_id).await.unwrap().metrics.tasks_completed == 1
+        }).await;
+        let sessions = session_mgr.sessions.read().await;
+        let agent = sessions[&session_id].agents.iter().find(|a| a.id == coder).unwrap();
+        assert_eq!(agent.models_used[&task_id], ModelPreference::GPT51);
+        let prompt = PromptTemplates::default().render(AgentRole::Coder, Some(TemplateType::SoftwareDev), &task);
+        let usage = EchoProvider.complete(ModelPreference::GPT51, &prompt).await.unwrap().usage;
+        assert_eq!(agent.cost_incurred, ModelClients::cost_of(ModelPreference::GPT51, &usage));
+    }
+
+    #[tokio::test]
+    async fn test_model_fallback_exhausted() {
+        let outage = ScriptedProvider::default().on(
+            |model, _| matches!(model, ModelPreference::ClaudeOpus45 | ModelPreference::GPT51),
+            Reply::Status(503),
+        );
+        let clients = ModelClients::with_provider(Arc::new(outage));
+        let err = clients.complete(ModelPreference::ClaudeOpus45, "fix the bug").await.unwrap_err();
+        match &err {
+            SwarmError::AllModelsFailed { tried, source } => {
+                assert_eq!(tried, &[ModelPreference::ClaudeOpus45, ModelPreference::GPT51]);
+                assert!(matches!(
+                    source.as_deref(),
+                    Some(SwarmError::ModelApi { model: ModelPreference::GPT51, .. })
+                ));
+            }
+            other => panic!("expected AllModelsFailed, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_terminal_errors_skip_retries() {
+        // Run one task on a coder backed by `clients`, until it fails
+        async fn run_failing(clients: ModelClients) -> (usize, Vec<Task>) {
+            let session_mgr = make_manager_with(clients);
+            let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+            let mut task = make_task("fix the bug", vec![]);
+            task.session_id = Some(session_id);
+            session_mgr.task_queue.enqueue(task).await.unwrap();
+            session_mgr.dispatch_ready(1).await.unwrap();
+            wait_until(|| async {
+                session_mgr.task_queue.in_progress_len().await == 0
+            }).await;
+            (session_mgr.task_queue.pending_len_for(session_id).await, session_mgr.task_queue.dead_letter().await)
+        }
+
+        // A rejected request fails at once: no fallback, no retry
+        let failing = |status| Arc::new(ScriptedProvider::default().on(|_, _| true, Reply::Status(status)));
+        let provider = failing(400);
+        let (pending, dead) = run_failing(ModelClients::with_provider(provider.clone())).await;
+        assert_eq!((pending, dead.len()), (0, 1));
+        assert_eq!(dead[0].attempts, 1);
+        assert_eq!(provider.calls(), 1);
+
+        // An outage falls back along the chain, then the task is retried
+        let provider = failing(503);
+        let (pending, dead) = run_failing(ModelClients::with_provider(provider.clone())).await;
+        assert_eq!((pending, dead.len()), (1, 0));
+        assert!(provider.calls() > 1);
+
+        // A custom classifier can declare the outage terminal
+        let clients = ModelClients::with_provider(failing(503))
+            .with_retry_classifier(|e| !matches!(e, SwarmError::ModelApi { status: Some(503), .. }));
+        let (pending, dead) = run_failing(clients).await;
+        assert_eq!((pending, dead.len()), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_short_circuits_during_cooldown() {
+        let provider = Arc::new(ScriptedProvider::default().on(|_, _| true, Reply::Status(503)));
+        let cooldown = Duration::from_millis(100);
+        let clients = ModelClients::with_provider(provider.clone())
+            .with_circuit_breaker(ModelPreference::GPT51, 3, cooldown)
+            .with_circuit_breaker(ModelPreference::ClaudeOpus45, 3, cooldown);
+        let metrics = MetricsRegistry::new();
+        clients.report_to(metrics.clone());
+        let calls = || provider.calls();
+
+        // Each call tries both models in the chain
+        for _ in 0..3 {
+            let err = clients.complete(ModelPreference::GPT51, "deploy").await.unwrap_err();
+            assert!(matches!(err, SwarmError::AllModelsFailed { .. }));
+        }
+        assert_eq!(calls(), 6);
+        assert_eq!(clients.circuit_state(ModelPreference::GPT51), Some(CircuitState::Open));
+        assert!(metrics.render_prometheus().contains("swarm_circuit_state{model=\"GPT51\"} 2\n"));
+
+        // Open: refused without reaching the provider
+        for _ in 0..5 {
+            let err = clients.complete(ModelPreference::GPT51, "deploy").await.unwrap_err();
+            assert!(matches!(err, SwarmError::CircuitOpen(ModelPreference::GPT51)));
+        }
+        assert_eq!(calls(), 6);
+
+        // A failed probe reopens for another cooldown
+        tokio::time::sleep(cooldown).await;
+        assert!(clients.complete(ModelPreference::GPT51, "deploy").await.is_err());
+        assert_eq!(calls(), 8);
+        assert!(matches!(
+            clients.complete(ModelPreference::GPT51, "deploy").await,
+            Err(SwarmError::CircuitOpen(_))
+        ));
+
+        // A successful probe closes it
+        provider.clear();
+        tokio::time::sleep(cooldown).await;
+        let response = clients.complete(ModelPreference::GPT51, "deploy").await.unwrap().response;
+        assert_eq!(response.model, ModelPreference::GPT51);
+        assert_eq!(clients.circuit_state(ModelPreference::GPT51), Some(CircuitState::Closed));
+        // Not called since, so never probed
+        assert_eq!(clients.circuit_state(ModelPreference::ClaudeOpus45), Some(CircuitState::Open));
+        assert!(metrics.render_prometheus().contains("swarm_circuit_state{model=\"GPT51\"} 0\n"));
+        assert_eq!(clients.circuit_state(ModelPreference::Gemini3Pro), None);
+    }
+
+    #[tokio::test]
+    async fn test_non_retriable_failure_leaves_the_breaker_alone() {
+        let provider = Arc::new(
+            ScriptedProvider::default()
+                .on(|_, prompt| prompt == "malformed", Reply::Status(400))
+                .on(|_, _| true, Reply::Status(503)),
+        );
+        let cooldown = Duration::from_millis(50);
+        let clients = ModelClients::with_provider(provider).with_circuit_breaker(ModelPreference::GPT51, 1, cooldown);
+        assert!(clients.complete(ModelPreference::GPT51, "deploy").await.is_err());
+        assert_eq!(clients.circuit_state(ModelPreference::GPT51), Some(CircuitState::Open));
+
+        // The probe fails on its request, which says nothing of the model
+        tokio::time::sleep(cooldown).await;
+        let err = clients.complete(ModelPreference::GPT51, "malformed").await.unwrap_err();
+        assert!(matches!(err, SwarmError::ModelApi { status: Some(400), .. }), "{err:?}");
+        assert_eq!(clients.circuit_state(ModelPreference::GPT51), Some(CircuitState::HalfOpen));
+    }
+
+    #[derive(Default)]
+    struct MemoryAuditSink(std::sync::Mutex<Vec<AuditRecord>>);
+
+    #[async_trait]
+    impl AuditSink for MemoryAuditSink {
+        async fn record(&self, record: AuditRecord) -> Result<(), SwarmError> {
+            self.0.lock().unwrap().push(record);
+            Ok(())
+        }
+    }
+
+    struct Redactor;
+
+    impl TaskInterceptor for Redactor {
+        fn before(&self, task: &mut Task) {
+            task.description = task.description.replace("123-45-6789", "[SSN]");
+        }
+    }
+
+    /// Tags descriptions and results, to show the order interceptors run in
+    struct Tag(&'static str);
+
+    impl TaskInterceptor for Tag {
+        fn before(&self, task: &mut Task) {
+            task.description.push_str(self.0);
+        }
+
+        fn after(&self, _task: &Task, result: &mut TaskResult) {
+            result.output.push_str(self.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_interceptors_rewrite_tasks_and_results_in_order() {
+        let sink = Arc::new(MemoryAuditSink::default());
+        let clients = ModelClients::with_provider(Arc::new(ScriptedProvider::default()))
+            .with_audit_sink(sink.clone())
+            .with_audit_full_text(true);
+        let session_mgr = make_manager_with(clients)
+            .with_task_interceptor(Arc::new(Redactor))
+            .with_task_interceptor(Arc::new(Tag(" #1")))
+            .with_task_interceptor(Arc::new(Tag(" #2")));
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let coder = session_mgr.sessions.read().await[&session_id].agents[1].id;
+        let task = make_task("summarize the chart of patient 123-45-6789", vec![]);
+        session_mgr.assign_task(session_id, coder, task.clone()).await.unwrap();
+
+        wait_until(|| async { session_mgr.task_queue.get_result(task.id).await.is_some() }).await;
+        let result = session_mgr.task_queue.get_result(task.id).await.unwrap();
+        assert!(result.output.contains("summarize the chart of patient [SSN] #1 #2"));
+        assert!(result.output.ends_with(" #1 #2"));
+        // No model, the verifier's included, saw the original
+        let records = sink.0.lock().unwrap().clone();
+        assert!(records.len() >= 2);
+        assert!(records.iter().all(|r| !r.prompt.as_ref().unwrap().contains("123-45-6789")));
+    }
+
+    #[tokio::test]
+    async fn test_audit_records_one_entry_per_model_call() {
+        let provider = Arc::new(ScriptedProvider::default());
+        let sink = Arc::new(MemoryAuditSink::default());
+        let session_mgr = make_manager_with(ModelClients::with_provider(provider.clone()).with_audit_sink(sink.clone()));
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let coder = session_mgr.sessions.read().await[&session_id].agents[1].id;
+        for i in 0..3 {
+            session_mgr.assign_task(session_id, coder, make_task(&format!("audit step {i}"), vec![])).await.unwrap();
+        }
+        wait_until(|| async {
+            session_mgr.get_session_status(session_id).await.unwrap().metrics.tasks_completed == 3
+        }).await;
+
+        // Coder calls (batched or not) and verifier calls alike
+        let records = sink.0.lock().unwrap().clone();
+        assert_eq!(records.len(), provider.calls());
+        assert!(records.iter().all(|r| r.session_id == session_id && r.cost > 0.0));
+        assert!(records.iter().any(|r| r.agent_id == coder));
+        assert!(records.iter().all(|r| r.prompt.is_none() && r.response.is_none()));
+
+        // Full text on request, appended to a file as JSON lines
+        let path = std::env::temp_dir().join(format!("audit-{}.jsonl", Uuid::new_v4()));
+        let clients = ModelClients::new()
+            .with_audit_sink(Arc::new(FileAuditSink::open(&path).await.unwrap()))
+            .with_audit_full_text(true);
+        let (session_id, agent_id) = (SessionId::new_v4(), AgentId::new_v4());
+        let response = clients.complete_for(session_id, agent_id, ModelPreference::GPT51, "ship it").await.unwrap();
+        // Served from the cache, so no second record
+        clients.complete_for(session_id, agent_id, ModelPreference::GPT51, "ship it").await.unwrap();
+        let written = tokio::fs::read_to_string(&path).await.unwrap();
+        let _ = tokio::fs::remove_file(&path).await;
+        let lines: Vec<AuditRecord> = written.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].prompt_hash, audit_hash("ship it"));
+        assert_eq!(lines[0].response_hash, audit_hash(&response.response.text));
+        assert_eq!(lines[0].prompt.as_deref(), Some("ship it"));
+
+        // A fallback gets a record per model tried, the failure unbilled
+        let provider = ScriptedProvider::default().on(|model, _| model == ModelPreference::GPT51, Reply::Status(503));
+        let sink = Arc::new(MemoryAuditSink::default());
+        let clients = ModelClients::with_provider(Arc::new(provider)).with_audit_sink(sink.clone());
+        let response = clients.complete_for(session_id, agent_id, ModelPreference::GPT51, "ship it").await.unwrap();
+        assert_eq!(response.response.model, ModelPreference::ClaudeOpus45);
+        let records = sink.0.lock().unwrap().clone();
+        assert_eq!(records.len(), 2);
+        assert_eq!((records[0].model, records[0].cost), (ModelPreference::GPT51, 0.0));
+        assert!(records[0].error.is_some());
+        assert_eq!((records[1].model, records[1].error.as_deref()), (ModelPreference::ClaudeOpus45, None));
+    }
+
+    #[tokio::test]
+    async fn test_prompt_cache_hit_miss_and_expiry() {
+        let provider = Arc::new(ScriptedProvider::default());
+        let clients = ModelClients::with_provider(provider.clone())
+            .with_prompt_cache(PromptCache::new(Duration::from_millis(50)));
+        let calls = || provider.calls();
+
+        let first = clients.complete(ModelPreference::GPT51, "plan it").await.unwrap();
+        assert!(!first.hit);
+        let second = clients.complete(ModelPreference::GPT51, "plan it").await.unwrap();
+        assert!(second.hit);
+        assert_eq!(second.response.text, first.response.text);
+        assert_eq!(calls(), 1);
+
+        // Different prompt or model: miss
+        assert!(!clients.complete(ModelPreference::GPT51, "plan that").await.unwrap().hit);
+        assert!(!clients.complete(ModelPreference::Gemini3Pro, "plan it").await.unwrap().hit);
+        assert_eq!(calls(), 3);
+
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        assert!(!clients.complete(ModelPreference::GPT51, "plan it").await.unwrap().hit);
+        assert_eq!(calls(), 4);
+        // The expired entries were swept on insert
+        assert_eq!(clients.cache.len().await, 1);
+
+        // Inserted again, an entry outlives its first expiry
+        let cache = PromptCache::new(Duration::from_millis(200));
+        cache.insert(ModelPreference::GPT51, "plan it", first.response.clone()).await;
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        cache.insert(ModelPreference::GPT51, "plan it", first.response.clone()).await;
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        cache.insert(ModelPreference::GPT51, "plan that", first.response).await;
+        assert!(cache.contains(ModelPreference::GPT51, "plan it").await);
+        assert_eq!(cache.len().await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_cache_hits_are_not_billed() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let session_id = session_mgr
+            .create_session("user123".to_string(), small_project(), None)
+            .await
+            .unwrap();
+        let planner = session_mgr.sessions.read().await[&session_id].agents[0].id;
+
+        for _ in 0..2 {
+            session_mgr
+                .assign_task(session_id, planner, make_task("plan the project", vec![]))
+                .await
+                .unwrap();
+        }
+        wait_until(|| async {
+            session_mgr.get_session_status(session_id).await.unwrap().metrics.tasks_completed == 2
+        }).await;
+
+        let metrics = session_mgr.get_session_status(session_id).await.unwrap().metrics;
+        assert!(metrics.total_cost > 0.0);
+        assert_eq!(metrics.cost_saved, metrics.total_cost);
+    }
+
+    #[tokio::test]
+    async fn test_batcher_fails_only_unparsed_task() {
+        let batch: Vec<Task> = (0..3).map(|i| make_task(&format!("step {i}"), vec![])).collect();
+        let dropped = batch[1].id;
+        let marker = format!("{BATCH_TASK_MARKER}{dropped}>>>");
+        let drop_one = ScriptedProvider::default().on(|_, _| true, Reply::Map(Arc::new(move |text| text.replace(&marker, ""))));
+        let clients = ModelClients::with_provider(Arc::new(drop_one));
+
+        let results = TaskBatcher::execute(&clients, SessionId::nil(), AgentId::nil(), ModelPreference::ClaudeOpus45, batch).await;
+        assert_eq!(results.len(), 3);
+        for (task, result) in &results {
+            if task.id == dropped {
+                assert!(matches!(result, Err(SwarmError::TaskExecutionFailed)));
+            } else {
+                assert!(result.as_ref().unwrap().response.text.contains(&task.description));
+            }
+        }
+    }
+
+    #[test]
+    fn test_adaptive_batch_size_follows_latency_and_errors() {
+        let controller = BatchController::new(2, 8, Duration::from_millis(200));
+        let metrics = MetricsRegistry::new();
+        controller.report_to(metrics.clone());
+        let (opus, gemini) = (ModelPreference::ClaudeOpus45, ModelPreference::Gemini3Pro);
+        assert_eq!(controller.batch_size(opus), 2);
+
+        // Fast calls grow it one at a time, up to the max
+        let fast = Duration::from_millis(50);
+        let grown: Vec<usize> = (0..8).map(|_| controller.observe(opus, fast, true)).collect();
+        assert_eq!(grown, [3, 4, 5, 6, 7, 8, 8, 8]);
+        assert!(metrics.render_prometheus().contains("swarm_batch_size{model=\"ClaudeOpus45\"} 8"));
+        // Other models are sized separately
+        assert_eq!(controller.batch_size(gemini), 2);
+
+        // Slow calls halve it, down to the min
+        let slow = Duration::from_millis(800);
+        let shrunk: Vec<usize> = (0..3).map(|_| controller.observe(opus, slow, true)).collect();
+        assert_eq!(shrunk, [4, 2, 2]);
+
+        // Failing fast calls shrink it once the error rate passes the limit;
+        // a lone failure doesn't
+        for _ in 0..5 {
+            controller.observe(gemini, fast, true);
+        }
+        assert_eq!(controller.batch_size(gemini), 7);
+        let failing: Vec<usize> = (0..3).map(|_| controller.observe(gemini, fast, false)).collect();
+        assert_eq!(failing, [8, 4, 2]);
+        assert!(metrics.render_prometheus().contains("swarm_batch_size{model=\"Gemini3Pro\"} 2"));
+    }
+
+    #[tokio::test]
+    async fn test_coder_batches_queued_tasks_into_one_call() {
+        let provider = Arc::new(ScriptedProvider::default());
+        let session_mgr = make_manager_on(
+            AgentPool::new(Arc::new(ModelClients::with_provider(provider.clone())))
+                .with_task_batcher(TaskBatcher::new(3, Duration::from_millis(100))),
+        );
+        let session_id = session_mgr
+            .create_session("user123".to_string(), small_project(), None)
+            .await
+            .unwrap();
+        let coder = session_mgr.sessions.read().await[&session_id]
+            .agents.iter()
+            .find(|a| a.role == AgentRole::Coder)
+            .unwrap()
+            .id;
+
+        for i in 0..3 {
+            session_mgr
+                .assign_task(session_id, coder, make_task(&format!("tiny action {i}"), vec![]))
+                .await
+                .unwrap();
+        }
+        wait_until(|| async {
+            session_mgr.get_session_status(session_id).await.unwrap().metrics.tasks_completed == 3
+        }).await;
+        // One batched coder call, then a verification call per task
+        assert_eq!(provider.calls(), 1 + 3);
+    }
+
+    #[tokio::test]
+    async fn test_every_template_spawns_a_verifier() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let templates = [
+            TemplateType::HospitalIntegration,
+            TemplateType::ResearchSprint,
+            TemplateType::SoftwareDev,
+            TemplateType::Manufacturing,
+        ];
+        for template in templates {
+            let project = ProjectSpec { template, ..small_project() };
+            let session_id = session_mgr.create_session("user123".to_string(), project, None).await.unwrap();
+            let verifiers: Vec<ModelPreference> = session_mgr.sessions.read().await[&session_id]
+                .agents.iter()
+                .filter(|a| a.role == AgentRole::Verifier)
+                .map(|a| a.model)
+                .collect();
+            assert_eq!(verifiers, vec![ModelPreference::Gemini3Pro], "{:?}", template);
+        }
+
+        // small_project has 2 coders
+        let session_mgr = make_manager(Arc::new(RedisClient::new()))
+            .with_verifier_config(VerifierConfig { coders_per_verifier: Some(1), ..Default::default() })
+            .with_model_router(
+                ModelRouter::default().route(AgentRole::Verifier, Complexity::Small, ModelPreference::GPT51),
+            );
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let verifiers = session_mgr.sessions.read().await[&session_id]
+            .agents.iter()
+            .filter(|a| a.role == AgentRole::Verifier && a.model == ModelPreference::GPT51)
+            .count();
+        assert_eq!(verifiers, 2);
+
+        // A verifier per coder still keeps the largest roster under the cap
+        let largest = ProjectSpec {
+            parallelization: ParallelizationMode::Turbo,
+            replication_count: MAX_AGENTS_PER_SESSION,
+            estimated_complexity: Complexity::XLarge,
+            requires_browser: true,
+            ..small_project()
+        };
+        let roster = session_mgr.initial_roster(&largest);
+        let count = |role| roster.iter().filter(|(r, _)| *r == role).count();
+        assert!(roster.len() <= MAX_AGENTS_PER_SESSION && roster.len() > MAX_AGENTS_PER_SESSION - 4, "{} agents", roster.len());
+        assert_eq!(count(AgentRole::Verifier), count(AgentRole::Coder));
+    }
+
+    #[tokio::test]
+    async fn test_verification_backlog_holds_coders_back() {
+        let checks = Arc::new(Semaphore::new(0));
+        let code = Arc::new(Semaphore::new(0));
+        let provider = Arc::new(
+            ScriptedProvider::default()
+                .on(verifying, Reply::Gated(checks.clone()))
+                .on(|_, _| true, Reply::Gated(code.clone())),
+        );
+        let session_mgr = make_manager_with(ModelClients::with_provider(provider.clone())).with_verifier_config(VerifierConfig { max_backlog_per_verifier: Some(1.0), ..Default::default() });
+        // small_project has 2 coders, 1 tester and 1 verifier
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let mut events = session_mgr.subscribe();
+        let tasks: Vec<Task> = (0..8).map(|i| make_task(&format!("module {i}"), vec![])).collect();
+        session_mgr.submit_plan(session_id, tasks).await.unwrap();
+        let dispatcher = session_mgr.spawn_dispatcher(10, Duration::from_millis(2)).unwrap();
+        let verifier_working = || async {
+            session_mgr.sessions.read().await[&session_id].agents()
+                .iter()
+                .any(|a| a.role == AgentRole::Verifier && a.status == AgentStatus::Working)
+        };
+        let gauge = || {
+            let rendered = session_mgr.agent_pool.metrics.render_prometheus();
+            rendered.lines()
+                .find_map(|line| line.strip_prefix("swarm_verification_backlog "))
+                .and_then(|value| value.parse::<f64>().ok())
+                .unwrap()
+        };
+
+        // Two checks put the verifier past the limit
+        for backlog in [1.0, 2.0] {
+            code.add_permits(1);
+            wait_until(|| async {
+                session_mgr.get_session_status(session_id).await.unwrap().verification_backlog == backlog
+            }).await;
+        }
+        wait_until(verifier_working).await;
+        wait_until(|| async { gauge() == 2.0 }).await;
+
+        // So the next goes to the idle tester instead of queueing behind them
+        code.add_permits(1);
+        let tester = loop {
+            if let SwarmEvent::TaskStarted { agent_id, role: Some(AgentRole::Tester), verification, .. } = events.recv().await.unwrap() {
+                assert!(verification);
+                break agent_id;
+            }
+        };
+        let status = session_mgr.get_session_status(session_id).await.unwrap();
+        assert_eq!(status.verification_backlog, 2.0, "the tester's check isn't the verifier's");
+        let pending = session_mgr.task_queue.pending_len_for(session_id).await;
+        assert!(pending >= 8 - 4, "{pending} tasks still queued");
+
+        // Draining the backlog lets the coders finish the plan
+        checks.add_permits(64);
+        code.add_permits(64);
+        wait_until(|| async {
+            session_mgr.get_session_status(session_id).await.unwrap().metrics.tasks_completed == 8
+        }).await;
+        let status = session_mgr.get_session_status(session_id).await.unwrap();
+        assert_eq!((status.verification_backlog, status.metrics.tasks_assigned), (0.0, 8));
+        let checked = session_mgr.sessions.read().await[&session_id].agents()
+            .iter()
+            .find(|a| a.id == tester)
+            .map(|a| a.tasks_completed);
+        assert!(checked >= Some(1));
+        wait_until(|| async { gauge() == 0.0 }).await;
+        dispatcher.abort();
+    }
+
+    #[test]
+    fn test_agent_counts_per_mode_and_complexity() {
+        use Complexity::*;
+        use ParallelizationMode::*;
+
+        // (mode, replication, complexity) => (coders, testers)
+        let table = [
+            (Sequential, 1, Small, 1, 1),
+            (Sequential, 1, Medium, 1, 1),
+            (Sequential, 1, Large, 1, 1),
+            (Sequential, 1, XLarge, 1, 1),
+            (Batch10, 1, Small, 2, 1),
+            (Batch10, 1, Medium, 5, 1),
+            (Batch10, 1, Large, 7, 1),
+            (Batch10, 1, XLarge, 10, 2),
+            (Batch100, 1, Small, 25, 6),
+            (Batch100, 1, Medium, 50, 12),
+            (Batch100, 1, Large, 75, 18),
+            (Batch100, 1, XLarge, 100, 25),
+            (Turbo, 1, Small, 2, 1),
+            (Turbo, 1, Medium, 5, 1),
+            (Turbo, 1, Large, 7, 1),
+            (Turbo, 1, XLarge, 10, 2),
+            // Past the cap: 99,999 instances count as 1,000
+            (Turbo, 99_999, Small, 2_500, 625),
+            (Turbo, 99_999, Medium, 5_000, 1_250),
+            (Turbo, 99_999, Large, 7_500, 1_875),
+            // ...and the whole count, planner and testers included, stays under it
+            (Turbo, 99_999, XLarge, 7_999, 1_999),
+        ];
+
+        for (mode, replication_count, complexity, coders, testers) in table {
+            for requires_browser in [false, true] {
+                let spec = ProjectSpec {
+                    parallelization: mode,
+                    replication_count,
+                    estimated_complexity: complexity,
+                    requires_browser,
+                    ..small_project()
+                };
+                let expected = AgentCounts {
+                    planners: 1,
+                    coders,
+                    testers,
+                    browsers: usize::from(requires_browser),
+                };
+                assert_eq!(compute_agent_counts(&spec), expected, "{:?} x{} {:?}", mode, replication_count, complexity);
+            }
+        }
+    }
+
+    #[test]
+    fn test_default_model_routes() {
+        let router = ModelRouter::default();
+        for complexity in [Complexity::Small, Complexity::Medium, Complexity::Large, Complexity::XLarge] {
+            assert_eq!(router.model_for(AgentRole::Planner, complexity), ModelPreference::GPT51);
+            assert_eq!(router.model_for(AgentRole::Coder, complexity), ModelPreference::ClaudeOpus45);
+            assert_eq!(router.model_for(AgentRole::Tester, complexity), ModelPreference::Gemini3Pro);
+            assert_eq!(router.model_for(AgentRole::Browser, complexity), ModelPreference::None);
+            assert_eq!(router.model_for(AgentRole::Verifier, complexity), ModelPreference::Gemini3Pro);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_model_router_overrides_small_jobs() {
+        let roles = [AgentRole::Planner, AgentRole::Coder, AgentRole::Tester, AgentRole::Verifier];
+        let router = roles.into_iter().fold(ModelRouter::default(), |router, role| {
+            router.route(role, Complexity::Small, ModelPreference::GPT51)
+        });
+        let session_mgr = make_manager(Arc::new(RedisClient::new())).with_model_router(router);
+
+        let small = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let large = session_mgr
+            .create_session(
+                "user123".to_string(),
+                ProjectSpec { estimated_complexity: Complexity::Large, ..small_project() },
+                None,
+            )
+            .await
+            .unwrap();
+
+        let sessions = session_mgr.sessions.read().await;
+        assert!(sessions[&small].agents.iter().all(|a| a.model == ModelPreference::GPT51));
+        // Other complexities keep the defaults
+        let coder = sessions[&large].agents.iter().find(|a| a.role == AgentRole::Coder).unwrap();
+        assert_eq!(coder.model, ModelPreference::ClaudeOpus45);
+    }
+
+    #[tokio::test]
+    async fn test_quality_hint_picks_the_cheapest_capable_model() {
+        let clients = ModelClients::new();
+        let cost = |model| ModelClients::cost_of(model, &AVG_USAGE_PER_ACTION);
+        let cheap = clients.select_model(QualityTier::Low, AgentRole::Coder);
+        let capable = clients.select_model(QualityTier::High, AgentRole::Coder);
+        assert_eq!((cheap, capable), (ModelPreference::GPT51, ModelPreference::ClaudeOpus45));
+        assert!(cost(cheap) < cost(capable));
+        assert_eq!(clients.select_model(QualityTier::High, AgentRole::Browser), ModelPreference::None);
+        // A re-rated model is picked once it's good enough
+        let clients = clients.with_model_quality(ModelPreference::Gemini3Pro, QualityTier::High);
+        assert_eq!(clients.select_model(QualityTier::High, AgentRole::Tester), ModelPreference::Gemini3Pro);
+
+        // End to end: an Opus coder runs a low-quality task on the cheaper model
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let coder = session_mgr.sessions.read().await[&session_id].agents
+            .iter()
+            .find(|a| a.model == ModelPreference::ClaudeOpus45)
+            .unwrap()
+            .id;
+        let mut task = make_task("rename a variable", vec![]);
+        task.quality = Some(QualityTier::Low);
+        let task_id = task.id;
+        session_mgr.assign_task(session_id, coder, task).await.unwrap();
+        wait_until(|| async {
+            session_mgr.get_session_status(session_id).await.unwrap().metrics.tasks_completed == 1
+        }).await;
+        let sessions = session_mgr.sessions.read().await;
+        let agent = sessions[&session_id].agents.iter().find(|a| a.id == coder).unwrap();
+        assert_eq!(agent.models_used[&task_id], ModelPreference::GPT51);
+    }
+
+    #[tokio::test]
+    async fn test_prompt_templates_render_task_fields() {
+        let json = r#"[{"role": "Coder", "template": "ResearchSprint",
+                        "text": "[{priority}] {description} ({estimated_time_min} min, skills: {skills}) {unknown} {task_id}"}]"#;
+        let templates = PromptTemplates::from_json(json).unwrap();
+        let mut task = Task::new("summarise the trial data", 2.5);
+        task.priority = TaskPriority::High;
+        task.required_skills = vec!["stats".to_string(), "R".to_string()];
+        assert_eq!(
+            templates.render(AgentRole::Coder, Some(TemplateType::ResearchSprint), &task),
+            format!("[High] summarise the trial data (2.5 min, skills: stats, R) {{unknown}} {}", task.id),
+        );
+        // Other projects and roles keep the defaults, which end with the task
+        let hospital = templates.render(AgentRole::Coder, Some(TemplateType::HospitalIntegration), &task);
+        assert!(hospital.contains("HL7") && hospital.ends_with("\n\nsummarise the trial data"), "{hospital}");
+        let planning = templates.render(AgentRole::Planner, Some(TemplateType::ResearchSprint), &task);
+        assert_eq!(planning, PromptTemplates::default().render(AgentRole::Planner, None, &task));
+        assert_eq!(PromptTemplates::empty().render(AgentRole::Tester, None, &task), task.description);
+        assert!(matches!(PromptTemplates::from_json("{"), Err(SwarmError::InvalidSpec(_))));
+
+        // Agents prompt with their session's template
+        let mut templates = PromptTemplates::empty();
+        templates.set(AgentRole::Coder, Some(TemplateType::HospitalIntegration), "As a clinical coder: {description}");
+        let clients = ModelClients::with_provider(Arc::new(ScriptedProvider::default()));
+        let session_mgr = make_manager_on(AgentPool::new(Arc::new(clients)).with_prompt_templates(templates));
+        let spec = ProjectSpec { template: TemplateType::HospitalIntegration, ..small_project() };
+        let session_id = session_mgr.create_session("user123".to_string(), spec, None).await.unwrap();
+        let task = make_task("map ADT messages", vec![]);
+        session_mgr.submit_plan(session_id, vec![task.clone()]).await.unwrap();
+        session_mgr.dispatch_ready(1).await.unwrap();
+        wait_until(|| async { session_mgr.task_queue.get_result(task.id).await.is_some() }).await;
+        let result = session_mgr.task_queue.get_result(task.id).await.unwrap();
+        assert!(result.output.ends_with("] As a clinical coder: map ADT messages"), "{}", result.output);
+    }
+
+    #[tokio::test]
+    async fn test_result_violating_its_schema_is_rejected_and_retried() {
+        let provider = Arc::new(ScriptedProvider::default().on_each(
+            |_, prompt| prompt.ends_with("\n\nadd login"),
+            vec![
+                Reply::text("Done! I changed src/login.rs."),
+                Reply::text("```json\n{\"files\": [\"src/login.rs\"]}\n```"),
+            ],
+        ));
+        let session_mgr = make_manager_with(ModelClients::with_provider(provider.clone()));
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let coder = session_mgr.sessions.read().await[&session_id].agents[1].id;
+
+        let mut task = make_task("add login", vec![]);
+        task.result_schema = Some(serde_json::json!({
+            "type": "object",
+            "required": ["files"],
+            "properties": { "files": { "type": "array", "items": { "type": "string" } } },
+        }));
+        task.retry_policy.base_delay_ms = 0;
+        assert!(matches!(
+            task.check_result(r#"{"files": "src/login.rs"}"#),
+            Err(SwarmError::ResultSchemaViolation { errors, .. }) if errors.len() == 1 && errors[0].starts_with("/files")
+        ));
+
+        session_mgr.task_queue.enqueue(task.clone()).await.unwrap();
+        let started = session_mgr.task_queue.dequeue().await.unwrap();
+        assert!(started.compiled_schema.is_some());
+        session_mgr.assign_task(session_id, coder, started).await.unwrap();
+        wait_until(|| async { session_mgr.task_queue.pending_len().await == 1 }).await;
+        let metrics = session_mgr.get_session_status(session_id).await.unwrap().metrics;
+        assert_eq!((metrics.tasks_completed, metrics.tasks_failed), (0, 0));
+
+        let retried = session_mgr.task_queue.dequeue().await.unwrap();
+        assert_eq!((retried.id, retried.attempts), (task.id, 1));
+        session_mgr.assign_task(session_id, coder, retried).await.unwrap();
+        wait_until(|| async { session_mgr.task_queue.get_result(task.id).await.is_some() }).await;
+        let result = session_mgr.task_queue.get_result(task.id).await.unwrap();
+        assert!(result.output.contains("\"files\""));
+        assert_eq!(provider.calls_where(|p| p.ends_with("\n\nadd login")), 2);
+
+        // A schema that can't compile is refused up front, not paid for per attempt
+        let mut broken = make_task("add logout", vec![]);
+        broken.result_schema = Some(serde_json::json!({ "type": 12 }));
+        let enqueued = session_mgr.task_queue.enqueue(broken.clone()).await;
+        assert!(matches!(enqueued, Err(SwarmError::InvalidSpec(_))));
+        let submitted = session_mgr.submit_plan(session_id, vec![make_task("fine", vec![]), broken]).await;
+        assert!(matches!(submitted, Err(SwarmError::InvalidSpec(_))));
+        assert_eq!(session_mgr.task_queue.pending_len().await, 0);
+    }
+
+    #[test]
+    fn test_project_spec_builder_defaults_and_clamps() {
+        let spec = ProjectSpec::builder("Hospital rollout")
+            .template(TemplateType::HospitalIntegration)
+            .parallelization(ParallelizationMode::Turbo)
+            .replication_count(5_000)
+            .complexity(Complexity::Large)
+            .budget_usd(250.0)
+            .build()
+            .unwrap();
+        assert_eq!(spec.replication_count, MAX_AGENTS_PER_SESSION / TURBO_AGENTS_PER_INSTANCE);
+        assert_eq!(spec.estimated_complexity, Complexity::Large);
+        assert_eq!(spec.budget_usd, Some(250.0));
+        assert!(!spec.requires_browser);
+
+        // Only Turbo scales with replication
+        let spec = ProjectSpec::builder("Batch").replication_count(5_000).build().unwrap();
+        assert_eq!(spec.replication_count, 5_000);
+    }
+
+    #[tokio::test]
+    async fn test_project_spec_builder_rejections() {
+        let invalid = |result: Result<ProjectSpec, SwarmError>| matches!(result, Err(SwarmError::InvalidSpec(_)));
+        assert!(invalid(ProjectSpec::builder("").build()));
+        assert!(invalid(ProjectSpec::builder("   ").build()));
+        assert!(invalid(ProjectSpec::builder("Sprint").replication_count(0).build()));
+        assert!(invalid(ProjectSpec::builder("Sprint").budget_usd(0.0).build()));
+        assert!(invalid(ProjectSpec::builder("Sprint").budget_usd(f64::NAN).build()));
+
+        // Hand-built specs get the same checks on session creation
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let err = session_mgr
+            .create_session("user123".to_string(), ProjectSpec { replication_count: 0, ..small_project() }, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, SwarmError::InvalidSpec(_)));
+        assert_eq!(session_mgr.user_usage("user123").await, UserUsage::default());
+    }
+
+    #[tokio::test]
+    async fn test_failed_verification_requeues_task() {
+        let rejecting = ScriptedProvider::default().on(
+            |model, _| model == ModelPreference::Gemini3Pro,
+            Reply::Map(Arc::new(|text| text + "\nFAIL: tests don't compile")),
+        );
+        let session_mgr = make_manager_with(ModelClients::with_provider(Arc::new(rejecting)));
+        let session_id = session_mgr
+            .create_session("user123".to_string(), small_project(), None)
+            .await
+            .unwrap();
+        let coder = session_mgr.sessions.read().await[&session_id].agents
+            .iter()
+            .find(|a| a.role == AgentRole::Coder)
+            .unwrap()
+            .id;
+
+        session_mgr.task_queue.enqueue(make_task("add login", vec![])).await.unwrap();
+        let task = session_mgr.task_queue.dequeue().await.unwrap();
+        session_mgr.assign_task(session_id, coder, task.clone()).await.unwrap();
+
+        wait_until(|| async {
+            session_mgr.get_session_status(session_id).await.unwrap().metrics.verification_failed == 1
+        }).await;
+        let metrics = session_mgr.get_session_status(session_id).await.unwrap().metrics;
+        assert_eq!(metrics.tasks_completed, 0);
+        assert_eq!(session_mgr.task_queue.in_progress_len().await, 0);
+        assert_eq!(session_mgr.task_queue.pending_len().await, 1);
+
+        let retried = session_mgr.task_queue.pending.read().await.peek().unwrap().task.clone();
+        assert_eq!((retried.id, retried.attempts), (task.id, 1));
+    }
+
+    #[tokio::test]
+    async fn test_verification_needs_an_explicit_pass() {
+        assert!(verification_passed("Checked the tests.\npass  \n\n"));
+        assert!(!verification_passed(""));
+        assert!(!verification_passed("Looks good to me"));
+        assert!(!verification_passed("PASS\nFAIL: no tests"));
+
+        // An empty answer, then one without a verdict, before a PASS
+        let verifier = ScriptedProvider::default().on_each(
+            verifying,
+            vec![Reply::text(""), Reply::text("Looks good to me"), Reply::text("PASS")],
+        );
+        // Uncached, so each retry is verified afresh
+        let session_mgr = make_manager_with(
+            ModelClients::with_provider(Arc::new(verifier)).with_prompt_cache(PromptCache::new(Duration::ZERO)),
+        );
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let mut task = make_task("add login", vec![]);
+        task.retry_policy = RetryPolicy { base_delay_ms: 1, ..RetryPolicy::default() };
+        session_mgr.submit_plan(session_id, vec![task]).await.unwrap();
+        let dispatcher = session_mgr.spawn_dispatcher(4, Duration::from_millis(5)).unwrap();
+
+        wait_until(|| async {
+            session_mgr.get_session_status(session_id).await.unwrap().metrics.tasks_completed == 1
+        }).await;
+        assert_eq!(session_mgr.get_session_status(session_id).await.unwrap().metrics.verification_failed, 2);
+        dispatcher.abort();
+    }
+
+    const SPENDING_USAGE: TokenUsage = TokenUsage { input_tokens: 50_000, output_tokens: 50_000 };
+
+    #[tokio::test]
+    async fn test_task_aborted_past_its_cost_ceiling() {
+        let check_usage = TokenUsage { input_tokens: 1_000, output_tokens: 10 };
+        let provider = Arc::new(
+            ScriptedProvider::default()
+                .on(verifying, Reply::Text("FAIL".to_string(), Some(check_usage)))
+                .on(|_, _| true, Reply::Text("patch".to_string(), Some(SPENDING_USAGE))),
+        );
+        // Uncached, so every retry pays again
+        let session_mgr = make_manager_with(
+            ModelClients::with_provider(provider.clone()).with_prompt_cache(PromptCache::new(Duration::ZERO)),
+        );
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let (coder_model, verifier_model) = {
+            let sessions = session_mgr.sessions.read().await;
+            let model_of = |role| sessions[&session_id].agents.iter().find(|a| a.role == role).unwrap().model;
+            (model_of(AgentRole::Coder), model_of(AgentRole::Verifier))
+        };
+        let per_attempt = ModelClients::cost_of(coder_model, &SPENDING_USAGE);
+        let per_check = ModelClients::cost_of(verifier_model, &check_usage);
+        assert!(per_check > 0.0 && per_check < 0.1 * per_attempt);
+
+        // Plenty of retries left, but only room for two attempts' spend
+        let mut task = make_task("refactor everything", vec![]);
+        task.retry_policy = RetryPolicy { max_attempts: 10, base_delay_ms: 1, ..RetryPolicy::default() };
+        task.max_cost_usd = Some(2.5 * per_attempt);
+        session_mgr.submit_plan(session_id, vec![task.clone()]).await.unwrap();
+        let mut events = session_mgr.subscribe();
+        let dispatcher = session_mgr.spawn_dispatcher(4, Duration::from_millis(5)).unwrap();
+
+        wait_until(|| async {
+            session_mgr.get_session_status(session_id).await.unwrap().metrics.tasks_failed == 1
+        }).await;
+        dispatcher.abort();
+        // A third attempt would have cost what the first two did on
+        // average, taking it over; the verifications are charged to it too
+        assert_eq!(provider.calls_where(|p| !verifying(ModelPreference::None, p)), 2);
+        let dead = session_mgr.task_queue.dead_letter().await;
+        assert_eq!(dead.len(), 1);
+        assert_eq!((dead[0].id, dead[0].attempts), (task.id, 2));
+        assert!((dead[0].cost_incurred - 2.0 * (per_attempt + per_check)).abs() < 1e-9);
+        assert!(session_mgr.task_queue.get_result(task.id).await.is_none());
+        loop {
+            if let SwarmEvent::TaskVerified { task_id, passed, dead_lettered, .. } = events.recv().await.unwrap() {
+                if dead_lettered {
+                    assert_eq!((task_id, passed), (task.id, false));
+                    break;
+                }
+            }
+        }
+
+        // A stream is cut off as soon as it passes the ceiling
+        let words = vec!["fn "; 200];
+        let provider = Arc::new(
+            ScriptedProvider::default()
+                .on(|_, _| true, Reply::Words(words, SPENDING_USAGE)),
+        );
+        let session_mgr = make_manager_with(ModelClients::with_provider(provider.clone()));
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let mut task = make_task("write the parser", vec![]);
+        task.max_cost_usd = Some(1e-12);
+        session_mgr.submit_plan(session_id, vec![task.clone()]).await.unwrap();
+        let dispatcher = session_mgr.spawn_dispatcher(4, Duration::from_millis(5)).unwrap();
+        wait_until(|| async {
+            session_mgr.get_session_status(session_id).await.unwrap().metrics.tasks_failed == 1
+        }).await;
+        dispatcher.abort();
+        let dead = session_mgr.task_queue.dead_letter().await;
+        assert_eq!((dead.len(), dead[0].id), (1, task.id));
+        let shared_state = session_mgr.sessions.read().await[&session_id].shared_state.clone();
+        let output = shared_state.get(&format!("task:{}:output", task.id)).await.unwrap();
+        assert_eq!(output.as_deref(), Some("fn "));
+    }
+
+    #[tokio::test]
+    async fn test_streamed_completion_assembles_chunks_in_order() {
+        use futures::StreamExt;
+
+        let chunking = || {
+            let usage = TokenUsage { input_tokens: 10, output_tokens: 4 };
+            Arc::new(ScriptedProvider::default().on(|_, _| true, Reply::Words(vec!["fn ", "main() ", "{ ", "}"], usage)))
+        };
+        let model_clients = Arc::new(ModelClients::with_provider(chunking()));
+        let chunks: Vec<String> = model_clients
+            .complete_stream(ModelPreference::ClaudeOpus45, "write main")
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(chunks, ["fn ", "main() ", "{ ", "}"]);
+        // Cached whole, with the provider's usage
+        let cached = model_clients.complete(ModelPreference::ClaudeOpus45, "write main").await.unwrap();
+        assert!(cached.hit);
+        assert_eq!(cached.response.text, "fn main() { }");
+        assert_eq!(cached.response.usage, TokenUsage { input_tokens: 10, output_tokens: 4 });
+
+        // Coders stream too, leaving the assembled text in shared state once
+        // the partial text is no longer needed
+        let session_mgr = make_manager_with(ModelClients::with_provider(chunking()));
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let task = make_task("write main", vec![]);
+        session_mgr.submit_plan(session_id, vec![task.clone()]).await.unwrap();
+        session_mgr.dispatch_ready(4).await.unwrap();
+        wait_until(|| async {
+            session_mgr.get_session_status(session_id).await.unwrap().metrics.cost_by_role.contains_key(&AgentRole::Coder)
+        }).await;
+        let shared_state = session_mgr.sessions.read().await[&session_id].shared_state.clone();
+        let partial = shared_state.get(&format!("task:{}:partial", task.id)).await.unwrap();
+        assert_eq!(partial, None);
+        let output = shared_state.get(&format!("task:{}:output", task.id)).await.unwrap();
+        assert_eq!(output.as_deref(), Some("fn main() { }"));
+    }
+
+    #[tokio::test]
+    async fn test_hung_model_call_times_out_and_frees_the_agent() {
+        let timeout = Duration::from_millis(50);
+        let model_clients = || {
+            [ModelPreference::GPT51, ModelPreference::ClaudeOpus45, ModelPreference::Gemini3Pro]
+                .into_iter()
+                .fold(ModelClients::with_provider(Arc::new(ScriptedProvider::default().on(|_, _| true, Reply::Hang))), |clients, model| {
+                    clients.with_timeout(model, timeout)
+                })
+        };
+
+        // Each model in the chain is given up on in turn
+        let clients = model_clients();
+        assert_eq!(clients.timeout(ModelPreference::GPT51), timeout);
+        assert_eq!(ModelClients::new().timeout(ModelPreference::GPT51), DEFAULT_MODEL_TIMEOUT);
+        let err = clients.complete(ModelPreference::GPT51, "anything").await.unwrap_err();
+        assert!(matches!(
+            err,
+            SwarmError::AllModelsFailed { source: Some(ref e), .. }
+                if matches!(**e, SwarmError::ModelTimeout { model: ModelPreference::ClaudeOpus45, .. })
+        ));
+
+        // A stream keeps what arrived before the deadline
+        let mut stream = clients.open_stream(ModelPreference::GPT51, "write main").await.unwrap();
+        assert_eq!(stream.next_chunk().await.unwrap().unwrap(), "fn ");
+        let err = stream.next_chunk().await.unwrap().unwrap_err();
+        assert!(matches!(err, SwarmError::ModelTimeout { ref partial, .. } if partial == "fn "));
+        assert!(stream.next_chunk().await.is_none());
+        drop(stream);
+
+        // and its model's breaker counts the timeout
+        let clients = model_clients().with_circuit_breaker(ModelPreference::GPT51, 1, Duration::from_secs(60));
+        let mut stream = clients.open_stream(ModelPreference::GPT51, "write main").await.unwrap();
+        while let Some(Ok(_)) = stream.next_chunk().await {}
+        drop(stream);
+        assert_eq!(clients.circuit_state(ModelPreference::GPT51), Some(CircuitState::Open));
+
+        // The agent fails the task and goes back to idle
+        let session_mgr = make_manager_with(model_clients());
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let coder = session_mgr.sessions.read().await[&session_id].agents[1].id;
+        session_mgr.task_queue.enqueue(make_task("write main", vec![])).await.unwrap();
+        let task = session_mgr.task_queue.dequeue().await.unwrap();
+        let agent = || async {
+            session_mgr.sessions.read().await[&session_id].agents
+                .iter()
+                .find(|a| a.id == coder)
+                .cloned()
+                .unwrap()
+        };
+        session_mgr.assign_task(session_id, coder, task.clone()).await.unwrap();
+        let shared_state = session_mgr.sessions.read().await[&session_id].shared_state.clone();
+        let partial = format!("task:{}:partial", task.id);
+        wait_until(|| async { shared_state.get(&partial).await.unwrap().as_deref() == Some("fn ") }).await;
+        wait_until(|| async { agent().await.status == AgentStatus::Idle }).await;
+        assert_eq!(agent().await.tasks_completed, 0);
+        // Reported as a retriable failure, so the task is queued again
+        wait_until(|| async { session_mgr.task_queue.pending_len().await == 1 }).await;
+    }
+
+    #[tokio::test]
+    async fn test_max_concurrent_caps_calls_per_model() {
+        let provider = Arc::new(ScriptedProvider::default().on(|_, _| true, Reply::Slow(Duration::from_millis(30))));
+        let model_clients = Arc::new(
+            ModelClients::with_provider(provider.clone()).with_max_concurrent(ModelPreference::ClaudeOpus45, 2),
+        );
+        let agent_pool = AgentPool::new(model_clients.clone());
+        let shared_state = Arc::new(SharedState::new(SessionId::new_v4()));
+
+        for i in 0..8 {
+            let agent = agent_pool
+                .spawn_agent(
+                    SessionId::new_v4(),
+                    AgentRole::Coder,
+                    ModelPreference::ClaudeOpus45,
+                    shared_state.clone(),
+                    Arc::new(SessionControl::default()),
+                )
+                .await
+                .unwrap();
+            agent_pool.assign_task(agent.id, Task::new(format!("task {i}"), 1.0)).await.unwrap();
+        }
+
+        wait_until(|| async { model_clients.permits_in_use(ModelPreference::ClaudeOpus45) == Some(2) }).await;
+        assert!(agent_pool.metrics.render_prometheus().contains("swarm_model_calls_in_flight{model=\"ClaudeOpus45\"} 2\n"));
+        wait_until(|| async { provider.calls() == 8 && provider.in_flight.load(AtomicOrdering::SeqCst) == 0 }).await;
+        assert_eq!(provider.peak(), 2);
+        assert_eq!(model_clients.permits_in_use(ModelPreference::ClaudeOpus45), Some(0));
+        assert_eq!(model_clients.permits_in_use(ModelPreference::GPT51), None);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_agents_with_instant_models_take_turns_on_one_thread() {
+        let agent_pool = AgentPool::new(Arc::new(ModelClients::new()));
+        let mut reports = agent_pool.take_reports().unwrap();
+        let shared_state = Arc::new(SharedState::new(SessionId::new_v4()));
+        let (agents, tasks_each) = (8, 10);
+
+        let mut agent_ids = vec![];
+        for _ in 0..agents {
+            let agent = agent_pool
+                .spawn_agent(
+                    SessionId::new_v4(),
+                    AgentRole::Tester,
+                    ModelPreference::ClaudeOpus45,
+                    shared_state.clone(),
+                    Arc::new(SessionControl::default()),
+                )
+                .await
+                .unwrap();
+            agent_ids.push(agent.id);
+        }
+        // Every inbox is full before any agent runs
+        for &agent_id in &agent_ids {
+            for i in 0..tasks_each {
+                agent_pool.assign_task(agent_id, Task::new(format!("check {i}"), 1.0)).await.unwrap();
+            }
+        }
+
+        let mut order = vec![];
+        while order.len() < agents * tasks_each {
+            if let Some(AgentReport::Completed { agent_id, .. }) = reports.recv().await {
+                order.push(agent_id);
+            }
+        }
+        // Each round of completions has every agent in it once
+        for round in order.chunks(agents) {
+            let distinct: HashSet<&AgentId> = round.iter().collect();
+            assert_eq!(distinct.len(), agents, "one agent ran ahead of the others");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_spaces_out_requests() {
+        // 10 requests/s: a full bucket, then one request every 100ms
+        let limit = RateLimit { requests_per_minute: 600, tokens_per_minute: u64::MAX };
+        let limiter = RateLimiter::new(Duration::from_secs(5))
+            .with_limit(ModelPreference::GPT51, limit)
+            .unwrap();
+        let clients = ModelClients::new().with_rate_limiter(limiter);
+
+        let started = Instant::now();
+        let mut throttled = Duration::ZERO;
+        for i in 0..12 {
+            let response = clients
+                .complete(ModelPreference::GPT51, &format!("request {i}"))
+                .await
+                .unwrap();
+            assert_eq!(response.response.model, ModelPreference::GPT51);
+            throttled += response.throttled;
+        }
+        assert!(started.elapsed() >= Duration::from_millis(190));
+        // Slightly under the wall time: buckets refill while requests run
+        assert!(throttled >= Duration::from_millis(150));
+
+        // Unlimited models pass straight through
+        let other = clients.complete(ModelPreference::Gemini3Pro, "free").await.unwrap();
+        assert_eq!(other.throttled, Duration::ZERO);
+    }
 
-pub struct AgentPool {
-    agents: Arc<RwLock<HashMap<AgentId, AgentHandle>>>,
-    model_clients: Arc<ModelClients>,
-}
+    #[tokio::test]
+    async fn test_session_rate_caps_its_streaming_coders() {
+        let usage = TokenUsage { input_tokens: 10, output_tokens: 4 };
+        let provider = Arc::new(ScriptedProvider::default().on(|_, _| true, Reply::Words(vec!["fn ", "main() {}"], usage)));
+        let session_mgr = make_manager_with(ModelClients::with_provider(provider.clone()));
+
+        // One request a second for one session, none for the other
+        let capped_spec = ProjectSpec { max_requests_per_minute: Some(60), ..small_project() };
+        let capped = session_mgr.create_session("user123".to_string(), capped_spec, None).await.unwrap();
+        let free = session_mgr.create_session("user456".to_string(), small_project(), None).await.unwrap();
+        for (session_id, tag) in [(capped, "capped"), (free, "free")] {
+            let tasks = (0..2).map(|i| make_task(&format!("write {tag} module {i}"), vec![])).collect();
+            session_mgr.submit_plan(session_id, tasks).await.unwrap();
+        }
+        session_mgr.dispatch_ready(4).await.unwrap();
+
+        // Both sessions' coders start at once, but only one capped coder's
+        // stream gets through before the next second
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(provider.calls_where(|p| p.contains("write capped")), 1);
+        assert!(provider.calls_where(|p| p.contains("write free")) >= 2);
+        wait_until(|| async { provider.calls_where(|p| p.contains("write capped")) >= 2 }).await;
+        wait_until(|| async {
+            session_mgr.get_session_status(capped).await.unwrap().metrics.rate_limited_sec >= 0.5
+        }).await;
+
+        let clients = &session_mgr.agent_pool.model_clients;
+        assert!(matches!(clients.set_session_rate(capped, 0), Err(SwarmError::InvalidSpec(_))));
+        // Lifted along with the session
+        session_mgr.destroy_session(capped).await.unwrap();
+        assert!(!clients.session_rates.read().unwrap().contains_key(&capped));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_rejects_past_max_wait() {
+        let limit = RateLimit { requests_per_minute: 60, tokens_per_minute: u64::MAX };
+        let limiter = RateLimiter::new(Duration::from_millis(50))
+            .with_limit(ModelPreference::Gemini3Pro, limit)
+            .unwrap();
+        // A zero rate is refused up front rather than stalling every call
+        for zero in [RateLimit { requests_per_minute: 0, ..limit }, RateLimit { tokens_per_minute: 0, ..limit }] {
+            assert!(matches!(
+                RateLimiter::unlimited().with_limit(ModelPreference::Gemini3Pro, zero),
+                Err(SwarmError::InvalidSpec(_))
+            ));
+        }
+
+        assert_eq!(limiter.acquire(ModelPreference::Gemini3Pro, 10).await.unwrap(), Duration::ZERO);
+        assert!(matches!(
+            limiter.acquire(ModelPreference::Gemini3Pro, 10).await,
+            Err(SwarmError::RateLimited { model: ModelPreference::Gemini3Pro, .. })
+        ));
+
+        // Through ModelClients the saturated model falls back instead
+        let clients = ModelClients::new().with_rate_limiter(limiter);
+        let response = clients.complete(ModelPreference::Gemini3Pro, "tests").await.unwrap();
+        assert_eq!(response.response.model, ModelPreference::GPT51);
+    }
+
+    #[tokio::test]
+    async fn test_reclaim_stale_guards_late_completion() {
+        let queue = TaskQueue::new(10);
+        let (stuck, fresh) = (AgentId::new_v4(), AgentId::new_v4());
+        queue.enqueue(make_task("migrate schema", vec![])).await.unwrap();
+        let task = queue.dequeue().await.unwrap();
+        assert!(task.started_at.is_some());
+        queue.claim(task.id, stuck).await;
+
+        assert!(queue.reclaim_stale(Duration::from_secs(60)).await.is_empty());
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let reclaimed = queue.reclaim_stale(Duration::from_millis(20)).await;
+        assert_eq!(reclaimed, vec![ReclaimedTask {
+            task_id: task.id,
+            agent_id: Some(stuck),
+            outcome: FailureOutcome::Retrying { attempt: 1, delay: Duration::ZERO },
+        }]);
+        assert_eq!(queue.in_progress_len().await, 0);
+
+        // The stuck agent finishes just after losing the task
+        assert!(!queue.complete_as(task.id, stuck).await);
+
+        let retried = queue.dequeue().await.unwrap();
+        assert_eq!((retried.id, retried.attempts), (task.id, 1));
+        queue.claim(retried.id, fresh).await;
+        assert!(!queue.complete_as(task.id, stuck).await);
+        assert!(queue.complete_as(task.id, fresh).await);
+        // ...and never completes it a second time
+        assert!(!queue.complete_as(task.id, fresh).await);
+    }
+
+    #[tokio::test]
+    async fn test_stale_task_sweep_fails_the_stuck_agent() {
+        let session_mgr = make_manager_with(ModelClients::with_provider(slow(Duration::from_millis(300))));
+        let session_id = session_mgr
+            .create_session("user123".to_string(), small_project(), None)
+            .await
+            .unwrap();
+        let coder = session_mgr.sessions.read().await[&session_id].agents
+            .iter()
+            .find(|a| a.role == AgentRole::Coder)
+            .unwrap()
+            .id;
+
+        session_mgr.task_queue.enqueue(make_task("hangs on the model", vec![])).await.unwrap();
+        let task = session_mgr.task_queue.dequeue().await.unwrap();
+        let mut events = session_mgr.subscribe();
+        session_mgr.assign_task(session_id, coder, task.clone()).await.unwrap();
+
+        assert!(matches!(
+            session_mgr.spawn_stale_task_sweep(Duration::from_millis(50), Duration::ZERO),
+            Err(SwarmError::InvalidSpec(_))
+        ));
+        let sweep = session_mgr.spawn_stale_task_sweep(Duration::from_millis(50), Duration::from_millis(10)).unwrap();
+        wait_until(|| async { session_mgr.task_queue.pending_len().await == 1 }).await;
+        sweep.abort();
+        let agent_status = |agents: &[AgentHandle]| agents.iter().find(|a| a.id == coder).unwrap().status;
+        assert_eq!(agent_status(&session_mgr.sessions.read().await[&session_id].agents), AgentStatus::Failed);
+
+        // The late result is discarded rather than counted or verified, and
+        // doesn't make the agent look healthy again
+        loop {
+            if let SwarmEvent::TaskCompleted { task_id, outcome, .. } = events.recv().await.unwrap() {
+                assert_eq!((task_id, outcome), (task.id, CompletionOutcome::Uncounted));
+                break;
+            }
+        }
+        assert_eq!(agent_status(&session_mgr.sessions.read().await[&session_id].agents), AgentStatus::Failed);
+        let metrics = session_mgr.get_session_status(session_id).await.unwrap().metrics;
+        assert_eq!(metrics.tasks_completed, 0);
+        assert_eq!(session_mgr.task_queue.pending_len().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_task_mid_flight() {
+        let session_mgr = make_manager_with(ModelClients::with_provider(slow(Duration::from_secs(30))));
+        let mut events = session_mgr.subscribe();
+        let session_id = session_mgr
+            .create_session("user123".to_string(), small_project(), None)
+            .await
+            .unwrap();
+        let coder = session_mgr.sessions.read().await[&session_id].agents
+            .iter()
+            .find(|a| a.role == AgentRole::Coder)
+            .unwrap()
+            .id;
+
+        let mut queued = make_task("never picked up", vec![]);
+        queued.session_id = Some(session_id);
+        session_mgr.task_queue.enqueue(queued.clone()).await.unwrap();
+        session_mgr.cancel_task(session_id, queued.id).await.unwrap();
+        assert_eq!(session_mgr.task_queue.pending_len().await, 0);
+
+        session_mgr.task_queue.enqueue(make_task("rewrite everything", vec![])).await.unwrap();
+        let task = session_mgr.task_queue.dequeue().await.unwrap();
+        session_mgr.assign_task(session_id, coder, task.clone()).await.unwrap();
+        let agent = || async {
+            session_mgr.sessions.read().await[&session_id].agents
+                .iter()
+                .find(|a| a.id == coder)
+                .cloned()
+                .unwrap()
+        };
+        wait_until(|| async { agent().await.status == AgentStatus::Working }).await;
+
+        session_mgr.cancel_task(session_id, task.id).await.unwrap();
+        // Far sooner than the 30s model call
+        wait_until(|| async { agent().await.status == AgentStatus::Idle }).await;
+        assert_eq!(agent().await.tasks_completed, 0);
+        assert_eq!(session_mgr.get_session_status(session_id).await.unwrap().metrics.tasks_completed, 0);
+        assert_eq!(session_mgr.task_queue.in_progress_len().await, 0);
+        assert!(!session_mgr.task_queue.owned_by(task.id, coder).await);
+
+        let cancelled = loop {
+            if let SwarmEvent::TaskCancelled { task_id, agent_id, .. } = events.recv().await.unwrap() {
+                break (task_id, agent_id);
+            }
+        };
+        assert_eq!(cancelled, (task.id, coder));
+
+        // Settled tasks can't be cancelled again
+        assert!(matches!(
+            session_mgr.cancel_task(session_id, task.id).await,
+            Err(SwarmError::TaskNotFound(id)) if id == task.id,
+        ));
+        assert!(matches!(
+            session_mgr.cancel_task(session_id, queued.id).await,
+            Err(SwarmError::TaskNotFound(_)),
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_weighted_sessions_share_dispatch_slots() {
+        let session_mgr = make_manager_with(ModelClients::with_provider(slow(Duration::from_millis(10))));
+        let mut sessions = vec![];
+        for (user, weight) in [("big", 3), ("small", 1)] {
+            let session_id = session_mgr.create_session(user.to_string(), small_project(), None).await.unwrap();
+            session_mgr.agent_pool.set_session_weight(session_id, weight);
+            let plan = (0..50).map(|i| make_task(&format!("{user} {i}"), vec![])).collect();
+            session_mgr.submit_plan(session_id, plan).await.unwrap();
+            sessions.push(session_id);
+        }
+        assert_eq!(session_mgr.agent_pool.session_weight(sessions[0]), 3);
+
+        assert!(matches!(session_mgr.spawn_dispatcher(1, Duration::ZERO), Err(SwarmError::InvalidSpec(_))));
+        // One slot across the pool, so the sessions compete for it
+        let dispatcher = session_mgr.spawn_dispatcher(1, Duration::from_millis(2)).unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        dispatcher.abort();
+
+        let mut completed = vec![];
+        for &session_id in &sessions {
+            completed.push(session_mgr.get_session_status(session_id).await.unwrap().metrics.tasks_completed);
+        }
+        let (big, small) = (completed[0], completed[1]);
+        assert!(small > 0, "small session starved: {completed:?}");
+        assert!(big >= 2 * small, "expected ~3:1, got {completed:?}");
+        assert!(session_mgr.task_queue.pending_len_for(sessions[0]).await > 0);
+    }
+
+    #[tokio::test]
+    async fn test_least_loaded_spreads_uneven_tasks() {
+        // Gap between the busiest and idlest coder, shortly after dispatch starts
+        async fn spread(strategy: AssignmentStrategy) -> usize {
+            let uneven = ScriptedProvider::default()
+                .on(|_, prompt| prompt.contains("long"), Reply::Slow(Duration::from_millis(400)))
+                .on(|_, _| true, Reply::Slow(Duration::from_millis(10)));
+            let session_mgr = make_manager_with(ModelClients::with_provider(Arc::new(uneven)));
+            let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+            session_mgr.set_assignment_strategy(session_id, strategy).await.unwrap();
+            let mut plan = vec![make_task("long migration", vec![])];
+            plan.extend((0..8).map(|i| make_task(&format!("quick fix {i}"), vec![])));
+            session_mgr.submit_plan(session_id, plan).await.unwrap();
+
+            let dispatcher = session_mgr.spawn_dispatcher(20, Duration::from_millis(2)).unwrap();
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            dispatcher.abort();
+
+            let agents = session_mgr.sessions.read().await[&session_id].agents.clone();
+            let coders: Vec<AgentId> = agents.iter().filter(|a| a.role == AgentRole::Coder).map(|a| a.id).collect();
+            let loads: Vec<usize> = session_mgr.agent_pool.loads(&coders).await.into_iter().flatten().collect();
+            loads.iter().max().unwrap() - loads.iter().min().unwrap()
+        }
+
+        // Round-robin stacks quick fixes behind the long task; least-loaded
+        // keeps them on the free coder
+        let round_robin = spread(AssignmentStrategy::RoundRobin).await;
+        let least_loaded = spread(AssignmentStrategy::LeastLoaded).await;
+        assert!(least_loaded <= 1, "least-loaded spread {least_loaded}");
+        assert!(least_loaded < round_robin, "least-loaded {least_loaded} vs round-robin {round_robin}");
+    }
+
+    #[tokio::test]
+    async fn test_resource_pressure_downshifts_and_restores() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let spec = ProjectSpec {
+            parallelization: ParallelizationMode::Turbo,
+            replication_count: 10,
+            ..small_project()
+        };
+        let session_id = session_mgr.create_session("user123".to_string(), spec, None).await.unwrap();
+        let agent_count = || async { session_mgr.sessions.read().await[&session_id].agents.len() };
+        let turbo = agent_count().await;
+
+        let shed = session_mgr.apply_resource_pressure(PressureLevel::High).await.unwrap();
+        let downshifted = agent_count().await;
+        assert!(downshifted < turbo, "{downshifted} agents left of {turbo}");
+        assert_eq!(shed.terminated, turbo - downshifted);
+        assert_eq!(session_mgr.effective_mode(session_id).await.unwrap(), ParallelizationMode::Batch10);
+        assert_eq!(session_mgr.get_session_status(session_id).await.unwrap().status, SessionStatus::Active);
+
+        // New sessions start at the reduced size
+        let late = session_mgr.create_session("user456".to_string(), ProjectSpec {
+            parallelization: ParallelizationMode::Turbo,
+            replication_count: 10,
+            ..small_project()
+        }, None).await.unwrap();
+        assert_eq!(session_mgr.sessions.read().await[&late].agents.len(), downshifted);
+
+        assert_eq!(session_mgr.user_usage("user123").await.agents, downshifted);
+
+        let restored = session_mgr.apply_resource_pressure(PressureLevel::Normal).await.unwrap();
+        assert_eq!(restored.spawned, 2 * (turbo - downshifted));
+        assert_eq!(agent_count().await, turbo);
+        assert_eq!(session_mgr.user_usage("user123").await.agents, turbo);
+        assert_eq!(session_mgr.sessions.read().await[&session_id].agents_starting, 0);
+        assert_eq!(session_mgr.effective_mode(session_id).await.unwrap(), ParallelizationMode::Turbo);
+    }
+
+    #[tokio::test]
+    async fn test_tasks_prefer_coders_with_required_skills() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let rustacean = session_mgr.add_agent(session_id, AgentRole::Coder, vec!["rust".to_string()]).await.unwrap();
+        let generic: Vec<AgentId> = session_mgr.sessions.read().await[&session_id].agents
+            .iter()
+            .filter(|a| a.role == AgentRole::Coder && a.skills.is_empty())
+            .map(|a| a.id)
+            .collect();
+        assert_eq!(generic.len(), 2);
+
+        // Least-loaded would otherwise pick the first generic coder
+        let mut borrowck = make_task("fix the borrow checker error", vec![]);
+        borrowck.required_skills = vec!["rust".to_string()];
+        let mut proofs = make_task("port the proofs", vec![]);
+        proofs.required_skills = vec!["coq".to_string()];
+        session_mgr.submit_plan(session_id, vec![borrowck.clone(), proofs.clone()]).await.unwrap();
+
+        let dispatcher = session_mgr.spawn_dispatcher(4, Duration::from_millis(2)).unwrap();
+        wait_until(|| async { session_mgr.collect_results(session_id).await.len() == 2 }).await;
+        dispatcher.abort();
+
+        assert_eq!(session_mgr.task_queue.get_result(borrowck.id).await.unwrap().agent_id, rustacean);
+        // Nobody knows Coq, so any coder will do
+        assert!(generic.contains(&session_mgr.task_queue.get_result(proofs.id).await.unwrap().agent_id));
+    }
+
+    #[tokio::test]
+    async fn test_tasks_needing_streaming_skip_coders_that_cannot_stream() {
+        let provider = Arc::new(ScriptedProvider::default());
+        let opus = Capabilities::of(ModelPreference::ClaudeOpus45);
+        provider.set_capabilities(ModelPreference::ClaudeOpus45, Capabilities { supports_streaming: false, ..opus });
+        let session_mgr = make_manager_with(ModelClients::with_provider(provider.clone()));
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let opus_coder = session_mgr.sessions.read().await[&session_id].agents
+            .iter()
+            .find(|a| a.role == AgentRole::Coder)
+            .map(|a| (a.id, a.capabilities))
+            .unwrap();
+        assert!(!opus_coder.1.supports_streaming);
+        assert!(opus_coder.1.supports_tools);
+
+        let mut events = session_mgr.subscribe();
+        let mut live = make_task("stream the build log", vec![]);
+        live.required_capabilities = vec![Capability::Streaming];
+        let plain = make_task("write the parser", vec![]);
+        session_mgr.submit_plan(session_id, vec![live.clone(), plain.clone()]).await.unwrap();
+
+        // Only the plain task goes out; no coder here can ever stream, so
+        // the streaming one fails rather than waiting
+        session_mgr.dispatch_ready(4).await.unwrap();
+        wait_until(|| async { session_mgr.task_queue.get_result(plain.id).await.is_some() }).await;
+        assert!(session_mgr.task_queue.get_result(live.id).await.is_none());
+        assert_eq!(session_mgr.task_queue.pending_len_for(session_id).await, 0);
+        assert!(session_mgr.task_queue.dead_letter().await.iter().any(|t| t.id == live.id));
+        let unsupported = loop {
+            if let SwarmEvent::TaskUnsupported { task_id, capability, .. } = events.recv().await.unwrap() {
+                break (task_id, capability);
+            }
+        };
+        assert_eq!(unsupported, (live.id, Capability::Streaming));
+        assert_eq!(session_mgr.get_session_status(session_id).await.unwrap().metrics.tasks_failed, 1);
+
+        let mut rerun = make_task("stream the build log again", vec![]);
+        rerun.required_capabilities = vec![Capability::Streaming];
+        session_mgr.submit_plan(session_id, vec![rerun.clone()]).await.unwrap();
+        assert!(matches!(
+            session_mgr.assign_task_to_agent(session_id, rerun.id, opus_coder.0).await,
+            Err(SwarmError::UnsupportedCapability {
+                capability: Capability::Streaming,
+                model: ModelPreference::ClaudeOpus45,
+                ..
+            })
+        ));
+
+        // Capabilities are asked at spawn, and cover the fallback models too
+        provider.set_capabilities(ModelPreference::ClaudeOpus45, opus);
+        let gpt = Capabilities::of(ModelPreference::GPT51);
+        provider.set_capabilities(ModelPreference::GPT51, Capabilities { supports_streaming: false, ..gpt });
+        let falls_back = session_mgr.add_agent(session_id, AgentRole::Coder, vec![]).await.unwrap();
+        let sessions = session_mgr.sessions.read().await;
+        assert!(!sessions[&session_id].agents.iter().find(|a| a.id == falls_back).unwrap().capabilities.supports_streaming);
+        drop(sessions);
+
+        // So a coder spawned with the whole chain streaming can run it
+        provider.set_capabilities(ModelPreference::GPT51, gpt);
+        let streamer = session_mgr.add_agent(session_id, AgentRole::Coder, vec![]).await.unwrap();
+        session_mgr.dispatch_ready(4).await.unwrap();
+        wait_until(|| async { session_mgr.task_queue.get_result(rerun.id).await.is_some() }).await;
+        assert_eq!(session_mgr.task_queue.get_result(rerun.id).await.unwrap().agent_id, streamer);
+    }
+
+    #[tokio::test]
+    async fn test_task_pinned_to_an_agent_runs_there() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let (planner, coder) = {
+            let sessions = session_mgr.sessions.read().await;
+            let agents = &sessions[&session_id].agents;
+            // Not the coder the scheduler would pick first
+            (agents[0].id, agents.iter().rfind(|a| a.role == AgentRole::Coder).unwrap().id)
+        };
+        let first = make_task("write the parser", vec![]);
+        let second = make_task("test the parser", vec![first.id]);
+        session_mgr.submit_plan(session_id, vec![first.clone(), second.clone()]).await.unwrap();
+
+        assert!(matches!(
+            session_mgr.assign_task_to_agent(session_id, first.id, planner).await,
+            Err(SwarmError::WrongRole { role: AgentRole::Planner, required: AgentRole::Coder, .. })
+        ));
+        assert!(matches!(
+            session_mgr.assign_task_to_agent(session_id, second.id, coder).await,
+            Err(SwarmError::TaskBlocked(id)) if id == second.id
+        ));
+        session_mgr.assign_task_to_agent(session_id, first.id, coder).await.unwrap();
+        assert!(matches!(
+            session_mgr.assign_task_to_agent(session_id, first.id, coder).await,
+            Err(SwarmError::AgentBusy(id)) if id == coder
+        ));
+
+        wait_until(|| async { session_mgr.task_queue.get_result(first.id).await.is_some() }).await;
+        assert_eq!(session_mgr.task_queue.get_result(first.id).await.unwrap().agent_id, coder);
+        assert!(matches!(
+            session_mgr.assign_task_to_agent(session_id, first.id, coder).await,
+            Err(SwarmError::TaskNotFound(id)) if id == first.id
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_agent_scratch_is_private_to_its_handle() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let (mut first, second) = {
+            let sessions = session_mgr.sessions.read().await;
+            let agents = &sessions[&session_id].agents;
+            (agents[1].clone(), agents[2].clone())
+        };
+
+        first.set_scratch("draft", "fn parse() {}");
+        assert_eq!(first.scratch("draft"), Some("fn parse() {}"));
+        assert_eq!(second.scratch("draft"), None);
+        // Neither the roster's copy nor a serialized handle carries it
+        let sessions = session_mgr.sessions.read().await;
+        assert_eq!(sessions[&session_id].agents[1].scratch("draft"), None);
+        let json = serde_json::to_string(&first).unwrap();
+        assert!(!json.contains("fn parse"));
+        assert_eq!(serde_json::from_str::<AgentHandle>(&json).unwrap().scratch("draft"), None);
+
+        first.clear_scratch();
+        assert_eq!(first.scratch("draft"), None);
+    }
+
+    #[tokio::test]
+    async fn test_tasks_with_one_affinity_key_share_a_coder() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        // Round-robin would otherwise alternate between the two coders
+        session_mgr.set_assignment_strategy(session_id, AssignmentStrategy::RoundRobin).await.unwrap();
+
+        let mut tasks = vec![];
+        for description in ["add login", "add logout"] {
+            let mut task = make_task(description, vec![]);
+            task.affinity_key = Some("src/auth.rs".to_string());
+            session_mgr.submit_plan(session_id, vec![task.clone()]).await.unwrap();
+            session_mgr.dispatch_ready(4).await.unwrap();
+            // Settled too, so the first coder is idle again
+            wait_until(|| async {
+                session_mgr.task_queue.get_result(task.id).await.is_some()
+                    && session_mgr.agent_pool.in_flight_total().await == 0
+            }).await;
+            tasks.push(task);
+        }
+
+        let first = session_mgr.task_queue.get_result(tasks[0].id).await.unwrap().agent_id;
+        assert_eq!(session_mgr.task_queue.get_result(tasks[1].id).await.unwrap().agent_id, first);
+        let sessions = session_mgr.sessions.read().await;
+        let coder = sessions[&session_id].agents.iter().find(|a| a.id == first).unwrap();
+        assert_eq!(coder.affinity, ["src/auth.rs"]);
+    }
+
+    #[tokio::test]
+    async fn test_warm_pool_reuses_agents_across_sessions() {
+        let clients = ModelClients::with_provider(Arc::new(ScriptedProvider::default()));
+        let session_mgr = make_manager_on(AgentPool::new(Arc::new(clients)).with_warm_pool(1));
+        let cold_starts = || {
+            let rendered = session_mgr.agent_pool.metrics.render_prometheus();
+            let line = rendered.lines().find(|l| l.starts_with("swarm_agents_cold_started_total ")).unwrap().to_string();
+            line.rsplit(' ').next().unwrap().parse::<usize>().unwrap()
+        };
+
+        let first = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let first_agents = session_mgr.sessions.read().await[&first].agents.clone();
+        session_mgr.submit_plan(first, vec![make_task("draft the schema", vec![])]).await.unwrap();
+        session_mgr.dispatch_ready(4).await.unwrap();
+        wait_until(|| async { session_mgr.collect_results(first).await.len() == 1 }).await;
+        assert_eq!(cold_starts(), first_agents.len());
+        session_mgr.destroy_session(first).await.unwrap();
+        // One per role: the second coder didn't fit
+        assert_eq!(session_mgr.agent_pool.warm_len(), first_agents.len() - 1);
+
+        let second = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        assert_eq!(cold_starts(), first_agents.len() + 1);
+        assert_eq!(session_mgr.agent_pool.warm_len(), 0);
+        let second_agents = session_mgr.sessions.read().await[&second].agents.clone();
+        let reused = second_agents.iter().filter(|a| first_agents.iter().any(|f| f.id == a.id)).count();
+        assert_eq!(reused, first_agents.len() - 1);
+        assert!(second_agents.iter().all(|a| a.tasks_completed == 0 && a.models_used.is_empty()));
+
+        // Reused agents work for their new session
+        let task = make_task("draft the API", vec![]);
+        session_mgr.submit_plan(second, vec![task.clone()]).await.unwrap();
+        session_mgr.dispatch_ready(4).await.unwrap();
+        wait_until(|| async { session_mgr.collect_results(second).await.len() == 1 }).await;
+        let result = session_mgr.task_queue.get_result(task.id).await.unwrap();
+        assert_eq!(result.session_id, second);
+        assert_eq!(session_mgr.get_session_status(second).await.unwrap().metrics.tasks_completed, 1);
+    }
+
+    #[tokio::test]
+    async fn test_sequential_ids_are_predictable() {
+        let ids: Arc<dyn IdGenerator> = Arc::new(SequentialIdGenerator::new());
+        let session_mgr = SessionManager::new(
+            Arc::new(AgentPool::new(Arc::new(ModelClients::new())).with_id_generator(ids.clone())),
+            Arc::new(StateManager::new(Arc::new(RedisClient::new()))),
+            Arc::new(TaskQueue::new(1_000).with_id_generator(ids.clone())),
+        )
+        .with_id_generator(ids.clone());
+
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        assert_eq!(session_id, Uuid::from_u128(1));
+        // Its agents take the next ids, in whatever order they were spawned
+        let mut agent_ids: Vec<_> = session_mgr.sessions.read().await[&session_id].agents.iter().map(|a| a.id).collect();
+        agent_ids.sort();
+        assert_eq!(agent_ids, (2..=6).map(Uuid::from_u128).collect::<Vec<_>>());
+        assert_eq!(session_mgr.task_queue.ids().next_id(), Uuid::from_u128(7));
+    }
+
+    #[tokio::test]
+    async fn test_heartbeats_tell_hung_agents_from_busy_ones() {
+        let model_clients = Arc::new(ModelClients::with_provider(slow(Duration::from_millis(300))));
+        assert!(matches!(
+            AgentPool::new(model_clients.clone()).with_heartbeat_interval(Duration::ZERO),
+            Err(SwarmError::InvalidSpec(_))
+        ));
+        let session_mgr = make_manager_on(
+            AgentPool::new(model_clients).with_heartbeat_interval(Duration::from_millis(10)).unwrap(),
+        );
+        let session_id = session_mgr
+            .create_session("user123".to_string(), small_project(), None)
+            .await
+            .unwrap();
+        let agents = session_mgr.sessions.read().await[&session_id].agents.clone();
+        let (busy, hung) = (agents[1].id, agents[2].id);
+
+        // One coder in a long model call, the other's task dies
+        session_mgr.assign_task(session_id, busy, make_task("long refactor", vec![])).await.unwrap();
+        session_mgr.agent_pool.running.read().await[&hung].join.abort();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+
+        let max_silence = Duration::from_millis(100);
+        assert_eq!(session_mgr.sweep_unhealthy_agents(max_silence, false).await, vec![hung]);
+        let status = session_mgr.get_session_status(session_id).await.unwrap();
+        assert_eq!((status.agents_unhealthy, status.agents_working), (1, 1));
+        let pool_agents = session_mgr.agent_pool.agents.read().await;
+        assert!(Utc::now() - pool_agents[&busy].last_heartbeat < chrono::Duration::milliseconds(100));
+        drop(pool_agents);
+
+        // Respawning swaps in a fresh coder
+        assert_eq!(session_mgr.sweep_unhealthy_agents(max_silence, true).await, vec![hung]);
+        let agents = session_mgr.sessions.read().await[&session_id].agents.clone();
+        assert!(agents.iter().all(|a| a.id != hung));
+        assert_eq!(agents[2].role, AgentRole::Coder);
+        assert_eq!(session_mgr.get_session_status(session_id).await.unwrap().agents_unhealthy, 0);
+        assert!(session_mgr.sweep_unhealthy_agents(max_silence, true).await.is_empty());
+    }
+
+    /// Once `agent_id` has left `pool`, fill its slot with a stand-in, then
+    /// let spawns through again: a respawn held back by `gate` then fails
+    async fn take_slot_of(pool: &AgentPool, agent_id: AgentId, gate: tokio::sync::MutexGuard<'_, ()>) {
+        wait_until(|| async { !pool.running.read().await.contains_key(&agent_id) }).await;
+        let (inbox, _) = mpsc::channel(1);
+        let (bindings, _) = mpsc::channel(1);
+        pool.running.write().await.insert(AgentId::new_v4(), AgentTask {
+            session_id: SessionId::new_v4(),
+            inbox,
+            in_flight: Arc::default(),
+            heartbeat: Arc::new(Heartbeat::new(Duration::from_secs(60))),
+            bindings,
+            join: tokio::spawn(std::future::pending()),
+        });
+        drop(gate);
+    }
+
+    #[tokio::test]
+    async fn test_failed_respawn_gives_back_the_agents_quota() {
+        let sizing = make_manager(Arc::new(RedisClient::new()));
+        let sized = sizing.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let roster = sizing.get_session_status(sized).await.unwrap().agent_count;
+        let session_mgr = make_manager_on(
+            AgentPool::new(Arc::new(ModelClients::new()))
+                .with_max_agents(roster)
+                .with_heartbeat_interval(Duration::from_millis(10))
+                .unwrap(),
+        );
+        let session_id = session_mgr
+            .create_session("user123".to_string(), small_project(), None)
+            .await
+            .unwrap();
+        let hung = session_mgr.sessions.read().await[&session_id].agents[1].id;
+        session_mgr.agent_pool.running.read().await[&hung].join.abort();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+
+        // Something else takes the hung agent's slot before its replacement
+        // is spawned, so the respawn fails
+        let gate = session_mgr.agent_pool.spawn_gate.lock().await;
+        let (swept, ()) = tokio::join!(
+            session_mgr.sweep_unhealthy_agents(Duration::from_millis(100), true),
+            take_slot_of(&session_mgr.agent_pool, hung, gate),
+        );
+        assert_eq!(swept, vec![hung]);
+
+        let agents = session_mgr.sessions.read().await[&session_id].agents.clone();
+        assert!(agents.iter().all(|a| a.id != hung));
+        assert_eq!(agents.len(), roster - 1);
+        assert_eq!(session_mgr.user_usage("user123").await.agents, roster - 1);
+    }
+
+    #[tokio::test]
+    async fn test_panicked_agent_fails_and_its_task_runs_elsewhere() {
+        for respawn in [false, true] {
+            let panicking = ScriptedProvider::default().on_each(|_, _| true, vec![Reply::Panic, Reply::Echo]);
+            let session_mgr = make_manager_with(ModelClients::with_provider(Arc::new(panicking)))
+                .with_respawn_on_panic(respawn);
+            let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+            let coder = session_mgr.sessions.read().await[&session_id].agents[1].id;
+            let mut events = session_mgr.subscribe();
+            let task = make_task("parse the config", vec![]);
+            session_mgr.submit_plan(session_id, vec![task.clone()]).await.unwrap();
+            let dequeued = session_mgr.task_queue.dequeue().await.unwrap();
+            session_mgr.assign_task(session_id, coder, dequeued).await.unwrap();
+
+            let mut retried = false;
+            let respawned = loop {
+                match events.recv().await.unwrap() {
+                    SwarmEvent::AgentPanicked { agent_id, message, respawned, .. } => {
+                        assert_eq!((agent_id, message.as_str()), (coder, "malformed response"));
+                        break respawned;
+                    }
+                    SwarmEvent::TaskFailed { task_id, agent_id, will_retry, .. } => {
+                        assert_eq!((task_id, agent_id, will_retry), (task.id, coder, true));
+                        retried = true;
+                    }
+                    _ => {}
+                }
+            };
+            assert!(retried);
+            let agents = session_mgr.sessions.read().await[&session_id].agents.clone();
+            if respawn {
+                let replacement = respawned.unwrap();
+                assert!(agents.iter().all(|a| a.id != coder));
+                assert!(agents.iter().any(|a| a.id == replacement && a.status == AgentStatus::Idle));
+            } else {
+                assert_eq!(respawned, None);
+                assert_eq!(agents[1].status, AgentStatus::Failed);
+                assert_eq!(session_mgr.agent_pool.agents.read().await[&coder].status, AgentStatus::Failed);
+                assert_eq!(session_mgr.get_session_status(session_id).await.unwrap().agents_unhealthy, 1);
+            }
+
+            // The reclaimed task goes to a healthy coder
+            assert_eq!(session_mgr.dispatch_ready(4).await.unwrap(), 1);
+            wait_until(|| async { session_mgr.task_queue.get_result(task.id).await.is_some() }).await;
+            let result = session_mgr.task_queue.get_result(task.id).await.unwrap();
+            assert_ne!(result.agent_id, coder);
+            assert_eq!(session_mgr.agent_pool.in_flight_total().await, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_panicked_agent_whose_respawn_fails_leaves_the_session() {
+        let sizing = make_manager(Arc::new(RedisClient::new()));
+        let sized = sizing.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let roster = sizing.get_session_status(sized).await.unwrap().agent_count;
+        let panicking = ScriptedProvider::default().on_each(|_, _| true, vec![Reply::Panic]);
+        let pool = AgentPool::new(Arc::new(ModelClients::with_provider(Arc::new(panicking)))).with_max_agents(roster);
+        let session_mgr = make_manager_on(pool).with_respawn_on_panic(true);
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let coder = session_mgr.sessions.read().await[&session_id].agents[1].id;
+        let mut events = session_mgr.subscribe();
+
+        let gate = session_mgr.agent_pool.spawn_gate.lock().await;
+        session_mgr.submit_plan(session_id, vec![make_task("parse the config", vec![])]).await.unwrap();
+        let dequeued = session_mgr.task_queue.dequeue().await.unwrap();
+        session_mgr.assign_task(session_id, coder, dequeued).await.unwrap();
+        take_slot_of(&session_mgr.agent_pool, coder, gate).await;
+
+        let respawned = loop {
+            if let SwarmEvent::AgentPanicked { respawned, .. } = events.recv().await.unwrap() {
+                break respawned;
+            }
+        };
+        assert_eq!(respawned, None);
+        let agents = session_mgr.sessions.read().await[&session_id].agents.clone();
+        assert!(agents.iter().all(|a| a.id != coder));
+        assert_eq!(session_mgr.user_usage("user123").await.agents, roster - 1);
+    }
+
+    #[tokio::test]
+    async fn test_drain_waits_for_in_flight_tasks() {
+        let session_mgr = make_manager_with(ModelClients::with_provider(slow(Duration::from_millis(200))));
+
+        // Distinct descriptions so the prompt cache can't short-circuit the delay
+        let assign_slow_task = |session_id, description: &'static str| {
+            let session_mgr = session_mgr.clone();
+            async move {
+                let coder = session_mgr.sessions.read().await[&session_id]
+                    .agents.iter()
+                    .find(|a| a.role == AgentRole::Coder)
+                    .unwrap()
+                    .id;
+                session_mgr
+                    .assign_task(session_id, coder, make_task(description, vec![]))
+                    .await
+                    .unwrap();
+                coder
+            }
+        };
+
+        // Generous timeout: the task finishes and is counted
+        let patient = session_mgr
+            .create_session("user123".to_string(), small_project(), None)
+            .await
+            .unwrap();
+        assign_slow_task(patient, "slow patient task").await;
+        let report = session_mgr
+            .drain_session(patient, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!((report.completed, report.abandoned), (1, 0));
+        assert_eq!(report.metrics.tasks_completed, 1);
+        assert!(session_mgr.get_session_status(patient).await.is_err());
+
+        // Tiny timeout: the task is abandoned
+        let hasty = session_mgr
+            .create_session("user123".to_string(), small_project(), None)
+            .await
+            .unwrap();
+        let coder = assign_slow_task(hasty, "slow hasty task").await;
+        let report = session_mgr
+            .drain_session(hasty, Duration::from_millis(10))
+            .await
+            .unwrap();
+        assert_eq!((report.completed, report.abandoned), (0, 1));
+        assert_eq!(report.metrics.tasks_completed, 0);
+
+        // Drained sessions take no further work
+        assert!(matches!(
+            session_mgr.assign_task(hasty, coder, make_task("late", vec![])).await,
+            Err(SwarmError::SessionNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_paused_session_holds_queued_tasks_until_resume() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let session_id = session_mgr
+            .create_session("user123".to_string(), small_project(), None)
+            .await
+            .unwrap();
+        let coder = session_mgr.sessions.read().await[&session_id]
+            .agents.iter()
+            .find(|a| a.role == AgentRole::Coder)
+            .unwrap()
+            .id;
+
+        session_mgr.pause_session(session_id).await.unwrap();
+        // Tasks already routed to the agent (e.g. queued just before the pause)
+        for i in 0..3 {
+            session_mgr.agent_pool
+                .assign_task(coder, make_task(&format!("queued {i}"), vec![]))
+                .await
+                .unwrap();
+        }
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let metrics = session_mgr.get_session_status(session_id).await.unwrap().metrics;
+        assert_eq!((metrics.tasks_assigned, metrics.tasks_completed), (0, 0));
+
+        session_mgr.resume_session(session_id).await.unwrap();
+        wait_until(|| async {
+            session_mgr.get_session_status(session_id).await.unwrap().metrics.tasks_completed == 3
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_progress_and_time_remaining() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let session_id = session_mgr
+            .create_session("user123".to_string(), small_project(), None)
+            .await
+            .unwrap();
+        let coder = session_mgr.sessions.read().await[&session_id].agents[1].id;
+
+        // Nothing assigned yet: 0%, not NaN, and no estimate
+        let status = session_mgr.get_session_status(session_id).await.unwrap();
+        assert_eq!(status.progress_pct, 0.0);
+        assert_eq!(status.estimated_remaining_sec, None);
+        assert_eq!(status.metrics.throughput_tasks_per_sec(), 0.0);
+
+        let reports = &session_mgr.agent_pool.reports_tx;
+        for _ in 0..4 {
+            reports.send(AgentReport::Started { session_id, agent_id: coder, verification: false }).unwrap();
+        }
+        reports.send(AgentReport::Completed {
+            session_id,
+            agent_id: coder,
+            task: Task::new("fake task", 1.0),
+            output: String::new(),
+            model: ModelPreference::ClaudeOpus45,
+            usage: TokenUsage::default(),
+            cache_hit: false,
+            throttled_sec: 0.5,
+            duration_sec: 2.0,
+        }).unwrap();
+        session_mgr
+            .submit_plan(session_id, (0..3).map(|i| make_task(&format!("t{i}"), vec![])).collect())
+            .await
+            .unwrap();
+        // Another session's backlog doesn't count toward this estimate
+        session_mgr.task_queue.enqueue(make_task("elsewhere", vec![])).await.unwrap();
+
+        wait_until(|| async {
+            session_mgr.get_session_status(session_id).await.unwrap().metrics.tasks_completed == 1
+        }).await;
+        let status = session_mgr.get_session_status(session_id).await.unwrap();
+        assert_eq!(status.progress_pct, 25.0);
+        assert_eq!(status.estimated_remaining_sec, Some(6.0));
+        assert_eq!(status.metrics.throughput_tasks_per_sec(), 0.5);
+        assert_eq!(status.metrics.rate_limited_sec, 0.5);
+    }
+
+    #[test]
+    fn test_eta_stabilizes_and_counts_down() {
+        let mut eta = EtaEstimator::default();
+        assert_eq!(eta.project(12, 1, 1), None);
+
+        // Noisy durations settling around 10s, one agent, 12 tasks to go
+        let durations = [14.0, 7.0, 12.0, 8.5, 11.0, 9.5, 10.5, 9.75, 10.25, 10.0, 10.1, 9.9];
+        let mut projections = vec![];
+        let mut swings = vec![];
+        for (done, duration) in durations.into_iter().enumerate() {
+            let before = eta.average_sec();
+            eta.observe(duration);
+            let after = eta.average_sec().unwrap();
+            swings.push(before.map_or(0.0, |avg| (after - avg).abs()));
+            projections.push(eta.project(durations.len() - done - 1, 1, 1).unwrap());
+        }
+        assert!(projections.windows(2).all(|w| w[1] < w[0]), "{projections:?}");
+        assert_eq!(projections.last(), Some(&0.0));
+        assert!(swings[swings.len() - 1] < swings[1] / 10.0, "{swings:?}");
+        assert!((eta.average_sec().unwrap() - 10.0).abs() < 0.5);
+
+        // 12 tasks on 4 agents take 3 waves, unless a chain of 6 forces more
+        let avg = eta.average_sec().unwrap();
+        assert_eq!(eta.project(12, 1, 4), Some(3.0 * avg));
+        assert_eq!(eta.project(12, 6, 4), Some(6.0 * avg));
+    }
+
+    #[tokio::test]
+    async fn test_estimate_cost_scales_with_the_run() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let small = ProjectSpec::builder("Landing page")
+            .template(TemplateType::SoftwareDev)
+            .parallelization(ParallelizationMode::Sequential)
+            .build()
+            .unwrap();
+        let huge = ProjectSpec::builder("Factory line")
+            .template(TemplateType::Manufacturing)
+            .parallelization(ParallelizationMode::Turbo)
+            .replication_count(100)
+            .build()
+            .unwrap();
+
+        let small_estimate = session_mgr.estimate_cost(&small);
+        let huge_estimate = session_mgr.estimate_cost(&huge);
+        assert!(huge_estimate.expected > small_estimate.expected);
+        assert!(small_estimate.low < small_estimate.expected && small_estimate.expected < small_estimate.high);
+
+        // 72 actions, each one Opus coder call plus one Gemini verification
+        let per_action = ModelClients::cost_of(ModelPreference::ClaudeOpus45, &AVG_USAGE_PER_ACTION)
+            + ModelClients::cost_of(ModelPreference::Gemini3Pro, &AVG_USAGE_PER_ACTION);
+        assert!((small_estimate.expected - 72.0 * per_action).abs() < 1e-9);
+        // ...worked through by a single coder
+        assert_eq!(small_estimate.estimated_duration, Duration::from_secs_f64(72.0 * AVG_SECS_PER_ACTION));
+        let absurd = ProjectSpec { replication_count: usize::MAX, parallelization: ParallelizationMode::Sequential, ..small };
+        assert_eq!(session_mgr.estimate_cost(&absurd).estimated_duration, Duration::MAX);
+
+        // Nothing was spawned
+        assert!(session_mgr.agent_pool.agents.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_plan_only_builds_the_dag_without_executing() {
+        let plan = Reply::text(r#"Here is the plan:
+                [{"id": 1, "description": "design schema", "estimated_time_min": 10, "depends_on": []},
+                 {"id": 2, "description": "build API", "estimated_time_min": 20, "depends_on": [1]},
+                 {"id": 3, "description": "write docs", "estimated_time_min": 5, "depends_on": [1]}]"#);
+        let sink = Arc::new(MemoryAuditSink::default());
+        let clients = ModelClients::with_provider(Arc::new(ScriptedProvider::default().on(|_, _| true, plan)))
+            .with_audit_sink(sink.clone());
+        let session_mgr = make_manager_with(clients);
+
+        let plan = session_mgr.plan_only(&small_project()).await.unwrap();
+        let [schema, api, docs] = &plan.tasks[..] else { panic!("expected 3 tasks: {plan:?}") };
+        assert_eq!(api.dependencies, vec![schema.id]);
+        assert_eq!(docs.dependencies, vec![schema.id]);
+        assert_eq!(plan.critical_path, vec![schema.id, api.id]);
+        assert_eq!((plan.critical_path_min, plan.total_estimated_min), (30.0, 35.0));
+        assert!(plan.cost > 0.0);
+        // The planning call is audited outside any session
+        let records = sink.0.lock().unwrap().clone();
+        assert_eq!(records.len(), 1);
+        assert_eq!((records[0].session_id, records[0].agent_id), (SessionId::nil(), AgentId::nil()));
+
+        // Nothing was spawned, queued or run
+        assert!(session_mgr.sessions.read().await.is_empty());
+        assert!(session_mgr.agent_pool.agents.read().await.is_empty());
+        assert_eq!(session_mgr.task_queue.pending_len().await, 0);
+        assert!(session_mgr.metrics().render_prometheus().contains("swarm_task_duration_seconds_count 0"));
+
+        let cyclic = r#"[{"id": 1, "description": "a", "depends_on": [2]},
+                         {"id": 2, "description": "b", "depends_on": [1]}]"#;
+        assert!(matches!(parse_plan(cyclic, &RandomIdGenerator), Err(SwarmError::CyclicDependency(_))));
+        assert!(matches!(parse_plan("no plan today", &RandomIdGenerator), Err(SwarmError::InvalidPlan(_))));
+    }
+
+    #[tokio::test]
+    async fn test_valid_plan_document_submits_as_a_dag() {
+        let json = r#"{
+            "metadata": {"author": "ops"},
+            "tasks": [
+                {"id": "schema", "description": "define the schema", "estimated_time_min": 5},
+                {"id": "parser", "description": "write the parser", "depends_on": ["schema"], "priority": "High"},
+                {"id": "docs", "description": "document it", "depends_on": ["schema", "parser"]}
+            ]
+        }"#;
+        let document = PlanDocument::from_json(json).unwrap();
+        assert_eq!(document.metadata["author"], "ops");
+        assert!(jsonschema::JSONSchema::compile(&PlanDocument::schema()).unwrap()
+            .is_valid(&serde_json::to_value(&document).unwrap()));
+
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let ids = session_mgr.submit_plan_document(session_id, &document).await.unwrap();
+        assert_eq!(session_mgr.task_queue.pending_len_for(session_id).await, 3);
+        // Only the schema task is ready; the others wait on it
+        let first = session_mgr.task_queue.dequeue().await.unwrap();
+        assert_eq!((first.id, first.estimated_time_min), (ids[0], 5.0));
+        assert!(session_mgr.task_queue.dequeue().await.is_none());
+        let parser = session_mgr.task_queue.outstanding_for(session_id).await
+            .into_iter()
+            .find(|t| t.id == ids[1])
+            .unwrap();
+        assert_eq!((parser.dependencies, parser.priority), (vec![ids[0]], TaskPriority::High));
+    }
+
+    #[test]
+    fn test_plan_document_reports_dangling_dependencies() {
+        let task = |id: &str, depends_on: &[&str], estimated_time_min| PlanTask {
+            id: id.to_string(),
+            description: format!("do {id}"),
+            estimated_time_min,
+            depends_on: depends_on.iter().map(|d| d.to_string()).collect(),
+            priority: TaskPriority::Normal,
+        };
+        let document = PlanDocument {
+            metadata: HashMap::new(),
+            tasks: vec![task("a", &[], 1.0), task("b", &["a", "ghost"], -2.0), task("a", &[], 1.0)],
+        };
+        assert_eq!(document.validate(), Err(vec![
+            PlanError::DuplicateId { id: "a".to_string() },
+            PlanError::NegativeTime { task: "b".to_string(), estimated_time_min: -2.0 },
+            PlanError::UnknownDependency { task: "b".to_string(), dependency: "ghost".to_string() },
+        ]));
+
+        // Malformed JSON fails the schema, pointing at the offending value
+        let errors = PlanDocument::from_json(r#"{"tasks": [{"id": "a", "description": 7}]}"#).unwrap_err();
+        assert!(matches!(&errors[..], [PlanError::Schema { path, .. }] if path == "/tasks/0/description"));
+        let errors = PlanDocument::from_json(r#"{"tasks": [{"id": "a", "description": "x", "depends_on": ["b"]}]}"#);
+        assert_eq!(errors, Err(vec![PlanError::UnknownDependency { task: "a".to_string(), dependency: "b".to_string() }]));
+    }
+
+    #[tokio::test]
+    async fn test_cyclic_plan_document_is_rejected_before_enqueueing() {
+        let json = r#"{"tasks": [
+            {"id": "a", "description": "a", "depends_on": ["c"]},
+            {"id": "b", "description": "b", "depends_on": ["a"]},
+            {"id": "c", "description": "c", "depends_on": ["b"]},
+            {"id": "d", "description": "d"}
+        ]}"#;
+        let errors = PlanDocument::from_json(json).unwrap_err();
+        let [PlanError::Cycle { tasks }] = &errors[..] else { panic!("{errors:?}") };
+        let mut cycle = tasks.clone();
+        cycle.sort();
+        assert_eq!(cycle, ["a", "b", "c"]);
+
+        let document: PlanDocument = serde_json::from_str(json).unwrap();
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        assert!(matches!(
+            session_mgr.submit_plan_document(session_id, &document).await,
+            Err(SwarmError::InvalidPlanDocument(errors)) if errors.len() == 1
+        ));
+        assert_eq!(session_mgr.task_queue.pending_len().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_list_sessions_filters_and_sorts() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let mut created = vec![];
+        for user in ["alice", "bob", "alice"] {
+            created.push(session_mgr.create_session(user.to_string(), small_project(), None).await.unwrap());
+            // Distinct creation times for the ordering check
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        session_mgr.pause_session(created[2]).await.unwrap();
+
+        let ids = |reports: Vec<SessionStatusReport>| reports.into_iter().map(|r| r.session_id).collect::<Vec<_>>();
+        assert_eq!(
+            ids(session_mgr.list_sessions(SessionFilter::default()).await),
+            vec![created[2], created[1], created[0]]
+        );
+
+        let alice = SessionFilter { user_id: Some("alice".to_string()), ..SessionFilter::default() };
+        assert_eq!(ids(session_mgr.list_sessions(alice.clone()).await), vec![created[2], created[0]]);
+
+        let active = SessionFilter { status: Some(SessionStatus::Active), ..SessionFilter::default() };
+        assert_eq!(ids(session_mgr.list_sessions(active).await), vec![created[1], created[0]]);
+
+        let alice_active = SessionFilter { status: Some(SessionStatus::Active), ..alice };
+        let reports = session_mgr.list_sessions(alice_active).await;
+        assert_eq!(reports.len(), 1);
+        assert_eq!((reports[0].session_id, reports[0].user_id.as_str()), (created[0], "alice"));
+        assert_eq!(reports[0].agent_count, 5);
+
+        let first_created = reports[0].created_at;
+        let newer = SessionFilter { created_after: Some(first_created), ..SessionFilter::default() };
+        assert_eq!(ids(session_mgr.list_sessions(newer).await), vec![created[2], created[1]]);
+    }
+
+    #[tokio::test]
+    async fn test_spawn_rate_brings_agents_online_gradually() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new())).with_spawn_rate(20);
+        let mut events = session_mgr.subscribe();
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+
+        // Active with its first agent, the rest still to come
+        let report = session_mgr.get_session_status(session_id).await.unwrap();
+        assert_eq!(report.status, SessionStatus::Active);
+        assert_eq!((report.agent_count, report.agents_starting), (1, 4));
+        assert_eq!(session_mgr.user_usage("user123").await.agents, 5);
+
+        let mut spawned_at = vec![];
+        while spawned_at.len() < 5 {
+            if let SwarmEvent::AgentSpawned { .. } = events.recv().await.unwrap() {
+                spawned_at.push(Instant::now());
+            }
+        }
+        // About 50ms apart, not in one burst
+        for pair in spawned_at[1..].windows(2) {
+            assert!(pair[1] - pair[0] >= Duration::from_millis(30), "{:?}", pair[1] - pair[0]);
+        }
+        let report = session_mgr.get_session_status(session_id).await.unwrap();
+        assert_eq!((report.agent_count, report.agents_starting, report.metrics.agents_spawned), (5, 0, 5));
 
-impl AgentPool {
-    pub fn new(model_clients: Arc<ModelClients>) -> Self {
-        Self {
-            agents: Arc::new(RwLock::new(HashMap::new())),
-            model_clients,
+        // Destroying a session mid-spawn stops it and frees the whole roster
+        let growing = session_mgr.create_session("user456".to_string(), small_project(), None).await.unwrap();
+        session_mgr.destroy_session(growing).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        assert_eq!(session_mgr.user_usage("user456").await, UserUsage::default());
+
+        // Sessions growing at once share the rate rather than each getting it
+        let session_mgr = make_manager(Arc::new(RedisClient::new())).with_spawn_rate(20);
+        let mut events = session_mgr.subscribe();
+        let (a, b) = tokio::join!(
+            session_mgr.create_session("alice".to_string(), small_project(), None),
+            session_mgr.create_session("bob".to_string(), small_project(), None),
+        );
+        let (a, b) = (a.unwrap(), b.unwrap());
+        let mut spawned_at = vec![];
+        while spawned_at.len() < 10 {
+            if let SwarmEvent::AgentSpawned { .. } = events.recv().await.unwrap() {
+                spawned_at.push(Instant::now());
+            }
+        }
+        // Past the two spawned inline
+        for pair in spawned_at[2..].windows(2) {
+            assert!(pair[1] - pair[0] >= Duration::from_millis(30), "{:?}", pair[1] - pair[0]);
         }
+        for session_id in [a, b] {
+            assert_eq!(session_mgr.get_session_status(session_id).await.unwrap().agent_count, 5);
+        }
+
+        // A rate too high to tick at still spawns the roster
+        let session_mgr = make_manager(Arc::new(RedisClient::new())).with_spawn_rate(u32::MAX);
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        let online = || async { session_mgr.get_session_status(session_id).await.unwrap().agent_count == 5 };
+        wait_until(online).await;
     }
 
-    pub async fn spawn_agent(
-        &self,
-        session_id: SessionId,
-        role: AgentRole,
-        model: ModelPreference,
-        shared_state: Arc<SharedState>,
-    ) -> Result<AgentHandle, SwarmError> {
-        let agent_id = AgentId::new_v4();
+    #[tokio::test]
+    async fn test_aggregate_stats_sum_the_sessions() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let mut session_ids = vec![];
+        for (i, user) in ["alice", "bob", "carol"].into_iter().enumerate() {
+            let session_id = session_mgr.create_session(user.to_string(), small_project(), None).await.unwrap();
+            let plan = (0..=i).map(|n| make_task(&format!("{user} step {n}"), vec![])).collect();
+            session_mgr.submit_plan(session_id, plan).await.unwrap();
+            session_ids.push(session_id);
+        }
+        session_mgr.pause_session(session_ids[0]).await.unwrap();
+        session_mgr.resume_session(session_ids[0]).await.unwrap();
 
-        let handle = AgentHandle {
-            id: agent_id,
-            role,
-            model,
-            status: AgentStatus::Idle,
-            tasks_completed: 0,
-            cost_incurred: 0.0,
+        let dispatcher = session_mgr.spawn_dispatcher(4, Duration::from_millis(5)).unwrap();
+        let settled = || async {
+            let reports = session_mgr.list_sessions(SessionFilter::default()).await;
+            reports.iter().map(|r| r.metrics.tasks_completed).sum::<usize>() == 6
+                && reports.iter().all(|r| r.agents_working == 0 && r.verification_backlog == 0.0)
         };
+        wait_until(settled).await;
+        dispatcher.abort();
+        session_mgr.pause_session(session_ids[2]).await.unwrap();
 
-        // Spawn async task for this agent
-        let agent_handle = handle.clone();
-        let model_clients = self.model_clients.clone();
-        
-        tokio::spawn(async move {
-            Self::agent_loop(
-                agent_handle,
-                session_id,
-                model_clients,
-                shared_state,
-            ).await;
-        });
-
-        self.agents.write().await.insert(agent_id, handle.clone());
+        let reports = session_mgr.list_sessions(SessionFilter::default()).await;
+        let stats = session_mgr.aggregate_stats(2).await;
+        assert_eq!((stats.sessions, stats.active_sessions), (3, 2));
+        assert_eq!(stats.agents.total(), reports.iter().map(|r| r.agent_count).sum::<usize>());
+        assert_eq!(stats.agents.idle, reports.iter().map(|r| r.agents_idle).sum::<usize>());
+        assert_eq!(stats.metrics.tasks_completed, 6);
+        let total_cost: f64 = reports.iter().map(|r| r.metrics.total_cost).sum();
+        assert!(total_cost > 0.0);
+        assert!((stats.metrics.total_cost - total_cost).abs() < 1e-12);
+        // The six tasks and a check of each, all within the current window
+        let window = metrics::THROUGHPUT_WINDOW_SECS as f64;
+        assert!((stats.tasks_per_sec * window - 12.0).abs() < 1e-9);
 
-        Ok(handle)
+        let mut by_cost: Vec<_> = reports.iter().map(|r| (r.session_id, r.metrics.total_cost)).collect();
+        by_cost.sort_by(|a, b| b.1.total_cmp(&a.1));
+        let top: Vec<_> = stats.top_sessions.iter().map(|s| (s.session_id, s.cost)).collect();
+        assert_eq!(top, by_cost[..2]);
+        assert_eq!(stats.top_sessions[0].user_id, "carol");
     }
 
-    async fn agent_loop(
-        mut agent: AgentHandle,
-        session_id: SessionId,
-        model_clients: Arc<ModelClients>,
-        shared_state: Arc<SharedState>,
-    ) {
-        loop {
-            // Wait for task assignment
-            // (In production: listen to message bus)
-            tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+    #[tokio::test]
+    async fn test_deadline_skips_work_that_cannot_finish_and_fails_the_session() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let mut events = session_mgr.subscribe();
+        let spec = ProjectSpec { deadline: Some(Utc::now() + chrono::Duration::milliseconds(400)), ..small_project() };
+        let session_id = session_mgr.create_session("user123".to_string(), spec, None).await.unwrap();
+        let quick = (0..2).map(|i| Task::new(format!("quick {i}"), 0.001));
+        let plan: Vec<_> = quick.chain([Task::new("ten minute job", 10.0), Task::new("endless job", f64::MAX)]).collect();
+        session_mgr.submit_plan(session_id, plan).await.unwrap();
 
-            // Execute task based on role
-            match agent.role {
-                AgentRole::Planner => {
-                    // Planning logic
-                }
-                AgentRole::Coder => {
-                    // Coding logic
-                }
-                AgentRole::Tester => {
-                    // Testing logic
-                }
-                AgentRole::Browser => {
-                    // Browser automation logic
-                }
-                AgentRole::Verifier => {
-                    // Verification logic
-                }
+        let report = session_mgr.get_session_status(session_id).await.unwrap();
+        let remaining = report.deadline_remaining_sec.unwrap();
+        assert!(remaining > 0.0 && remaining <= 0.4, "{remaining}");
+
+        let dispatcher = session_mgr.spawn_dispatcher(4, Duration::from_millis(5)).unwrap();
+        let metrics = loop {
+            if let SwarmEvent::DeadlineExceeded { session_id: id, metrics } = events.recv().await.unwrap() {
+                break (id == session_id).then_some(metrics).unwrap();
             }
+        };
+        dispatcher.abort();
+
+        // The quick tasks ran; the long ones were never started, and are
+        // dropped along with the session's agents
+        assert_eq!((metrics.tasks_completed, metrics.tasks_assigned), (2, 2));
+        let report = session_mgr.get_session_status(session_id).await.unwrap();
+        assert_eq!((report.status, report.deadline_remaining_sec), (SessionStatus::Failed, Some(0.0)));
+        assert_eq!(report.agent_count, 0);
+        assert_eq!(session_mgr.task_queue.pending_len_for(session_id).await, 0);
+        assert_eq!(session_mgr.user_usage("user123").await, UserUsage { sessions: 1, agents: 0 });
+        // Failed for good: it can be neither resumed nor paused
+        for attempt in [session_mgr.resume_session(session_id).await, session_mgr.pause_session(session_id).await] {
+            assert!(matches!(attempt, Err(SwarmError::SessionNotActive(SessionStatus::Failed))));
         }
-    }
+        assert_eq!(session_mgr.get_session_status(session_id).await.unwrap().status, SessionStatus::Failed);
+        // Destroying it afterwards releases the rest once
+        session_mgr.destroy_session(session_id).await.unwrap();
+        assert_eq!(session_mgr.user_usage("user123").await, UserUsage::default());
 
-    pub async fn terminate_agent(
-        &self,
-        agent_id: AgentId,
-    ) -> Result<(), SwarmError> {
-        self.agents.write().await.remove(&agent_id);
-        Ok(())
+        // Deadlines must lie ahead
+        let late = ProjectSpec { deadline: Some(Utc::now()), ..small_project() };
+        let err = session_mgr.create_session("user123".to_string(), late, None).await.unwrap_err();
+        assert!(matches!(err, SwarmError::InvalidSpec(_)), "{err:?}");
     }
-}
 
-// ============================================================================
-// STATE MANAGER (CRDT-based)
-// ============================================================================
+    #[tokio::test]
+    async fn test_list_sessions_filters_by_tags() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let tagged = |env: &str, team: &str| ProjectSpec {
+            tags: HashMap::from([("env".to_string(), env.to_string()), ("team".to_string(), team.to_string())]),
+            ..small_project()
+        };
+        let prod_oncology = session_mgr.create_session("alice".to_string(), tagged("prod", "oncology"), None).await.unwrap();
+        let prod_radiology = session_mgr.create_session("bob".to_string(), tagged("prod", "radiology"), None).await.unwrap();
+        let untagged = session_mgr.create_session("carol".to_string(), small_project(), None).await.unwrap();
 
-pub struct StateManager {
-    redis: Arc<RedisClient>,
-}
+        let listed = |tags: &[(&str, &str)]| {
+            let filter = SessionFilter {
+                tags: tags.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+                ..SessionFilter::default()
+            };
+            let session_mgr = &session_mgr;
+            async move {
+                let mut ids: Vec<_> = session_mgr.list_sessions(filter).await.into_iter().map(|r| r.session_id).collect();
+                ids.sort();
+                ids
+            }
+        };
+        let mut prod = vec![prod_oncology, prod_radiology];
+        prod.sort();
+        assert_eq!(listed(&[("env", "prod")]).await, prod);
+        assert_eq!(listed(&[("env", "prod"), ("team", "oncology")]).await, vec![prod_oncology]);
+        assert!(listed(&[("env", "staging")]).await.is_empty());
+        assert_eq!(listed(&[]).await.len(), 3);
 
-impl StateManager {
-    pub fn new(redis: Arc<RedisClient>) -> Self {
-        Self { redis }
+        // Retagged later, and reported with its tags
+        session_mgr.set_tags(untagged, HashMap::from([("team".to_string(), "oncology".to_string())])).await.unwrap();
+        let mut oncology = vec![prod_oncology, untagged];
+        oncology.sort();
+        assert_eq!(listed(&[("team", "oncology")]).await, oncology);
+        let report = session_mgr.get_session_status(prod_radiology).await.unwrap();
+        assert_eq!(report.tags["team"], "radiology");
     }
 
-    pub async fn create_state_space(
-        &self,
-        session_id: SessionId,
-    ) -> Result<Arc<SharedState>, SwarmError> {
-        Ok(Arc::new(SharedState {
-            session_id,
-            data: Arc::new(RwLock::new(HashMap::new())),
-        }))
+    #[tokio::test]
+    async fn test_assign_rejected_unless_active() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
+        let session_id = session_mgr
+            .create_session("user123".to_string(), small_project(), None)
+            .await
+            .unwrap();
+        let agent_id = session_mgr.sessions.read().await[&session_id].agents[0].id;
+
+        session_mgr.pause_session(session_id).await.unwrap();
+        assert!(matches!(
+            session_mgr.assign_task(session_id, agent_id, make_task("t", vec![])).await,
+            Err(SwarmError::SessionNotActive(SessionStatus::Paused))
+        ));
     }
 
-    pub async fn destroy_state_space(
-        &self,
-        session_id: SessionId,
-    ) -> Result<(), SwarmError> {
-        // Clean up Redis keys
-        Ok(())
+    fn make_task(description: &str, dependencies: Vec<TaskId>) -> Task {
+        let mut task = Task::new(description, 1.0);
+        task.dependencies = dependencies;
+        task
     }
-}
 
-pub struct SharedState {
-    session_id: SessionId,
-    data: Arc<RwLock<HashMap<String, String>>>,
-}
+    #[tokio::test]
+    async fn test_typed_state_round_trip() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct PlanFragment {
+            step: u32,
+            owner: AgentId,
+            files: Vec<String>,
+        }
 
-impl SharedState {
-    pub async fn set(&self, key: &str, value: String) -> Result<(), SwarmError> {
-        self.data.write().await.insert(key.to_string(), value);
-        Ok(())
-    }
+        let state = SharedState::new(SessionId::new_v4());
+        let fragment = PlanFragment {
+            step: 3,
+            owner: AgentId::new_v4(),
+            files: vec!["src/auth.rs".to_string(), "src/db.rs".to_string()],
+        };
+        state.set_typed("plan:3", &fragment).await.unwrap();
+        assert_eq!(state.get_typed::<PlanFragment>("plan:3").await.unwrap(), Some(fragment));
+        assert_eq!(state.get_typed::<PlanFragment>("plan:4").await.unwrap(), None);
 
-    pub async fn get(&self, key: &str) -> Result<Option<String>, SwarmError> {
-        Ok(self.data.read().await.get(key).cloned())
+        // Raw writes stay readable as strings, and decode failures name the key
+        state.set("plan:5", "not json".to_string()).await.unwrap();
+        let err = state.get_typed::<PlanFragment>("plan:5").await.unwrap_err();
+        assert!(matches!(err, SwarmError::Serialization { ref key, .. } if key == "plan:5"));
     }
-}
 
-// ============================================================================
-// TASK QUEUE (Priority DAG)
-// ============================================================================
+    #[tokio::test]
+    async fn test_compare_and_swap() {
+        let redis_backed = StateManager::new(Arc::new(RedisClient::new()))
+            .create_state_space(SessionId::new_v4())
+            .await
+            .unwrap();
+        for state in [Arc::new(SharedState::new(SessionId::new_v4())), redis_backed] {
+            assert!(state.compare_and_swap("lock", None, "coder-1".to_string()).await.unwrap());
+            assert!(!state.compare_and_swap("lock", None, "coder-2".to_string()).await.unwrap());
+            assert!(!state
+                .compare_and_swap("lock", Some("coder-2".to_string()), "coder-3".to_string())
+                .await
+                .unwrap());
+            assert!(state
+                .compare_and_swap("lock", Some("coder-1".to_string()), "coder-2".to_string())
+                .await
+                .unwrap());
+            assert_eq!(state.get("lock").await.unwrap().as_deref(), Some("coder-2"));
 
-pub struct TaskQueue {
-    pending: Arc<RwLock<Vec<Task>>>,
-    in_progress: Arc<RwLock<HashMap<TaskId, Task>>>,
-    completed: Arc<RwLock<Vec<Task>>>,
-}
+            state.set("name", "plan".to_string()).await.unwrap();
+            assert!(matches!(state.increment("name", 1).await, Err(SwarmError::StateError { .. })));
+        }
+    }
 
-impl TaskQueue {
-    pub fn new() -> Self {
-        Self {
-            pending: Arc::new(RwLock::new(Vec::new())),
-            in_progress: Arc::new(RwLock::new(HashMap::new())),
-            completed: Arc::new(RwLock::new(Vec::new())),
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_increments_are_not_lost() {
+        let redis_backed = StateManager::new(Arc::new(RedisClient::new()))
+            .create_state_space(SessionId::new_v4())
+            .await
+            .unwrap();
+        for state in [Arc::new(SharedState::new(SessionId::new_v4())), redis_backed] {
+            let workers: Vec<_> = (0..100)
+                .map(|_| {
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        for _ in 0..5 {
+                            state.increment("tasks_done", 2).await.unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for worker in workers {
+                worker.await.unwrap();
+            }
+            assert_eq!(state.get("tasks_done").await.unwrap().as_deref(), Some("1000"));
+            assert_eq!(state.increment("tasks_done", -1000).await.unwrap(), 0);
         }
     }
 
-    pub async fn enqueue(&self, task: Task) -> Result<(), SwarmError> {
-        self.pending.write().await.push(task);
-        Ok(())
+    /// Two orchestrator instances sharing one session's state through
+    /// `left` and `right` (which may be the same backend)
+    async fn shared_state_suite(left: Arc<dyn StateBackend>, right: Arc<dyn StateBackend>) {
+        let session_id = SessionId::new_v4();
+        let first = StateManager::with_backend(left);
+        let second = StateManager::with_backend(right);
+        let left = first.create_state_space(session_id).await.unwrap();
+        let right = second.create_state_space(session_id).await.unwrap();
+
+        // Local miss on `right` falls back to the backend
+        left.set("plan", "v1".to_string()).await.unwrap();
+        assert_eq!(right.get("plan").await.unwrap().as_deref(), Some("v1"));
+
+        // A newer write on `left` invalidates `right`'s cached copy
+        left.set("plan", "v2".to_string()).await.unwrap();
+        wait_until(|| async { right.get("plan").await.unwrap().as_deref() == Some("v2") }).await;
+
+        // LWW holds across instances: a stale write is rejected and the newer value kept
+        let stale = Version { timestamp: 1, agent_id: AgentId::new_v4() };
+        assert!(!right.set_versioned("plan", "old".to_string(), stale).await.unwrap());
+        assert_eq!(right.get("plan").await.unwrap().as_deref(), Some("v2"));
+
+        // Swaps and counters see each other's writes
+        assert!(left.compare_and_swap("lock", None, "left".to_string()).await.unwrap());
+        assert!(!right.compare_and_swap("lock", None, "right".to_string()).await.unwrap());
+        assert!(right.compare_and_swap("lock", Some("left".to_string()), "right".to_string()).await.unwrap());
+        assert_eq!(left.increment("done", 2).await.unwrap(), 2);
+        assert_eq!(right.increment("done", 3).await.unwrap(), 5);
+
+        first.destroy_state_space(session_id).await.unwrap();
+        let prefix = SharedState::state_prefix(session_id);
+        assert!(first.backend().scan(&prefix).await.unwrap().is_empty());
     }
 
-    pub async fn dequeue(&self) -> Option<Task> {
-        let mut pending = self.pending.write().await;
-        pending.pop()
+    #[tokio::test]
+    async fn test_shared_state_suite_on_each_backend() {
+        let memory: Arc<dyn StateBackend> = Arc::new(MemoryBackend::new());
+        shared_state_suite(memory.clone(), memory).await;
+
+        let redis: Arc<dyn StateBackend> = Arc::new(RedisBackend::new(Arc::new(RedisClient::new())));
+        shared_state_suite(redis.clone(), redis).await;
     }
-}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Task {
-    pub id: TaskId,
-    pub description: String,
-    pub estimated_time_min: f64,
-    pub dependencies: Vec<TaskId>,
-    pub assigned_to: Option<AgentId>,
-}
+    /// A `MemoryBackend` also holding hashes, as Redis did under the
+    /// layout before `StateBackend`, and recording the prefixes scanned
+    #[derive(Default)]
+    struct LegacyBackend {
+        strings: MemoryBackend,
+        hashes: std::sync::Mutex<HashMap<String, Vec<(String, String)>>>,
+        scans: std::sync::Mutex<Vec<String>>,
+    }
 
-// ============================================================================
-// MODEL CLIENTS
-// ============================================================================
+    #[async_trait]
+    impl StateBackend for LegacyBackend {
+        async fn get(&self, key: &str) -> Result<Option<String>, SwarmError> {
+            self.strings.get(key).await
+        }
 
-pub struct ModelClients {
-    // Placeholder - implement actual API clients
-}
+        async fn set(&self, key: &str, value: String) -> Result<(), SwarmError> {
+            self.strings.set(key, value).await
+        }
 
-pub struct RedisClient {
-    // Placeholder - implement actual Redis client
-}
+        async fn delete(&self, key: &str) -> Result<(), SwarmError> {
+            self.hashes.lock().unwrap().remove(key);
+            self.strings.delete(key).await
+        }
 
-// ============================================================================
-// ERROR TYPES
-// ============================================================================
+        async fn scan(&self, prefix: &str) -> Result<Vec<String>, SwarmError> {
+            self.scans.lock().unwrap().push(prefix.to_string());
+            self.strings.scan(prefix).await
+        }
 
-#[derive(Debug)]
-pub enum SwarmError {
-    SessionNotFound,
-    AgentSpawnFailed,
-    TaskExecutionFailed,
-    StateError,
-}
+        async fn cas(&self, key: &str, expected: Option<&str>, new: String) -> Result<bool, SwarmError> {
+            self.strings.cas(key, expected, new).await
+        }
 
-impl std::fmt::Display for SwarmError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            SwarmError::SessionNotFound => write!(f, "Session not found"),
-            SwarmError::AgentSpawnFailed => write!(f, "Failed to spawn agent"),
-            SwarmError::TaskExecutionFailed => write!(f, "Task execution failed"),
-            SwarmError::StateError => write!(f, "State management error"),
+        async fn hash_fields(&self, key: &str) -> Result<Vec<(String, String)>, SwarmError> {
+            Ok(self.hashes.lock().unwrap().get(key).cloned().unwrap_or_default())
         }
     }
-}
 
-impl std::error::Error for SwarmError {}
+    #[tokio::test]
+    async fn test_state_and_checkpoints_from_the_old_layout_carry_over() {
+        let backend = Arc::new(LegacyBackend::default());
+        let on_backend = || SessionManager::new(
+            Arc::new(AgentPool::new(Arc::new(ModelClients::with_provider(Arc::new(ScriptedProvider::default()))))),
+            Arc::new(StateManager::with_backend(backend.clone())),
+            Arc::new(TaskQueue::new(1_000)),
+        );
+        let session_mgr = on_backend();
+        let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+        session_mgr.checkpoint_session(session_id).await.unwrap();
 
-// ============================================================================
-// TESTS
-// ============================================================================
+        // As an earlier release left it: no index, state in one hash
+        backend.delete(&SessionManager::index_key(session_id)).await.unwrap();
+        let entry = |value: &str, timestamp| serde_json::to_string(&LwwEntry {
+            value: value.to_string(),
+            version: Version { timestamp, agent_id: SharedState::ORCHESTRATOR },
+        }).unwrap();
+        backend.hashes.lock().unwrap().insert(
+            SharedState::legacy_key(session_id),
+            vec![("plan".to_string(), entry("v1", 7)), ("owner".to_string(), entry("alice", 3))],
+        );
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        let restarted = on_backend();
+        assert_eq!(restarted.restore_all().await.unwrap(), vec![session_id]);
+        let state = restarted.sessions.read().await[&session_id].shared_state.clone();
+        assert_eq!(state.get("plan").await.unwrap().as_deref(), Some("v1"));
+        assert_eq!(state.get("owner").await.unwrap().as_deref(), Some("alice"));
+        assert!(backend.hashes.lock().unwrap().is_empty());
+        // A write after the move outranks what came from the hash
+        state.set("plan", "v2".to_string()).await.unwrap();
+        assert_eq!(state.get("plan").await.unwrap().as_deref(), Some("v2"));
+
+        // Old checkpoints are indexed once; from then on only the index is scanned
+        restarted.checkpoint_session(session_id).await.unwrap();
+        backend.scans.lock().unwrap().clear();
+        assert_eq!(on_backend().restore_all().await.unwrap(), vec![session_id]);
+        assert_eq!(*backend.scans.lock().unwrap(), vec![SESSION_INDEX_PREFIX.to_string()]);
+
+        restarted.destroy_session(session_id).await.unwrap();
+        assert!(on_backend().restore_all().await.unwrap().is_empty());
+    }
 
     #[tokio::test]
-    async fn test_session_creation() {
-        let redis = Arc::new(RedisClient {});
-        let state_manager = Arc::new(StateManager::new(redis));
-        let model_clients = Arc::new(ModelClients {});
-        let agent_pool = Arc::new(AgentPool::new(model_clients));
-        let task_queue = Arc::new(TaskQueue::new());
-        
-        let session_mgr = SessionManager::new(
-            agent_pool,
-            state_manager,
-            task_queue,
+    async fn test_restore_all_skips_a_corrupt_checkpoint() {
+        let backend: Arc<dyn StateBackend> = Arc::new(MemoryBackend::new());
+        let on_backend = || SessionManager::new(
+            Arc::new(AgentPool::new(Arc::new(ModelClients::new()))),
+            Arc::new(StateManager::with_backend(backend.clone())),
+            Arc::new(TaskQueue::new(1_000)),
         );
+        let session_mgr = on_backend();
+        let mut healthy = vec![];
+        for _ in 0..2 {
+            let session_id = session_mgr.create_session("user123".to_string(), small_project(), None).await.unwrap();
+            session_mgr.checkpoint_session(session_id).await.unwrap();
+            healthy.push(session_id);
+        }
+        let corrupt = SessionId::new_v4();
+        backend.set(&SessionManager::checkpoint_key(corrupt), "{not a session".to_string()).await.unwrap();
+        backend.set(&SessionManager::index_key(corrupt), String::new()).await.unwrap();
 
-        let project = ProjectSpec {
-            name: "Test Hospital Integration".to_string(),
-            template: TemplateType::HospitalIntegration,
-            replication_count: 100,
-            parallelization: ParallelizationMode::Turbo,
-            requires_browser: false,
-            estimated_complexity: Complexity::Medium,
+        let mut restored = on_backend().restore_all().await.unwrap();
+        restored.sort();
+        healthy.sort();
+        assert_eq!(restored, healthy);
+    }
+
+    /// Needs a server: `REDIS_URL=redis://127.0.0.1/ cargo test --features redis`
+    #[cfg(feature = "redis")]
+    #[tokio::test]
+    async fn test_redis_shared_state_across_instances() {
+        let Ok(url) = std::env::var("REDIS_URL") else {
+            eprintln!("REDIS_URL not set; skipping");
+            return;
         };
+        let connect = || async { Arc::new(RedisBackend::new(Arc::new(RedisClient::connect(&url).await.unwrap()))) };
+        shared_state_suite(connect().await, connect().await).await;
+    }
+
+    #[tokio::test]
+    async fn test_dequeue_respects_dependencies() {
+        let queue = TaskQueue::new(1_000);
+        let schema = make_task("design schema", vec![]);
+        let migrate = make_task("write migration", vec![schema.id]);
+
+        queue.enqueue(schema.clone()).await.unwrap();
+        queue.enqueue(migrate.clone()).await.unwrap();
+
+        // The migration was enqueued last but is blocked on the schema
+        assert_eq!(queue.ready_tasks().await, vec![schema.id]);
+        assert_eq!(queue.dequeue().await.unwrap().id, schema.id);
+        assert!(queue.dequeue().await.is_none());
+
+        assert!(queue.complete(schema.id).await);
+        assert_eq!(queue.ready_tasks().await, vec![migrate.id]);
+
+        // Re-enqueueing the schema blocks the migration again
+        queue.enqueue(schema.clone()).await.unwrap();
+        assert_eq!(queue.ready_tasks().await, vec![schema.id]);
+    }
+
+    #[tokio::test]
+    async fn test_is_dag_valid_detects_cycle() {
+        let queue = TaskQueue::new(1_000);
+        let mut a = make_task("a", vec![]);
+        let b = make_task("b", vec![a.id]);
+        a.dependencies.push(b.id);
+
+        queue.enqueue(a).await.unwrap();
+        assert!(queue.is_dag_valid().await.is_ok());
+
+        queue.enqueue(b).await.unwrap();
+        assert!(matches!(queue.is_dag_valid().await, Err(SwarmError::CyclicDependency(_))));
+    }
 
+    #[tokio::test]
+    async fn test_execution_waves_group_a_diamond_by_depth() {
+        let queue = TaskQueue::new(1_000);
+        let (session_id, other) = (SessionId::new_v4(), SessionId::new_v4());
+        let task = |description, dependencies| Task { session_id: Some(session_id), ..make_task(description, dependencies) };
+        // design -> (frontend, backend) -> release, plus a shortcut that
+        // mustn't pull release into an earlier wave
+        let design = task("design", vec![]);
+        let frontend = task("frontend", vec![design.id]);
+        let backend = task("backend", vec![design.id]);
+        let release = task("release", vec![frontend.id, backend.id, design.id]);
+        queue.enqueue_plan(vec![design.clone(), frontend.clone(), backend.clone(), release.clone()]).await.unwrap();
+        // Another session's plan is a DAG of its own
+        let elsewhere = Task { session_id: Some(other), ..make_task("audit", vec![]) };
+        queue.enqueue(elsewhere.clone()).await.unwrap();
+
+        let mut middle = vec![frontend.id, backend.id];
+        middle.sort();
+        let waves = vec![vec![design.id], middle, vec![release.id]];
+        assert_eq!(queue.execution_waves(session_id).await, ExecutionWaves { waves: waves.clone(), current: Some(0) });
+        assert_eq!(queue.execution_waves(other).await.waves, vec![vec![elsewhere.id]]);
+
+        let started = queue.dequeue_for(session_id).await.unwrap();
+        assert_eq!(started.id, design.id);
+        queue.complete(design.id).await;
+        assert_eq!(queue.current_wave(session_id).await, Some(1));
+        // Half the middle wave done still leaves it current
+        let started = queue.dequeue_for(session_id).await.unwrap();
+        queue.complete(started.id).await;
+        assert_eq!(queue.current_wave(session_id).await, Some(1));
+        let started = queue.dequeue_for(session_id).await.unwrap();
+        queue.complete(started.id).await;
+        assert_eq!(queue.current_wave(session_id).await, Some(2));
+
+        let started = queue.dequeue_for(session_id).await.unwrap();
+        queue.complete(started.id).await;
+        assert_eq!(queue.execution_waves(session_id).await, ExecutionWaves { waves, current: None });
+        assert_eq!(queue.current_wave(other).await, Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_submit_plan_reports_cycle_path() {
+        let session_mgr = make_manager(Arc::new(RedisClient::new()));
         let session_id = session_mgr
-            .create_session("user123".to_string(), project)
+            .create_session("user123".to_string(), small_project(), None)
             .await
             .unwrap();
 
-        let status = session_mgr
-            .get_session_status(session_id)
+        // design -> build -> test -> design
+        let mut design = make_task("design", vec![]);
+        let build = make_task("build", vec![design.id]);
+        let test = make_task("test", vec![build.id]);
+        design.dependencies.push(test.id);
+        let docs = make_task("docs", vec![design.id]);
+        let expected = [design.id, test.id, build.id];
+
+        let err = session_mgr
+            .submit_plan(session_id, vec![design, build, test, docs])
             .await
-            .unwrap();
+            .unwrap_err();
+        let SwarmError::CyclicDependency(cycle) = err else {
+            panic!("expected a cycle, got {err:?}");
+        };
+        // The walk may start anywhere on the cycle, but follows dependency order
+        let start = cycle.iter().position(|id| *id == expected[0]).unwrap();
+        let rotated: Vec<TaskId> = cycle.iter().cycle().skip(start).take(cycle.len()).copied().collect();
+        assert_eq!(rotated, expected);
 
-        assert_eq!(status.status, SessionStatus::Active);
-        assert!(status.agent_count > 0);
+        // Failing fast means nothing from the plan was enqueued
+        assert_eq!(session_mgr.task_queue.pending_len().await, 0);
+
+        let schema = make_task("schema", vec![]);
+        let migrate = make_task("migrate", vec![schema.id]);
+        session_mgr.submit_plan(session_id, vec![schema, migrate]).await.unwrap();
+        assert_eq!(session_mgr.task_queue.pending_len().await, 2);
+        assert!(session_mgr.task_queue.validate_dag().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_enqueue_batch_is_all_or_nothing() {
+        let queue = TaskQueue::new(10_000);
+        // 1,000 production lines of 10 dependent stations each
+        let mut tasks: Vec<Task> = Vec::with_capacity(10_000);
+        for i in 0..10_000 {
+            let deps = if i % 10 == 0 { vec![] } else { vec![tasks[i - 1].id] };
+            tasks.push(make_task(&format!("station {i}"), deps));
+        }
+        let ids: HashSet<TaskId> = tasks.iter().map(|t| t.id).collect();
+
+        queue.enqueue_batch(tasks).await.unwrap();
+        assert_eq!(queue.pending_len().await, 10_000);
+        let ready = queue.ready_tasks().await;
+        assert_eq!(ready.len(), 1_000);
+        assert!(ready.iter().all(|id| ids.contains(id)));
+        assert!(queue.validate_dag().await.is_ok());
+
+        // One more doesn't fit, so none of the batch goes in
+        let overflow = vec![make_task("spare", vec![]), make_task("spare", vec![])];
+        assert!(matches!(queue.enqueue_batch(overflow).await, Err(SwarmError::QueueFull)));
+        assert_eq!(queue.pending_len().await, 10_000);
+
+        // Nor when the batch loops, even with room to spare
+        let queue = TaskQueue::new(10);
+        let mut weld = make_task("weld", vec![]);
+        let paint = make_task("paint", vec![weld.id]);
+        weld.dependencies.push(paint.id);
+        let inspect = make_task("inspect", vec![]);
+        assert!(matches!(
+            queue.enqueue_batch(vec![inspect, weld, paint]).await,
+            Err(SwarmError::CyclicDependency(_))
+        ));
+        assert_eq!(queue.pending_len().await, 0);
+
+        // A loop through a whole 10,000-task chain is one error naming it all
+        let queue = TaskQueue::new(10_000);
+        let mut chain: Vec<Task> = Vec::with_capacity(10_000);
+        for i in 0..10_000 {
+            let deps = chain.last().map(|t: &Task| vec![t.id]).unwrap_or_default();
+            chain.push(make_task(&format!("station {i}"), deps));
+        }
+        let last = chain[9_999].id;
+        chain[0].dependencies.push(last);
+        let ids: HashSet<TaskId> = chain.iter().map(|t| t.id).collect();
+        let Err(SwarmError::CyclicDependency(cycle)) = queue.enqueue_batch(chain).await else {
+            panic!("expected a cycle");
+        };
+        assert_eq!(cycle.iter().copied().collect::<HashSet<_>>(), ids);
+        assert_eq!(queue.pending_len().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_session_load_follows_tasks_through_the_queue() {
+        let queue = TaskQueue::new(1_000);
+        let (session_id, other) = (SessionId::new_v4(), SessionId::new_v4());
+        let ours = |description: &str, deps: Vec<TaskId>| Task { session_id: Some(session_id), ..make_task(description, deps) };
+        let schema = ours("schema", vec![]);
+        let api = ours("api", vec![schema.id]);
+        let docs = ours("docs", vec![api.id]);
+        let mut flaky = ours("flaky", vec![]);
+        flaky.retry_policy = RetryPolicy { max_attempts: 2, base_delay_ms: 0, ..RetryPolicy::default() };
+        queue.enqueue_batch(vec![schema.clone(), api.clone(), docs.clone()]).await.unwrap();
+        queue.enqueue(flaky.clone()).await.unwrap();
+        queue.enqueue(Task { session_id: Some(other), ..make_task("elsewhere", vec![]) }).await.unwrap();
+        // Agrees with walking the queue at every step
+        let check = |pending, in_progress, critical_path| {
+            let queue = &queue;
+            async move {
+                let load = queue.load_for(session_id).await;
+                assert_eq!(load, SessionLoad { pending, in_progress, critical_path });
+                assert_eq!(queue.outstanding_for(session_id).await.len(), pending + in_progress);
+                assert_eq!(queue.pending_len_for(session_id).await, pending);
+            }
+        };
+        check(4, 0, 3).await;
+
+        assert_eq!(queue.dequeue_task(session_id, schema.id).await.unwrap().id, schema.id);
+        assert_eq!(queue.dequeue_task(session_id, flaky.id).await.unwrap().id, flaky.id);
+        check(2, 2, 3).await;
+        assert!(queue.complete(schema.id).await);
+        check(2, 1, 2).await;
+        assert!(matches!(queue.fail(flaky.id).await, Some(FailureOutcome::Retrying { .. })));
+        check(3, 0, 2).await;
+        assert!(queue.cancel(docs.id, session_id).await.is_some());
+        check(2, 0, 1).await;
+        queue.dequeue_task(session_id, api.id).await.unwrap();
+        assert_eq!(queue.reclaim_stale(Duration::ZERO).await.len(), 1);
+        check(2, 0, 1).await;
+
+        let (taken, _) = queue.hand_off(session_id).await;
+        assert_eq!(taken.len(), 2);
+        check(0, 0, 0).await;
+        assert_eq!(queue.load_for(other).await.pending, 1);
+    }
+
+    #[tokio::test]
+    async fn test_dequeue_orders_by_priority_then_duration() {
+        let queue = TaskQueue::new(1_000);
+        let mut low = make_task("polish docs", vec![]);
+        low.priority = TaskPriority::Low;
+        let mut slow = make_task("calibrate line", vec![]);
+        slow.priority = TaskPriority::Critical;
+        slow.estimated_time_min = 30.0;
+        let mut fast = make_task("order parts", vec![]);
+        fast.priority = TaskPriority::Critical;
+        fast.estimated_time_min = 5.0;
+        let mut blocked = make_task("assemble", vec![TaskId::new_v4()]);
+        blocked.priority = TaskPriority::Critical;
+
+        for task in [low.clone(), slow.clone(), blocked.clone(), fast.clone()] {
+            queue.enqueue(task).await.unwrap();
+        }
+        assert_eq!(queue.peek_priority().await, Some(TaskPriority::Critical));
+
+        let order: Vec<TaskId> = [
+            queue.dequeue().await.unwrap().id,
+            queue.dequeue().await.unwrap().id,
+            queue.dequeue().await.unwrap().id,
+        ].to_vec();
+        assert_eq!(order, vec![fast.id, slow.id, low.id]);
+
+        // Only the blocked task remains and it is never handed out
+        assert!(queue.dequeue().await.is_none());
+        assert_eq!(queue.peek_priority().await, Some(TaskPriority::Critical));
+    }
+
+    #[tokio::test]
+    async fn test_priority_aging_serves_a_low_task_through_a_high_flood() {
+        // A backlog of high-priority work topped up as fast as it drains
+        async fn run_flood(queue: &TaskQueue, rounds: usize) -> Option<usize> {
+            let mut low = make_task("polish docs", vec![]);
+            low.priority = TaskPriority::Low;
+            let low_id = queue.enqueue(low).await.unwrap();
+            let high = || {
+                let mut task = make_task("stamp part", vec![]);
+                task.priority = TaskPriority::High;
+                task
+            };
+            for _ in 0..4 {
+                queue.enqueue(high()).await.unwrap();
+            }
+            for round in 0..rounds {
+                queue.enqueue(high()).await.unwrap();
+                let task = queue.dequeue().await.unwrap();
+                queue.complete(task.id).await;
+                if task.id == low_id {
+                    return Some(round);
+                }
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            None
+        }
+
+        assert_eq!(run_flood(&TaskQueue::new(1_000), 50).await, None);
+        let aging = TaskQueue::new(1_000).with_priority_aging(Duration::from_millis(10));
+        assert!(run_flood(&aging, 500).await.is_some(), "low-priority task starved");
+
+        // Time spent blocked on a dependency doesn't count as waiting
+        let aging = TaskQueue::new(1_000).with_priority_aging(Duration::from_millis(10));
+        let anchor = make_task("pour foundation", vec![]);
+        aging.enqueue(anchor.clone()).await.unwrap();
+        aging.dequeue().await.unwrap();
+        let mut blocked = make_task("paint walls", vec![anchor.id]);
+        blocked.priority = TaskPriority::Low;
+        aging.enqueue(blocked.clone()).await.unwrap();
+        assert!(aging.dequeue().await.is_none());
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let mut urgent = make_task("fix leak", vec![]);
+        urgent.priority = TaskPriority::High;
+        aging.enqueue(urgent.clone()).await.unwrap();
+        aging.complete(anchor.id).await;
+        assert_eq!(aging.dequeue().await.unwrap().id, urgent.id);
+        assert_eq!(aging.dequeue().await.unwrap().id, blocked.id);
     }
 }
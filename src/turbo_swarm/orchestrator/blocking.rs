@@ -0,0 +1,109 @@
+//! Blocking facade over `SessionManager`, for CLI tools and scripts that
+//! don't run an async runtime of their own
+//!
+//! `BlockingSessionManager` owns a multi-threaded Tokio runtime, so agents
+//! and report processing keep running in the background between calls.
+//! Its methods block the calling thread until the call completes.
+//!
+//! It must not be used from within an async context: calling a method (or
+//! dropping the facade) on a runtime thread panics, as with
+//! `Runtime::block_on`. Async code should use `SessionManager` directly.
+
+use std::future::Future;
+use std::sync::Arc;
+
+use tokio::runtime::Runtime;
+
+use super::{
+    AgentPool, ProjectSpec, SessionId, SessionManager, SessionMetrics, SessionStatusReport, StateManager,
+    SwarmError, TaskQueue, UserId,
+};
+
+pub struct BlockingSessionManager {
+    manager: SessionManager,
+    // Declared last so the manager's handles drop before their runtime
+    runtime: Runtime,
+}
+
+impl BlockingSessionManager {
+    /// See `SessionManager::new`
+    pub fn new(
+        agent_pool: Arc<AgentPool>,
+        state_manager: Arc<StateManager>,
+        task_queue: Arc<TaskQueue>,
+    ) -> std::io::Result<Self> {
+        Self::build(|| SessionManager::new(agent_pool, state_manager, task_queue))
+    }
+
+    /// Wrap the manager returned by `build`, which runs inside the facade's
+    /// runtime so the manager can start its background tasks. Use it to
+    /// apply `SessionManager`'s `with_*` options.
+    pub fn build(build: impl FnOnce() -> SessionManager) -> std::io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+        let manager = {
+            let _entered = runtime.enter();
+            build()
+        };
+        Ok(Self { manager, runtime })
+    }
+
+    /// The wrapped manager, for methods the facade doesn't cover; run them
+    /// with `block_on`
+    pub fn manager(&self) -> &SessionManager {
+        &self.manager
+    }
+
+    /// Run `future` on the facade's runtime, blocking until it completes
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+
+    /// See `SessionManager::create_session`
+    pub fn create_session(
+        &self,
+        user_id: UserId,
+        project_spec: ProjectSpec,
+        idempotency_key: Option<String>,
+    ) -> Result<SessionId, SwarmError> {
+        self.block_on(self.manager.create_session(user_id, project_spec, idempotency_key))
+    }
+
+    /// See `SessionManager::get_session_status`
+    pub fn get_session_status(&self, session_id: SessionId) -> Result<SessionStatusReport, SwarmError> {
+        self.block_on(self.manager.get_session_status(session_id))
+    }
+
+    /// See `SessionManager::destroy_session`
+    pub fn destroy_session(&self, session_id: SessionId) -> Result<SessionMetrics, SwarmError> {
+        self.block_on(self.manager.destroy_session(session_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::turbo_swarm::orchestrator::tests::small_project;
+    use crate::turbo_swarm::orchestrator::{ModelClients, ParallelizationMode, RedisClient, SessionStatus};
+
+    #[test]
+    fn test_create_and_destroy_without_a_runtime() {
+        let sessions = BlockingSessionManager::new(
+            Arc::new(AgentPool::new(Arc::new(ModelClients::new()))),
+            Arc::new(StateManager::new(Arc::new(RedisClient::new()))),
+            Arc::new(TaskQueue::new(1_000)),
+        ).unwrap();
+        let spec = ProjectSpec {
+            name: "Scripted".to_string(),
+            parallelization: ParallelizationMode::Sequential,
+            ..small_project()
+        };
+
+        let session_id = sessions.create_session("user123".to_string(), spec, None).unwrap();
+        let status = sessions.get_session_status(session_id).unwrap();
+        assert_eq!((status.status, status.user_id.as_str()), (SessionStatus::Active, "user123"));
+        assert!(status.agent_count > 0);
+
+        sessions.destroy_session(session_id).unwrap();
+        assert!(matches!(sessions.get_session_status(session_id), Err(SwarmError::SessionNotFound)));
+    }
+}
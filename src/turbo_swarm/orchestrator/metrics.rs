@@ -0,0 +1,324 @@
+//! Prometheus metrics for the Turbo Swarm orchestrator
+//!
+//! Counters are plain atomics behind an `Arc`, so a `MetricsRegistry` is cheap
+//! to clone into an HTTP handler and `render_prometheus` never blocks the
+//! orchestrator.
+
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+
+use super::{AgentStatus, CircuitState, ModelPreference};
+
+/// Upper bounds (seconds) of the task duration histogram buckets
+const TASK_DURATION_BUCKETS: [f64; 8] = [0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0];
+
+/// Seconds of completions `tasks_per_sec` averages over
+pub const THROUGHPUT_WINDOW_SECS: usize = 30;
+
+/// Models with a provider behind them, in export order
+const MODELS: [ModelPreference; 3] = [
+    ModelPreference::GPT51,
+    ModelPreference::ClaudeOpus45,
+    ModelPreference::Gemini3Pro,
+];
+
+#[derive(Clone, Default)]
+pub struct MetricsRegistry {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    active_sessions: AtomicI64,
+    agents_idle: AtomicI64,
+    agents_working: AtomicI64,
+    /// Agent tasks started from scratch, not taken from the warm pool
+    agents_cold_started: AtomicU64,
+    total_cost: AtomicF64,
+    rate_limit_wait: AtomicF64,
+    /// Checks waiting or running per verifier, across active sessions
+    verification_backlog: AtomicF64,
+    task_duration: Histogram,
+    throughput: Throughput,
+    /// Indexed like `MODELS`
+    model_calls_in_flight: [AtomicI64; MODELS.len()],
+    /// Indexed like `MODELS`; 0 closed, 1 half-open, 2 open
+    circuit_state: [AtomicI64; MODELS.len()],
+    /// Indexed like `MODELS`; 0 until the model's first batched call
+    batch_size: [AtomicI64; MODELS.len()],
+}
+
+impl MetricsRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn session_created(&self) {
+        self.inner.active_sessions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn session_destroyed(&self) {
+        self.inner.active_sessions.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn agent_spawned(&self) {
+        self.adjust_status(AgentStatus::Idle, 1);
+    }
+
+    pub fn agent_cold_started(&self) {
+        self.inner.agents_cold_started.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn agent_terminated(&self, status: AgentStatus) {
+        self.adjust_status(status, -1);
+    }
+
+    pub fn agent_status_changed(&self, from: AgentStatus, to: AgentStatus) {
+        if from != to {
+            self.adjust_status(from, -1);
+            self.adjust_status(to, 1);
+        }
+    }
+
+    pub fn task_completed(&self, duration_sec: f64, cost: f64) {
+        self.inner.task_duration.observe(duration_sec);
+        self.inner.total_cost.add(cost);
+        self.inner.throughput.record(unix_secs());
+    }
+
+    /// Tasks completed per second, averaged over the last
+    /// `THROUGHPUT_WINDOW_SECS`
+    pub fn tasks_per_sec(&self) -> f64 {
+        self.inner.throughput.rate(unix_secs())
+    }
+
+    pub fn rate_limit_waited(&self, wait_sec: f64) {
+        self.inner.rate_limit_wait.add(wait_sec);
+    }
+
+    pub fn verification_backlog_changed(&self, backlog: f64) {
+        self.inner.verification_backlog.set(backlog);
+    }
+
+    pub fn model_call_started(&self, model: ModelPreference) {
+        self.adjust_model_calls(model, 1);
+    }
+
+    pub fn model_call_finished(&self, model: ModelPreference) {
+        self.adjust_model_calls(model, -1);
+    }
+
+    pub fn circuit_state_changed(&self, model: ModelPreference, state: CircuitState) {
+        let value = match state {
+            CircuitState::Closed => 0,
+            CircuitState::HalfOpen => 1,
+            CircuitState::Open => 2,
+        };
+        if let Some(i) = MODELS.iter().position(|m| *m == model) {
+            self.inner.circuit_state[i].store(value, Ordering::Relaxed);
+        }
+    }
+
+    pub fn batch_size_changed(&self, model: ModelPreference, size: usize) {
+        if let Some(i) = MODELS.iter().position(|m| *m == model) {
+            self.inner.batch_size[i].store(size as i64, Ordering::Relaxed);
+        }
+    }
+
+    fn adjust_model_calls(&self, model: ModelPreference, delta: i64) {
+        if let Some(i) = MODELS.iter().position(|m| *m == model) {
+            self.inner.model_calls_in_flight[i].fetch_add(delta, Ordering::Relaxed);
+        }
+    }
+
+    fn adjust_status(&self, status: AgentStatus, delta: i64) {
+        let gauge = match status {
+            AgentStatus::Idle => &self.inner.agents_idle,
+            AgentStatus::Working => &self.inner.agents_working,
+            // Not exported as gauges
+            _ => return,
+        };
+        gauge.fetch_add(delta, Ordering::Relaxed);
+    }
+
+    /// Render every metric in the Prometheus text exposition format
+    pub fn render_prometheus(&self) -> String {
+        let inner = &self.inner;
+        let mut out = String::new();
+
+        gauge(&mut out, "swarm_active_sessions", "Sessions currently held by the orchestrator",
+            inner.active_sessions.load(Ordering::Relaxed));
+        gauge(&mut out, "swarm_agents_idle", "Agents waiting for a task",
+            inner.agents_idle.load(Ordering::Relaxed));
+        gauge(&mut out, "swarm_agents_working", "Agents executing a task",
+            inner.agents_working.load(Ordering::Relaxed));
+        counter(&mut out, "swarm_agents_cold_started_total", "Agents started fresh rather than reused from the warm pool",
+            inner.agents_cold_started.load(Ordering::Relaxed));
+        gauge(&mut out, "swarm_total_cost_usd", "Model spend across all sessions",
+            inner.total_cost.get());
+        counter(&mut out, "swarm_rate_limit_wait_seconds_total", "Time agents spent queued on model rate limits",
+            inner.rate_limit_wait.get());
+        gauge(&mut out, "swarm_verification_backlog", "Checks waiting or running per verifier across active sessions",
+            inner.verification_backlog.get());
+        gauge(&mut out, "swarm_tasks_per_second", "Task completions per second over the recent window",
+            self.tasks_per_sec());
+
+        // With a concurrency limit, this is the permits in use
+        let name = "swarm_model_calls_in_flight";
+        let _ = writeln!(out, "# HELP {} Provider calls currently in flight, per model", name);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        for (model, calls) in MODELS.iter().zip(&inner.model_calls_in_flight) {
+            let _ = writeln!(out, "{}{{model=\"{:?}\"}} {}", name, model, calls.load(Ordering::Relaxed));
+        }
+
+        let name = "swarm_circuit_state";
+        let _ = writeln!(out, "# HELP {} Circuit breaker per model: 0 closed, 1 half-open, 2 open", name);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        for (model, state) in MODELS.iter().zip(&inner.circuit_state) {
+            let _ = writeln!(out, "{}{{model=\"{:?}\"}} {}", name, model, state.load(Ordering::Relaxed));
+        }
+
+        let name = "swarm_batch_size";
+        let _ = writeln!(out, "# HELP {} Tasks per call the adaptive batcher allows, per model", name);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        for (model, size) in MODELS.iter().zip(&inner.batch_size) {
+            let _ = writeln!(out, "{}{{model=\"{:?}\"}} {}", name, model, size.load(Ordering::Relaxed));
+        }
+
+        let histogram = &inner.task_duration;
+        let name = "swarm_task_duration_seconds";
+        let _ = writeln!(out, "# HELP {} Wall-clock time per completed task", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (bound, count) in TASK_DURATION_BUCKETS.iter().zip(&histogram.buckets) {
+            cumulative += count.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let count = histogram.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "{}_sum {}", name, histogram.sum.get());
+        let _ = writeln!(out, "{}_count {}", name, count);
+
+        out
+    }
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+#[derive(Default)]
+struct Histogram {
+    // Per-bucket (non-cumulative) counts; overflow only shows up in `count`
+    buckets: [AtomicU64; TASK_DURATION_BUCKETS.len()],
+    count: AtomicU64,
+    sum: AtomicF64,
+}
+
+impl Histogram {
+    fn observe(&self, value: f64) {
+        if let Some(i) = TASK_DURATION_BUCKETS.iter().position(|bound| value <= *bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.add(value);
+    }
+}
+
+/// Completions per second of the current window, one bucket per second.
+/// Each bucket packs the second it counts (high half) with its count (low
+/// half), so a bucket is reused for a new second in one atomic update.
+#[derive(Default)]
+struct Throughput {
+    buckets: [AtomicU64; THROUGHPUT_WINDOW_SECS],
+}
+
+impl Throughput {
+    fn record(&self, now: u32) {
+        let bucket = &self.buckets[now as usize % THROUGHPUT_WINDOW_SECS];
+        let _ = bucket.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |packed| {
+            let count = if (packed >> 32) as u32 == now { packed as u32 } else { 0 };
+            Some((u64::from(now) << 32) | u64::from(count.saturating_add(1)))
+        });
+    }
+
+    fn rate(&self, now: u32) -> f64 {
+        let total: u64 = self.buckets.iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .filter(|packed| now.wrapping_sub((packed >> 32) as u32) < THROUGHPUT_WINDOW_SECS as u32)
+            .map(|packed| u64::from(packed as u32))
+            .sum();
+        total as f64 / THROUGHPUT_WINDOW_SECS as f64
+    }
+}
+
+fn unix_secs() -> u32 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as u32)
+}
+
+/// f64 stored as bits so it can be updated lock-free
+#[derive(Default)]
+struct AtomicF64(AtomicU64);
+
+impl AtomicF64 {
+    fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn set(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    fn add(&self, delta: f64) {
+        let _ = self.0.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+            Some((f64::from_bits(bits) + delta).to_bits())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let registry = MetricsRegistry::new();
+        registry.task_completed(0.05, 0.01);
+        registry.task_completed(2.0, 0.02);
+        registry.task_completed(1000.0, 0.0);
+
+        let rendered = registry.render_prometheus();
+        assert!(rendered.contains("swarm_task_duration_seconds_bucket{le=\"0.1\"} 1\n"));
+        assert!(rendered.contains("swarm_task_duration_seconds_bucket{le=\"5\"} 2\n"));
+        assert!(rendered.contains("swarm_task_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(rendered.contains("swarm_task_duration_seconds_count 3\n"));
+        assert!(rendered.contains("swarm_total_cost_usd 0.03"));
+    }
+
+    #[test]
+    fn test_throughput_counts_only_the_window() {
+        let throughput = Throughput::default();
+        for _ in 0..3 {
+            throughput.record(1_000);
+        }
+        throughput.record(1_001);
+        let window = THROUGHPUT_WINDOW_SECS as f64;
+        assert_eq!(throughput.rate(1_001), 4.0 / window);
+
+        // A second's bucket is reused a window later, dropping its old count
+        throughput.record(1_000 + THROUGHPUT_WINDOW_SECS as u32);
+        assert_eq!(throughput.rate(1_000 + THROUGHPUT_WINDOW_SECS as u32), 2.0 / window);
+        assert_eq!(throughput.rate(1_001 + 2 * THROUGHPUT_WINDOW_SECS as u32), 0.0);
+    }
+}
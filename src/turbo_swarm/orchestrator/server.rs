@@ -0,0 +1,331 @@
+//! HTTP and WebSocket API over a shared `SessionManager`
+//!
+//! Routes:
+//! - `POST /sessions` creates a session from a `CreateSessionRequest`
+//! - `GET /sessions/:id` returns its `SessionStatusReport`
+//! - `POST /sessions/:id/pause` pauses it
+//! - `DELETE /sessions/:id` destroys it, returning the final metrics
+//! - `GET /sessions/:id/events` upgrades to a WebSocket streaming its
+//!   `SwarmEvent`s as JSON text frames
+//!
+//! Every request is made as the user the router's `Authenticator` finds in
+//! it, or refused with 401. Sessions are created for that user, and one
+//! user's sessions are 404 to everyone else.
+//!
+//! Errors come back as `{"error": "..."}` with a status from `status_for`.
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, HeaderName, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use super::{ProjectSpec, SessionId, SessionManager, SessionMetrics, SessionStatusReport, SwarmError, SwarmEvent, UserId};
+
+/// Who a request comes from. The API trusts nothing else about the caller:
+/// `None` refuses the request.
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    async fn authenticate(&self, headers: &HeaderMap) -> Option<UserId>;
+}
+
+/// Takes the user from a header an authenticating proxy in front of the
+/// server sets, such as `X-Forwarded-User`. Only safe when every request
+/// comes through that proxy and it drops the header from what clients send.
+pub struct TrustedHeader(pub HeaderName);
+
+#[async_trait]
+impl Authenticator for TrustedHeader {
+    async fn authenticate(&self, headers: &HeaderMap) -> Option<UserId> {
+        let user = headers.get(&self.0)?.to_str().ok()?.trim();
+        (!user.is_empty()).then(|| user.to_string())
+    }
+}
+
+/// Body of `POST /sessions`; the session belongs to the authenticated caller
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateSessionRequest {
+    pub project_spec: ProjectSpec,
+    /// Makes retried creates return the first session; see
+    /// `SessionManager::create_session`
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+}
+
+/// Body of a `201 Created` from `POST /sessions`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CreateSessionResponse {
+    pub session_id: SessionId,
+}
+
+/// What the handlers share
+#[derive(Clone)]
+struct Api {
+    manager: Arc<SessionManager>,
+    auth: Arc<dyn Authenticator>,
+}
+
+impl Api {
+    async fn caller(&self, headers: &HeaderMap) -> Result<UserId, ApiError> {
+        self.auth.authenticate(headers).await.ok_or(ApiError::Unauthenticated)
+    }
+
+    /// `session_id`'s status, if the caller owns it; another user's session
+    /// is reported missing rather than forbidden, so ids can't be probed
+    async fn owned(&self, headers: &HeaderMap, session_id: SessionId) -> Result<SessionStatusReport, ApiError> {
+        let caller = self.caller(headers).await?;
+        let report = self.manager.get_session_status(session_id).await?;
+        if report.user_id != caller {
+            return Err(SwarmError::SessionNotFound.into());
+        }
+        Ok(report)
+    }
+}
+
+/// The API's routes, serving `manager` to the callers `auth` accepts
+pub fn router(manager: Arc<SessionManager>, auth: Arc<dyn Authenticator>) -> Router {
+    Router::new()
+        .route("/sessions", post(create_session))
+        .route("/sessions/:id", get(session_status).delete(destroy_session))
+        .route("/sessions/:id/pause", post(pause_session))
+        .route("/sessions/:id/events", get(session_events))
+        .with_state(Api { manager, auth })
+}
+
+/// Serve `router(manager, auth)` on `listener` until the process stops
+pub async fn serve(
+    listener: tokio::net::TcpListener,
+    manager: Arc<SessionManager>,
+    auth: Arc<dyn Authenticator>,
+) -> std::io::Result<()> {
+    axum::serve(listener, router(manager, auth)).await
+}
+
+/// HTTP status for a failed call: missing resources are 404, exhausted
+/// quotas and rate limits 429, requests that clash with a session's state
+/// 409, model calls that timed out 504
+pub fn status_for(err: &SwarmError) -> StatusCode {
+    match err {
+        SwarmError::SessionNotFound | SwarmError::AgentNotFound | SwarmError::TaskNotFound(_) => {
+            StatusCode::NOT_FOUND
+        }
+        SwarmError::QuotaExceeded { .. } | SwarmError::RateLimited { .. } | SwarmError::QueueFull => {
+            StatusCode::TOO_MANY_REQUESTS
+        }
+        SwarmError::InvalidSpec(_)
+        | SwarmError::InvalidPlan(_)
+        | SwarmError::InvalidPlanDocument(_)
+        | SwarmError::CyclicDependency(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        SwarmError::SessionExists(_)
+        | SwarmError::SessionNotActive(_)
+        | SwarmError::UserCancelling(_)
+        | SwarmError::AgentBusy(_)
+        | SwarmError::WrongRole { .. }
+        | SwarmError::UnsupportedCapability { .. }
+        | SwarmError::TaskStarted(_)
+        | SwarmError::TaskBlocked(_)
+        | SwarmError::BudgetExceeded
+        | SwarmError::TaskBudgetExceeded { .. }
+        | SwarmError::Deadlock { .. }
+        | SwarmError::StaleMigration { .. }
+        | SwarmError::InvalidTransition { .. } => StatusCode::CONFLICT,
+        SwarmError::CircuitOpen(_) | SwarmError::PoolSaturated { .. } => StatusCode::SERVICE_UNAVAILABLE,
+        SwarmError::ModelApi { .. } | SwarmError::AllModelsFailed { .. } => StatusCode::BAD_GATEWAY,
+        SwarmError::ModelTimeout { .. } => StatusCode::GATEWAY_TIMEOUT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// A failed call on its way out as a response
+enum ApiError {
+    /// No user found in the request; 401
+    Unauthenticated,
+    Swarm(SwarmError),
+}
+
+impl From<SwarmError> for ApiError {
+    fn from(err: SwarmError) -> Self {
+        ApiError::Swarm(err)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            ApiError::Unauthenticated => (StatusCode::UNAUTHORIZED, "Not authenticated".to_string()),
+            ApiError::Swarm(err) => (status_for(&err), err.to_string()),
+        };
+        (status, Json(serde_json::json!({ "error": message }))).into_response()
+    }
+}
+
+async fn create_session(
+    State(api): State<Api>,
+    headers: HeaderMap,
+    Json(request): Json<CreateSessionRequest>,
+) -> Result<(StatusCode, Json<CreateSessionResponse>), ApiError> {
+    let user_id = api.caller(&headers).await?;
+    let CreateSessionRequest { project_spec, idempotency_key } = request;
+    let session_id = api.manager.create_session(user_id, project_spec, idempotency_key).await?;
+    Ok((StatusCode::CREATED, Json(CreateSessionResponse { session_id })))
+}
+
+async fn session_status(
+    State(api): State<Api>,
+    headers: HeaderMap,
+    Path(session_id): Path<SessionId>,
+) -> Result<Json<SessionStatusReport>, ApiError> {
+    Ok(Json(api.owned(&headers, session_id).await?))
+}
+
+async fn pause_session(
+    State(api): State<Api>,
+    headers: HeaderMap,
+    Path(session_id): Path<SessionId>,
+) -> Result<StatusCode, ApiError> {
+    api.owned(&headers, session_id).await?;
+    api.manager.pause_session(session_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn destroy_session(
+    State(api): State<Api>,
+    headers: HeaderMap,
+    Path(session_id): Path<SessionId>,
+) -> Result<Json<SessionMetrics>, ApiError> {
+    api.owned(&headers, session_id).await?;
+    Ok(Json(api.manager.destroy_session(session_id).await?))
+}
+
+async fn session_events(
+    State(api): State<Api>,
+    headers: HeaderMap,
+    Path(session_id): Path<SessionId>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, ApiError> {
+    // Strangers never get a receiver on everyone's events, even briefly
+    api.owned(&headers, session_id).await?;
+    let events = api.manager.subscribe();
+    Ok(ws.on_upgrade(move |socket| stream_events(socket, session_id, events)))
+}
+
+/// Forward `session_id`'s events until the client hangs up. A client that
+/// falls behind skips the events it missed rather than stalling others.
+async fn stream_events(mut socket: WebSocket, session_id: SessionId, mut events: broadcast::Receiver<SwarmEvent>) {
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) if event.session_id() == session_id => {
+                    let Ok(text) = serde_json::to_string(&event) else { continue };
+                    if socket.send(Message::Text(text)).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return,
+            },
+            // Only a close or a dropped connection ends the stream early
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::turbo_swarm::orchestrator::tests::small_project;
+    use crate::turbo_swarm::orchestrator::{
+        AgentPool, ModelClients, ParallelizationMode, QuotaConfig, RedisClient, SessionStatus, StateManager,
+        TaskQueue,
+    };
+    use axum::body::{Body, to_bytes};
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    fn make_manager() -> Arc<SessionManager> {
+        let model_clients = Arc::new(ModelClients::new());
+        Arc::new(SessionManager::new(
+            Arc::new(AgentPool::new(model_clients)),
+            Arc::new(StateManager::new(Arc::new(RedisClient::new()))),
+            Arc::new(TaskQueue::new(1_000)),
+        ).with_quota_config(QuotaConfig { max_concurrent_sessions_per_user: 1, ..QuotaConfig::default() }))
+    }
+
+    fn app(manager: Arc<SessionManager>) -> Router {
+        router(manager, Arc::new(TrustedHeader(HeaderName::from_static("x-forwarded-user"))))
+    }
+
+    fn create_request(user_id: &str) -> Request<Body> {
+        let body = CreateSessionRequest {
+            project_spec: ProjectSpec {
+                name: "API Test".to_string(),
+                parallelization: ParallelizationMode::Sequential,
+                ..small_project()
+            },
+            idempotency_key: None,
+        };
+        Request::post("/sessions")
+            .header("content-type", "application/json")
+            .header("x-forwarded-user", user_id)
+            .body(Body::from(serde_json::to_vec(&body).unwrap()))
+            .unwrap()
+    }
+
+    async fn json<T: serde::de::DeserializeOwned>(response: Response) -> T {
+        serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_create_then_get_status() {
+        let manager = make_manager();
+        let app = app(manager.clone());
+
+        let response = app.clone().oneshot(create_request("user123")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let CreateSessionResponse { session_id } = json(response).await;
+
+        let as_user = |request: axum::http::request::Builder, user: &str| {
+            request.header("x-forwarded-user", user).body(Body::empty()).unwrap()
+        };
+        let status = as_user(Request::get(format!("/sessions/{session_id}")), "user123");
+        let response = app.clone().oneshot(status).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let report: SessionStatusReport = json(response).await;
+        assert_eq!((report.session_id, report.status), (session_id, SessionStatus::Active));
+        assert_eq!(report.user_id, "user123");
+
+        // Errors map to their status codes
+        let response = app.clone().oneshot(create_request("user123")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let missing = as_user(Request::get(format!("/sessions/{}", SessionId::new_v4())), "user123");
+        let response = app.clone().oneshot(missing).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = json(response).await;
+        assert_eq!(body["error"], "Session not found");
+
+        // Nobody else sees or touches the session, and anonymous calls are refused
+        for request in [
+            Request::get(format!("/sessions/{session_id}")),
+            Request::post(format!("/sessions/{session_id}/pause")),
+            Request::delete(format!("/sessions/{session_id}")),
+        ] {
+            let response = app.clone().oneshot(as_user(request, "mallory")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+        let anonymous = Request::get(format!("/sessions/{session_id}")).body(Body::empty()).unwrap();
+        assert_eq!(app.clone().oneshot(anonymous).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(manager.get_session_status(session_id).await.unwrap().status, SessionStatus::Active);
+
+        let delete = as_user(Request::delete(format!("/sessions/{session_id}")), "user123");
+        assert_eq!(app.oneshot(delete).await.unwrap().status(), StatusCode::OK);
+    }
+}